@@ -0,0 +1,70 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+use crate::common::JsonWrapper;
+use crate::QuoteData;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct AgentInfo {
+    pub agent_uuid: String,
+    pub agent_name: String,
+    pub tpm_hash_alg: String,
+    pub tpm_enc_alg: String,
+    pub tpm_sign_alg: String,
+}
+
+// This is an Info request which gets some information about this keylime agent
+// It should return a AgentInfo object as JSON
+pub async fn info(
+    req: HttpRequest,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    debug!("Returning agent information");
+
+    let response = JsonWrapper::success(AgentInfo {
+        agent_uuid: data.agent_uuid.clone(),
+        agent_name: data.agent_name.clone(),
+        tpm_hash_alg: data.hash_alg.to_string(),
+        tpm_enc_alg: data.enc_alg.to_string(),
+        tpm_sign_alg: data.sign_alg.to_string(),
+    });
+
+    info!("GET info returning 200 response");
+    HttpResponse::Ok().json(response)
+}
+
+#[cfg(feature = "testing")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::API_VERSION;
+    use actix_web::{test, web, App};
+
+    #[actix_rt::test]
+    async fn test_agent_info() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{API_VERSION}/agent/info"),
+                web::get().to(info),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{API_VERSION}/agent/info"))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<AgentInfo> = test::read_body_json(resp).await;
+        assert_eq!(result.results.agent_uuid, quotedata.agent_uuid);
+        assert_eq!(result.results.agent_name, quotedata.agent_name);
+        assert_eq!(result.results.tpm_hash_alg, "sha256");
+        assert_eq!(result.results.tpm_enc_alg, "rsa");
+        assert_eq!(result.results.tpm_sign_alg, "rsassa");
+    }
+}
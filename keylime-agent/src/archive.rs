@@ -0,0 +1,305 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+// Minimal bindings to libarchive, which is already linked by compress-tools,
+// to read the entries of an archive along with their metadata, as
+// compress-tools does not expose the permissions and times of the entries
+
+use crate::{Error, Result};
+use std::{
+    ffi::{CStr, CString},
+    os::{
+        raw::{c_char, c_int, c_long, c_void},
+        unix::ffi::OsStrExt,
+    },
+    path::Path,
+    ptr, slice,
+};
+
+#[repr(C)]
+struct RawArchive {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct RawEntry {
+    _private: [u8; 0],
+}
+
+const ARCHIVE_OK: c_int = 0;
+const ARCHIVE_EOF: c_int = 1;
+const ARCHIVE_WARN: c_int = -20;
+
+const AE_IFMT: libc::mode_t = 0o170000;
+const AE_IFREG: libc::mode_t = 0o100000;
+const AE_IFDIR: libc::mode_t = 0o040000;
+const AE_IFLNK: libc::mode_t = 0o120000;
+
+// Size of the blocks read from the archive file
+const READ_BLOCK_SIZE: usize = 10240;
+
+#[link(name = "archive")]
+extern "C" {
+    fn archive_read_new() -> *mut RawArchive;
+    fn archive_read_support_filter_all(a: *mut RawArchive) -> c_int;
+    fn archive_read_support_format_all(a: *mut RawArchive) -> c_int;
+    fn archive_read_open_filename(
+        a: *mut RawArchive,
+        filename: *const c_char,
+        block_size: usize,
+    ) -> c_int;
+    fn archive_read_next_header(
+        a: *mut RawArchive,
+        entry: *mut *mut RawEntry,
+    ) -> c_int;
+    fn archive_read_data_block(
+        a: *mut RawArchive,
+        buff: *mut *const c_void,
+        size: *mut usize,
+        offset: *mut i64,
+    ) -> c_int;
+    fn archive_error_string(a: *mut RawArchive) -> *const c_char;
+    fn archive_read_free(a: *mut RawArchive) -> c_int;
+    fn archive_entry_pathname(entry: *mut RawEntry) -> *const c_char;
+    fn archive_entry_hardlink(entry: *mut RawEntry) -> *const c_char;
+    fn archive_entry_symlink(entry: *mut RawEntry) -> *const c_char;
+    fn archive_entry_filetype(entry: *mut RawEntry) -> libc::mode_t;
+    fn archive_entry_perm(entry: *mut RawEntry) -> libc::mode_t;
+    fn archive_entry_mtime_is_set(entry: *mut RawEntry) -> c_int;
+    fn archive_entry_mtime(entry: *mut RawEntry) -> libc::time_t;
+    fn archive_entry_mtime_nsec(entry: *mut RawEntry) -> c_long;
+    fn archive_entry_size_is_set(entry: *mut RawEntry) -> c_int;
+    fn archive_entry_size(entry: *mut RawEntry) -> i64;
+}
+
+/// The type of an archive entry
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum EntryKind {
+    File,
+    Directory,
+    /// A symbolic link to the given target
+    Symlink(String),
+    /// A hard link to the given entry of the archive
+    Hardlink(String),
+}
+
+/// An entry of an archive, along with the metadata stored in the archive
+#[derive(Debug)]
+pub(crate) struct Entry {
+    pub name: String,
+    pub kind: EntryKind,
+    pub perm: libc::mode_t,
+    /// The modification time, in seconds and nanoseconds since the epoch
+    pub mtime: Option<(libc::time_t, c_long)>,
+    /// The size of the data, if stored in the archive
+    pub size: Option<u64>,
+}
+
+/// Reads the entries of an archive file sequentially, in a single pass
+pub(crate) struct ArchiveReader {
+    archive: *mut RawArchive,
+}
+
+// Converts a string returned by libarchive, which may be NULL
+fn to_string(value: *const c_char) -> Option<String> {
+    if value.is_null() {
+        None
+    } else {
+        // SAFETY: the string is NUL terminated and owned by libarchive,
+        // which keeps it valid until the next call on the archive
+        Some(
+            unsafe { CStr::from_ptr(value) }
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+}
+
+impl ArchiveReader {
+    /// Opens the archive file, with any of the formats and compressions
+    /// supported by libarchive
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let filename =
+            CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+                Error::Other(format!(
+                    "Invalid archive path {}",
+                    path.display()
+                ))
+            })?;
+
+        // SAFETY: the archive is freed when the reader is dropped, and the
+        // filename is copied by libarchive
+        let reader = ArchiveReader {
+            archive: unsafe { archive_read_new() },
+        };
+        if reader.archive.is_null() {
+            return Err(Error::Other(
+                "Unable to allocate the archive reader".to_string(),
+            ));
+        }
+        unsafe {
+            if archive_read_support_filter_all(reader.archive) != ARCHIVE_OK
+                || archive_read_support_format_all(reader.archive)
+                    != ARCHIVE_OK
+                || archive_read_open_filename(
+                    reader.archive,
+                    filename.as_ptr(),
+                    READ_BLOCK_SIZE,
+                ) != ARCHIVE_OK
+            {
+                return Err(reader.error(&format!(
+                    "Unable to open archive {}",
+                    path.display()
+                )));
+            }
+        }
+        Ok(reader)
+    }
+
+    fn error(&self, context: &str) -> Error {
+        // SAFETY: the archive is valid until the reader is dropped
+        let message =
+            to_string(unsafe { archive_error_string(self.archive) })
+                .unwrap_or_else(|| "unknown error".to_string());
+        Error::Other(format!("{context}: {message}"))
+    }
+
+    /// Reads the header of the next entry, or returns None at the end of the
+    /// archive. The data of the previous entry is skipped if it was not read.
+    pub(crate) fn next_entry(&mut self) -> Result<Option<Entry>> {
+        let mut entry = ptr::null_mut();
+
+        // SAFETY: the entry is owned by libarchive and is only accessed
+        // before the next call on the archive
+        unsafe {
+            match archive_read_next_header(self.archive, &mut entry) {
+                ARCHIVE_EOF => return Ok(None),
+                ARCHIVE_OK | ARCHIVE_WARN => {}
+                _ => {
+                    return Err(
+                        self.error("Unable to read the archive entry header")
+                    )
+                }
+            }
+
+            let name = to_string(archive_entry_pathname(entry)).ok_or_else(
+                || Error::Other("Archive entry without name".to_string()),
+            )?;
+            let kind = if let Some(target) =
+                to_string(archive_entry_hardlink(entry))
+            {
+                EntryKind::Hardlink(target)
+            } else {
+                match archive_entry_filetype(entry) & AE_IFMT {
+                    AE_IFREG => EntryKind::File,
+                    AE_IFDIR => EntryKind::Directory,
+                    AE_IFLNK => EntryKind::Symlink(
+                        to_string(archive_entry_symlink(entry))
+                            .unwrap_or_default(),
+                    ),
+                    _ => {
+                        return Err(Error::Other(format!(
+                            "Unsupported type of archive entry {name}"
+                        )))
+                    }
+                }
+            };
+            let mtime = if archive_entry_mtime_is_set(entry) != 0 {
+                Some((
+                    archive_entry_mtime(entry),
+                    archive_entry_mtime_nsec(entry),
+                ))
+            } else {
+                None
+            };
+
+            let size = if archive_entry_size_is_set(entry) != 0 {
+                u64::try_from(archive_entry_size(entry)).ok()
+            } else {
+                None
+            };
+
+            Ok(Some(Entry {
+                name,
+                kind,
+                perm: archive_entry_perm(entry),
+                mtime,
+                size,
+            }))
+        }
+    }
+
+    /// Reads the whole data of the current entry, failing if it is larger
+    /// than `limit` bytes
+    pub(crate) fn read_data(&mut self, limit: usize) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.read_data_blocks(|offset, block| {
+            // The blocks of sparse files may leave holes, which are filled
+            // with zeros
+            let start = usize::try_from(offset).map_err(|_| {
+                Error::Other("Invalid archive entry data offset".to_string())
+            })?;
+            let end = start + block.len();
+            if end > limit {
+                return Err(Error::Other(format!(
+                    "Archive entry data larger than {limit} bytes"
+                )));
+            }
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[start..end].copy_from_slice(block);
+            Ok(())
+        })?;
+        Ok(data)
+    }
+
+    /// Reads the data of the current entry block by block, passing each
+    /// block to `f` along with its offset in the entry, so that the data is
+    /// never held in memory at once
+    pub(crate) fn read_data_blocks(
+        &mut self,
+        mut f: impl FnMut(u64, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        loop {
+            let mut buff = ptr::null();
+            let mut size = 0;
+            let mut offset = 0;
+
+            // SAFETY: the block is owned by libarchive and is only accessed
+            // by `f`, before the next call on the archive
+            unsafe {
+                match archive_read_data_block(
+                    self.archive,
+                    &mut buff,
+                    &mut size,
+                    &mut offset,
+                ) {
+                    ARCHIVE_EOF => return Ok(()),
+                    ARCHIVE_OK | ARCHIVE_WARN => {}
+                    _ => {
+                        return Err(self
+                            .error("Unable to read the archive entry data"))
+                    }
+                }
+                if size == 0 {
+                    continue;
+                }
+                let offset = u64::try_from(offset).map_err(|_| {
+                    Error::Other(
+                        "Invalid archive entry data offset".to_string(),
+                    )
+                })?;
+                f(offset, slice::from_raw_parts(buff as *const u8, size))?;
+            }
+        }
+    }
+}
+
+impl Drop for ArchiveReader {
+    fn drop(&mut self) {
+        // SAFETY: the archive was allocated by archive_read_new and is not
+        // used after being freed
+        _ = unsafe { archive_read_free(self.archive) };
+    }
+}
@@ -0,0 +1,157 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+use crate::{Error, Result};
+use keylime::{
+    algorithms::{HashAlgorithm, SignAlgorithm},
+    tpm,
+};
+use log::*;
+use openssl::{
+    pkey::{PKeyRef, Public},
+    rand::rand_bytes,
+};
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+use tss_esapi::handles::KeyHandle;
+
+// Length of the random nonces used for the benchmark, matching the length
+// of the nonces sent by the verifier
+const BENCH_NONCE_LEN: usize = 20;
+
+/// Timing statistics of the quotes generated by `bench_quotes`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct QuoteBenchStats {
+    pub count: u64,
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+    pub total: Duration,
+}
+
+impl QuoteBenchStats {
+    /// Returns the number of quotes generated per second
+    pub(crate) fn throughput(&self) -> f64 {
+        self.count as f64 / self.total.as_secs_f64()
+    }
+}
+
+impl fmt::Display for QuoteBenchStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "quotes: {}", self.count)?;
+        writeln!(f, "min latency: {:.3} ms", self.min.as_secs_f64() * 1e3)?;
+        writeln!(f, "max latency: {:.3} ms", self.max.as_secs_f64() * 1e3)?;
+        writeln!(f, "mean latency: {:.3} ms", self.mean.as_secs_f64() * 1e3)?;
+        write!(f, "throughput: {:.2} quotes/s", self.throughput())
+    }
+}
+
+/// Generates `count` quotes with random nonces using the same parameters
+/// as the identity quotes, and returns the latency statistics
+pub(crate) fn bench_quotes(
+    ctx: &mut tpm::Context,
+    count: u64,
+    pubkey: &PKeyRef<Public>,
+    ak_handle: KeyHandle,
+    hash_alg: HashAlgorithm,
+    sign_alg: SignAlgorithm,
+) -> Result<QuoteBenchStats> {
+    if count == 0 {
+        return Err(Error::Other(
+            "The number of quotes to benchmark must be positive".to_string(),
+        ));
+    }
+
+    let mut nonce = [0u8; BENCH_NONCE_LEN];
+    let mut min = Duration::MAX;
+    let mut max = Duration::ZERO;
+    let mut total = Duration::ZERO;
+
+    for i in 0..count {
+        rand_bytes(&mut nonce)?;
+
+        let start = Instant::now();
+        let _ = ctx.quote(
+            &nonce,
+            0,
+            pubkey,
+            ak_handle,
+            hash_alg,
+            &[hash_alg],
+            sign_alg,
+        )?;
+        let elapsed = start.elapsed();
+        debug!("Quote {} generated in {:?}", i + 1, elapsed);
+
+        min = min.min(elapsed);
+        max = max.max(elapsed);
+        total += elapsed;
+    }
+
+    Ok(QuoteBenchStats {
+        count,
+        min,
+        max,
+        mean: Duration::from_nanos((total.as_nanos() / count as u128) as u64),
+        total,
+    })
+}
+
+#[cfg(feature = "testing")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+    use keylime::algorithms::EncryptionAlgorithm;
+
+    #[test]
+    fn test_bench_quotes() {
+        let mut ctx = tpm::Context::new().unwrap(); //#[allow_ci]
+        let ek_result =
+            ctx.create_ek(EncryptionAlgorithm::Rsa, None).unwrap(); //#[allow_ci]
+        let ak = ctx
+            .create_ak(
+                ek_result.key_handle,
+                HashAlgorithm::Sha256,
+                SignAlgorithm::RsaSsa,
+            )
+            .unwrap(); //#[allow_ci]
+        let ak_handle = ctx.load_ak(ek_result.key_handle, &ak).unwrap(); //#[allow_ci]
+        let (pubkey, _) = crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+
+        let stats = bench_quotes(
+            &mut ctx,
+            3,
+            &pubkey,
+            ak_handle,
+            HashAlgorithm::Sha256,
+            SignAlgorithm::RsaSsa,
+        )
+        .unwrap(); //#[allow_ci]
+
+        assert_eq!(stats.count, 3);
+        assert!(stats.min <= stats.mean);
+        assert!(stats.mean <= stats.max);
+        assert!(stats.max <= stats.total);
+        assert!(stats.throughput() > 0.0);
+
+        let report = stats.to_string();
+        for field in
+            ["min latency", "max latency", "mean latency", "throughput"]
+        {
+            assert!(report.contains(field));
+        }
+
+        assert!(bench_quotes(
+            &mut ctx,
+            0,
+            &pubkey,
+            ak_handle,
+            HashAlgorithm::Sha256,
+            SignAlgorithm::RsaSsa,
+        )
+        .is_err());
+    }
+}
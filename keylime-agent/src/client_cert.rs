@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+use crate::common::{JsonWrapper, SUPPORTED_API_VERSIONS};
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::{
+    dev::{Extensions, ServiceRequest, ServiceResponse},
+    rt::net::TcpStream,
+    HttpResponse,
+};
+use log::*;
+use std::any::Any;
+
+/// Marker stored in the connection data when the client presented a
+/// certificate signed by the trusted CA during the TLS handshake
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ClientCertificate;
+
+/// Records in the connection data whether the client presented a
+/// certificate. Certificates that fail the verification abort the handshake,
+/// so any certificate present was verified.
+pub(crate) fn on_connect(conn: &dyn Any, ext: &mut Extensions) {
+    if let Some(tls) = conn.downcast_ref::<TlsStream<TcpStream>>() {
+        if tls.ssl().peer_certificate().is_some() {
+            _ = ext.insert(ClientCertificate);
+        }
+    }
+}
+
+/// Parses the comma separated list of endpoints that accept requests without
+/// a client certificate
+pub(crate) fn parse_optional_endpoints(endpoints: &str) -> Vec<String> {
+    endpoints
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|e| format!("/{}", e.trim_start_matches('/')))
+        .collect()
+}
+
+// The endpoints are matched with and without the API version prefix, so that
+// "/agent/info" matches "/v2.1/agent/info"
+fn is_optional(path: &str, optional_endpoints: &[String]) -> bool {
+    let unversioned = SUPPORTED_API_VERSIONS
+        .iter()
+        .find_map(|v| path.strip_prefix(&format!("/{v}")))
+        .unwrap_or(path);
+
+    optional_endpoints
+        .iter()
+        .any(|e| e == path || e == unversioned)
+}
+
+/// Rejects the requests made without a client certificate, unless the
+/// requested endpoint is in the list of optional endpoints
+pub(crate) fn check(
+    req: ServiceRequest,
+    optional_endpoints: &[String],
+) -> Result<ServiceRequest, ServiceResponse> {
+    if req.conn_data::<ClientCertificate>().is_some()
+        || is_optional(req.path(), optional_endpoints)
+    {
+        return Ok(req);
+    }
+
+    warn!(
+        "{} {} rejected: a client certificate is required",
+        req.method(),
+        req.path()
+    );
+    Err(req.into_response(HttpResponse::Forbidden().json(
+        JsonWrapper::error(
+            403,
+            "A client certificate is required for this endpoint",
+        ),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::API_VERSION;
+    use actix_web::{dev::Service, test as actix_test, web, App};
+    use futures::future::{ok, Either};
+
+    #[test]
+    fn test_parse_optional_endpoints() {
+        assert!(parse_optional_endpoints("").is_empty());
+        assert_eq!(
+            parse_optional_endpoints(" /version, agent/info ,"),
+            vec!["/version".to_string(), "/agent/info".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_is_optional() {
+        let optional = parse_optional_endpoints("/version, /agent/info");
+
+        assert!(is_optional("/version", &optional));
+        assert!(is_optional("/agent/info", &optional));
+        assert!(is_optional(
+            &format!("/{API_VERSION}/agent/info"),
+            &optional
+        ));
+        // The endpoints also match under a supported API version prefix
+        assert!(is_optional(&format!("/{API_VERSION}/version"), &optional));
+        assert!(!is_optional("/v0.1/agent/info", &optional));
+        assert!(!is_optional(
+            &format!("/{API_VERSION}/agent/info/extra"),
+            &optional
+        ));
+        assert!(!is_optional(
+            &format!("/{API_VERSION}/keys/pubkey"),
+            &optional
+        ));
+        assert!(!is_optional("/", &optional));
+    }
+
+    #[actix_rt::test]
+    async fn test_check() {
+        let optional = parse_optional_endpoints("/version");
+
+        let app = actix_test::init_service(
+            App::new()
+                .wrap_fn(move |req, srv| match check(req, &optional) {
+                    Ok(req) => Either::Left(srv.call(req)),
+                    Err(resp) => Either::Right(ok(resp)),
+                })
+                .route("/version", web::get().to(HttpResponse::Ok))
+                .route(
+                    &format!("/{API_VERSION}/keys/pubkey"),
+                    web::get().to(HttpResponse::Ok),
+                ),
+        )
+        .await;
+
+        // The test requests carry no client certificate
+        let req = actix_test::TestRequest::get().uri("/version").to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = actix_test::TestRequest::get()
+            .uri(&format!("/{API_VERSION}/keys/pubkey"))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 403);
+    }
+}
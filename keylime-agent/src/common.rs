@@ -2,7 +2,7 @@
 // Copyright 2021 Keylime Authors
 
 use crate::error::{Error, Result};
-use crate::permissions;
+use crate::{crypto, permissions, serialization};
 use keylime::algorithms::{
     EncryptionAlgorithm, HashAlgorithm, SignAlgorithm,
 };
@@ -21,9 +21,10 @@ use std::{
     env,
     ffi::CString,
     fmt::{self, Debug, Display},
-    fs::File,
+    fs::{self, File},
     path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
 use tss_esapi::structures::{Private, Public};
 use tss_esapi::traits::Marshall;
@@ -31,31 +32,47 @@ use tss_esapi::utils::PublicKey;
 use tss_esapi::{
     structures::PcrSlot, traits::UnMarshall, utils::TpmsContext,
 };
+use zeroize::{Zeroize, ZeroizeOnDrop};
 
 /*
  * Constants and static variables
  */
 pub const API_VERSION: &str = "v2.1";
+// All the API versions served, including the current API_VERSION
+pub const SUPPORTED_API_VERSIONS: &[&str] = &[API_VERSION];
 pub const TPM_DATA_PCR: usize = 16;
 pub const IMA_PCR: usize = 10;
 pub static RSA_PUBLICKEY_EXPORTABLE: &str = "rsa placeholder";
 pub static IMA_ML: &str =
     "/sys/kernel/security/ima/ascii_runtime_measurements";
+pub static IMA_BINARY_ML: &str =
+    "/sys/kernel/security/ima/binary_runtime_measurements";
 pub static MEASUREDBOOT_ML: &str =
     "/sys/kernel/security/tpm0/binary_bios_measurements";
 pub static KEY: &str = "secret";
 pub const AGENT_UUID_LEN: usize = 36;
-pub const AUTH_TAG_LEN: usize = 48;
 pub const AES_128_KEY_LEN: usize = 16;
 pub const AES_256_KEY_LEN: usize = 32;
 pub const AES_BLOCK_SIZE: usize = 16;
+pub const TLS_LOAD_ATTEMPTS: u32 = 5;
+pub const IMA_REQUESTS_RETRY_AFTER: u64 = 5;
+pub const TLS_LOAD_RETRY_DELAY_MS: u64 = 500;
+pub const KEY_EXCHANGE_TIMEOUT: u64 = 60;
+pub const STATSD_PUSH_INTERVAL: u64 = 10;
+pub const MAX_QUOTE_JOBS: usize = 64;
+pub const QUOTE_JOB_EXPIRY: u64 = 300;
+pub const TPM_ERROR_LOG_WINDOW: u64 = 60;
+pub const TPM_ERROR_LOG_FLUSH_INTERVAL: u64 = 5;
 
 cfg_if::cfg_if! {
     if #[cfg(test)] {
         // Secure mount of tpmfs (False is generally used for development environments)
         pub static MOUNT_SECURE: bool = false;
 
-        pub(crate) fn ima_ml_path_get() -> PathBuf {
+        pub(crate) fn ima_ml_path_get(ima_ml_path: &str) -> PathBuf {
+            if ima_ml_path != "default" {
+                return PathBuf::from(ima_ml_path);
+            }
             Path::new(env!("CARGO_MANIFEST_DIR"))
                 .join("test-data")
                 .join("ima")
@@ -64,8 +81,17 @@ cfg_if::cfg_if! {
     } else {
         pub static MOUNT_SECURE: bool = true;
 
-        pub(crate) fn ima_ml_path_get() -> PathBuf {
-            Path::new(IMA_ML).to_path_buf()
+        // Use the ASCII measurement list if available, falling back to the
+        // binary measurement list otherwise
+        pub(crate) fn ima_ml_path_get(ima_ml_path: &str) -> PathBuf {
+            if ima_ml_path != "default" {
+                return PathBuf::from(ima_ml_path);
+            }
+            let ascii_path = Path::new(IMA_ML);
+            if !ascii_path.exists() && Path::new(IMA_BINARY_ML).exists() {
+                return Path::new(IMA_BINARY_ML).to_path_buf();
+            }
+            ascii_path.to_path_buf()
         }
     }
 }
@@ -119,27 +145,27 @@ where
 pub type KeySet = Vec<SymmKey>;
 
 // a key of len AES_128_KEY_LEN or AES_256_KEY_LEN
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+//
+// The key is overwritten when dropped, so that it does not linger in memory
+// after the payload was decrypted. This includes the U and V key halves
+// stored in a KeySet, which are dropped once combined.
+#[derive(
+    Debug,
+    Clone,
+    Serialize,
+    Deserialize,
+    PartialEq,
+    Eq,
+    Zeroize,
+    ZeroizeOnDrop,
+)]
 pub struct SymmKey {
     bytes: Vec<u8>,
 }
 
 impl SymmKey {
     pub(crate) fn xor(&self, other: &Self) -> Result<Self> {
-        let my_bytes = self.as_ref();
-        let other_bytes = other.as_ref();
-        if my_bytes.len() != other_bytes.len() {
-            return Err(Error::Other(
-                "cannot xor differing length slices".to_string(),
-            ));
-        }
-        let mut outbuf = vec![0u8; my_bytes.len()];
-        for (out, (x, y)) in
-            outbuf.iter_mut().zip(my_bytes.iter().zip(other_bytes))
-        {
-            *out = x ^ y;
-        }
-        Ok(Self { bytes: outbuf })
+        crypto::combine_key_halves(self.as_ref(), other.as_ref())
     }
 }
 
@@ -164,9 +190,10 @@ impl TryFrom<&[u8]> for SymmKey {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct AuthTag {
     bytes: Vec<u8>,
+    hash_alg: HashAlgorithm,
 }
 
 impl AsRef<[u8]> for AuthTag {
@@ -175,19 +202,28 @@ impl AsRef<[u8]> for AuthTag {
     }
 }
 
-impl TryFrom<&[u8]> for AuthTag {
-    type Error = String;
-
-    fn try_from(v: &[u8]) -> std::result::Result<Self, Self::Error> {
+impl AuthTag {
+    // Creates an auth tag, checking its length matches the size of the HMAC
+    // computed with the given hash algorithm
+    pub fn new(
+        v: &[u8],
+        hash_alg: HashAlgorithm,
+    ) -> std::result::Result<Self, String> {
         match v.len() {
-            AUTH_TAG_LEN => {
-                Ok(AuthTag { bytes: v.to_vec() })
-            }
+            len if len == hash_alg.digest_size() => Ok(AuthTag {
+                bytes: v.to_vec(),
+                hash_alg,
+            }),
             other => Err(format!(
-                "auth tag length {other} does not correspond to valid SHA-384 HMAC",
+                "auth tag length {other} does not correspond to valid {hash_alg} HMAC",
             )),
         }
     }
+
+    // The hash algorithm of the HMAC
+    pub fn hash_alg(&self) -> HashAlgorithm {
+        self.hash_alg
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -213,14 +249,63 @@ impl From<Vec<u8>> for EncryptedData {
     }
 }
 
+/// The format used to store the agent data, set in 'agent_data_format'
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AgentDataFormat {
+    Json,
+    /// CBOR, as defined in RFC 8949
+    Cbor,
+}
+
+impl AgentDataFormat {
+    // The data stored in JSON is an object, which cannot start with '{' when
+    // stored in CBOR, as it is encoded as a map
+    fn detect(data: &[u8]) -> Self {
+        match data.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{') => AgentDataFormat::Json,
+            _ => AgentDataFormat::Cbor,
+        }
+    }
+}
+
+impl TryFrom<&str> for AgentDataFormat {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value {
+            "json" => Ok(AgentDataFormat::Json),
+            "cbor" => Ok(AgentDataFormat::Cbor),
+            other => Err(Error::Configuration(format!(
+                "Invalid agent data format '{other}' set in 'agent_data_format': use \"json\" or \"cbor\""
+            ))),
+        }
+    }
+}
+
 // TPM data and agent related that can be persisted and loaded on agent startup.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) struct AgentData {
     pub ak_hash_alg: HashAlgorithm,
     pub ak_sign_alg: SignAlgorithm,
+    #[serde(
+        serialize_with = "serialization::serialize_as_bytes",
+        deserialize_with = "serialization::deserialize_as_bytes"
+    )]
     ak_public: Vec<u8>,
+    #[serde(
+        serialize_with = "serialization::serialize_as_bytes",
+        deserialize_with = "serialization::deserialize_as_bytes"
+    )]
     ak_private: Vec<u8>,
+    #[serde(
+        serialize_with = "serialization::serialize_as_bytes",
+        deserialize_with = "serialization::deserialize_as_bytes"
+    )]
     ek_hash: Vec<u8>,
+    // The digest of the last successful registration with this AK, used to
+    // skip registering again if nothing changed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    registration_digest: Option<String>,
 }
 
 impl AgentData {
@@ -239,21 +324,73 @@ impl AgentData {
             ak_public,
             ak_private,
             ek_hash,
+            registration_digest: None,
         })
     }
 
+    /// Load the agent data stored in either format
     pub(crate) fn load(path: &Path) -> Result<Self> {
-        let file = File::open(path)?;
-        let data: Self = serde_json::from_reader(file)?;
+        let data = fs::read(path)?;
+        let data: Self = match AgentDataFormat::detect(&data) {
+            AgentDataFormat::Json => serde_json::from_slice(&data)?,
+            AgentDataFormat::Cbor => ciborium::from_reader(data.as_slice())?,
+        };
         Ok(data)
     }
 
-    pub(crate) fn store(&self, path: &Path) -> Result<()> {
-        let file = File::create(path)?;
-        serde_json::to_writer_pretty(file, self)?;
+    pub(crate) fn store(
+        &self,
+        path: &Path,
+        format: AgentDataFormat,
+    ) -> Result<()> {
+        let mut file = File::create(path)?;
+        match format {
+            AgentDataFormat::Json => {
+                serde_json::to_writer_pretty(file, self)?
+            }
+            AgentDataFormat::Cbor => ciborium::into_writer(self, file)?,
+        }
         Ok(())
     }
 
+    /// Store the agent data, or, if `tolerate_write_failure` is set, log a
+    /// warning and continue when the file cannot be written (e.g. because
+    /// it is on a read-only mount). Returns whether the data was stored.
+    pub(crate) fn store_or_tolerate(
+        &self,
+        path: &Path,
+        format: AgentDataFormat,
+        tolerate_write_failure: bool,
+    ) -> Result<bool> {
+        match self.store(path, format) {
+            Ok(()) => Ok(true),
+            Err(Error::Io(e)) if tolerate_write_failure => {
+                warn!(
+                    "Could not store agent data in {}: {}. Running in non-persistent mode, a new AK will be generated on every start",
+                    path.display(),
+                    e
+                );
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub(crate) fn registration_digest(&self) -> Option<&str> {
+        self.registration_digest.as_deref()
+    }
+
+    /// Records the digest of a successful registration, as calculated by
+    /// `registrar_agent::registration_digest`
+    pub(crate) fn set_registration_digest(&mut self, digest: Option<String>) {
+        self.registration_digest = digest;
+    }
+
+    /// Returns whether the agent was registered with the same data already
+    pub(crate) fn is_registered(&self, digest: &str) -> bool {
+        self.registration_digest.as_deref() == Some(digest)
+    }
+
     pub(crate) fn get_ak(&self) -> Result<tpm::AKResult> {
         let public = Public::unmarshall(&self.ak_public)?;
         let private = Private::try_from(self.ak_private.clone())?;
@@ -284,8 +421,7 @@ pub(crate) fn hash_ek_pubkey(ek_pub: Public) -> Result<String> {
     let pem = openssl_key.public_key_to_pem()?;
 
     // Calculate the SHA-256 hash of the public key in PEM format
-    let mut hash = hash(MessageDigest::sha256(), &pem)?;
-    Ok(hex::encode(hash))
+    Ok(keylime::crypto::hash_ek_to_uuid(&pem))
 }
 
 #[cfg(test)]
@@ -354,6 +490,67 @@ mod tests {
         Ok(())
     }
 
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_agent_data_registration() -> Result<()> {
+        let mut ctx = tpm::Context::new()?;
+        let ek_result = ctx.create_ek(EncryptionAlgorithm::Rsa, None)?;
+        let ek_hash = hash_ek_pubkey(ek_result.public)?;
+        let ak = ctx.create_ak(
+            ek_result.key_handle,
+            HashAlgorithm::Sha256,
+            SignAlgorithm::RsaSsa,
+        )?;
+
+        let mut data = AgentData::create(
+            HashAlgorithm::Sha256,
+            SignAlgorithm::RsaSsa,
+            &ak,
+            ek_hash.as_bytes(),
+        )?;
+        assert!(!data.is_registered("abcd"));
+        data.set_registration_digest(Some("abcd".to_string()));
+
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("agent_data.json");
+        data.store(&path, AgentDataFormat::Json)?;
+
+        // The restored AK is loaded instead of generating a new one, and the
+        // registration is skipped only if the registration data is the same
+        let restored = AgentData::load(&path)?;
+        assert!(restored.valid(
+            HashAlgorithm::Sha256,
+            SignAlgorithm::RsaSsa,
+            ek_hash.as_bytes()
+        ));
+        let restored_ak = restored.get_ak()?;
+        assert_eq!(restored_ak.public, ak.public);
+        assert!(ctx.load_ak(ek_result.key_handle, &restored_ak).is_ok());
+        assert!(restored.is_registered("abcd"));
+        assert!(!restored.is_registered("abce"));
+        Ok(())
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_hash_ek_to_uuid() -> Result<()> {
+        let mut ctx = tpm::Context::new()?;
+
+        let ek_result = ctx
+            .create_ek(EncryptionAlgorithm::Rsa, None)
+            .expect("Failed to create EK");
+
+        let key = SubjectPublicKeyInfo::try_from(ek_result.public.clone())?;
+        let key_der = picky_asn1_der::to_vec(&key)?;
+        let pem = PKey::public_key_from_der(&key_der)?.public_key_to_pem()?;
+
+        assert_eq!(
+            hash_ek_pubkey(ek_result.public)?,
+            keylime::crypto::hash_ek_to_uuid(&pem)
+        );
+        Ok(())
+    }
+
     #[cfg(feature = "testing")]
     #[test]
     fn test_hash() -> Result<()> {
@@ -375,4 +572,103 @@ mod tests {
         assert!(result.is_ok());
         Ok(())
     }
+
+    #[test]
+    fn test_agent_data_store_or_tolerate() {
+        let temp_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let data = AgentData {
+            ak_hash_alg: HashAlgorithm::Sha256,
+            ak_sign_alg: SignAlgorithm::RsaSsa,
+            ak_public: vec![1, 2, 3],
+            ak_private: vec![4, 5, 6],
+            ek_hash: vec![7, 8, 9],
+            registration_digest: None,
+        };
+
+        // Simulate a write failure with a path in a missing directory
+        let path = temp_dir.path().join("missing").join("agent_data.json");
+        let format = AgentDataFormat::Json;
+        assert!(data.store_or_tolerate(&path, format, false).is_err());
+        assert!(!data.store_or_tolerate(&path, format, true).unwrap()); //#[allow_ci]
+        assert!(!path.exists());
+
+        let path = temp_dir.path().join("agent_data.json");
+        assert!(data.store_or_tolerate(&path, format, true).unwrap()); //#[allow_ci]
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_agent_data_format() {
+        let temp_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let data = AgentData {
+            ak_hash_alg: HashAlgorithm::Sha256,
+            ak_sign_alg: SignAlgorithm::RsaSsa,
+            ak_public: (0..=255).collect(),
+            ak_private: vec![4, 5, 6],
+            ek_hash: b"ek hash".to_vec(),
+            registration_digest: Some("abcd".to_string()),
+        };
+
+        let json_path = temp_dir.path().join("agent_data.json");
+        data.store(&json_path, AgentDataFormat::Json).unwrap(); //#[allow_ci]
+        let cbor_path = temp_dir.path().join("agent_data.cbor");
+        data.store(&cbor_path, AgentDataFormat::Cbor).unwrap(); //#[allow_ci]
+
+        // The format is detected when loading
+        let json = fs::read(&json_path).unwrap(); //#[allow_ci]
+        let cbor = fs::read(&cbor_path).unwrap(); //#[allow_ci]
+        assert_eq!(AgentDataFormat::detect(&json), AgentDataFormat::Json);
+        assert_eq!(AgentDataFormat::detect(&cbor), AgentDataFormat::Cbor);
+        assert!(cbor.len() < json.len());
+        assert_eq!(AgentData::load(&json_path).unwrap(), data); //#[allow_ci]
+        assert_eq!(AgentData::load(&cbor_path).unwrap(), data); //#[allow_ci]
+
+        assert_eq!(
+            AgentDataFormat::try_from("cbor").unwrap(), //#[allow_ci]
+            AgentDataFormat::Cbor
+        );
+        assert!(matches!(
+            AgentDataFormat::try_from("yaml"),
+            Err(Error::Configuration(_))
+        ));
+    }
+
+    #[test]
+    fn test_auth_tag_len() {
+        let sha256_tag = [0u8; 32];
+        let sha384_tag = [0u8; 48];
+
+        assert!(AuthTag::new(&sha256_tag, HashAlgorithm::Sha256).is_ok());
+        assert!(AuthTag::new(&sha384_tag, HashAlgorithm::Sha384).is_ok());
+        assert!(AuthTag::new(&sha256_tag, HashAlgorithm::Sha384).is_err());
+        assert!(AuthTag::new(&sha384_tag, HashAlgorithm::Sha256).is_err());
+
+        let tag = AuthTag::new(&sha256_tag, HashAlgorithm::Sha256).unwrap(); //#[allow_ci]
+        assert_eq!(tag.hash_alg(), HashAlgorithm::Sha256);
+    }
+
+    // Only compiles if the type is zeroized when dropped
+    fn assert_zeroize_on_drop<T: ZeroizeOnDrop>(_: &T) {}
+
+    #[test]
+    fn test_symm_key_zeroized_on_drop() {
+        let key = SymmKey::try_from(&[0x42u8; AES_256_KEY_LEN][..]).unwrap(); //#[allow_ci]
+        let copy = key.clone();
+        assert_zeroize_on_drop(&key);
+
+        // The zeroization run on drop wipes the whole buffer, which is
+        // inspected while still allocated, before being freed
+        let mut halves: KeySet = vec![key, copy];
+        for key in halves.iter_mut() {
+            let ptr = key.as_ref().as_ptr();
+            key.zeroize();
+            assert!(key.as_ref().is_empty());
+            // SAFETY: zeroizing the key keeps its buffer allocated, and
+            // initializes the whole capacity with zeros
+            let wiped =
+                unsafe { std::slice::from_raw_parts(ptr, AES_256_KEY_LEN) };
+            assert!(wiped.iter().all(|b| *b == 0));
+        }
+        drop(halves);
+    }
 }
@@ -1,7 +1,10 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2022 Keylime Authors
 
-use crate::{error::Error, permissions, tpm};
+use crate::{
+    common::AgentDataFormat, crypto::PayloadCipherMode, error::Error,
+    permissions, tpm,
+};
 use config::{
     builder::DefaultState, Config, ConfigBuilder, ConfigError, Environment,
     File, FileFormat, Map, Source, Value,
@@ -11,14 +14,22 @@ use keylime::algorithms::{
     EncryptionAlgorithm, HashAlgorithm, SignAlgorithm,
 };
 use log::*;
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Verifier};
 use serde::{Deserialize, Serialize};
 use std::{
-    env,
+    env, fs,
+    net::IpAddr,
     path::{Path, PathBuf},
+    thread,
+    time::Duration,
 };
 use uuid::Uuid;
 
 pub static CONFIG_VERSION: &str = "2.0";
+
+// Options excluded from the configuration hash, as they contain secrets
+static CONFIG_HASH_EXCLUDED: &[&str] =
+    &["server_key_password", "tpm_ownerpassword"];
 pub static DEFAULT_UUID: &str = "d432fbb3-d2f1-4a97-9ef7-75bd81c00000";
 pub static DEFAULT_IP: &str = "127.0.0.1";
 pub static DEFAULT_PORT: u32 = 9002;
@@ -56,8 +67,85 @@ pub static DEFAULT_TPM_SIGNING_ALG: &str = "rsassa";
 pub static DEFAULT_EK_HANDLE: &str = "generate";
 pub static DEFAULT_RUN_AS: &str = "keylime:tss";
 pub static DEFAULT_AGENT_DATA_PATH: &str = "agent_data.json";
+pub static DEFAULT_AGENT_NAME: &str = "";
+pub static DEFAULT_VERIFY_TLS_ON_STARTUP: bool = true;
+pub static DEFAULT_MAX_KEYSET_SIZE: u32 = 10;
+pub static DEFAULT_TPM_SESSION_ENCRYPTION: bool = false;
+pub static DEFAULT_IMA_ML_PATH: &str = "default";
+pub static DEFAULT_ENABLE_LANDING_PAGE: bool = false;
+pub static DEFAULT_GENERATE_SELF_SIGNED_ON_FAILURE: bool = false;
+pub static DEFAULT_SYSTEM_FACTS_COMMAND: &str = "";
+pub static DEFAULT_MAX_IMA_REQUESTS: u32 = 0;
+pub static DEFAULT_WRITE_KEY_FILE: bool = true;
+pub static DEFAULT_TRANSPORT_KEY_ROTATION_INTERVAL: u64 = 0;
+pub static DEFAULT_ENABLE_DEBUG_ENDPOINTS: bool = false;
+pub static DEFAULT_CLOCK_SKEW_TOLERANCE: u64 = 60;
+pub static DEFAULT_RLIMIT_NOFILE: u64 = 0;
+pub static DEFAULT_RLIMIT_NPROC: u64 = 0;
+pub static DEFAULT_STATSD_ENDPOINT: &str = "";
+pub static DEFAULT_TOLERATE_READONLY_STATE: bool = false;
+pub static DEFAULT_ENABLE_REPROVISION: bool = false;
+pub static DEFAULT_AK_RSA_KEY_BITS: u32 = 2048;
+pub static DEFAULT_AK_ECC_CURVE: &str = "nist_p192";
+pub static DEFAULT_ASYNC_QUOTES: bool = false;
+pub static DEFAULT_REVOCATION_SELF_ONLY: bool = true;
+pub static DEFAULT_PAYLOAD_PULL_URL: &str = "";
+pub static DEFAULT_INCLUDE_FIRMWARE_VERSION: bool = false;
+pub static DEFAULT_FIRMWARE_VERSION_PCR: &str = "";
+pub static DEFAULT_PAYLOAD_UNZIP_THREADS: u32 = 1;
+pub static DEFAULT_PAYLOAD_DENIED_EXTENSIONS: &str = "";
+pub static DEFAULT_STARTUP_TIMEOUT: u64 = 0;
+pub static DEFAULT_PAYLOAD_SCRIPT_REQUIRED: bool = false;
+pub static DEFAULT_SECURE_MOUNT_UNSHARE: bool = false;
+pub static DEFAULT_CONFIG_HASH_PCR: &str = "";
+pub static DEFAULT_TSS_LOG_LEVEL: &str = "";
+pub static DEFAULT_VERIFY_REGISTRATION: bool = false;
+pub static DEFAULT_QUOTE_PCR_SELECTION: &str = "";
+pub static DEFAULT_REQUIRE_EK_CERT: bool = false;
+pub static DEFAULT_SKIP_UNCHANGED_REGISTRATION: bool = false;
+pub static DEFAULT_SECURE_MOUNT_WAIT: u64 = 0;
+pub static DEFAULT_REGISTRAR_RETRY_ATTEMPTS: u32 = 5;
+pub static DEFAULT_REGISTRAR_RETRY_INTERVAL: u64 = 1;
+pub static DEFAULT_REGISTRAR_RETRY_MAX_INTERVAL: u64 = 30;
+pub static DEFAULT_AUTO_RECOVER_ACTIVATION: bool = false;
+pub static DEFAULT_MTLS_OPTIONAL_ENDPOINTS: &str = "";
+pub static DEFAULT_ENABLE_METRICS: bool = false;
+pub static DEFAULT_NONCE_MIN_LENGTH: u32 = 1;
+pub static DEFAULT_NONCE_MAX_LENGTH: u32 = 64;
+pub static DEFAULT_RESPONSE_TIMEOUT: u64 = 0;
+pub static DEFAULT_REGISTRAR_TLS: bool = false;
+pub static DEFAULT_PAYLOAD_WAIT_TIMEOUT: u64 = 30;
+pub static DEFAULT_EK_CERT_NV_INDEX: &str = "";
+pub static DEFAULT_REVOCATION_NOTIFICATION_TIMEOUT: u64 = 10;
+pub static DEFAULT_SERVER_CPU_AFFINITY: &str = "";
+pub static DEFAULT_REVOCATION_ALLOWED_ACTIONS: &str = "";
+pub static DEFAULT_SECURE_MOUNT_MODE: &str = "mount";
+pub static DEFAULT_SECURE_MOUNT_PATH: &str = "";
+pub static DEFAULT_PAYLOAD_CIPHER_MODE: &str = "gcm";
+pub static DEFAULT_ENABLE_TPM_CLOCK_CHECK: bool = false;
+pub static DEFAULT_TPM_CLOCK_SKEW_FATAL: bool = false;
+pub static DEFAULT_MIN_KEY_BITS: u32 = 2048;
+pub static DEFAULT_MEASUREDBOOT_ML_PATH: &str = "default";
+pub static DEFAULT_REJECT_KEY_REPLACEMENT: bool = false;
+pub static DEFAULT_AGENT_BINARY_PCR: &str = "";
+pub static DEFAULT_TPM_TCTI_FALLBACK: &str = "";
+pub static DEFAULT_AGENT_DATA_FORMAT: &str = "json";
+pub static DEFAULT_INCLUDE_IMA_PCR_AGGREGATE: bool = false;
+pub static DEFAULT_PCR_MEASUREMENT_LOG: &str = "default";
+// The file in the keylime_dir used when 'pcr_measurement_log' is "default"
+pub static PCR_MEASUREMENT_LOG_FILE: &str = "pcr_measurements.log";
+pub static DEFAULT_HMAC_HASH_ALG: &str = "sha384";
+pub static DEFAULT_STATE_SNAPSHOT_PATH: &str = "";
+pub static DEFAULT_CONFIG_SIGNATURE_KEY: &str = "";
+pub static DEFAULT_REGISTRAR_CA: &str = "";
+pub static DEFAULT_MAX_PAYLOAD_RUNS: u32 = 1;
 pub static DEFAULT_CONFIG: &str = "/etc/keylime/agent.conf";
 pub static DEFAULT_CONFIG_SYS: &str = "/usr/etc/keylime/agent.conf";
+// Timeout in seconds for fetching a remote configuration file
+pub static REMOTE_CONFIG_TIMEOUT: u64 = 10;
+pub static OPENSTACK_METADATA_URL: &str =
+    "http://169.254.169.254/openstack/latest/meta_data.json";
+pub static OPENSTACK_METADATA_TIMEOUT: u64 = 5;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct EnvConfig {
@@ -95,6 +183,76 @@ pub(crate) struct EnvConfig {
     pub ek_handle: Option<String>,
     pub run_as: Option<String>,
     pub agent_data_path: Option<String>,
+    pub agent_name: Option<String>,
+    pub verify_tls_on_startup: Option<bool>,
+    pub max_keyset_size: Option<u32>,
+    pub tpm_session_encryption: Option<bool>,
+    pub ima_ml_path: Option<String>,
+    pub enable_landing_page: Option<bool>,
+    pub generate_self_signed_on_failure: Option<bool>,
+    pub system_facts_command: Option<String>,
+    pub max_ima_requests: Option<u32>,
+    pub write_key_file: Option<bool>,
+    pub transport_key_rotation_interval: Option<u64>,
+    pub enable_debug_endpoints: Option<bool>,
+    pub clock_skew_tolerance: Option<u64>,
+    pub rlimit_nofile: Option<u64>,
+    pub rlimit_nproc: Option<u64>,
+    pub statsd_endpoint: Option<String>,
+    pub tolerate_readonly_state: Option<bool>,
+    pub enable_reprovision: Option<bool>,
+    pub ak_rsa_key_bits: Option<u32>,
+    pub ak_ecc_curve: Option<String>,
+    pub async_quotes: Option<bool>,
+    pub revocation_self_only: Option<bool>,
+    pub payload_pull_url: Option<String>,
+    pub include_firmware_version: Option<bool>,
+    pub firmware_version_pcr: Option<String>,
+    pub payload_unzip_threads: Option<u32>,
+    pub payload_denied_extensions: Option<String>,
+    pub startup_timeout: Option<u64>,
+    pub payload_script_required: Option<bool>,
+    pub secure_mount_unshare: Option<bool>,
+    pub config_hash_pcr: Option<String>,
+    pub tss_log_level: Option<String>,
+    pub verify_registration: Option<bool>,
+    pub quote_pcr_selection: Option<String>,
+    pub require_ek_cert: Option<bool>,
+    pub skip_unchanged_registration: Option<bool>,
+    pub secure_mount_wait: Option<u64>,
+    pub registrar_retry_attempts: Option<u32>,
+    pub registrar_retry_interval: Option<u64>,
+    pub registrar_retry_max_interval: Option<u64>,
+    pub auto_recover_activation: Option<bool>,
+    pub mtls_optional_endpoints: Option<String>,
+    pub enable_metrics: Option<bool>,
+    pub nonce_min_length: Option<u32>,
+    pub nonce_max_length: Option<u32>,
+    pub response_timeout: Option<u64>,
+    pub registrar_tls: Option<bool>,
+    pub payload_wait_timeout: Option<u64>,
+    pub ek_cert_nv_index: Option<String>,
+    pub revocation_notification_timeout: Option<u64>,
+    pub server_cpu_affinity: Option<String>,
+    pub revocation_allowed_actions: Option<String>,
+    pub secure_mount_mode: Option<String>,
+    pub secure_mount_path: Option<String>,
+    pub payload_cipher_mode: Option<String>,
+    pub enable_tpm_clock_check: Option<bool>,
+    pub tpm_clock_skew_fatal: Option<bool>,
+    pub min_key_bits: Option<u32>,
+    pub measuredboot_ml_path: Option<String>,
+    pub reject_key_replacement: Option<bool>,
+    pub agent_binary_pcr: Option<String>,
+    pub tpm_tcti_fallback: Option<String>,
+    pub agent_data_format: Option<String>,
+    pub include_ima_pcr_aggregate: Option<bool>,
+    pub pcr_measurement_log: Option<String>,
+    pub hmac_hash_alg: Option<String>,
+    pub state_snapshot_path: Option<String>,
+    pub config_signature_key: Option<String>,
+    pub registrar_ca: Option<String>,
+    pub max_payload_runs: Option<u32>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
@@ -133,6 +291,76 @@ pub(crate) struct AgentConfig {
     pub ek_handle: String,
     pub run_as: String,
     pub agent_data_path: String,
+    pub agent_name: String,
+    pub verify_tls_on_startup: bool,
+    pub max_keyset_size: u32,
+    pub tpm_session_encryption: bool,
+    pub ima_ml_path: String,
+    pub enable_landing_page: bool,
+    pub generate_self_signed_on_failure: bool,
+    pub system_facts_command: String,
+    pub max_ima_requests: u32,
+    pub write_key_file: bool,
+    pub transport_key_rotation_interval: u64,
+    pub enable_debug_endpoints: bool,
+    pub clock_skew_tolerance: u64,
+    pub rlimit_nofile: u64,
+    pub rlimit_nproc: u64,
+    pub statsd_endpoint: String,
+    pub tolerate_readonly_state: bool,
+    pub enable_reprovision: bool,
+    pub ak_rsa_key_bits: u32,
+    pub ak_ecc_curve: String,
+    pub async_quotes: bool,
+    pub revocation_self_only: bool,
+    pub payload_pull_url: String,
+    pub include_firmware_version: bool,
+    pub firmware_version_pcr: String,
+    pub payload_unzip_threads: u32,
+    pub payload_denied_extensions: String,
+    pub startup_timeout: u64,
+    pub payload_script_required: bool,
+    pub secure_mount_unshare: bool,
+    pub config_hash_pcr: String,
+    pub tss_log_level: String,
+    pub verify_registration: bool,
+    pub quote_pcr_selection: String,
+    pub require_ek_cert: bool,
+    pub skip_unchanged_registration: bool,
+    pub secure_mount_wait: u64,
+    pub registrar_retry_attempts: u32,
+    pub registrar_retry_interval: u64,
+    pub registrar_retry_max_interval: u64,
+    pub auto_recover_activation: bool,
+    pub mtls_optional_endpoints: String,
+    pub enable_metrics: bool,
+    pub nonce_min_length: u32,
+    pub nonce_max_length: u32,
+    pub response_timeout: u64,
+    pub registrar_tls: bool,
+    pub payload_wait_timeout: u64,
+    pub ek_cert_nv_index: String,
+    pub revocation_notification_timeout: u64,
+    pub server_cpu_affinity: String,
+    pub revocation_allowed_actions: String,
+    pub secure_mount_mode: String,
+    pub secure_mount_path: String,
+    pub payload_cipher_mode: String,
+    pub enable_tpm_clock_check: bool,
+    pub tpm_clock_skew_fatal: bool,
+    pub min_key_bits: u32,
+    pub measuredboot_ml_path: String,
+    pub reject_key_replacement: bool,
+    pub agent_binary_pcr: String,
+    pub tpm_tcti_fallback: String,
+    pub agent_data_format: String,
+    pub include_ima_pcr_aggregate: bool,
+    pub pcr_measurement_log: String,
+    pub hmac_hash_alg: String,
+    pub state_snapshot_path: String,
+    pub config_signature_key: String,
+    pub registrar_ca: String,
+    pub max_payload_runs: u32,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
@@ -280,6 +508,288 @@ impl EnvConfig {
             _ = agent
                 .insert("agent_data_path".to_string(), v.to_string().into());
         }
+        if let Some(ref v) = self.agent_name {
+            _ = agent.insert("agent_name".to_string(), v.to_string().into());
+        }
+        if let Some(v) = self.verify_tls_on_startup {
+            _ = agent.insert("verify_tls_on_startup".to_string(), v.into());
+        }
+        if let Some(v) = self.max_keyset_size {
+            _ = agent.insert("max_keyset_size".to_string(), v.into());
+        }
+        if let Some(v) = self.tpm_session_encryption {
+            _ = agent.insert("tpm_session_encryption".to_string(), v.into());
+        }
+        if let Some(ref v) = self.ima_ml_path {
+            _ = agent.insert("ima_ml_path".to_string(), v.to_string().into());
+        }
+        if let Some(v) = self.enable_landing_page {
+            _ = agent.insert("enable_landing_page".to_string(), v.into());
+        }
+        if let Some(v) = self.generate_self_signed_on_failure {
+            _ = agent.insert(
+                "generate_self_signed_on_failure".to_string(),
+                v.into(),
+            );
+        }
+        if let Some(ref v) = self.system_facts_command {
+            _ = agent.insert(
+                "system_facts_command".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(v) = self.max_ima_requests {
+            _ = agent.insert("max_ima_requests".to_string(), v.into());
+        }
+        if let Some(v) = self.write_key_file {
+            _ = agent.insert("write_key_file".to_string(), v.into());
+        }
+        if let Some(v) = self.transport_key_rotation_interval {
+            _ = agent.insert(
+                "transport_key_rotation_interval".to_string(),
+                v.into(),
+            );
+        }
+        if let Some(v) = self.enable_debug_endpoints {
+            _ = agent.insert("enable_debug_endpoints".to_string(), v.into());
+        }
+        if let Some(v) = self.clock_skew_tolerance {
+            _ = agent.insert("clock_skew_tolerance".to_string(), v.into());
+        }
+        if let Some(v) = self.rlimit_nofile {
+            _ = agent.insert("rlimit_nofile".to_string(), v.into());
+        }
+        if let Some(v) = self.rlimit_nproc {
+            _ = agent.insert("rlimit_nproc".to_string(), v.into());
+        }
+        if let Some(ref v) = self.statsd_endpoint {
+            _ = agent
+                .insert("statsd_endpoint".to_string(), v.to_string().into());
+        }
+        if let Some(v) = self.tolerate_readonly_state {
+            _ = agent.insert("tolerate_readonly_state".to_string(), v.into());
+        }
+        if let Some(v) = self.enable_reprovision {
+            _ = agent.insert("enable_reprovision".to_string(), v.into());
+        }
+        if let Some(v) = self.ak_rsa_key_bits {
+            _ = agent.insert("ak_rsa_key_bits".to_string(), v.into());
+        }
+        if let Some(ref v) = self.ak_ecc_curve {
+            _ = agent
+                .insert("ak_ecc_curve".to_string(), v.to_string().into());
+        }
+        if let Some(v) = self.async_quotes {
+            _ = agent.insert("async_quotes".to_string(), v.into());
+        }
+        if let Some(v) = self.revocation_self_only {
+            _ = agent.insert("revocation_self_only".to_string(), v.into());
+        }
+        if let Some(ref v) = self.payload_pull_url {
+            _ = agent
+                .insert("payload_pull_url".to_string(), v.to_string().into());
+        }
+        if let Some(v) = self.include_firmware_version {
+            _ = agent
+                .insert("include_firmware_version".to_string(), v.into());
+        }
+        if let Some(ref v) = self.firmware_version_pcr {
+            _ = agent.insert(
+                "firmware_version_pcr".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(v) = self.payload_unzip_threads {
+            _ = agent.insert("payload_unzip_threads".to_string(), v.into());
+        }
+        if let Some(ref v) = self.payload_denied_extensions {
+            _ = agent.insert(
+                "payload_denied_extensions".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(v) = self.startup_timeout {
+            _ = agent.insert("startup_timeout".to_string(), v.into());
+        }
+        if let Some(v) = self.payload_script_required {
+            _ = agent.insert("payload_script_required".to_string(), v.into());
+        }
+        if let Some(v) = self.secure_mount_unshare {
+            _ = agent.insert("secure_mount_unshare".to_string(), v.into());
+        }
+        if let Some(ref v) = self.config_hash_pcr {
+            _ = agent
+                .insert("config_hash_pcr".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.tss_log_level {
+            _ = agent
+                .insert("tss_log_level".to_string(), v.to_string().into());
+        }
+        if let Some(v) = self.verify_registration {
+            _ = agent.insert("verify_registration".to_string(), v.into());
+        }
+        if let Some(ref v) = self.quote_pcr_selection {
+            _ = agent.insert(
+                "quote_pcr_selection".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(v) = self.require_ek_cert {
+            _ = agent.insert("require_ek_cert".to_string(), v.into());
+        }
+        if let Some(v) = self.skip_unchanged_registration {
+            _ = agent
+                .insert("skip_unchanged_registration".to_string(), v.into());
+        }
+        if let Some(v) = self.secure_mount_wait {
+            _ = agent.insert("secure_mount_wait".to_string(), v.into());
+        }
+        if let Some(v) = self.registrar_retry_attempts {
+            _ = agent
+                .insert("registrar_retry_attempts".to_string(), v.into());
+        }
+        if let Some(v) = self.registrar_retry_interval {
+            _ = agent
+                .insert("registrar_retry_interval".to_string(), v.into());
+        }
+        if let Some(v) = self.registrar_retry_max_interval {
+            _ = agent
+                .insert("registrar_retry_max_interval".to_string(), v.into());
+        }
+        if let Some(v) = self.auto_recover_activation {
+            _ = agent.insert("auto_recover_activation".to_string(), v.into());
+        }
+        if let Some(ref v) = self.mtls_optional_endpoints {
+            _ = agent.insert(
+                "mtls_optional_endpoints".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(v) = self.enable_metrics {
+            _ = agent.insert("enable_metrics".to_string(), v.into());
+        }
+        if let Some(v) = self.nonce_min_length {
+            _ = agent.insert("nonce_min_length".to_string(), v.into());
+        }
+        if let Some(v) = self.nonce_max_length {
+            _ = agent.insert("nonce_max_length".to_string(), v.into());
+        }
+        if let Some(v) = self.response_timeout {
+            _ = agent.insert("response_timeout".to_string(), v.into());
+        }
+        if let Some(v) = self.registrar_tls {
+            _ = agent.insert("registrar_tls".to_string(), v.into());
+        }
+        if let Some(v) = self.payload_wait_timeout {
+            _ = agent.insert("payload_wait_timeout".to_string(), v.into());
+        }
+        if let Some(ref v) = self.ek_cert_nv_index {
+            _ = agent
+                .insert("ek_cert_nv_index".to_string(), v.to_string().into());
+        }
+        if let Some(v) = self.revocation_notification_timeout {
+            _ = agent.insert(
+                "revocation_notification_timeout".to_string(),
+                v.into(),
+            );
+        }
+        if let Some(ref v) = self.server_cpu_affinity {
+            _ = agent.insert(
+                "server_cpu_affinity".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.revocation_allowed_actions {
+            _ = agent.insert(
+                "revocation_allowed_actions".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.secure_mount_mode {
+            _ = agent.insert(
+                "secure_mount_mode".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.secure_mount_path {
+            _ = agent.insert(
+                "secure_mount_path".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.payload_cipher_mode {
+            _ = agent.insert(
+                "payload_cipher_mode".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(v) = self.enable_tpm_clock_check {
+            _ = agent.insert("enable_tpm_clock_check".to_string(), v.into());
+        }
+        if let Some(v) = self.tpm_clock_skew_fatal {
+            _ = agent.insert("tpm_clock_skew_fatal".to_string(), v.into());
+        }
+        if let Some(v) = self.min_key_bits {
+            _ = agent.insert("min_key_bits".to_string(), v.into());
+        }
+        if let Some(ref v) = self.measuredboot_ml_path {
+            _ = agent.insert(
+                "measuredboot_ml_path".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(v) = self.reject_key_replacement {
+            _ = agent.insert("reject_key_replacement".to_string(), v.into());
+        }
+        if let Some(ref v) = self.agent_binary_pcr {
+            _ = agent
+                .insert("agent_binary_pcr".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.tpm_tcti_fallback {
+            _ = agent.insert(
+                "tpm_tcti_fallback".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.agent_data_format {
+            _ = agent.insert(
+                "agent_data_format".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(v) = self.include_ima_pcr_aggregate {
+            _ = agent
+                .insert("include_ima_pcr_aggregate".to_string(), v.into());
+        }
+        if let Some(ref v) = self.pcr_measurement_log {
+            _ = agent.insert(
+                "pcr_measurement_log".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.hmac_hash_alg {
+            _ = agent
+                .insert("hmac_hash_alg".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.state_snapshot_path {
+            _ = agent.insert(
+                "state_snapshot_path".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.config_signature_key {
+            _ = agent.insert(
+                "config_signature_key".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.registrar_ca {
+            _ = agent
+                .insert("registrar_ca".to_string(), v.to_string().into());
+        }
+        if let Some(v) = self.max_payload_runs {
+            _ = agent.insert("max_payload_runs".to_string(), v.into());
+        }
         agent
     }
 
@@ -291,12 +801,59 @@ impl EnvConfig {
 impl KeylimeConfig {
     pub fn new() -> Result<Self, Error> {
         // Get the base configuration file from the environment variable or the default locations
-        let setting = config_get_setting()?.build()?;
-        let config: KeylimeConfig = setting.try_deserialize()?;
+        Self::from_layers(config_get_layers()?, false)
+    }
+
+    /// Load the configuration without accessing the network, to validate it:
+    /// a remote configuration file is not fetched and the 'openstack' UUID
+    /// is not resolved
+    pub(crate) fn new_offline() -> Result<Self, Error> {
+        if let Ok(env_cfg) = env::var("KEYLIME_AGENT_CONFIG") {
+            if env_cfg.starts_with("http://")
+                || env_cfg.starts_with("https://")
+            {
+                return Err(Error::Configuration(format!(
+                    "The remote configuration {env_cfg} cannot be validated without fetching it"
+                )));
+            }
+        }
+        Self::from_layers(config_get_layers()?, true)
+    }
+
+    // Merge the layers into the configuration and validate it. If `offline`
+    // is set, the keywords resolved through the network are not resolved
+    fn from_layers(
+        layers: Vec<ConfigLayer>,
+        offline: bool,
+    ) -> Result<Self, Error> {
+        let setting = config_build(layers).build()?;
+        let mut config: KeylimeConfig = setting.try_deserialize()?;
+        if offline && config.agent.uuid == "openstack" {
+            // The UUID would be fetched from the OpenStack metadata service
+            config.agent.uuid = "generate".to_string();
+        }
 
         // Replace keywords with actual values
         config_translate_keywords(&config)
     }
+
+    /// Calculate the SHA-256 hash of the effective configuration, excluding
+    /// the options containing secrets. This allows the verifier to detect
+    /// configuration drift by comparing it with an expected hash.
+    pub(crate) fn hash(&self) -> Result<String, Error> {
+        let mut value = serde_json::to_value(&self.agent)?;
+        if let Some(options) = value.as_object_mut() {
+            for option in CONFIG_HASH_EXCLUDED {
+                _ = options.remove(*option);
+            }
+        }
+
+        let digest = openssl::hash::hash(
+            MessageDigest::sha256(),
+            serde_json::to_string(&value)?.as_bytes(),
+        )?;
+        Ok(hex::encode(digest))
+    }
 }
 
 impl Source for EnvConfig {
@@ -445,163 +1002,790 @@ impl Source for KeylimeConfig {
             "agent_data_path".to_string(),
             self.agent.agent_data_path.to_string().into(),
         );
-
-        Ok(Map::from([("agent".to_string(), m.into())]))
-    }
-
-    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
-        Box::new(self.clone())
-    }
-}
-
-impl Default for AgentConfig {
-    fn default() -> Self {
-        // In case the process is executed by privileged user
-        let run_as = if permissions::get_euid() == 0 {
-            DEFAULT_RUN_AS.to_string()
-        } else {
-            "".to_string()
-        };
-
-        AgentConfig {
-            version: CONFIG_VERSION.to_string(),
-            ip: DEFAULT_IP.to_string(),
-            port: DEFAULT_PORT,
-            registrar_ip: DEFAULT_REGISTRAR_IP.to_string(),
-            registrar_port: DEFAULT_REGISTRAR_PORT,
-            uuid: DEFAULT_UUID.to_string(),
-            contact_ip: DEFAULT_CONTACT_IP.to_string(),
-            contact_port: DEFAULT_CONTACT_PORT,
-            tpm_hash_alg: DEFAULT_TPM_HASH_ALG.to_string(),
-            tpm_encryption_alg: DEFAULT_TPM_ENCRYPTION_ALG.to_string(),
-            tpm_signing_alg: DEFAULT_TPM_SIGNING_ALG.to_string(),
-            agent_data_path: "default".to_string(),
-            enable_revocation_notifications:
-                DEFAULT_ENABLE_REVOCATION_NOTIFICATIONS,
-            revocation_cert: "default".to_string(),
-            revocation_notification_ip: DEFAULT_REVOCATION_NOTIFICATION_IP
-                .to_string(),
-            revocation_notification_port:
-                DEFAULT_REVOCATION_NOTIFICATION_PORT,
-            secure_size: DEFAULT_SECURE_SIZE.to_string(),
-            payload_script: DEFAULT_PAYLOAD_SCRIPT.to_string(),
-            dec_payload_file: DEFAULT_DEC_PAYLOAD_FILE.to_string(),
-            enc_keyname: DEFAULT_ENC_KEYNAME.to_string(),
-            extract_payload_zip: DEFAULT_EXTRACT_PAYLOAD_ZIP,
-            server_key: "default".to_string(),
-            server_key_password: DEFAULT_SERVER_KEY_PASSWORD.to_string(),
-            server_cert: "default".to_string(),
-            trusted_client_ca: "default".to_string(),
-            revocation_actions: DEFAULT_REVOCATION_ACTIONS.to_string(),
-            revocation_actions_dir: DEFAULT_REVOCATION_ACTIONS_DIR
-                .to_string(),
-            allow_payload_revocation_actions:
-                DEFAULT_ALLOW_PAYLOAD_REVOCATION_ACTIONS,
-            keylime_dir: DEFAULT_KEYLIME_DIR.to_string(),
-            enable_agent_mtls: DEFAULT_ENABLE_AGENT_MTLS,
-            enable_insecure_payload: DEFAULT_ENABLE_INSECURE_PAYLOAD,
-            run_as,
-            tpm_ownerpassword: DEFAULT_TPM_OWNERPASSWORD.to_string(),
-            ek_handle: DEFAULT_EK_HANDLE.to_string(),
-        }
-    }
-}
-
-impl Default for KeylimeConfig {
-    fn default() -> Self {
-        let c = KeylimeConfig {
-            agent: AgentConfig::default(),
-        };
-
-        // The default config should never fail to translate keywords
-        config_translate_keywords(&c).unwrap() //#[allow_ci]
-    }
-}
-
-fn config_get_env_setting() -> Result<impl Source, Error> {
-    let env_config: EnvConfig = Config::builder()
-        // Add environment variables overrides
-        .add_source(
-            Environment::with_prefix("KEYLIME_AGENT")
-                .separator(".")
-                .prefix_separator("_"),
-        )
-        .build()?
-        .try_deserialize()?;
-
-    // Log debug message for configuration obtained from environment
-    env_config
-        .get_map()
-        .iter()
-        .for_each(|(c, v)| debug!("Environment configuration {c}={v}"));
-
-    Ok(env_config)
-}
-
-fn config_get_file_setting() -> Result<ConfigBuilder<DefaultState>, Error> {
-    let default_config = KeylimeConfig::default();
-
-    Ok(Config::builder()
-        // Default values
-        .add_source(default_config)
-        // Add system configuration file
-        .add_source(
-            File::new(DEFAULT_CONFIG_SYS, FileFormat::Toml).required(false),
-        )
-        // Add system configuration snippets
-        .add_source(
-            glob("/usr/etc/keylime/agent.conf.d/*")
-                .map_err(Error::GlobPattern)?
-                .filter_map(|entry| entry.ok())
-                .map(|path| {
-                    File::new(&path.display().to_string(), FileFormat::Toml)
-                        .required(false)
-                })
-                .collect::<Vec<_>>(),
-        )
-        .add_source(
-            File::new(DEFAULT_CONFIG, FileFormat::Toml).required(false),
-        )
-        // Add user configuration snippets
+        _ = m.insert(
+            "agent_name".to_string(),
+            self.agent.agent_name.to_string().into(),
+        );
+        _ = m.insert(
+            "verify_tls_on_startup".to_string(),
+            self.agent.verify_tls_on_startup.into(),
+        );
+        _ = m.insert(
+            "max_keyset_size".to_string(),
+            self.agent.max_keyset_size.into(),
+        );
+        _ = m.insert(
+            "tpm_session_encryption".to_string(),
+            self.agent.tpm_session_encryption.into(),
+        );
+        _ = m.insert(
+            "ima_ml_path".to_string(),
+            self.agent.ima_ml_path.to_string().into(),
+        );
+        _ = m.insert(
+            "enable_landing_page".to_string(),
+            self.agent.enable_landing_page.into(),
+        );
+        _ = m.insert(
+            "generate_self_signed_on_failure".to_string(),
+            self.agent.generate_self_signed_on_failure.into(),
+        );
+        _ = m.insert(
+            "system_facts_command".to_string(),
+            self.agent.system_facts_command.to_string().into(),
+        );
+        _ = m.insert(
+            "max_ima_requests".to_string(),
+            self.agent.max_ima_requests.into(),
+        );
+        _ = m.insert(
+            "write_key_file".to_string(),
+            self.agent.write_key_file.into(),
+        );
+        _ = m.insert(
+            "transport_key_rotation_interval".to_string(),
+            self.agent.transport_key_rotation_interval.into(),
+        );
+        _ = m.insert(
+            "enable_debug_endpoints".to_string(),
+            self.agent.enable_debug_endpoints.into(),
+        );
+        _ = m.insert(
+            "clock_skew_tolerance".to_string(),
+            self.agent.clock_skew_tolerance.into(),
+        );
+        _ = m.insert(
+            "rlimit_nofile".to_string(),
+            self.agent.rlimit_nofile.into(),
+        );
+        _ = m.insert(
+            "rlimit_nproc".to_string(),
+            self.agent.rlimit_nproc.into(),
+        );
+        _ = m.insert(
+            "statsd_endpoint".to_string(),
+            self.agent.statsd_endpoint.to_string().into(),
+        );
+        _ = m.insert(
+            "tolerate_readonly_state".to_string(),
+            self.agent.tolerate_readonly_state.into(),
+        );
+        _ = m.insert(
+            "enable_reprovision".to_string(),
+            self.agent.enable_reprovision.into(),
+        );
+        _ = m.insert(
+            "ak_rsa_key_bits".to_string(),
+            self.agent.ak_rsa_key_bits.into(),
+        );
+        _ = m.insert(
+            "ak_ecc_curve".to_string(),
+            self.agent.ak_ecc_curve.to_string().into(),
+        );
+        _ = m.insert(
+            "async_quotes".to_string(),
+            self.agent.async_quotes.into(),
+        );
+        _ = m.insert(
+            "revocation_self_only".to_string(),
+            self.agent.revocation_self_only.into(),
+        );
+        _ = m.insert(
+            "payload_pull_url".to_string(),
+            self.agent.payload_pull_url.to_string().into(),
+        );
+        _ = m.insert(
+            "include_firmware_version".to_string(),
+            self.agent.include_firmware_version.into(),
+        );
+        _ = m.insert(
+            "firmware_version_pcr".to_string(),
+            self.agent.firmware_version_pcr.to_string().into(),
+        );
+        _ = m.insert(
+            "payload_unzip_threads".to_string(),
+            self.agent.payload_unzip_threads.into(),
+        );
+        _ = m.insert(
+            "payload_denied_extensions".to_string(),
+            self.agent.payload_denied_extensions.to_string().into(),
+        );
+        _ = m.insert(
+            "startup_timeout".to_string(),
+            self.agent.startup_timeout.into(),
+        );
+        _ = m.insert(
+            "payload_script_required".to_string(),
+            self.agent.payload_script_required.into(),
+        );
+        _ = m.insert(
+            "secure_mount_unshare".to_string(),
+            self.agent.secure_mount_unshare.into(),
+        );
+        _ = m.insert(
+            "config_hash_pcr".to_string(),
+            self.agent.config_hash_pcr.to_string().into(),
+        );
+        _ = m.insert(
+            "tss_log_level".to_string(),
+            self.agent.tss_log_level.to_string().into(),
+        );
+        _ = m.insert(
+            "verify_registration".to_string(),
+            self.agent.verify_registration.into(),
+        );
+        _ = m.insert(
+            "quote_pcr_selection".to_string(),
+            self.agent.quote_pcr_selection.to_string().into(),
+        );
+        _ = m.insert(
+            "require_ek_cert".to_string(),
+            self.agent.require_ek_cert.into(),
+        );
+        _ = m.insert(
+            "skip_unchanged_registration".to_string(),
+            self.agent.skip_unchanged_registration.into(),
+        );
+        _ = m.insert(
+            "secure_mount_wait".to_string(),
+            self.agent.secure_mount_wait.into(),
+        );
+        _ = m.insert(
+            "registrar_retry_attempts".to_string(),
+            self.agent.registrar_retry_attempts.into(),
+        );
+        _ = m.insert(
+            "registrar_retry_interval".to_string(),
+            self.agent.registrar_retry_interval.into(),
+        );
+        _ = m.insert(
+            "registrar_retry_max_interval".to_string(),
+            self.agent.registrar_retry_max_interval.into(),
+        );
+        _ = m.insert(
+            "auto_recover_activation".to_string(),
+            self.agent.auto_recover_activation.into(),
+        );
+        _ = m.insert(
+            "mtls_optional_endpoints".to_string(),
+            self.agent.mtls_optional_endpoints.to_string().into(),
+        );
+        _ = m.insert(
+            "enable_metrics".to_string(),
+            self.agent.enable_metrics.into(),
+        );
+        _ = m.insert(
+            "nonce_min_length".to_string(),
+            self.agent.nonce_min_length.into(),
+        );
+        _ = m.insert(
+            "nonce_max_length".to_string(),
+            self.agent.nonce_max_length.into(),
+        );
+        _ = m.insert(
+            "response_timeout".to_string(),
+            self.agent.response_timeout.into(),
+        );
+        _ = m.insert(
+            "registrar_tls".to_string(),
+            self.agent.registrar_tls.into(),
+        );
+        _ = m.insert(
+            "payload_wait_timeout".to_string(),
+            self.agent.payload_wait_timeout.into(),
+        );
+        _ = m.insert(
+            "ek_cert_nv_index".to_string(),
+            self.agent.ek_cert_nv_index.to_string().into(),
+        );
+        _ = m.insert(
+            "revocation_notification_timeout".to_string(),
+            self.agent.revocation_notification_timeout.into(),
+        );
+        _ = m.insert(
+            "server_cpu_affinity".to_string(),
+            self.agent.server_cpu_affinity.to_string().into(),
+        );
+        _ = m.insert(
+            "revocation_allowed_actions".to_string(),
+            self.agent.revocation_allowed_actions.to_string().into(),
+        );
+        _ = m.insert(
+            "secure_mount_mode".to_string(),
+            self.agent.secure_mount_mode.to_string().into(),
+        );
+        _ = m.insert(
+            "secure_mount_path".to_string(),
+            self.agent.secure_mount_path.to_string().into(),
+        );
+        _ = m.insert(
+            "payload_cipher_mode".to_string(),
+            self.agent.payload_cipher_mode.to_string().into(),
+        );
+        _ = m.insert(
+            "enable_tpm_clock_check".to_string(),
+            self.agent.enable_tpm_clock_check.into(),
+        );
+        _ = m.insert(
+            "tpm_clock_skew_fatal".to_string(),
+            self.agent.tpm_clock_skew_fatal.into(),
+        );
+        _ = m.insert(
+            "min_key_bits".to_string(),
+            self.agent.min_key_bits.into(),
+        );
+        _ = m.insert(
+            "measuredboot_ml_path".to_string(),
+            self.agent.measuredboot_ml_path.to_string().into(),
+        );
+        _ = m.insert(
+            "reject_key_replacement".to_string(),
+            self.agent.reject_key_replacement.into(),
+        );
+        _ = m.insert(
+            "agent_binary_pcr".to_string(),
+            self.agent.agent_binary_pcr.to_string().into(),
+        );
+        _ = m.insert(
+            "tpm_tcti_fallback".to_string(),
+            self.agent.tpm_tcti_fallback.to_string().into(),
+        );
+        _ = m.insert(
+            "agent_data_format".to_string(),
+            self.agent.agent_data_format.to_string().into(),
+        );
+        _ = m.insert(
+            "include_ima_pcr_aggregate".to_string(),
+            self.agent.include_ima_pcr_aggregate.into(),
+        );
+        _ = m.insert(
+            "pcr_measurement_log".to_string(),
+            self.agent.pcr_measurement_log.to_string().into(),
+        );
+        _ = m.insert(
+            "hmac_hash_alg".to_string(),
+            self.agent.hmac_hash_alg.to_string().into(),
+        );
+        _ = m.insert(
+            "state_snapshot_path".to_string(),
+            self.agent.state_snapshot_path.to_string().into(),
+        );
+        _ = m.insert(
+            "config_signature_key".to_string(),
+            self.agent.config_signature_key.to_string().into(),
+        );
+        _ = m.insert(
+            "registrar_ca".to_string(),
+            self.agent.registrar_ca.to_string().into(),
+        );
+        _ = m.insert(
+            "max_payload_runs".to_string(),
+            self.agent.max_payload_runs.into(),
+        );
+
+        Ok(Map::from([("agent".to_string(), m.into())]))
+    }
+
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        // In case the process is executed by privileged user
+        let run_as = if permissions::get_euid() == 0 {
+            DEFAULT_RUN_AS.to_string()
+        } else {
+            "".to_string()
+        };
+
+        AgentConfig {
+            version: CONFIG_VERSION.to_string(),
+            ip: DEFAULT_IP.to_string(),
+            port: DEFAULT_PORT,
+            registrar_ip: DEFAULT_REGISTRAR_IP.to_string(),
+            registrar_port: DEFAULT_REGISTRAR_PORT,
+            uuid: DEFAULT_UUID.to_string(),
+            contact_ip: DEFAULT_CONTACT_IP.to_string(),
+            contact_port: DEFAULT_CONTACT_PORT,
+            tpm_hash_alg: DEFAULT_TPM_HASH_ALG.to_string(),
+            tpm_encryption_alg: DEFAULT_TPM_ENCRYPTION_ALG.to_string(),
+            tpm_signing_alg: DEFAULT_TPM_SIGNING_ALG.to_string(),
+            agent_data_path: "default".to_string(),
+            enable_revocation_notifications:
+                DEFAULT_ENABLE_REVOCATION_NOTIFICATIONS,
+            revocation_cert: "default".to_string(),
+            revocation_notification_ip: DEFAULT_REVOCATION_NOTIFICATION_IP
+                .to_string(),
+            revocation_notification_port:
+                DEFAULT_REVOCATION_NOTIFICATION_PORT,
+            secure_size: DEFAULT_SECURE_SIZE.to_string(),
+            payload_script: DEFAULT_PAYLOAD_SCRIPT.to_string(),
+            dec_payload_file: DEFAULT_DEC_PAYLOAD_FILE.to_string(),
+            enc_keyname: DEFAULT_ENC_KEYNAME.to_string(),
+            extract_payload_zip: DEFAULT_EXTRACT_PAYLOAD_ZIP,
+            server_key: "default".to_string(),
+            server_key_password: DEFAULT_SERVER_KEY_PASSWORD.to_string(),
+            server_cert: "default".to_string(),
+            trusted_client_ca: "default".to_string(),
+            revocation_actions: DEFAULT_REVOCATION_ACTIONS.to_string(),
+            revocation_actions_dir: DEFAULT_REVOCATION_ACTIONS_DIR
+                .to_string(),
+            allow_payload_revocation_actions:
+                DEFAULT_ALLOW_PAYLOAD_REVOCATION_ACTIONS,
+            keylime_dir: DEFAULT_KEYLIME_DIR.to_string(),
+            enable_agent_mtls: DEFAULT_ENABLE_AGENT_MTLS,
+            enable_insecure_payload: DEFAULT_ENABLE_INSECURE_PAYLOAD,
+            run_as,
+            tpm_ownerpassword: DEFAULT_TPM_OWNERPASSWORD.to_string(),
+            ek_handle: DEFAULT_EK_HANDLE.to_string(),
+            agent_name: DEFAULT_AGENT_NAME.to_string(),
+            verify_tls_on_startup: DEFAULT_VERIFY_TLS_ON_STARTUP,
+            max_keyset_size: DEFAULT_MAX_KEYSET_SIZE,
+            tpm_session_encryption: DEFAULT_TPM_SESSION_ENCRYPTION,
+            ima_ml_path: DEFAULT_IMA_ML_PATH.to_string(),
+            enable_landing_page: DEFAULT_ENABLE_LANDING_PAGE,
+            generate_self_signed_on_failure:
+                DEFAULT_GENERATE_SELF_SIGNED_ON_FAILURE,
+            system_facts_command: DEFAULT_SYSTEM_FACTS_COMMAND.to_string(),
+            max_ima_requests: DEFAULT_MAX_IMA_REQUESTS,
+            write_key_file: DEFAULT_WRITE_KEY_FILE,
+            transport_key_rotation_interval:
+                DEFAULT_TRANSPORT_KEY_ROTATION_INTERVAL,
+            enable_debug_endpoints: DEFAULT_ENABLE_DEBUG_ENDPOINTS,
+            clock_skew_tolerance: DEFAULT_CLOCK_SKEW_TOLERANCE,
+            rlimit_nofile: DEFAULT_RLIMIT_NOFILE,
+            rlimit_nproc: DEFAULT_RLIMIT_NPROC,
+            statsd_endpoint: DEFAULT_STATSD_ENDPOINT.to_string(),
+            tolerate_readonly_state: DEFAULT_TOLERATE_READONLY_STATE,
+            enable_reprovision: DEFAULT_ENABLE_REPROVISION,
+            ak_rsa_key_bits: DEFAULT_AK_RSA_KEY_BITS,
+            ak_ecc_curve: DEFAULT_AK_ECC_CURVE.to_string(),
+            async_quotes: DEFAULT_ASYNC_QUOTES,
+            revocation_self_only: DEFAULT_REVOCATION_SELF_ONLY,
+            payload_pull_url: DEFAULT_PAYLOAD_PULL_URL.to_string(),
+            include_firmware_version: DEFAULT_INCLUDE_FIRMWARE_VERSION,
+            firmware_version_pcr: DEFAULT_FIRMWARE_VERSION_PCR.to_string(),
+            payload_unzip_threads: DEFAULT_PAYLOAD_UNZIP_THREADS,
+            payload_denied_extensions: DEFAULT_PAYLOAD_DENIED_EXTENSIONS
+                .to_string(),
+            startup_timeout: DEFAULT_STARTUP_TIMEOUT,
+            payload_script_required: DEFAULT_PAYLOAD_SCRIPT_REQUIRED,
+            secure_mount_unshare: DEFAULT_SECURE_MOUNT_UNSHARE,
+            config_hash_pcr: DEFAULT_CONFIG_HASH_PCR.to_string(),
+            tss_log_level: DEFAULT_TSS_LOG_LEVEL.to_string(),
+            verify_registration: DEFAULT_VERIFY_REGISTRATION,
+            quote_pcr_selection: DEFAULT_QUOTE_PCR_SELECTION.to_string(),
+            require_ek_cert: DEFAULT_REQUIRE_EK_CERT,
+            skip_unchanged_registration: DEFAULT_SKIP_UNCHANGED_REGISTRATION,
+            secure_mount_wait: DEFAULT_SECURE_MOUNT_WAIT,
+            registrar_retry_attempts: DEFAULT_REGISTRAR_RETRY_ATTEMPTS,
+            registrar_retry_interval: DEFAULT_REGISTRAR_RETRY_INTERVAL,
+            registrar_retry_max_interval:
+                DEFAULT_REGISTRAR_RETRY_MAX_INTERVAL,
+            auto_recover_activation: DEFAULT_AUTO_RECOVER_ACTIVATION,
+            mtls_optional_endpoints: DEFAULT_MTLS_OPTIONAL_ENDPOINTS
+                .to_string(),
+            enable_metrics: DEFAULT_ENABLE_METRICS,
+            nonce_min_length: DEFAULT_NONCE_MIN_LENGTH,
+            nonce_max_length: DEFAULT_NONCE_MAX_LENGTH,
+            response_timeout: DEFAULT_RESPONSE_TIMEOUT,
+            registrar_tls: DEFAULT_REGISTRAR_TLS,
+            payload_wait_timeout: DEFAULT_PAYLOAD_WAIT_TIMEOUT,
+            ek_cert_nv_index: DEFAULT_EK_CERT_NV_INDEX.to_string(),
+            revocation_notification_timeout:
+                DEFAULT_REVOCATION_NOTIFICATION_TIMEOUT,
+            server_cpu_affinity: DEFAULT_SERVER_CPU_AFFINITY.to_string(),
+            revocation_allowed_actions: DEFAULT_REVOCATION_ALLOWED_ACTIONS
+                .to_string(),
+            secure_mount_mode: DEFAULT_SECURE_MOUNT_MODE.to_string(),
+            secure_mount_path: DEFAULT_SECURE_MOUNT_PATH.to_string(),
+            payload_cipher_mode: DEFAULT_PAYLOAD_CIPHER_MODE.to_string(),
+            enable_tpm_clock_check: DEFAULT_ENABLE_TPM_CLOCK_CHECK,
+            tpm_clock_skew_fatal: DEFAULT_TPM_CLOCK_SKEW_FATAL,
+            min_key_bits: DEFAULT_MIN_KEY_BITS,
+            measuredboot_ml_path: DEFAULT_MEASUREDBOOT_ML_PATH.to_string(),
+            reject_key_replacement: DEFAULT_REJECT_KEY_REPLACEMENT,
+            agent_binary_pcr: DEFAULT_AGENT_BINARY_PCR.to_string(),
+            tpm_tcti_fallback: DEFAULT_TPM_TCTI_FALLBACK.to_string(),
+            agent_data_format: DEFAULT_AGENT_DATA_FORMAT.to_string(),
+            include_ima_pcr_aggregate: DEFAULT_INCLUDE_IMA_PCR_AGGREGATE,
+            pcr_measurement_log: DEFAULT_PCR_MEASUREMENT_LOG.to_string(),
+            hmac_hash_alg: DEFAULT_HMAC_HASH_ALG.to_string(),
+            state_snapshot_path: DEFAULT_STATE_SNAPSHOT_PATH.to_string(),
+            config_signature_key: DEFAULT_CONFIG_SIGNATURE_KEY.to_string(),
+            registrar_ca: DEFAULT_REGISTRAR_CA.to_string(),
+            max_payload_runs: DEFAULT_MAX_PAYLOAD_RUNS,
+        }
+    }
+}
+
+impl Default for KeylimeConfig {
+    fn default() -> Self {
+        let c = KeylimeConfig {
+            agent: AgentConfig::default(),
+        };
+
+        // The default config should never fail to translate keywords
+        config_translate_keywords(&c).unwrap() //#[allow_ci]
+    }
+}
+
+fn config_get_env_setting() -> Result<impl Source, Error> {
+    let env_config: EnvConfig = Config::builder()
+        // Add environment variables overrides
         .add_source(
-            glob("/etc/keylime/agent.conf.d/*")
-                .map_err(Error::GlobPattern)?
-                .filter_map(|entry| entry.ok())
-                .map(|path| {
-                    File::new(&path.display().to_string(), FileFormat::Toml)
-                        .required(false)
-                })
-                .collect::<Vec<_>>(),
+            Environment::with_prefix("KEYLIME_AGENT")
+                .separator(".")
+                .prefix_separator("_"),
         )
-        // Add environment variables overrides
-        .add_source(config_get_env_setting()?))
+        .build()?
+        .try_deserialize()?;
+
+    // Log debug message for configuration obtained from environment
+    env_config
+        .get_map()
+        .iter()
+        .for_each(|(c, v)| debug!("Environment configuration {c}={v}"));
+
+    Ok(env_config)
 }
 
-fn config_get_setting() -> Result<ConfigBuilder<DefaultState>, Error> {
+/// A source of configuration, labeled with where it comes from
+type ConfigLayer = (String, Box<dyn Source + Send + Sync>);
+
+/// Build the configuration from the layers, in increasing order of precedence
+fn config_build(layers: Vec<ConfigLayer>) -> ConfigBuilder<DefaultState> {
+    Config::builder().add_source(
+        layers
+            .into_iter()
+            .map(|(_, source)| source)
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Get the layer for the configuration file in `path`, labeled with `label`.
+/// If `key_path` is set, the file is only loaded if its signature is valid
+fn config_file_layer(
+    label: String,
+    path: &str,
+    key_path: Option<&Path>,
+) -> Result<ConfigLayer, Error> {
+    match key_path {
+        Some(key_path) if Path::new(path).exists() => {
+            let contents = config_read_signed(Path::new(path), key_path)?;
+            info!("Verified signature of configuration file {path}");
+            Ok(
+                (
+                    label,
+                    Box::new(File::from_str(&contents, FileFormat::Toml)),
+                ),
+            )
+        }
+        _ => Ok((
+            label,
+            Box::new(File::new(path, FileFormat::Toml).required(false)),
+        )),
+    }
+}
+
+/// Get the layers for the configuration file in `path`, labeled with `kind`,
+/// followed by the configuration snippets in the `<path>.d` directory
+fn config_file_layers(
+    kind: &str,
+    path: &str,
+    key_path: Option<&Path>,
+) -> Result<Vec<ConfigLayer>, Error> {
+    let mut layers: Vec<ConfigLayer> =
+        vec![config_file_layer(format!("{kind} {path}"), path, key_path)?];
+    for snippet in glob(&format!("{path}.d/*"))
+        .map_err(Error::GlobPattern)?
+        .filter_map(|entry| entry.ok())
+    {
+        let snippet = snippet.display().to_string();
+        layers.push(config_file_layer(
+            format!("snippet {snippet}"),
+            &snippet,
+            key_path,
+        )?);
+    }
+    Ok(layers)
+}
+
+fn config_get_file_layers(
+    key_path: Option<&Path>,
+) -> Result<Vec<ConfigLayer>, Error> {
+    // Default values
+    let mut layers: Vec<ConfigLayer> =
+        vec![("default".to_string(), Box::new(KeylimeConfig::default()))];
+    // Add system configuration file and snippets
+    layers.extend(config_file_layers("sys", DEFAULT_CONFIG_SYS, key_path)?);
+    // Add user configuration file and snippets
+    layers.extend(config_file_layers("user", DEFAULT_CONFIG, key_path)?);
+    // Add environment variables overrides
+    layers.push(("env".to_string(), Box::new(config_get_env_setting()?)));
+    Ok(layers)
+}
+
+/// Fetch configuration data (e.g. a configuration file) from a http(s) URL,
+/// failing if no response is received within `timeout` seconds
+///
+/// The request is performed in a separate thread using a blocking client, as
+/// the configuration is loaded from within the async runtime
+fn config_fetch_remote(url: &str, timeout: u64) -> Result<String, Error> {
+    let url = url.to_string();
+    let handle = thread::spawn(move || -> Result<String, String> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(timeout))
+            .build()
+            .map_err(|e| format!("Failed to create HTTP client: {e}"))?;
+        let resp = client
+            .get(&url)
+            .send()
+            .map_err(|e| format!("Failed to fetch {url}: {e}"))?;
+        if !resp.status().is_success() {
+            return Err(format!(
+                "Failed to fetch {url}: received {}",
+                resp.status()
+            ));
+        }
+        resp.text().map_err(|e| {
+            format!("Failed to read data fetched from {url}: {e}")
+        })
+    });
+
+    match handle.join() {
+        Ok(result) => result.map_err(Error::Configuration),
+        Err(_) => Err(Error::Configuration(
+            "Failed to join the thread fetching the configuration"
+                .to_string(),
+        )),
+    }
+}
+
+/// Read the configuration file in `path`, verifying its detached signature
+/// stored alongside it with the `.sig` extension appended, using the public
+/// key in PEM format in `key_path`
+///
+/// The signature is expected to be a SHA-256 signature, e.g. generated with
+/// `openssl dgst -sha256 -sign <key> -out agent.conf.sig agent.conf`
+fn config_read_signed(path: &Path, key_path: &Path) -> Result<String, Error> {
+    let mut sig_path = path.as_os_str().to_owned();
+    sig_path.push(".sig");
+
+    let key = fs::read(key_path).map_err(|e| {
+        Error::Configuration(format!(
+            "Failed to read configuration signature key {}: {e}",
+            key_path.display()
+        ))
+    })?;
+    let key = PKey::public_key_from_pem(&key).map_err(|e| {
+        Error::Configuration(format!(
+            "Failed to parse configuration signature key {}: {e}",
+            key_path.display()
+        ))
+    })?;
+
+    let contents = fs::read(path).map_err(|e| {
+        Error::Configuration(format!(
+            "Failed to read configuration file {}: {e}",
+            path.display()
+        ))
+    })?;
+    let signature = fs::read(&sig_path).map_err(|e| {
+        Error::Configuration(format!(
+            "Failed to read configuration signature {}: {e}",
+            Path::new(&sig_path).display()
+        ))
+    })?;
+
+    let valid = Verifier::new(MessageDigest::sha256(), &key)
+        .and_then(|mut verifier| {
+            verifier.verify_oneshot(&signature, &contents)
+        })
+        .map_err(|e| {
+            Error::Configuration(format!(
+                "Failed to verify configuration signature: {e}"
+            ))
+        })?;
+    if !valid {
+        return Err(Error::Configuration(format!(
+            "Invalid signature for configuration file {}",
+            path.display()
+        )));
+    }
+
+    String::from_utf8(contents).map_err(|e| {
+        Error::Configuration(format!(
+            "Invalid configuration file {}: {e}",
+            path.display()
+        ))
+    })
+}
+
+/// Get the path to the public key used to verify the configuration files,
+/// set in the 'config_signature_key' option, if any
+///
+/// The option is looked up in the environment and in the local
+/// configuration files, which are only trusted once verified with the key
+fn config_get_signature_key() -> Result<Option<PathBuf>, Error> {
+    let key_path = match env::var("KEYLIME_AGENT_CONFIG") {
+        Ok(p) if p.starts_with("http://") || p.starts_with("https://") => {
+            // The remote configuration is not fetched just to find the key
+            env::var("KEYLIME_AGENT_CONFIG_SIGNATURE_KEY").unwrap_or_default()
+        }
+        _ => config_build(config_get_layers_verified(None)?)
+            .build()?
+            .get_string("agent.config_signature_key")?,
+    };
+    if key_path.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(PathBuf::from(key_path)))
+}
+
+/// Get the configuration sources, in increasing order of precedence
+///
+/// If the 'config_signature_key' option is set, every configuration file
+/// loaded, including the snippets, must have a valid signature
+fn config_get_layers() -> Result<Vec<ConfigLayer>, Error> {
+    config_get_layers_verified(config_get_signature_key()?.as_deref())
+}
+
+/// Get the configuration sources, verifying the signature of every
+/// configuration file with the public key in `key_path`, if set
+fn config_get_layers_verified(
+    key_path: Option<&Path>,
+) -> Result<Vec<ConfigLayer>, Error> {
     if let Ok(env_cfg) = env::var("KEYLIME_AGENT_CONFIG") {
+        if env_cfg.starts_with("http://") || env_cfg.starts_with("https://") {
+            if key_path.is_some() {
+                return Err(Error::Configuration(
+                    "Signature verification is not supported for remote configuration".to_string(),
+                ));
+            }
+            info!("Fetching configuration from {}", env_cfg);
+            let contents =
+                match config_fetch_remote(&env_cfg, REMOTE_CONFIG_TIMEOUT) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        error!("Could not load remote configuration: {}", e);
+                        return Err(e);
+                    }
+                };
+            return Ok(vec![
+                (
+                    format!("user {env_cfg}"),
+                    Box::new(File::from_str(&contents, FileFormat::Toml)),
+                ),
+                // Add environment variables overrides
+                ("env".to_string(), Box::new(config_get_env_setting()?)),
+            ]);
+        }
         if !env_cfg.is_empty() {
             let path = Path::new(&env_cfg);
             if (path.exists()) {
-                return Ok(Config::builder()
-                    .add_source(
-                        File::new(&env_cfg, FileFormat::Toml).required(true),
-                    )
+                let layer: ConfigLayer = match key_path {
+                    Some(_) => config_file_layer(
+                        format!("user {env_cfg}"),
+                        &env_cfg,
+                        key_path,
+                    )?,
+                    None => (
+                        format!("user {env_cfg}"),
+                        Box::new(
+                            File::new(&env_cfg, FileFormat::Toml)
+                                .required(true),
+                        ),
+                    ),
+                };
+                return Ok(vec![
+                    layer,
                     // Add environment variables overrides
-                    .add_source(config_get_env_setting()?));
+                    ("env".to_string(), Box::new(config_get_env_setting()?)),
+                ]);
             } else {
                 warn!("Configuration set in KEYLIME_AGENT_CONFIG environment variable not found");
                 return Err(Error::Configuration("Configuration set in KEYLIME_AGENT_CONFIG environment variable not found".to_string()));
             }
         }
     }
-    config_get_file_setting()
+    config_get_file_layers(key_path)
+}
+
+fn config_get_setting() -> Result<ConfigBuilder<DefaultState>, Error> {
+    Ok(config_build(config_get_layers()?))
+}
+
+/// Find the source of the value of each option in the 'agent' table, which
+/// is the last layer that sets it
+fn config_trace_layers(
+    layers: &[ConfigLayer],
+) -> Result<Map<String, String>, Error> {
+    let mut sources = Map::new();
+    for (label, source) in layers {
+        let options = match source.collect()?.remove("agent") {
+            Some(agent) => agent.into_table()?,
+            None => continue,
+        };
+        for name in options.into_keys() {
+            _ = sources.insert(name, label.clone());
+        }
+    }
+    Ok(sources)
+}
+
+/// Get the merged options in the 'agent' table, before the keywords are
+/// replaced, with the source that provided each value, sorted by name
+pub(crate) fn config_trace_sources(
+) -> Result<Vec<(String, Value, String)>, Error> {
+    let layers = config_get_layers()?;
+    let mut sources = config_trace_layers(&layers)?;
+    let mut options: Vec<(String, Value, String)> = config_build(layers)
+        .build()?
+        .get_table("agent")?
+        .into_iter()
+        .map(|(name, value)| {
+            let source = sources
+                .remove(&name)
+                .unwrap_or_else(|| "default".to_string());
+            (name, value, source)
+        })
+        .collect();
+    options.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(options)
+}
+
+/// Merge the options in the 'agent' table set in the layers, skipping the
+/// default values, so that only the options set explicitly are returned
+fn config_merge_set_options(
+    layers: Vec<ConfigLayer>,
+) -> Result<Map<String, Value>, Error> {
+    let layers = layers
+        .into_iter()
+        .filter(|(label, _)| label != "default")
+        .collect();
+    match config_build(layers).build()?.get_table("agent") {
+        Ok(options) => Ok(options),
+        Err(ConfigError::NotFound(_)) => Ok(Map::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Get the options in the 'agent' table as merged from the configuration
+/// files and the environment, before the keywords are replaced. The options
+/// not set, which take the default value, are not included
+pub(crate) fn config_get_merged_options() -> Result<Map<String, Value>, Error>
+{
+    config_merge_set_options(config_get_layers()?)
 }
 
 /// Replace the options that support keywords with the final value
 fn config_translate_keywords(
     config: &KeylimeConfig,
 ) -> Result<KeylimeConfig, Error> {
-    let uuid = get_uuid(&config.agent.uuid);
+    let uuid = get_uuid(&config.agent.uuid)?;
 
     let env_keylime_dir = env::var("KEYLIME_DIR").ok();
     let keylime_dir = match env_keylime_dir {
@@ -638,6 +1822,13 @@ fn config_translate_keywords(
         DEFAULT_AGENT_DATA_PATH,
     );
 
+    let pcr_measurement_log = config_get_file_path(
+        "pcr_measurement_log",
+        &config.agent.pcr_measurement_log,
+        keylime_dir,
+        PCR_MEASUREMENT_LOG_FILE,
+    );
+
     let mut server_key = config_get_file_path(
         "server_key",
         &config.agent.server_key,
@@ -652,6 +1843,15 @@ fn config_translate_keywords(
         DEFAULT_SERVER_CERT,
     );
 
+    if config.agent.enable_agent_mtls
+        && config.agent.trusted_client_ca.is_empty()
+    {
+        return Err(Error::Configuration(
+            "Agent mTLS is enabled, but trusted_client_ca option was not provided"
+                .to_string(),
+        ));
+    }
+
     let mut trusted_client_ca = config_get_file_path(
         "trusted_client_ca",
         &config.agent.trusted_client_ca,
@@ -659,53 +1859,332 @@ fn config_translate_keywords(
         DEFAULT_TRUSTED_CLIENT_CA,
     );
 
+    // The registrar is verified with the trusted client CA if not set
+    let registrar_ca = match config.agent.registrar_ca.as_ref() {
+        "" => trusted_client_ca.clone(),
+        path => config_get_file_path(
+            "registrar_ca",
+            path,
+            keylime_dir,
+            DEFAULT_TRUSTED_CLIENT_CA,
+        ),
+    };
+
     let ek_handle = match config.agent.ek_handle.as_ref() {
         "generate" => "".to_string(),
         "" => "".to_string(),
         s => s.to_string(),
     };
 
-    // Validate the configuration
-
-    // If revocation notifications is enabled, verify all the required options for revocation
-    if config.agent.enable_revocation_notifications {
-        if config.agent.revocation_notification_ip.is_empty() {
-            error!("The option 'enable_revocation_notifications' is set as 'true' but 'revocation_notification_ip' was set as empty");
-            return Err(Error::Configuration("The option 'enable_revocation_notifications' is set as 'true' but 'revocation_notification_ip' was set as empty".to_string()));
-        }
-        if config.agent.revocation_cert.is_empty() {
-            error!("The option 'enable_revocation_notifications' is set as 'true' 'revocation_cert' was set as empty");
-            return Err(Error::Configuration("The option 'enable_revocation_notifications' is set as 'true' but 'revocation_notification_cert' was set as empty".to_string()));
+    // If no name was given, use the system hostname
+    let agent_name = match config.agent.agent_name.as_ref() {
+        "" => get_hostname()?,
+        s => s.to_string(),
+    };
+
+    let ip = parse_ip_address("ip", &config.agent.ip)?.to_string();
+    let registrar_ip =
+        parse_host("registrar_ip", &config.agent.registrar_ip)?;
+    let (contact_ip, contact_port) = config_get_contact_address(config)?;
+
+    // Validate the PCR selection, which is parsed again when the agent starts
+    _ = parse_pcr_selection(&config.agent.quote_pcr_selection)?;
+    _ = parse_ek_cert_nv_index(&config.agent.ek_cert_nv_index)?;
+    _ = parse_cpu_list(&config.agent.server_cpu_affinity)?;
+    _ = PayloadCipherMode::try_from(
+        config.agent.payload_cipher_mode.as_str(),
+    )?;
+    _ = AgentDataFormat::try_from(config.agent.agent_data_format.as_str())?;
+
+    // Validate the configuration
+
+    let (min, max) = (
+        config.agent.nonce_min_length as usize,
+        config.agent.nonce_max_length as usize,
+    );
+    if min == 0 || min > max || max > tpm::MAX_NONCE_SIZE {
+        return Err(Error::Configuration(format!(
+            "The options 'nonce_min_length' and 'nonce_max_length' must satisfy 1 <= nonce_min_length <= nonce_max_length <= {}",
+            tpm::MAX_NONCE_SIZE
+        )));
+    }
+
+    if config.agent.max_payload_runs == 0 {
+        return Err(Error::Configuration(
+            "The option 'max_payload_runs' must be at least 1".to_string(),
+        ));
+    }
+
+    if config.agent.registrar_retry_attempts == 0 {
+        return Err(Error::Configuration(
+            "The option 'registrar_retry_attempts' must be at least 1"
+                .to_string(),
+        ));
+    }
+
+    // If revocation notifications is enabled, verify all the required options for revocation
+    if config.agent.enable_revocation_notifications {
+        if config.agent.revocation_notification_ip.is_empty() {
+            error!("The option 'enable_revocation_notifications' is set as 'true' but 'revocation_notification_ip' was set as empty");
+            return Err(Error::Configuration("The option 'enable_revocation_notifications' is set as 'true' but 'revocation_notification_ip' was set as empty".to_string()));
+        }
+        if config.agent.revocation_cert.is_empty() {
+            error!("The option 'enable_revocation_notifications' is set as 'true' 'revocation_cert' was set as empty");
+            return Err(Error::Configuration("The option 'enable_revocation_notifications' is set as 'true' but 'revocation_notification_cert' was set as empty".to_string()));
+        }
+        let actions_dir = match config.agent.revocation_actions_dir.as_ref() {
+            "" => {
+                error!("The option 'enable_revocation_notifications' is set as 'true' but the revocation actions directory was set as empty in 'revocation_actions_dir'");
+                return Err(Error::Configuration("The option 'enable_revocation_notifications' is set as 'true' but 'revocation_actions_dir' was set as empty".to_string()));
+            }
+            dir => dir.to_string(),
+        };
+    }
+
+    let mut revocation_cert = config_get_file_path(
+        "revocation_cert",
+        &config.agent.revocation_cert,
+        keylime_dir,
+        &format!("secure/unzipped/{DEFAULT_REVOCATION_CERT}"),
+    );
+
+    Ok(KeylimeConfig {
+        agent: AgentConfig {
+            keylime_dir: keylime_dir.display().to_string(),
+            uuid,
+            agent_name,
+            server_key,
+            server_cert,
+            trusted_client_ca,
+            registrar_ca,
+            ek_handle,
+            agent_data_path,
+            pcr_measurement_log,
+            revocation_cert,
+            ip,
+            registrar_ip,
+            contact_ip,
+            contact_port,
+            ..config.agent.clone()
+        },
+    })
+}
+
+/// Get the address where the verifier and tenant can reach the agent.
+///
+/// If 'contact_ip' or 'contact_port' are not set, fall back to 'ip' and 'port'.
+/// The resulting address must be a hostname or a specific IP address,
+/// otherwise the registrar would store an address where the agent cannot be
+/// reached.
+fn config_get_contact_address(
+    config: &KeylimeConfig,
+) -> Result<(String, u32), Error> {
+    let (ip_option, contact_ip) = match config.agent.contact_ip.trim() {
+        "" => ("ip", config.agent.ip.trim()),
+        ip => ("contact_ip", ip),
+    };
+
+    let (port_option, contact_port) = match config.agent.contact_port {
+        0 => ("port", config.agent.port),
+        port => ("contact_port", port),
+    };
+
+    if contact_port == 0 || contact_port > u16::MAX as u32 {
+        let message = format!("No usable contact port for the agent: invalid port {contact_port} set in '{port_option}'. Set 'contact_port' with the port the verifier can connect to");
+        error!("{}", message);
+        return Err(Error::Configuration(message));
+    }
+
+    let unspecified = |host: &str| {
+        host.parse::<IpAddr>()
+            .map(|ip| ip.is_unspecified())
+            .unwrap_or(false)
+    };
+
+    match parse_host(ip_option, contact_ip) {
+        Ok(host) if !unspecified(&host) => Ok((host, contact_port)),
+        _ => {
+            let message = format!("No usable contact address for the agent: the address '{contact_ip}' set in '{ip_option}' cannot be used to reach the agent. Set 'contact_ip' with an address the verifier can connect to");
+            error!("{}", message);
+            Err(Error::Configuration(message))
+        }
+    }
+}
+
+/// Parse an IP address set in the configuration. IPv6 addresses can be
+/// enclosed in brackets, e.g. "[::1]".
+pub(crate) fn parse_ip_address(
+    option: &str,
+    ip: &str,
+) -> Result<IpAddr, Error> {
+    let ip = ip.trim();
+    let unbracketed = ip
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(ip);
+
+    unbracketed.parse::<IpAddr>().map_err(|e| {
+        let message =
+            format!("Invalid IP address '{ip}' set in '{option}': {e}");
+        error!("{}", message);
+        Error::Configuration(message)
+    })
+}
+
+/// Parse a host set in the configuration, which can be a hostname or an IP
+/// address. Only IPv6 addresses can be enclosed in brackets, e.g. "[::1]".
+/// IP addresses are returned without brackets, and hostnames unchanged.
+pub(crate) fn parse_host(option: &str, host: &str) -> Result<String, Error> {
+    let host = host.trim();
+    let invalid = |reason: &str| {
+        let message =
+            format!("Invalid host '{host}' set in '{option}': {reason}");
+        error!("{}", message);
+        Error::Configuration(message)
+    };
+
+    if let Some(unbracketed) =
+        host.strip_prefix('[').and_then(|s| s.strip_suffix(']'))
+    {
+        return match unbracketed.parse::<IpAddr>() {
+            Ok(ip @ IpAddr::V6(_)) => Ok(ip.to_string()),
+            _ => Err(invalid(
+                "only IPv6 addresses can be enclosed in brackets",
+            )),
+        };
+    }
+
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(ip.to_string());
+    }
+
+    // A hostname is made of labels of letters, digits and hyphens, and the
+    // last label is not numeric, which would be a mistyped IPv4 address
+    let labels: Vec<&str> = host.split('.').collect();
+    let last_label = host.rsplit('.').next().unwrap_or_default();
+    let valid_label = |label: &&str| {
+        (1..=63).contains(&label.len())
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+    };
+    if host.is_empty()
+        || host.len() > 253
+        || !labels.iter().all(valid_label)
+        || last_label.chars().all(|c| c.is_ascii_digit())
+    {
+        return Err(invalid("not a valid hostname or IP address"));
+    }
+    Ok(host.to_string())
+}
+
+/// Parse the NV index set in the 'ek_cert_nv_index' option, given as an
+/// hexadecimal string, e.g. "0x1c00002". An empty string results in None.
+pub(crate) fn parse_ek_cert_nv_index(
+    index: &str,
+) -> Result<Option<u32>, Error> {
+    let index = index.trim();
+    if index.is_empty() {
+        return Ok(None);
+    }
+
+    let invalid = |reason: String| {
+        let message = format!(
+            "Invalid NV index '{index}' set in 'ek_cert_nv_index': {reason}"
+        );
+        error!("{}", message);
+        Error::Configuration(message)
+    };
+    let value = u32::from_str_radix(index.trim_start_matches("0x"), 16)
+        .map_err(|e| invalid(e.to_string()))?;
+    if !(tpm::NV_INDEX_FIRST..=tpm::NV_INDEX_LAST).contains(&value) {
+        return Err(invalid(format!(
+            "NV indices must be between {:#x} and {:#x}",
+            tpm::NV_INDEX_FIRST,
+            tpm::NV_INDEX_LAST
+        )));
+    }
+    Ok(Some(value))
+}
+
+/// Parse the PCRs set in the 'quote_pcr_selection' option.
+///
+/// The selection is a comma separated list of PCR indices or ranges of
+/// indices, e.g. "0-7,10". An empty selection results in an empty list.
+pub(crate) fn parse_pcr_selection(
+    selection: &str,
+) -> Result<Vec<u32>, Error> {
+    let invalid = |item: &str| {
+        Error::Configuration(format!(
+            "Invalid PCR selection set in 'quote_pcr_selection': '{item}' is not a PCR index between 0 and 23 or a range of them"
+        ))
+    };
+    let parse_pcr =
+        |item: &str, value: &str| match value.trim().parse::<u32>() {
+            Ok(pcr) if pcr < 24 => Ok(pcr),
+            _ => Err(invalid(item)),
+        };
+
+    let mut pcrs = Vec::new();
+    for item in selection.split(',').map(str::trim) {
+        if item.is_empty() {
+            continue;
+        }
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let first = parse_pcr(item, first)?;
+                let last = parse_pcr(item, last)?;
+                if first > last {
+                    return Err(invalid(item));
+                }
+                pcrs.extend(first..=last);
+            }
+            None => pcrs.push(parse_pcr(item, item)?),
+        }
+    }
+
+    pcrs.sort_unstable();
+    pcrs.dedup();
+    Ok(pcrs)
+}
+
+/// Parse the cores set in the 'server_cpu_affinity' option.
+///
+/// The list is a comma separated list of core indices or ranges of indices,
+/// e.g. "0-3,8". An empty list results in an empty list.
+pub(crate) fn parse_cpu_list(list: &str) -> Result<Vec<usize>, Error> {
+    let invalid = |item: &str| {
+        Error::Configuration(format!(
+            "Invalid core list set in 'server_cpu_affinity': '{item}' is not a core index or a range of them"
+        ))
+    };
+    let parse_core =
+        |item: &str, value: &str| match value.trim().parse::<usize>() {
+            Ok(core) if core < libc::CPU_SETSIZE as usize => Ok(core),
+            _ => Err(invalid(item)),
+        };
+
+    let mut cores = Vec::new();
+    for item in list.split(',').map(str::trim) {
+        if item.is_empty() {
+            continue;
         }
-        let actions_dir = match config.agent.revocation_actions_dir.as_ref() {
-            "" => {
-                error!("The option 'enable_revocation_notifications' is set as 'true' but the revocation actions directory was set as empty in 'revocation_actions_dir'");
-                return Err(Error::Configuration("The option 'enable_revocation_notifications' is set as 'true' but 'revocation_actions_dir' was set as empty".to_string()));
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let first = parse_core(item, first)?;
+                let last = parse_core(item, last)?;
+                if first > last {
+                    return Err(invalid(item));
+                }
+                cores.extend(first..=last);
             }
-            dir => dir.to_string(),
-        };
+            None => cores.push(parse_core(item, item)?),
+        }
     }
 
-    let mut revocation_cert = config_get_file_path(
-        "revocation_cert",
-        &config.agent.revocation_cert,
-        keylime_dir,
-        &format!("secure/unzipped/{DEFAULT_REVOCATION_CERT}"),
-    );
-
-    Ok(KeylimeConfig {
-        agent: AgentConfig {
-            keylime_dir: keylime_dir.display().to_string(),
-            uuid,
-            server_key,
-            server_cert,
-            trusted_client_ca,
-            ek_handle,
-            agent_data_path,
-            revocation_cert,
-            ..config.agent.clone()
-        },
-    })
+    cores.sort_unstable();
+    cores.dedup();
+    Ok(cores)
 }
 
 /// Expand a file path from the configuration file.
@@ -737,8 +2216,29 @@ fn config_get_file_path(
     }
 }
 
-fn get_uuid(agent_uuid_config: &str) -> String {
-    match agent_uuid_config {
+/// Get the system hostname, used as the default agent name
+fn get_hostname() -> Result<String, Error> {
+    let mut buf = [0u8; 256];
+    let ret = unsafe {
+        libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len())
+    };
+    if ret != 0 {
+        let e = std::io::Error::last_os_error();
+        error!("Could not get the system hostname: {}", e);
+        return Err(Error::Configuration(format!(
+            "Could not get the system hostname to use as agent_name: {e}"
+        )));
+    }
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    Ok(String::from_utf8_lossy(&buf[..len]).to_string())
+}
+
+fn get_uuid(agent_uuid_config: &str) -> Result<String, Error> {
+    if let Some(path) = agent_uuid_config.strip_prefix("file:") {
+        return get_file_uuid(Path::new(path));
+    }
+
+    Ok(match agent_uuid_config {
         "hash_ek" => {
             info!("Using hashed EK as UUID");
             // DO NOT change this to something else. It is used later to set the correct value.
@@ -749,6 +2249,7 @@ fn get_uuid(agent_uuid_config: &str) -> String {
             info!("Generated a new UUID: {}", &agent_uuid);
             agent_uuid.to_string()
         }
+        "openstack" => resolve_openstack_uuid(OPENSTACK_METADATA_URL),
         uuid_config => match Uuid::parse_str(uuid_config) {
             Ok(uuid_config) => uuid_config.to_string(),
             Err(_) => {
@@ -758,6 +2259,75 @@ fn get_uuid(agent_uuid_config: &str) -> String {
                 agent_uuid.to_string()
             }
         },
+    })
+}
+
+// Read the UUID from a file managed externally (e.g. by a provisioning
+// system), falling back to a generated UUID if the file does not exist
+fn get_file_uuid(path: &Path) -> Result<String, Error> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            warn!("UUID file {} not found", path.display());
+            let agent_uuid = Uuid::new_v4();
+            info!("Using generated UUID: {}", &agent_uuid);
+            return Ok(agent_uuid.to_string());
+        }
+        Err(e) => {
+            return Err(Error::Configuration(format!(
+                "Failed to read UUID file {}: {e}",
+                path.display()
+            )))
+        }
+    };
+
+    let uuid = Uuid::parse_str(contents.trim()).map_err(|e| {
+        Error::Configuration(format!(
+            "Invalid UUID in file {}: {e}",
+            path.display()
+        ))
+    })?;
+    info!("Using UUID from file {}: {}", path.display(), &uuid);
+    Ok(uuid.to_string())
+}
+
+// Fetch the instance UUID from the OpenStack metadata service
+fn get_openstack_uuid(metadata_url: &str) -> Result<String, Error> {
+    let contents =
+        config_fetch_remote(metadata_url, OPENSTACK_METADATA_TIMEOUT)?;
+    let metadata: serde_json::Value = serde_json::from_str(&contents)
+        .map_err(|e| {
+            Error::Configuration(format!(
+                "Failed to parse OpenStack metadata: {e}"
+            ))
+        })?;
+    let uuid = metadata["uuid"].as_str().ok_or_else(|| {
+        Error::Configuration(
+            "OpenStack metadata does not contain the instance UUID"
+                .to_string(),
+        )
+    })?;
+    Uuid::parse_str(uuid).map(|u| u.to_string()).map_err(|e| {
+        Error::Configuration(format!(
+            "Invalid instance UUID in OpenStack metadata: {e}"
+        ))
+    })
+}
+
+// Use the OpenStack instance UUID as the agent UUID, falling back to a
+// generated UUID if it cannot be obtained
+fn resolve_openstack_uuid(metadata_url: &str) -> String {
+    match get_openstack_uuid(metadata_url) {
+        Ok(uuid) => {
+            info!("Using OpenStack instance UUID: {}", &uuid);
+            uuid
+        }
+        Err(e) => {
+            warn!("Could not get the OpenStack instance UUID: {}", e);
+            let agent_uuid = Uuid::new_v4();
+            info!("Using generated UUID: {}", &agent_uuid);
+            agent_uuid.to_string()
+        }
     }
 }
 
@@ -765,6 +2335,97 @@ fn get_uuid(agent_uuid_config: &str) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_config_read_signed() {
+        use openssl::{rsa::Rsa, sign::Signer};
+
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let config_path = dir.path().join("agent.conf");
+        let sig_path = dir.path().join("agent.conf.sig");
+        let key_path = dir.path().join("config-key.pem");
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(); //#[allow_ci]
+        fs::write(&key_path, key.public_key_to_pem().unwrap()).unwrap(); //#[allow_ci]
+
+        let contents = "[agent]\nuuid = \"hash_ek\"\n";
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap(); //#[allow_ci]
+        let signature =
+            signer.sign_oneshot_to_vec(contents.as_bytes()).unwrap(); //#[allow_ci]
+        fs::write(&config_path, contents).unwrap(); //#[allow_ci]
+        fs::write(&sig_path, signature).unwrap(); //#[allow_ci]
+
+        let result = config_read_signed(&config_path, &key_path);
+        assert_eq!(result.unwrap(), contents); //#[allow_ci]
+
+        // Tamper with the configuration file
+        fs::write(&config_path, "[agent]\nuuid = \"generate\"\n").unwrap(); //#[allow_ci]
+        assert!(config_read_signed(&config_path, &key_path).is_err());
+
+        // Missing signature
+        fs::remove_file(&sig_path).unwrap(); //#[allow_ci]
+        assert!(config_read_signed(&config_path, &key_path).is_err());
+    }
+
+    #[test]
+    fn test_config_file_layers_signed() {
+        use openssl::{rsa::Rsa, sign::Signer};
+
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let config_path = dir.path().join("agent.conf");
+        let snippets = dir.path().join("agent.conf.d");
+        let snippet_path = snippets.join("01-port.conf");
+        let key_path = dir.path().join("config-key.pem");
+        fs::create_dir(&snippets).unwrap(); //#[allow_ci]
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(); //#[allow_ci]
+        fs::write(&key_path, key.public_key_to_pem().unwrap()).unwrap(); //#[allow_ci]
+
+        let write_signed = |path: &Path, contents: &str| {
+            let mut signer =
+                Signer::new(MessageDigest::sha256(), &key).unwrap(); //#[allow_ci]
+            let signature =
+                signer.sign_oneshot_to_vec(contents.as_bytes()).unwrap(); //#[allow_ci]
+            let mut sig_path = path.as_os_str().to_owned();
+            sig_path.push(".sig");
+            fs::write(path, contents).unwrap(); //#[allow_ci]
+            fs::write(sig_path, signature).unwrap(); //#[allow_ci]
+        };
+        write_signed(&config_path, "[agent]\nuuid = \"hash_ek\"\n");
+        write_signed(&snippet_path, "[agent]\nport = 9003\n");
+
+        let path = config_path.display().to_string();
+        let layers =
+            config_file_layers("user", &path, Some(&key_path)).unwrap(); //#[allow_ci]
+        let config = config_build(layers).build().unwrap(); //#[allow_ci]
+        assert_eq!(config.get_string("agent.uuid").unwrap(), "hash_ek"); //#[allow_ci]
+        assert_eq!(config.get_int("agent.port").unwrap(), 9003); //#[allow_ci]
+
+        // A snippet without a valid signature is rejected as well
+        fs::write(&snippet_path, "[agent]\nport = 9004\n").unwrap(); //#[allow_ci]
+        assert!(config_file_layers("user", &path, Some(&key_path)).is_err());
+        fs::write(snippets.join("02-unsigned.conf"), "[agent]\n").unwrap(); //#[allow_ci]
+        write_signed(&snippet_path, "[agent]\nport = 9003\n");
+        assert!(config_file_layers("user", &path, Some(&key_path)).is_err());
+
+        // Without a key, the files are loaded without verification
+        assert!(config_file_layers("user", &path, None).is_ok());
+    }
+
+    #[test]
+    fn test_duplicate_key() {
+        // The TOML parser rejects keys set twice in the same table, naming
+        // the duplicated key, instead of silently keeping one of the values
+        let contents =
+            "[agent]\nport = 9002\nip = \"127.0.0.1\"\nport = 9003\n";
+        let result = Config::builder()
+            .add_source(KeylimeConfig::default())
+            .add_source(File::from_str(contents, FileFormat::Toml))
+            .build();
+        let err = result.unwrap_err().to_string(); //#[allow_ci]
+        assert!(err.contains("duplicate key: `port`"), "{err}");
+    }
 
     #[test]
     fn test_default() {
@@ -780,6 +2441,27 @@ mod tests {
         assert_eq!(expected, default);
     }
 
+    #[test]
+    fn test_default_pcr_measurement_log() {
+        let path =
+            concat!(env!("CARGO_MANIFEST_DIR"), "/../keylime-agent.conf");
+        let conf = Config::builder()
+            .add_source(File::new(path, FileFormat::Toml))
+            .build()
+            .unwrap(); //#[allow_ci]
+
+        // The default set in the configuration file is the same as when the
+        // option is not set
+        assert_eq!(
+            conf.get_string("agent.pcr_measurement_log").unwrap(), //#[allow_ci]
+            AgentConfig::default().pcr_measurement_log
+        );
+
+        let default = KeylimeConfig::default();
+        assert!(Path::new(&default.agent.pcr_measurement_log)
+            .ends_with(PCR_MEASUREMENT_LOG_FILE));
+    }
+
     #[test]
     fn get_revocation_cert_path_default() {
         let test_config = KeylimeConfig::default();
@@ -907,21 +2589,453 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn get_agent_name_default() {
+        let test_config = KeylimeConfig::default();
+        let hostname = get_hostname().unwrap(); //#[allow_ci]
+        assert!(!hostname.is_empty());
+        assert_eq!(test_config.agent.agent_name, hostname);
+    }
+
+    #[test]
+    fn get_agent_name_override() {
+        let test_config = KeylimeConfig {
+            agent: AgentConfig {
+                agent_name: "my-agent".to_string(),
+                ..Default::default()
+            },
+        };
+        let result = config_translate_keywords(&test_config);
+        assert!(result.is_ok());
+        let test_config = result.unwrap(); //#[allow_ci]
+        assert_eq!(test_config.agent.agent_name, "my-agent");
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_config_fetch_remote() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let contents = "[agent]\nagent_name = \"remote\"\n";
+
+        let mock_server = MockServer::start().await;
+        let mock = Mock::given(method("GET"))
+            .and(path("/agent.conf"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string(contents),
+            );
+        mock_server.register(mock).await;
+
+        let url = format!("{}/agent.conf", mock_server.uri());
+        let fetched =
+            config_fetch_remote(&url, REMOTE_CONFIG_TIMEOUT).unwrap(); //#[allow_ci]
+        assert_eq!(fetched, contents);
+
+        let remote: KeylimeConfig = Config::builder()
+            .add_source(KeylimeConfig::default())
+            .add_source(File::from_str(&fetched, FileFormat::Toml))
+            .build()
+            .unwrap() //#[allow_ci]
+            .try_deserialize()
+            .unwrap(); //#[allow_ci]
+        assert_eq!(remote.agent.agent_name, "remote");
+
+        // Missing file results in a configuration error
+        let url = format!("{}/missing.conf", mock_server.uri());
+        let result = config_fetch_remote(&url, REMOTE_CONFIG_TIMEOUT);
+        assert!(matches!(result, Err(Error::Configuration(_))));
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_resolve_openstack_uuid() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let metadata = r#"{"uuid": "D432FBB3-D2F1-4A97-9EF7-75BD81C00000", "name": "instance"}"#;
+
+        let mock_server = MockServer::start().await;
+        let mock = Mock::given(method("GET"))
+            .and(path("/openstack/latest/meta_data.json"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string(metadata),
+            );
+        mock_server.register(mock).await;
+
+        let url =
+            format!("{}/openstack/latest/meta_data.json", mock_server.uri());
+        assert_eq!(
+            resolve_openstack_uuid(&url),
+            "d432fbb3-d2f1-4a97-9ef7-75bd81c00000"
+        );
+
+        // On failure, a new UUID is generated
+        let url = format!("{}/missing", mock_server.uri());
+        assert!(get_openstack_uuid(&url).is_err());
+        let uuid = resolve_openstack_uuid(&url);
+        assert_ne!(uuid, "d432fbb3-d2f1-4a97-9ef7-75bd81c00000");
+        let _ = Uuid::parse_str(&uuid).unwrap(); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_parse_ip_address() {
+        assert_eq!(
+            parse_ip_address("ip", "::1").unwrap(), //#[allow_ci]
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        );
+        assert_eq!(
+            parse_ip_address("ip", "[::1]").unwrap(), //#[allow_ci]
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        );
+        assert_eq!(
+            parse_ip_address("ip", "0.0.0.0").unwrap(), //#[allow_ci]
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        );
+        assert!(parse_ip_address("ip", "256.0.0.1").is_err());
+        assert!(parse_ip_address("ip", "[127.0.0.1").is_err());
+        assert!(parse_ip_address("ip", "").is_err());
+
+        // The addresses are stored without the brackets
+        let config = KeylimeConfig {
+            agent: AgentConfig {
+                ip: "[::]".to_string(),
+                registrar_ip: "[::1]".to_string(),
+                ..Default::default()
+            },
+        };
+        let config = config_translate_keywords(&config).unwrap(); //#[allow_ci]
+        assert_eq!(config.agent.ip, "::");
+        assert_eq!(config.agent.registrar_ip, "::1");
+
+        let config = KeylimeConfig {
+            agent: AgentConfig {
+                registrar_ip: "invalid host".to_string(),
+                ..Default::default()
+            },
+        };
+        assert!(config_translate_keywords(&config).is_err());
+    }
+
+    #[test]
+    fn test_parse_host() {
+        assert_eq!(parse_host("registrar_ip", "[::1]").unwrap(), "::1"); //#[allow_ci]
+        assert_eq!(parse_host("registrar_ip", "::1").unwrap(), "::1"); //#[allow_ci]
+        assert_eq!(
+            parse_host("registrar_ip", "127.0.0.1").unwrap(), //#[allow_ci]
+            "127.0.0.1"
+        );
+        assert_eq!(
+            parse_host("registrar_ip", "registrar.example.com").unwrap(), //#[allow_ci]
+            "registrar.example.com"
+        );
+        assert_eq!(
+            parse_host("registrar_ip", "localhost").unwrap(), //#[allow_ci]
+            "localhost"
+        );
+
+        // Only IPv6 addresses are bracketed
+        assert!(parse_host("registrar_ip", "[127.0.0.1]").is_err());
+        assert!(parse_host("registrar_ip", "[registrar]").is_err());
+        assert!(parse_host("registrar_ip", "").is_err());
+        assert!(parse_host("registrar_ip", "256.0.0.1").is_err());
+        assert!(parse_host("registrar_ip", "-registrar").is_err());
+        assert!(parse_host("registrar_ip", "registrar..example").is_err());
+        assert!(parse_host("registrar_ip", "registrar:8890").is_err());
+    }
+
+    #[test]
+    fn test_contact_address() {
+        let mut config = KeylimeConfig::default();
+        config.agent.ip = "10.0.0.1".to_string();
+        config.agent.port = 9003;
+
+        // Without contact address, fall back to the binding address
+        config.agent.contact_ip = "".to_string();
+        config.agent.contact_port = 0;
+        let (ip, port) = config_get_contact_address(&config).unwrap(); //#[allow_ci]
+        assert_eq!(ip, "10.0.0.1");
+        assert_eq!(port, 9003);
+
+        config.agent.contact_ip = "192.168.1.1".to_string();
+        config.agent.contact_port = 9004;
+        let (ip, port) = config_get_contact_address(&config).unwrap(); //#[allow_ci]
+        assert_eq!(ip, "192.168.1.1");
+        assert_eq!(port, 9004);
+
+        // The agent binding to all interfaces is not a usable contact address
+        config.agent.ip = "0.0.0.0".to_string();
+        config.agent.contact_ip = "".to_string();
+        assert!(config_get_contact_address(&config).is_err());
+
+        config.agent.ip = "::".to_string();
+        assert!(config_get_contact_address(&config).is_err());
+
+        config.agent.ip = "".to_string();
+        assert!(config_get_contact_address(&config).is_err());
+
+        // No usable port
+        config.agent.contact_ip = "192.168.1.1".to_string();
+        config.agent.port = 0;
+        config.agent.contact_port = 0;
+        assert!(config_get_contact_address(&config).is_err());
+
+        config.agent.contact_port = 65536;
+        assert!(config_get_contact_address(&config).is_err());
+
+        // IPv6 addresses are accepted with or without brackets
+        config.agent.contact_ip = "[::1]".to_string();
+        config.agent.contact_port = 9004;
+        let (ip, _) = config_get_contact_address(&config).unwrap(); //#[allow_ci]
+        assert_eq!(ip, "::1");
+
+        // A hostname is accepted as well
+        config.agent.contact_ip = "agent.example.com".to_string();
+        let (ip, _) = config_get_contact_address(&config).unwrap(); //#[allow_ci]
+        assert_eq!(ip, "agent.example.com");
+    }
+
+    #[test]
+    fn test_payload_cipher_mode() {
+        for mode in ["gcm", "cbc-hmac"] {
+            let test_config = KeylimeConfig {
+                agent: AgentConfig {
+                    payload_cipher_mode: mode.to_string(),
+                    ..Default::default()
+                },
+            };
+            assert!(config_translate_keywords(&test_config).is_ok());
+        }
+
+        let test_config = KeylimeConfig {
+            agent: AgentConfig {
+                payload_cipher_mode: "cbc".to_string(),
+                ..Default::default()
+            },
+        };
+        assert!(matches!(
+            config_translate_keywords(&test_config),
+            Err(Error::Configuration(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_ek_cert_nv_index() {
+        assert_eq!(parse_ek_cert_nv_index("").unwrap(), None); //#[allow_ci]
+        assert_eq!(
+            parse_ek_cert_nv_index("0x1c00002").unwrap(), //#[allow_ci]
+            Some(0x1c00002)
+        );
+        assert_eq!(
+            parse_ek_cert_nv_index("01c0000a").unwrap(), //#[allow_ci]
+            Some(0x1c0000a)
+        );
+        for invalid in ["0x81000000", "0xffffff", "nvindex", "0x"] {
+            assert!(parse_ek_cert_nv_index(invalid).is_err(), "{invalid}");
+        }
+
+        let mut config = KeylimeConfig::default();
+        config.agent.ek_cert_nv_index = "0x81010001".to_string();
+        assert!(config_translate_keywords(&config).is_err());
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("").unwrap(), Vec::<usize>::new()); //#[allow_ci]
+        assert_eq!(parse_cpu_list("0-3,8").unwrap(), vec![0, 1, 2, 3, 8]); //#[allow_ci]
+        assert_eq!(
+            parse_cpu_list(" 8, 2-3 ,8,1023").unwrap(), //#[allow_ci]
+            vec![2, 3, 8, 1023]
+        );
+        for invalid in ["1024", "0-1024", "3-0", "a", "1,b", "-3", "1-2-3"] {
+            assert!(parse_cpu_list(invalid).is_err(), "{invalid}");
+        }
+
+        let mut config = KeylimeConfig::default();
+        config.agent.server_cpu_affinity = "0,x".to_string();
+        assert!(config_translate_keywords(&config).is_err());
+    }
+
+    #[test]
+    fn test_parse_pcr_selection() {
+        assert_eq!(parse_pcr_selection("").unwrap(), Vec::<u32>::new()); //#[allow_ci]
+        assert_eq!(
+            parse_pcr_selection("0-7,10").unwrap(), //#[allow_ci]
+            vec![0, 1, 2, 3, 4, 5, 6, 7, 10]
+        );
+        assert_eq!(
+            parse_pcr_selection(" 10, 2-3 ,10,23").unwrap(), //#[allow_ci]
+            vec![2, 3, 10, 23]
+        );
+        for invalid in ["24", "0-24", "7-0", "a", "1,b", "-3", "1-2-3"] {
+            assert!(parse_pcr_selection(invalid).is_err(), "{invalid}");
+        }
+
+        let mut config = KeylimeConfig::default();
+        config.agent.quote_pcr_selection = "0-7,24".to_string();
+        let result = config_translate_keywords(&config);
+        assert!(
+            matches!(result, Err(Error::Configuration(m)) if m.contains("'24'"))
+        );
+    }
+
+    #[test]
+    fn test_config_trace_layers() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let sys = dir.path().join("sys.conf");
+        let user = dir.path().join("user.conf");
+        let snippets = dir.path().join("sys.conf.d");
+        let snippet = snippets.join("10-port.conf");
+        fs::create_dir(&snippets).unwrap(); //#[allow_ci]
+        fs::write(&sys, "[agent]\nport = 1000\nip = \"10.0.0.1\"\n").unwrap(); //#[allow_ci]
+        fs::write(&snippet, "[agent]\nport = 2000\n").unwrap(); //#[allow_ci]
+        fs::write(&user, "[agent]\nip = \"10.0.0.2\"\n").unwrap(); //#[allow_ci]
+
+        let sys = sys.display().to_string();
+        let user = user.display().to_string();
+        let mut layers: Vec<ConfigLayer> =
+            vec![("default".to_string(), Box::new(KeylimeConfig::default()))];
+        layers.extend(config_file_layers("sys", &sys, None).unwrap()); //#[allow_ci]
+        layers.extend(config_file_layers("user", &user, None).unwrap()); //#[allow_ci]
+
+        // The value set in the snippet overrides the one in the file
+        let sources = config_trace_layers(&layers).unwrap(); //#[allow_ci]
+        assert_eq!(sources["port"], format!("snippet {}", snippet.display()));
+        assert_eq!(sources["ip"], format!("user {user}"));
+        assert_eq!(sources["uuid"], "default");
+
+        let options = config_build(layers)
+            .build()
+            .unwrap() //#[allow_ci]
+            .get_table("agent")
+            .unwrap(); //#[allow_ci]
+        assert_eq!(options["port"].clone().into_int().unwrap(), 2000); //#[allow_ci]
+        assert_eq!(options["ip"].clone().into_string().unwrap(), "10.0.0.2"); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_config_merge_set_options() {
+        let layers: Vec<ConfigLayer> = vec![
+            ("default".to_string(), Box::new(KeylimeConfig::default())),
+            (
+                "user".to_string(),
+                Box::new(File::from_str(
+                    "[agent]\nport = 1000\n",
+                    FileFormat::Toml,
+                )),
+            ),
+        ];
+
+        // Only the option set in the file is returned
+        let options = config_merge_set_options(layers).unwrap(); //#[allow_ci]
+        assert_eq!(options.len(), 1);
+        assert_eq!(options["port"].clone().into_int().unwrap(), 1000); //#[allow_ci]
+
+        // No option is returned when nothing is set
+        let layers: Vec<ConfigLayer> =
+            vec![("default".to_string(), Box::new(KeylimeConfig::default()))];
+        let options = config_merge_set_options(layers).unwrap(); //#[allow_ci]
+        assert!(options.is_empty());
+    }
+
+    #[test]
+    fn test_config_from_layers() {
+        let layers = |toml: &str| -> Vec<ConfigLayer> {
+            vec![
+                ("default".to_string(), Box::new(KeylimeConfig::default())),
+                (
+                    "user".to_string(),
+                    Box::new(File::from_str(toml, FileFormat::Toml)),
+                ),
+            ]
+        };
+
+        let config = KeylimeConfig::from_layers(
+            layers("[agent]\nport = 1000\n"),
+            false,
+        )
+        .unwrap(); //#[allow_ci]
+        assert_eq!(config.agent.port, 1000);
+
+        // Enabling mTLS without a trusted CA is rejected
+        let result = KeylimeConfig::from_layers(layers(
+            "[agent]\nenable_agent_mtls = true\ntrusted_client_ca = \"\"\n",
+        ), false);
+        assert!(matches!(result, Err(Error::Configuration(_))));
+
+        // Without mTLS the CA is not needed
+        let result = KeylimeConfig::from_layers(layers(
+            "[agent]\nenable_agent_mtls = false\ntrusted_client_ca = \"\"\n",
+        ), false);
+        assert!(result.is_ok());
+
+        // Offline, the OpenStack metadata service is not queried
+        let config = KeylimeConfig::from_layers(
+            layers("[agent]\nuuid = \"openstack\"\n"),
+            true,
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(Uuid::parse_str(&config.agent.uuid).is_ok());
+    }
+
+    #[test]
+    fn test_config_hash() {
+        let config = KeylimeConfig::default();
+        let hash = config.hash().unwrap(); //#[allow_ci]
+        assert_eq!(hash.len(), 64);
+
+        // Identical configurations have the same hash
+        assert_eq!(KeylimeConfig::default().hash().unwrap(), hash); //#[allow_ci]
+
+        let mut changed = KeylimeConfig::default();
+        changed.agent.port = 9003;
+        assert_ne!(changed.hash().unwrap(), hash); //#[allow_ci]
+
+        // The secrets are not part of the hash
+        let mut secret = KeylimeConfig::default();
+        secret.agent.tpm_ownerpassword = "changed".to_string();
+        secret.agent.server_key_password = "changed".to_string();
+        assert_eq!(secret.hash().unwrap(), hash); //#[allow_ci]
+    }
+
     #[test]
     fn test_get_uuid() {
-        assert_eq!(get_uuid("hash_ek"), "hash_ek");
-        let _ = Uuid::parse_str(&get_uuid("generate")).unwrap(); //#[allow_ci]
+        assert_eq!(get_uuid("hash_ek").unwrap(), "hash_ek"); //#[allow_ci]
+        let _ = Uuid::parse_str(&get_uuid("generate").unwrap()).unwrap(); //#[allow_ci]
         assert_eq!(
-            get_uuid("D432FBB3-D2F1-4A97-9EF7-75BD81C00000"),
+            get_uuid("D432FBB3-D2F1-4A97-9EF7-75BD81C00000").unwrap(), //#[allow_ci]
             "d432fbb3-d2f1-4a97-9ef7-75bd81c00000"
         );
         assert_ne!(
-            get_uuid("D432FBB3-D2F1-4A97-9EF7-75BD81C0000X"),
+            get_uuid("D432FBB3-D2F1-4A97-9EF7-75BD81C0000X").unwrap(), //#[allow_ci]
             "d432fbb3-d2f1-4a97-9ef7-75bd81c0000X"
         );
-        let _ = Uuid::parse_str(&get_uuid(
-            "D432FBB3-D2F1-4A97-9EF7-75BD81C0000X",
-        ))
+        let _ = Uuid::parse_str(
+            &get_uuid("D432FBB3-D2F1-4A97-9EF7-75BD81C0000X").unwrap(), //#[allow_ci]
+        )
+        .unwrap(); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_get_uuid_file() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+
+        let valid = dir.path().join("valid");
+        fs::write(&valid, "D432FBB3-D2F1-4A97-9EF7-75BD81C00000\n").unwrap(); //#[allow_ci]
+        assert_eq!(
+            get_uuid(&format!("file:{}", valid.display())).unwrap(), //#[allow_ci]
+            "d432fbb3-d2f1-4a97-9ef7-75bd81c00000"
+        );
+
+        let invalid = dir.path().join("invalid");
+        fs::write(&invalid, "not-a-uuid").unwrap(); //#[allow_ci]
+        assert!(get_uuid(&format!("file:{}", invalid.display())).is_err());
+
+        // A missing file falls back to a generated UUID
+        let missing = dir.path().join("missing");
+        let _ = Uuid::parse_str(
+            &get_uuid(&format!("file:{}", missing.display())).unwrap(), //#[allow_ci]
+        )
         .unwrap(); //#[allow_ci]
     }
 
@@ -965,6 +3079,76 @@ mod tests {
             ("EK_HANDLE", "override_ek_handle"),
             ("RUN_AS", "override_run_as"),
             ("AGENT_DATA_PATH", "override_agent_data_path"),
+            ("AGENT_NAME", "override_agent_name"),
+            ("VERIFY_TLS_ON_STARTUP", "false"),
+            ("MAX_KEYSET_SIZE", "9999"),
+            ("TPM_SESSION_ENCRYPTION", "true"),
+            ("IMA_ML_PATH", "/test/ima/path"),
+            ("ENABLE_LANDING_PAGE", "true"),
+            ("GENERATE_SELF_SIGNED_ON_FAILURE", "true"),
+            ("SYSTEM_FACTS_COMMAND", "uname -r"),
+            ("MAX_IMA_REQUESTS", "5"),
+            ("WRITE_KEY_FILE", "false"),
+            ("TRANSPORT_KEY_ROTATION_INTERVAL", "3600"),
+            ("ENABLE_DEBUG_ENDPOINTS", "true"),
+            ("CLOCK_SKEW_TOLERANCE", "120"),
+            ("RLIMIT_NOFILE", "1024"),
+            ("RLIMIT_NPROC", "64"),
+            ("STATSD_ENDPOINT", "127.0.0.1:8125"),
+            ("TOLERATE_READONLY_STATE", "true"),
+            ("ENABLE_REPROVISION", "true"),
+            ("AK_RSA_KEY_BITS", "3072"),
+            ("AK_ECC_CURVE", "nist_p256"),
+            ("ASYNC_QUOTES", "true"),
+            ("REVOCATION_SELF_ONLY", "false"),
+            ("PAYLOAD_PULL_URL", "https://example.com/payload"),
+            ("INCLUDE_FIRMWARE_VERSION", "true"),
+            ("FIRMWARE_VERSION_PCR", "15"),
+            ("PAYLOAD_UNZIP_THREADS", "4"),
+            ("PAYLOAD_DENIED_EXTENSIONS", "so,ko"),
+            ("STARTUP_TIMEOUT", "300"),
+            ("PAYLOAD_SCRIPT_REQUIRED", "true"),
+            ("SECURE_MOUNT_UNSHARE", "true"),
+            ("CONFIG_HASH_PCR", "15"),
+            ("TSS_LOG_LEVEL", "debug"),
+            ("VERIFY_REGISTRATION", "true"),
+            ("QUOTE_PCR_SELECTION", "0-7,10"),
+            ("REQUIRE_EK_CERT", "true"),
+            ("SKIP_UNCHANGED_REGISTRATION", "true"),
+            ("SECURE_MOUNT_WAIT", "5"),
+            ("REGISTRAR_RETRY_ATTEMPTS", "10"),
+            ("REGISTRAR_RETRY_INTERVAL", "2"),
+            ("REGISTRAR_RETRY_MAX_INTERVAL", "60"),
+            ("AUTO_RECOVER_ACTIVATION", "true"),
+            ("MTLS_OPTIONAL_ENDPOINTS", "/version"),
+            ("ENABLE_METRICS", "true"),
+            ("NONCE_MIN_LENGTH", "8"),
+            ("NONCE_MAX_LENGTH", "32"),
+            ("RESPONSE_TIMEOUT", "30"),
+            ("REGISTRAR_TLS", "true"),
+            ("PAYLOAD_WAIT_TIMEOUT", "60"),
+            ("EK_CERT_NV_INDEX", "0x1c00002"),
+            ("REVOCATION_NOTIFICATION_TIMEOUT", "5"),
+            ("SERVER_CPU_AFFINITY", "0-1"),
+            ("REVOCATION_ALLOWED_ACTIONS", "local_action_hello_shell.sh"),
+            ("SECURE_MOUNT_MODE", "shm_dir"),
+            ("SECURE_MOUNT_PATH", "/run/keylime-secure"),
+            ("PAYLOAD_CIPHER_MODE", "cbc-hmac"),
+            ("ENABLE_TPM_CLOCK_CHECK", "true"),
+            ("TPM_CLOCK_SKEW_FATAL", "true"),
+            ("MIN_KEY_BITS", "4096"),
+            ("MEASUREDBOOT_ML_PATH", "/tmp/bios"),
+            ("REJECT_KEY_REPLACEMENT", "true"),
+            ("AGENT_BINARY_PCR", "23"),
+            ("TPM_TCTI_FALLBACK", "tabrmd,device:/dev/tpm0"),
+            ("AGENT_DATA_FORMAT", "cbor"),
+            ("INCLUDE_IMA_PCR_AGGREGATE", "true"),
+            ("PCR_MEASUREMENT_LOG", "/run/keylime/pcr_measurements.log"),
+            ("HMAC_HASH_ALG", "sha512"),
+            ("STATE_SNAPSHOT_PATH", "/tmp/state"),
+            ("CONFIG_SIGNATURE_KEY", "/path/to/key.pem"),
+            ("REGISTRAR_CA", "override_registrar_ca"),
+            ("MAX_PAYLOAD_RUNS", "2"),
         ]);
 
         for (c, v) in override_map.into_iter() {
@@ -0,0 +1,218 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+use crate::error::Error;
+use config::{Config, File, FileFormat, Map, Value};
+use serde::Deserialize;
+use std::path::Path;
+
+/// The type expected for the value of an option
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum OptionType {
+    String,
+    Integer,
+    Boolean,
+}
+
+/// The constraints on the value of an option
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct OptionSchema {
+    #[serde(rename = "type")]
+    pub kind: OptionType,
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+    pub values: Option<Vec<String>>,
+}
+
+/// The schema of the agent configuration, listing the allowed options in the
+/// 'agent' table and the constraints on their values. For example:
+///
+/// ```toml
+/// [agent.port]
+/// type = "integer"
+/// min = 1
+/// max = 65535
+///
+/// [agent.tpm_hash_alg]
+/// type = "string"
+/// values = ["sha256", "sha384"]
+/// ```
+#[derive(Debug, Deserialize)]
+pub(crate) struct ConfigSchema {
+    pub agent: Map<String, OptionSchema>,
+}
+
+impl ConfigSchema {
+    /// Load the schema from a TOML file
+    pub(crate) fn load(path: &Path) -> Result<Self, Error> {
+        let path = path.display().to_string();
+        Ok(Config::builder()
+            .add_source(File::new(&path, FileFormat::Toml).required(true))
+            .build()?
+            .try_deserialize()?)
+    }
+
+    /// Check the options against the schema, returning all the violations
+    /// found, sorted by option name
+    pub(crate) fn validate(
+        &self,
+        options: &Map<String, Value>,
+    ) -> Vec<String> {
+        let mut names: Vec<&String> = options.keys().collect();
+        names.sort();
+
+        let mut violations = Vec::new();
+        for name in names {
+            let value = &options[name];
+            let schema = match self.agent.get(name) {
+                Some(s) => s,
+                None => {
+                    violations.push(format!(
+                        "agent.{name}: option not allowed by the schema"
+                    ));
+                    continue;
+                }
+            };
+            if let Err(message) = schema.check(value) {
+                violations.push(format!("agent.{name}: {message}"));
+            }
+        }
+        violations
+    }
+}
+
+impl OptionSchema {
+    // The values are converted as when the configuration is deserialized, so
+    // that e.g. "true" is accepted as a boolean
+    fn check(&self, value: &Value) -> Result<(), String> {
+        match self.kind {
+            OptionType::Boolean => {
+                _ = value.clone().into_bool().map_err(|_| {
+                    format!("expected a boolean, found '{value}'")
+                })?;
+            }
+            OptionType::Integer => {
+                let v = value.clone().into_int().map_err(|_| {
+                    format!("expected an integer, found '{value}'")
+                })?;
+                if let Some(min) = self.min.filter(|min| v < *min) {
+                    return Err(format!(
+                        "value {v} is less than the minimum {min}"
+                    ));
+                }
+                if let Some(max) = self.max.filter(|max| v > *max) {
+                    return Err(format!(
+                        "value {v} is greater than the maximum {max}"
+                    ));
+                }
+            }
+            OptionType::String => {
+                let v = value.clone().into_string().map_err(|_| {
+                    format!("expected a string, found '{value}'")
+                })?;
+                if let Some(values) = &self.values {
+                    if !values.contains(&v) {
+                        return Err(format!(
+                            "value '{v}' is not one of {}",
+                            values.join(", ")
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &str = r#"
+        [agent.port]
+        type = "integer"
+        min = 1
+        max = 65535
+
+        [agent.enable_agent_mtls]
+        type = "boolean"
+
+        [agent.tpm_hash_alg]
+        type = "string"
+        values = ["sha256", "sha384"]
+
+        [agent.ip]
+        type = "string"
+    "#;
+
+    fn options(toml: &str) -> Map<String, Value> {
+        Config::builder()
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()
+            .unwrap() //#[allow_ci]
+            .get_table("agent")
+            .unwrap() //#[allow_ci]
+    }
+
+    fn schema() -> ConfigSchema {
+        Config::builder()
+            .add_source(File::from_str(SCHEMA, FileFormat::Toml))
+            .build()
+            .unwrap() //#[allow_ci]
+            .try_deserialize()
+            .unwrap() //#[allow_ci]
+    }
+
+    #[test]
+    fn test_validate_valid() {
+        let options = options(
+            r#"
+            [agent]
+            port = 9002
+            enable_agent_mtls = "true"
+            tpm_hash_alg = "sha256"
+            ip = "127.0.0.1"
+            "#,
+        );
+        assert!(schema().validate(&options).is_empty());
+    }
+
+    #[test]
+    fn test_validate_violations() {
+        let options = options(
+            r#"
+            [agent]
+            port = 70000
+            enable_agent_mtls = "maybe"
+            tpm_hash_alg = "sha1"
+            ip = "127.0.0.1"
+            unknown_option = 1
+            "#,
+        );
+
+        // All the violations are reported
+        assert_eq!(
+            schema().validate(&options),
+            vec![
+                "agent.enable_agent_mtls: expected a boolean, found 'maybe'",
+                "agent.port: value 70000 is greater than the maximum 65535",
+                "agent.tpm_hash_alg: value 'sha1' is not one of sha256, sha384",
+                "agent.unknown_option: option not allowed by the schema",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_schema() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("schema.toml");
+        std::fs::write(&path, SCHEMA).unwrap(); //#[allow_ci]
+        let schema = ConfigSchema::load(&path).unwrap(); //#[allow_ci]
+        assert_eq!(schema.agent.len(), 4);
+
+        std::fs::write(&path, "[agent.port]\ntype = \"float\"\n").unwrap(); //#[allow_ci]
+        assert!(ConfigSchema::load(&path).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::rt::net::TcpStream;
+use log::*;
+use std::{
+    any::Any, mem::size_of, os::unix::io::AsRawFd, ptr, time::Duration,
+};
+
+/// Sets the maximum time the response data written to the connection may
+/// remain unacknowledged by the client (TCP_USER_TIMEOUT). When a client
+/// stops reading the response, the kernel drops the connection once the
+/// timeout expires, releasing the resources held for it.
+pub(crate) fn set_response_timeout(conn: &dyn Any, timeout: Duration) {
+    let fd = if let Some(tls) = conn.downcast_ref::<TlsStream<TcpStream>>() {
+        tls.get_ref().as_raw_fd()
+    } else if let Some(tcp) = conn.downcast_ref::<TcpStream>() {
+        tcp.as_raw_fd()
+    } else {
+        return;
+    };
+
+    let millis = libc::c_uint::try_from(timeout.as_millis())
+        .unwrap_or(libc::c_uint::MAX);
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_USER_TIMEOUT,
+            ptr::addr_of!(millis).cast(),
+            size_of::<libc::c_uint>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        warn!(
+            "Could not set the response timeout on the connection: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{dev::Extensions, web, App, HttpResponse, HttpServer};
+    use std::io::{Read, Write};
+
+    // Large enough to fill the socket buffers of both ends, which are at most
+    // 4 MiB for sending and 6 MiB for receiving by default on Linux
+    const BODY_SIZE: usize = 16 * 1024 * 1024;
+    const RESPONSE_TIMEOUT: Duration = Duration::from_millis(250);
+
+    #[actix_rt::test]
+    async fn test_slow_reader_dropped() {
+        let server = HttpServer::new(|| {
+            App::new().route(
+                "/",
+                web::get().to(|| async {
+                    HttpResponse::Ok().body(vec![b'a'; BODY_SIZE])
+                }),
+            )
+        })
+        .on_connect(|conn: &dyn Any, _: &mut Extensions| {
+            set_response_timeout(conn, RESPONSE_TIMEOUT)
+        })
+        .workers(1)
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap(); //#[allow_ci]
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        _ = actix_rt::spawn(server);
+
+        // The client sends the request and does not read the response until
+        // the timeout has expired
+        let received = actix_rt::task::spawn_blocking(move || {
+            let mut client = std::net::TcpStream::connect(addr).unwrap(); //#[allow_ci]
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap(); //#[allow_ci]
+            std::thread::sleep(RESPONSE_TIMEOUT * 8);
+            let mut buf = Vec::new();
+            let result = client.read_to_end(&mut buf);
+            (result, buf.len())
+        })
+        .await
+        .unwrap(); //#[allow_ci]
+
+        handle.stop(false).await;
+
+        let (result, len) = received;
+        assert!(result.is_err());
+        assert!(len < BODY_SIZE);
+    }
+}
@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+use crate::error::Error;
+use log::*;
+use std::{
+    io,
+    mem::{self, size_of},
+};
+
+// Returns the CPU set the calling thread is allowed to run on
+fn get_affinity() -> io::Result<libc::cpu_set_t> {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    let ret = unsafe {
+        libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &mut set)
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(set)
+}
+
+/// Returns the cores the calling thread is allowed to run on
+pub(crate) fn available_cores() -> io::Result<Vec<usize>> {
+    let set = get_affinity()?;
+    Ok((0..libc::CPU_SETSIZE as usize)
+        .filter(|core| unsafe { libc::CPU_ISSET(*core, &set) })
+        .collect())
+}
+
+/// Checks that the cores set in 'server_cpu_affinity' exist and are
+/// available to the agent
+pub(crate) fn check_cores(cores: &[usize]) -> Result<(), Error> {
+    let available = available_cores()?;
+    match cores.iter().find(|core| !available.contains(core)) {
+        Some(core) => Err(Error::Configuration(format!(
+            "Invalid core set in 'server_cpu_affinity': core {core} does not exist or is not available to the agent"
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Pins the calling thread to the given cores
+pub(crate) fn set_thread_affinity(cores: &[usize]) -> io::Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    for core in cores {
+        unsafe { libc::CPU_SET(*core, &mut set) };
+    }
+    let ret = unsafe {
+        libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set)
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Pins the calling server worker thread to the cores set in
+/// 'server_cpu_affinity', if any
+pub(crate) fn pin_worker(cores: &[usize]) {
+    if cores.is_empty() {
+        return;
+    }
+    if let Err(e) = set_thread_affinity(cores) {
+        warn!("Unable to set the CPU affinity of the server worker: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_cores() {
+        let available = available_cores().unwrap(); //#[allow_ci]
+        assert!(!available.is_empty());
+        assert!(check_cores(&available).is_ok());
+        assert!(check_cores(&[libc::CPU_SETSIZE as usize]).is_err());
+    }
+
+    #[test]
+    fn test_set_thread_affinity() {
+        let core = available_cores().unwrap()[0]; //#[allow_ci]
+
+        // Only the thread calling the function is pinned
+        let cores = std::thread::spawn(move || {
+            set_thread_affinity(&[core]).unwrap(); //#[allow_ci]
+            available_cores().unwrap() //#[allow_ci]
+        })
+        .join()
+        .unwrap(); //#[allow_ci]
+        assert_eq!(cores, vec![core]);
+    }
+}
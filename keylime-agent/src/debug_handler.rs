@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+use crate::common::JsonWrapper;
+use crate::QuoteData;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use log::*;
+use serde::{Deserialize, Serialize};
+use tss_esapi::handles::ObjectHandle;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct TpmDebugInfo {
+    pub ak_handle: u32,
+    pub ak_name: String,
+    pub ek_handle: Option<u32>,
+    pub tpm_hash_alg: String,
+    pub tpm_enc_alg: String,
+    pub tpm_sign_alg: String,
+}
+
+// This is the handler for the GET request for the TPM debug information,
+// which returns a snapshot of the TPM objects and algorithms used by the agent
+pub async fn tpm(
+    req: HttpRequest,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    debug!("Returning TPM debug information");
+
+    let ak_name = {
+        let mut context = data.tpmcontext.lock().unwrap(); //#[allow_ci]
+        context
+            .as_mut()
+            .tr_get_name(ObjectHandle::from(data.ak_handle()))
+    };
+
+    let ak_name = match ak_name {
+        Ok(name) => hex::encode(name.value()),
+        Err(e) => {
+            warn!("GET debug/tpm returning 500 response. Unable to get AK name: {e:?}");
+            return HttpResponse::InternalServerError().json(
+                JsonWrapper::error(500, "Unable to get AK name".to_string()),
+            );
+        }
+    };
+
+    let response = JsonWrapper::success(TpmDebugInfo {
+        ak_handle: data.ak_handle().value(),
+        ak_name,
+        ek_handle: data.ek_handle.map(|h| h.value()),
+        tpm_hash_alg: data.hash_alg.to_string(),
+        tpm_enc_alg: data.enc_alg.to_string(),
+        tpm_sign_alg: data.sign_alg.to_string(),
+    });
+
+    info!("GET debug/tpm returning 200 response");
+    HttpResponse::Ok().json(response)
+}
+
+// Registers the debug endpoints, if enabled
+pub(crate) fn debug_config(cfg: &mut web::ServiceConfig, enabled: bool) {
+    if enabled {
+        _ = cfg.service(
+            web::scope("/debug")
+                .service(web::resource("/tpm").route(web::get().to(tpm))),
+        );
+    }
+}
+
+#[cfg(feature = "testing")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::API_VERSION;
+    use actix_web::{test, web, App};
+
+    #[actix_rt::test]
+    async fn test_debug_tpm() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app = test::init_service(
+            App::new().app_data(quotedata.clone()).service(
+                web::scope(&format!("/{API_VERSION}"))
+                    .configure(|cfg| debug_config(cfg, true)),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{API_VERSION}/debug/tpm"))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<TpmDebugInfo> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.results.ak_handle, quotedata.ak_handle().value());
+        assert!(!result.results.ak_name.is_empty());
+        assert_eq!(
+            result.results.ek_handle,
+            quotedata.ek_handle.map(|h| h.value())
+        );
+        assert_eq!(result.results.tpm_hash_alg, "sha256");
+        assert_eq!(result.results.tpm_enc_alg, "rsa");
+        assert_eq!(result.results.tpm_sign_alg, "rsassa");
+    }
+
+    #[actix_rt::test]
+    async fn test_debug_tpm_disabled() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app = test::init_service(
+            App::new().app_data(quotedata.clone()).service(
+                web::scope(&format!("/{API_VERSION}"))
+                    .configure(|cfg| debug_config(cfg, false)),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{API_VERSION}/debug/tpm"))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 404);
+    }
+}
@@ -31,12 +31,22 @@ pub(crate) enum Error {
     Conversion(String),
     #[error("Configuration error: {0}")]
     Configuration(String),
+    #[error("Failed to decrypt the server key {0}: check the password set in 'server_key_password'")]
+    ServerKeyPassword(String),
     #[error("Reqwest error: {0}")]
     Reqwest(#[from] reqwest::Error),
     #[error("Registrar error: received {code} from {addr}")]
     Registrar { addr: String, code: u16 },
+    #[error(
+        "Registrar rejected the activation: received {code} from {addr}"
+    )]
+    ActivationRejected { addr: String, code: u16 },
     #[error("Serialization/deserialization error: {0}")]
     Serde(#[from] serde_json::Error),
+    #[error("CBOR deserialization error: {0}")]
+    CborDe(#[from] ciborium::de::Error<std::io::Error>),
+    #[error("CBOR serialization error: {0}")]
+    CborSer(#[from] ciborium::ser::Error<std::io::Error>),
     #[error("Permission error")]
     Permission,
     #[error("Glob error")]
@@ -99,12 +109,23 @@ impl Error {
     pub(crate) fn http_code(&self) -> Result<u16> {
         match self {
             Error::Registrar { addr, code } => Ok(*code),
+            Error::ActivationRejected { addr, code } => Ok(*code),
             other => Err(Error::Other(format!(
                 "cannot get http code for Error type {other}"
             ))),
         }
     }
 
+    /// Whether the error may be transient, such as a transport error or a
+    /// server error response. Client error responses are not retryable.
+    pub(crate) fn is_retryable(&self) -> bool {
+        match self {
+            Error::Reqwest(_) => true,
+            Error::Registrar { code, .. } => *code >= 500,
+            _ => false,
+        }
+    }
+
     pub(crate) fn exe_code(&self) -> Result<Option<i32>> {
         match self {
             Error::Execution(code, _) => Ok(code.to_owned()),
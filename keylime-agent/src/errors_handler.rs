@@ -62,7 +62,8 @@ pub(crate) async fn api_default(req: HttpRequest) -> impl Responder {
     match req.head().method {
         http::Method::GET => {
             error = 400;
-            message = "Not Implemented: Use /keys/ or /quotes/ interfaces";
+            message =
+                "Not Implemented: Use /agent/, /keys/ or /quotes/ interfaces";
             response = HttpResponse::BadRequest()
                 .json(JsonWrapper::error(error, message));
         }
@@ -95,6 +96,37 @@ pub(crate) async fn api_default(req: HttpRequest) -> impl Responder {
     response
 }
 
+pub(crate) async fn agent_default(req: HttpRequest) -> impl Responder {
+    let error;
+    let response;
+    let message;
+
+    match req.head().method {
+        http::Method::GET => {
+            error = 400;
+            message = "URI not supported, only /info is supported for GET in /agent/ interface";
+            response = HttpResponse::BadRequest()
+                .json(JsonWrapper::error(error, message));
+        }
+        _ => {
+            error = 405;
+            message = "Method is not supported in /agent/ interface";
+            response = HttpResponse::MethodNotAllowed()
+                .insert_header(http::header::Allow(vec![http::Method::GET]))
+                .json(JsonWrapper::error(error, message));
+        }
+    };
+
+    warn!(
+        "{} returning {} response. {}",
+        req.head().method,
+        error,
+        message
+    );
+
+    response
+}
+
 pub(crate) async fn keys_default(req: HttpRequest) -> impl Responder {
     let error;
     let response;
@@ -166,6 +198,37 @@ pub(crate) async fn quotes_default(req: HttpRequest) -> impl Responder {
     response
 }
 
+pub(crate) async fn payload_default(req: HttpRequest) -> impl Responder {
+    let error;
+    let response;
+    let message;
+
+    match req.head().method {
+        http::Method::GET => {
+            error = 400;
+            message = "URI not supported, only /status is supported for GET in /payload/ interface";
+            response = HttpResponse::BadRequest()
+                .json(JsonWrapper::error(error, message));
+        }
+        _ => {
+            error = 405;
+            message = "Method is not supported in /payload/ interface";
+            response = HttpResponse::MethodNotAllowed()
+                .insert_header(http::header::Allow(vec![http::Method::GET]))
+                .json(JsonWrapper::error(error, message));
+        }
+    };
+
+    warn!(
+        "{} returning {} response. {}",
+        req.head().method,
+        error,
+        message
+    );
+
+    response
+}
+
 pub(crate) async fn notifications_default(
     req: HttpRequest,
 ) -> impl Responder {
@@ -327,6 +390,11 @@ mod tests {
         test_default(web::resource("/").to(api_default), "GET, POST").await
     }
 
+    #[actix_rt::test]
+    async fn test_agent_default() {
+        test_default(web::resource("/").to(agent_default), "GET").await
+    }
+
     #[actix_rt::test]
     async fn test_keys_default() {
         test_default(web::resource("/").to(keys_default), "GET, POST").await
@@ -337,6 +405,11 @@ mod tests {
         test_default(web::resource("/").to(quotes_default), "GET").await
     }
 
+    #[actix_rt::test]
+    async fn test_payload_default() {
+        test_default(web::resource("/").to(payload_default), "GET").await
+    }
+
     #[actix_rt::test]
     async fn test_notifications_default() {
         test_default(web::resource("/").to(notifications_default), "POST")
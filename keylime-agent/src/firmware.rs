@@ -0,0 +1,43 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+use crate::error::{Error, Result};
+use std::{fs, path::Path};
+
+// Directory where the kernel exposes the DMI identification data
+pub static DMI_ID_DIR: &str = "/sys/class/dmi/id";
+
+/// Reads the firmware (BIOS) version from the DMI identification data in
+/// `dmi_dir`
+pub(crate) fn read_firmware_version(dmi_dir: &Path) -> Result<String> {
+    let path = dmi_dir.join("bios_version");
+    let version = fs::read_to_string(&path).map_err(|e| {
+        Error::Other(format!(
+            "Unable to read firmware version from {}: {e}",
+            path.display()
+        ))
+    })?;
+
+    match version.trim() {
+        "" => Err(Error::Other(format!(
+            "Empty firmware version in {}",
+            path.display()
+        ))),
+        v => Ok(v.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_firmware_version() {
+        let dmi_dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data/dmi");
+        assert_eq!(read_firmware_version(&dmi_dir).unwrap(), "F.23"); //#[allow_ci]
+
+        let tempdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        assert!(read_firmware_version(tempdir.path()).is_err());
+    }
+}
@@ -5,21 +5,29 @@ use crate::crypto;
 use crate::{
     common::{
         AuthTag, EncryptedData, JsonWrapper, KeySet, SymmKey, AES_BLOCK_SIZE,
-        AGENT_UUID_LEN, AUTH_TAG_LEN,
+        AGENT_UUID_LEN, KEY_EXCHANGE_TIMEOUT,
     },
     config::KeylimeConfig,
-    payloads::{Payload, PayloadMessage},
+    payloads::{self, NamedPayload, NamedPayloads, Payload, PayloadMessage},
     Error, QuoteData, Result,
 };
-use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use actix_web::{rt, web, HttpRequest, HttpResponse, Responder};
 use base64::{engine::general_purpose, Engine as _};
 use log::*;
+use openssl::pkey::{PKey, Private};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::convert::TryInto;
-use tokio::sync::{
-    mpsc::{Receiver, Sender},
-    oneshot,
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{
+        mpsc::{Receiver, Sender},
+        oneshot,
+    },
+    time::sleep,
 };
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -28,6 +36,17 @@ pub struct KeylimeUKey {
     encrypted_key: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     payload: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    named_payloads: Option<BTreeMap<String, KeylimeNamedPayload>>,
+}
+
+// A file delivered with the U key together with its own key. The key is
+// encrypted with the combined U and V key, as the payload, so that the file
+// cannot be decrypted before the verifier releases the V key
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeylimeNamedPayload {
+    encrypted_key: String,
+    payload: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -50,14 +69,15 @@ pub struct KeylimeHMAC {
     hmac: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub(crate) struct UKey {
     decrypted_key: SymmKey,
     auth_tag: AuthTag,
     payload: Option<EncryptedData>,
+    named_payloads: NamedPayloads,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub(crate) struct VKey {
     decrypted_key: SymmKey,
 }
@@ -73,26 +93,67 @@ pub(crate) enum KeyMessage {
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) enum SymmKeyMessage {
     SymmKey(Option<SymmKey>),
+    KeyAccepted,
+    KeyRejected,
+}
+
+// What the keys worker does with a received U or V key
+#[derive(Debug, PartialEq)]
+enum KeyAction {
+    Store,
+    Ignore,
+    Reject,
+}
+
+// A key identical to the last one received for the same slot, or to one still
+// waiting for a match, is ignored, so that a retried request does not reset
+// the combined key. The whole message is compared, so a U key repeated with a
+// different auth_tag or payload is a different key. A different key replaces
+// the last one, unless replacing keys is rejected.
+fn check_key<'a, T: PartialEq + 'a>(
+    last: Option<&T>,
+    mut pending: impl Iterator<Item = &'a T>,
+    key: &T,
+    reject_replacement: bool,
+) -> KeyAction {
+    if last == Some(key) || pending.any(|k| k == key) {
+        KeyAction::Ignore
+    } else if reject_replacement && last.is_some() {
+        KeyAction::Reject
+    } else {
+        KeyAction::Store
+    }
+}
+
+// Both U and V keys were received but no combination of them matched the
+// auth_tag. The last keys are then forgotten, so that the correct key is not
+// rejected as a replacement of a key that cannot be used
+fn combination_failed(ukeys: &[UKey], vkeys: &[VKey]) -> bool {
+    !ukeys.is_empty() && !vkeys.is_empty()
 }
 
 // Attempt to combine U and V keys into the payload decryption key. An HMAC over
 // the agent's UUID using the decryption key must match the provided authentication
 // tag. Returning None is okay here in case we are still waiting on another handler to
-// process data.
+// process data. The payload and the named payloads delivered with the matching U
+// key are returned along with the key.
 fn try_combine_keys(
     ukeys: &mut Vec<UKey>,
     vkeys: &mut Vec<VKey>,
     uuid: &[u8],
-) -> Option<(SymmKey, Option<Payload>)> {
+) -> Option<(SymmKey, Option<EncryptedData>, NamedPayloads)> {
     // U, V keys and auth_tag must be present for this to succeed
     if ukeys.is_empty() || vkeys.is_empty() {
         debug!("Still waiting on u or v key");
         return None;
     }
 
-    for ukey in ukeys.iter() {
+    for (i, ukey) in ukeys.iter().enumerate() {
         for vkey in vkeys.iter() {
-            let symm_key = match ukey.decrypted_key.xor(&vkey.decrypted_key) {
+            let symm_key = match crypto::combine_key_halves(
+                ukey.decrypted_key.as_ref(),
+                vkey.decrypted_key.as_ref(),
+            ) {
                 Ok(k) => k,
                 Err(e) => {
                     continue;
@@ -105,6 +166,7 @@ fn try_combine_keys(
                 symm_key.as_ref(),
                 uuid,
                 ukey.auth_tag.as_ref(),
+                ukey.auth_tag.hash_alg(),
             )
             .is_ok()
             {
@@ -112,16 +174,11 @@ fn try_combine_keys(
                     "Successfully derived symmetric payload decryption key"
                 );
 
-                let payload =
-                    ukey.payload.as_ref().map(|encrypted_payload| Payload {
-                        symm_key: symm_key.clone(),
-                        encrypted_payload: encrypted_payload.clone(),
-                    });
-
+                let ukey = ukeys.swap_remove(i);
                 ukeys.clear();
                 vkeys.clear();
 
-                return Some((symm_key, payload));
+                return Some((symm_key, ukey.payload, ukey.named_payloads));
             }
         }
     }
@@ -130,6 +187,36 @@ fn try_combine_keys(
     None
 }
 
+// Decode a named payload delivered with the U key. Its key is decrypted when
+// the payload runs, after the U and V keys were combined
+fn decode_named_payload(
+    name: &str,
+    named: &KeylimeNamedPayload,
+) -> Result<NamedPayload> {
+    if !payloads::is_valid_payload_name(name) {
+        return Err(Error::Other(format!(
+            "invalid name {name:?}, expected a file name"
+        )));
+    }
+
+    let encrypted_key =
+        general_purpose::STANDARD.decode(&named.encrypted_key)?;
+    if encrypted_key.is_empty() {
+        return Err(Error::Other("the key is empty".to_string()));
+    }
+
+    let encrypted_payload =
+        general_purpose::STANDARD.decode(&named.payload)?;
+    if encrypted_payload.is_empty() {
+        return Err(Error::Other("the payload is empty".to_string()));
+    }
+
+    Ok(NamedPayload {
+        encrypted_key: encrypted_key.into(),
+        encrypted_payload: encrypted_payload.into(),
+    })
+}
+
 pub(crate) async fn u_key(
     body: web::Json<KeylimeUKey>,
     req: HttpRequest,
@@ -162,7 +249,7 @@ pub(crate) async fn u_key(
     // https://github.com/keylime/keylime/blob/f3c31b411dd3dd971fd9d614a39a150655c6797c/ \
     // keylime/crypto.py#L118
     let decrypted_key = match crypto::rsa_oaep_decrypt(
-        &quote_data.priv_key,
+        &quote_data.priv_key(),
         &encrypted_key,
     )
     .map_err(Error::from)
@@ -199,7 +286,7 @@ pub(crate) async fn u_key(
         }
     };
 
-    let auth_tag = match auth_tag.as_slice().try_into() {
+    let auth_tag = match AuthTag::new(&auth_tag, quote_data.hmac_hash_alg) {
         Ok(t) => t,
         Err(e) => {
             warn!("POST u_key returning 400 response: {e}");
@@ -213,6 +300,12 @@ pub(crate) async fn u_key(
             .decode(data)
             .map_err(Error::from)
         {
+            // An empty payload cannot be decrypted, so it is handled as if
+            // no payload was delivered
+            Ok(d) if d.is_empty() => {
+                debug!("Empty payload delivered with the U key, ignoring it");
+                None
+            }
             Ok(d) => Some(d.into()),
             Err(e) => {
                 warn!("POST u_key returning 400 response. Invalid base64 encoding in payload: {e}");
@@ -225,19 +318,57 @@ pub(crate) async fn u_key(
         None => None,
     };
 
+    let mut named_payloads = NamedPayloads::new();
+    if let Some(named) = &body.named_payloads {
+        for (name, n) in named {
+            match decode_named_payload(name, n) {
+                Ok(p) => {
+                    _ = named_payloads.insert(name.clone(), p);
+                }
+                Err(e) => {
+                    warn!("POST u_key returning 400 response. Invalid named payload {name}: {e}");
+                    return HttpResponse::BadRequest().json(
+                        JsonWrapper::error(
+                            400,
+                            format!("Invalid named payload {name}: {e}"),
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    *quote_data.last_key_received.lock().unwrap() = Some(Instant::now()); //#[allow_ci]
+
     let m = KeyMessage::UKey(UKey {
         decrypted_key,
         auth_tag,
         payload,
+        named_payloads,
     });
 
     debug!("Sending UKey message to keys worker");
 
-    if let Err(e) = quote_data.keys_tx.send((m, None)).await {
-        warn!("Failed to send UKey message to keys worker");
-        return HttpResponse::InternalServerError().json(JsonWrapper::error(
-            500,
-            "Failed to send UKey message to keys worker".to_string(),
+    let accepted = match send_key(quote_data.keys_tx.clone(), m).await {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            warn!("Failed to send UKey message to keys worker: {e}");
+            return HttpResponse::InternalServerError().json(
+                JsonWrapper::error(
+                    500,
+                    "Failed to send UKey message to keys worker".to_string(),
+                ),
+            );
+        }
+    };
+
+    quote_data.metrics.ukey_received();
+
+    if !accepted {
+        warn!("POST u_key returning 409 response. A different U key was already received");
+        return HttpResponse::Conflict().json(JsonWrapper::error(
+            409,
+            "A different U key was already received".to_string(),
         ));
     }
 
@@ -274,7 +405,7 @@ pub(crate) async fn v_key(
     // https://github.com/keylime/keylime/blob/f3c31b411dd3dd971fd9d614a39a150655c6797c/ \
     // keylime/crypto.py#L118
     let decrypted_key = match crypto::rsa_oaep_decrypt(
-        &quote_data.priv_key,
+        &quote_data.priv_key(),
         &encrypted_key,
     )
     .map_err(Error::from)
@@ -300,15 +431,32 @@ pub(crate) async fn v_key(
         }
     };
 
+    *quote_data.last_key_received.lock().unwrap() = Some(Instant::now()); //#[allow_ci]
+
     let m = KeyMessage::VKey(VKey { decrypted_key });
 
     debug!("Sending VKey message to keys worker");
 
-    if let Err(e) = quote_data.keys_tx.send((m, None)).await {
-        warn!("Failed to send VKey message to keys worker");
-        return HttpResponse::InternalServerError().json(JsonWrapper::error(
-            500,
-            "Failed to send VKey message to keys worker".to_string(),
+    let accepted = match send_key(quote_data.keys_tx.clone(), m).await {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            warn!("Failed to send VKey message to keys worker: {e}");
+            return HttpResponse::InternalServerError().json(
+                JsonWrapper::error(
+                    500,
+                    "Failed to send VKey message to keys worker".to_string(),
+                ),
+            );
+        }
+    };
+
+    quote_data.metrics.vkey_received();
+
+    if !accepted {
+        warn!("POST v_key returning 409 response. A different V key was already received");
+        return HttpResponse::Conflict().json(JsonWrapper::error(
+            409,
+            "A different V key was already received".to_string(),
         ));
     }
 
@@ -319,7 +467,7 @@ pub(crate) async fn pubkey(
     req: HttpRequest,
     data: web::Data<QuoteData>,
 ) -> impl Responder {
-    match crypto::pkey_pub_to_pem(&data.pub_key) {
+    match crypto::pkey_pub_to_pem(&data.pub_key()) {
         Ok(pubkey) => {
             let response = JsonWrapper::success(KeylimePubkey { pubkey });
             info!("GET pubkey returning 200 response.");
@@ -336,6 +484,80 @@ pub(crate) async fn pubkey(
     }
 }
 
+// Regenerates the transport key pair, unless a U or V key was received
+// recently, meaning a key exchange may be in progress. Returns whether the key
+// pair was rotated.
+pub(crate) fn rotate_transport_key(data: &QuoteData) -> Result<bool> {
+    let last_key_received = *data.last_key_received.lock().unwrap(); //#[allow_ci]
+    if let Some(received) = last_key_received {
+        if received.elapsed() < Duration::from_secs(KEY_EXCHANGE_TIMEOUT) {
+            return Ok(false);
+        }
+    }
+
+    let keys = crypto::rsa_generate_pair(2048)?;
+    *data.transport_keys.write().unwrap() = keys; //#[allow_ci]
+    Ok(true)
+}
+
+pub(crate) async fn rotate_transport_keys(
+    data: web::Data<QuoteData>,
+    interval: Duration,
+) {
+    loop {
+        sleep(interval).await;
+
+        match rotate_transport_key(&data) {
+            Ok(true) => info!("Rotated the transport key pair"),
+            Ok(false) => {
+                info!("Key exchange in progress, skipping transport key pair rotation")
+            }
+            Err(e) => warn!("Failed to rotate the transport key pair: {e}"),
+        }
+    }
+}
+
+// Send a U or V key to the keys worker, returning whether it was accepted
+async fn send_key(
+    keys_tx: Sender<(KeyMessage, Option<oneshot::Sender<SymmKeyMessage>>)>,
+    message: KeyMessage,
+) -> Result<bool> {
+    let (resp_tx, resp_rx) = oneshot::channel::<SymmKeyMessage>();
+
+    if let Err(e) = keys_tx.send((message, Some(resp_tx))).await {
+        return Err(Error::Sender(format!(
+            "Failed to send key message: {e}"
+        )));
+    };
+
+    match resp_rx.await {
+        Ok(SymmKeyMessage::KeyAccepted) => Ok(true),
+        Ok(SymmKeyMessage::KeyRejected) => Ok(false),
+        Ok(_) => Err(Error::Receiver(
+            "Invalid response for key message".to_string(),
+        )),
+        Err(e) => Err(Error::Receiver(format!(
+            "Failed to receive key message response: {e}"
+        ))),
+    }
+}
+
+// Reply to a U or V key message, if a response was requested
+fn reply_key(
+    resp_tx: Option<oneshot::Sender<SymmKeyMessage>>,
+    action: &KeyAction,
+) {
+    let message = match action {
+        KeyAction::Reject => SymmKeyMessage::KeyRejected,
+        _ => SymmKeyMessage::KeyAccepted,
+    };
+    if let Some(r) = resp_tx {
+        if r.send(message).is_err() {
+            debug!("Failed to send key message response");
+        }
+    }
+}
+
 async fn get_symm_key(
     keys_tx: Sender<(KeyMessage, Option<oneshot::Sender<SymmKeyMessage>>)>,
 ) -> Result<Option<SymmKey>> {
@@ -401,7 +623,11 @@ pub(crate) async fn verify(
             }
         };
 
-        match crypto::compute_hmac(k.as_ref(), param.challenge.as_bytes()) {
+        match crypto::compute_hmac(
+            k.as_ref(),
+            param.challenge.as_bytes(),
+            data.hmac_hash_alg,
+        ) {
             Ok(hmac) => {
                 let response = JsonWrapper::success(KeylimeHMAC {
                     hmac: hex::encode(hmac),
@@ -441,29 +667,161 @@ async fn request_run_payload(
     Ok(())
 }
 
+// Store a received key, evicting the oldest stored key when the maximum
+// number of stored keys is reached. A maximum of 0 means no limit.
+fn store_key<T>(keys: &mut Vec<T>, key: T, max_keyset_size: usize) {
+    if max_keyset_size > 0 && keys.len() >= max_keyset_size {
+        warn!(
+            "Maximum number of stored keys ({}) reached, discarding the oldest key",
+            max_keyset_size
+        );
+        _ = keys.remove(0);
+    }
+    keys.push(key);
+}
+
+// Fetch the encrypted payload from the given URL. The payload is expected in
+// the same format as the one pushed with the U key, without the base64
+// encoding. The request is aborted after `timeout`, and payloads larger
+// than `max_size` bytes are refused.
+async fn pull_payload(
+    url: &str,
+    timeout: Duration,
+    max_size: u64,
+) -> Result<EncryptedData> {
+    info!("Pulling encrypted payload from {}", url);
+
+    let too_large = || {
+        Error::Other(format!(
+            "the payload exceeds the secure mount size ({max_size} bytes)"
+        ))
+    };
+
+    let mut resp = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()?
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    if resp.content_length().unwrap_or(0) > max_size {
+        return Err(too_large());
+    }
+
+    // The length announced by the server is not trusted
+    let mut payload = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        if (payload.len() + chunk.len()) as u64 > max_size {
+            return Err(too_large());
+        }
+        payload.extend_from_slice(&chunk);
+    }
+    Ok(payload.into())
+}
+
+// Interval between the attempts to pull the payload
+const PAYLOAD_PULL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Pull the encrypted payload, retrying while it is not available or empty
+// until the timeout expires, as the keys can be delivered before the payload
+// is published
+async fn wait_for_payload(
+    url: &str,
+    timeout: Duration,
+    max_size: u64,
+) -> Result<EncryptedData> {
+    let start = Instant::now();
+    loop {
+        // Each attempt is limited to the time left to wait
+        let left = timeout.saturating_sub(start.elapsed());
+        let reason = match pull_payload(url, left, max_size).await {
+            Ok(payload) if !payload.as_ref().is_empty() => {
+                return Ok(payload)
+            }
+            Ok(_) => "the payload is empty".to_string(),
+            Err(e) => e.to_string(),
+        };
+        if start.elapsed() + PAYLOAD_PULL_INTERVAL > timeout {
+            return Err(Error::Other(format!(
+                "Timed out waiting for the payload: {reason}"
+            )));
+        }
+        debug!(
+            "Payload not available from {url} ({reason}), retrying in {:?}",
+            PAYLOAD_PULL_INTERVAL
+        );
+        sleep(PAYLOAD_PULL_INTERVAL).await;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn process_keys(
     mut ukeys: &mut Vec<UKey>,
     mut vkeys: &mut Vec<VKey>,
     uuid: String,
     payloads_tx: Sender<PayloadMessage>,
     run_payload: bool,
+    payload_pull_url: Option<&str>,
+    payload_wait_timeout: Duration,
+    payload_max_size: u64,
 ) -> Option<SymmKey> {
     match try_combine_keys(ukeys, vkeys, uuid.as_bytes()) {
-        Some((key, p)) => {
+        Some((key, p, named_payloads)) => {
             if run_payload {
-                if let Some(payload) = p {
-                    match request_run_payload(payloads_tx.clone(), payload)
-                        .await
-                    {
-                        Ok(_) => {
-                            debug!(
-                                "Sent RunPayload message to payloads worker"
-                            );
-                        }
-                        Err(e) => {
+                match (p, payload_pull_url) {
+                    (Some(encrypted_payload), _) => {
+                        let payload = Payload {
+                            symm_key: key.clone(),
+                            encrypted_payload,
+                            named_payloads,
+                        };
+                        if let Err(e) =
+                            request_run_payload(payloads_tx.clone(), payload)
+                                .await
+                        {
                             warn!("{e}");
                         }
                     }
+                    // If no payload was pushed with the U key, pull it. The
+                    // keys worker is not blocked while waiting for it.
+                    (None, Some(url)) => {
+                        let url = url.to_string();
+                        let symm_key = key.clone();
+                        let payloads_tx = payloads_tx.clone();
+                        _ = rt::spawn(async move {
+                            match wait_for_payload(
+                                &url,
+                                payload_wait_timeout,
+                                payload_max_size,
+                            )
+                            .await
+                            {
+                                Ok(encrypted_payload) => {
+                                    let payload = Payload {
+                                        symm_key,
+                                        encrypted_payload,
+                                        named_payloads,
+                                    };
+                                    if let Err(e) = request_run_payload(
+                                        payloads_tx,
+                                        payload,
+                                    )
+                                    .await
+                                    {
+                                        warn!("{e}");
+                                    }
+                                }
+                                Err(e) => {
+                                    warn!("Failed to pull payload from {url}: {e}");
+                                }
+                            }
+                        });
+                    }
+                    (None, None) if !named_payloads.is_empty() => {
+                        warn!("Named payloads cannot be run without a payload, ignoring them");
+                    }
+                    (None, None) => {}
                 }
             } else {
                 warn!("agent mTLS is disabled, and unless 'enable_insecure_payload' is set to 'True', payloads cannot be deployed'");
@@ -475,9 +833,15 @@ async fn process_keys(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn worker(
     run_payload: bool,
     uuid: String,
+    max_keyset_size: usize,
+    reject_key_replacement: bool,
+    payload_pull_url: Option<String>,
+    payload_wait_timeout: Duration,
+    payload_max_size: u64,
     mut keys_rx: Receiver<(
         KeyMessage,
         Option<oneshot::Sender<SymmKeyMessage>>,
@@ -487,6 +851,8 @@ pub(crate) async fn worker(
     let mut ukeys: Vec<UKey> = Vec::new();
     let mut vkeys: Vec<VKey> = Vec::new();
     let mut symm_key: Option<SymmKey> = None;
+    let mut last_ukey: Option<UKey> = None;
+    let mut last_vkey: Option<VKey> = None;
 
     debug!("Starting keys worker");
 
@@ -508,33 +874,85 @@ pub(crate) async fn worker(
                 keys_rx.close();
             }
             KeyMessage::UKey(ukey) => {
+                let action = check_key(
+                    last_ukey.as_ref(),
+                    ukeys.iter(),
+                    &ukey,
+                    reject_key_replacement,
+                );
+                reply_key(resp_tx, &action);
+                match action {
+                    KeyAction::Store => {}
+                    KeyAction::Ignore => {
+                        debug!("Ignoring U key identical to one already received");
+                        continue;
+                    }
+                    KeyAction::Reject => {
+                        warn!("Rejecting U key different from the one already received");
+                        continue;
+                    }
+                }
+
                 // Store received data
-                ukeys.push(ukey);
+                last_ukey = Some(ukey.clone());
+                store_key(&mut ukeys, ukey, max_keyset_size);
                 if let Some(key) = process_keys(
                     &mut ukeys,
                     &mut vkeys,
                     uuid.clone(),
                     payloads_tx.clone(),
                     run_payload,
+                    payload_pull_url.as_deref(),
+                    payload_wait_timeout,
+                    payload_max_size,
                 )
                 .await
                 {
                     symm_key = Some(key);
+                } else if combination_failed(&ukeys, &vkeys) {
+                    last_ukey = None;
+                    last_vkey = None;
                 }
             }
             KeyMessage::VKey(vkey) => {
+                let action = check_key(
+                    last_vkey.as_ref(),
+                    vkeys.iter(),
+                    &vkey,
+                    reject_key_replacement,
+                );
+                reply_key(resp_tx, &action);
+                match action {
+                    KeyAction::Store => {}
+                    KeyAction::Ignore => {
+                        debug!("Ignoring V key identical to one already received");
+                        continue;
+                    }
+                    KeyAction::Reject => {
+                        warn!("Rejecting V key different from the one already received");
+                        continue;
+                    }
+                }
+
                 // Store received data
-                vkeys.push(vkey);
+                last_vkey = Some(vkey.clone());
+                store_key(&mut vkeys, vkey, max_keyset_size);
                 if let Some(key) = process_keys(
                     &mut ukeys,
                     &mut vkeys,
                     uuid.clone(),
                     payloads_tx.clone(),
                     run_payload,
+                    payload_pull_url.as_deref(),
+                    payload_wait_timeout,
+                    payload_max_size,
                 )
                 .await
                 {
                     symm_key = Some(key);
+                } else if combination_failed(&ukeys, &vkeys) {
+                    last_ukey = None;
+                    last_vkey = None;
                 }
             }
         }
@@ -548,8 +966,9 @@ pub(crate) async fn worker(
 mod tests {
     use super::*;
     #[cfg(feature = "testing")]
-    use crate::crypto::testing::{
-        encrypt_aead, pkey_pub_from_pem, rsa_oaep_encrypt,
+    use crate::crypto::{
+        encrypt_aead,
+        testing::{pkey_pub_from_pem, rsa_oaep_encrypt},
     };
     use crate::{
         common::{AES_128_KEY_LEN, AES_256_KEY_LEN, API_VERSION},
@@ -559,6 +978,7 @@ mod tests {
     };
     use actix_rt::Arbiter;
     use actix_web::{test, web, App};
+    use keylime::algorithms::HashAlgorithm;
     use openssl::{
         encrypt::Encrypter,
         hash::MessageDigest,
@@ -577,6 +997,9 @@ mod tests {
     const U: &[u8; AES_256_KEY_LEN] = b"01234567890123456789012345678901";
     const V: &[u8; AES_256_KEY_LEN] = b"ABCDEFGHIJABCDEFGHIJABCDEFGHIJAB";
 
+    const PAYLOAD_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+    const PAYLOAD_MAX_SIZE: u64 = 1 << 20;
+
     fn prepare_keys(
         key_len: usize,
         payload: Option<EncryptedData>,
@@ -592,13 +1015,16 @@ mod tests {
         let v: SymmKey = v_buf[..key_len][..].try_into().unwrap(); //#[allow_ci]
         let k = u.xor(&v).unwrap(); //#[allow_ci]
 
-        let hmac = compute_hmac(k.as_ref(), uuid.as_bytes()).unwrap(); //#[allow_ci]
-        let auth_tag: AuthTag = hmac.as_slice().try_into().unwrap(); //#[allow_ci]
+        let hmac =
+            compute_hmac(k.as_ref(), uuid.as_bytes(), HashAlgorithm::Sha384)
+                .unwrap(); //#[allow_ci]
+        let auth_tag = AuthTag::new(&hmac, HashAlgorithm::Sha384).unwrap(); //#[allow_ci]
 
         let ukey = UKey {
             decrypted_key: u,
             auth_tag,
             payload,
+            named_payloads: NamedPayloads::new(),
         };
         let vkey = VKey { decrypted_key: v };
 
@@ -626,6 +1052,7 @@ mod tests {
             payload: ukey
                 .payload
                 .map(|p| general_purpose::STANDARD.encode(p.as_ref())),
+            named_payloads: None,
         };
 
         let enc_v = KeylimeVKey {
@@ -691,9 +1118,21 @@ mod tests {
         assert!(ukeys.is_empty());
         assert!(vkeys.is_empty());
 
-        if let Some((k, p)) = result {
+        if let Some((k, _, _)) = result {
             assert!(k == k2);
         }
+
+        // Check the auth tag is verified with its own hash algorithm
+        let (mut u, v, k) = prepare_keys(key_len, None, uuid.to_string());
+        let hmac =
+            compute_hmac(k.as_ref(), uuid.as_bytes(), HashAlgorithm::Sha256)
+                .unwrap(); //#[allow_ci]
+        u.auth_tag = AuthTag::new(&hmac, HashAlgorithm::Sha256).unwrap(); //#[allow_ci]
+        ukeys.push(u);
+        vkeys.push(v);
+        let result =
+            try_combine_keys(&mut ukeys, &mut vkeys, uuid.as_bytes());
+        assert!(result.is_some());
     }
 
     #[test]
@@ -706,6 +1145,277 @@ mod tests {
         test_combine_keys(AES_256_KEY_LEN);
     }
 
+    #[test]
+    async fn test_decode_named_payload() {
+        use crate::crypto::encrypt_aead;
+
+        let iv = b"ABCDEFGHIJKLMNOP";
+        let payload_key = &U[..];
+
+        // Two named payloads, each encrypted with its own key, which is
+        // encrypted with the payload key
+        let mut named = BTreeMap::new();
+        for (name, key) in
+            [("ca-bundle.pem", &U[..16]), ("agent.conf", &V[..])]
+        {
+            let encrypted_key =
+                encrypt_aead(payload_key, &iv[..], key).unwrap(); //#[allow_ci]
+            let encrypted =
+                encrypt_aead(key, &iv[..], name.as_bytes()).unwrap(); //#[allow_ci]
+            _ = named.insert(
+                name.to_string(),
+                KeylimeNamedPayload {
+                    encrypted_key: general_purpose::STANDARD
+                        .encode(encrypted_key),
+                    payload: general_purpose::STANDARD.encode(encrypted),
+                },
+            );
+        }
+
+        for (name, key) in
+            [("ca-bundle.pem", &U[..16]), ("agent.conf", &V[..])]
+        {
+            let decoded = decode_named_payload(name, &named[name]).unwrap(); //#[allow_ci]
+
+            // The key is only decrypted with the payload key
+            let decrypted_key = crypto::decrypt_aead(
+                payload_key,
+                decoded.encrypted_key.as_ref(),
+            )
+            .unwrap(); //#[allow_ci]
+            assert_eq!(decrypted_key, key);
+            let decrypted =
+                crypto::decrypt_aead(key, decoded.encrypted_payload.as_ref())
+                    .unwrap(); //#[allow_ci]
+            assert_eq!(decrypted, name.as_bytes());
+        }
+
+        // Names that are not plain file names are rejected
+        let n = &named["agent.conf"];
+        assert!(decode_named_payload("../agent.conf", n).is_err());
+        assert!(decode_named_payload("/etc/agent.conf", n).is_err());
+
+        // The payload must not be empty
+        let empty = KeylimeNamedPayload {
+            encrypted_key: n.encrypted_key.clone(),
+            payload: String::new(),
+        };
+        assert!(decode_named_payload("agent.conf", &empty).is_err());
+        let empty = KeylimeNamedPayload {
+            encrypted_key: String::new(),
+            payload: n.payload.clone(),
+        };
+        assert!(decode_named_payload("agent.conf", &empty).is_err());
+    }
+
+    #[test]
+    async fn test_store_key() {
+        let mut ukeys = Vec::new();
+        let uuid = "test-uuid";
+
+        let (u1, _, _) =
+            prepare_keys(AES_128_KEY_LEN, None, uuid.to_string());
+        let (u2, _, _) =
+            prepare_keys(AES_128_KEY_LEN, None, uuid.to_string());
+        let (u3, _, _) =
+            prepare_keys(AES_128_KEY_LEN, None, uuid.to_string());
+        let u2_key = u2.decrypted_key.clone();
+        let u3_key = u3.decrypted_key.clone();
+
+        store_key(&mut ukeys, u1, 2);
+        store_key(&mut ukeys, u2, 2);
+        assert_eq!(ukeys.len(), 2);
+
+        // The oldest key is evicted when the limit is reached
+        store_key(&mut ukeys, u3, 2);
+        assert_eq!(ukeys.len(), 2);
+        assert!(ukeys[0].decrypted_key.as_ref() == u2_key.as_ref());
+        assert!(ukeys[1].decrypted_key.as_ref() == u3_key.as_ref());
+
+        // A limit of 0 means no limit
+        let (u4, _, _) =
+            prepare_keys(AES_128_KEY_LEN, None, uuid.to_string());
+        store_key(&mut ukeys, u4, 0);
+        assert_eq!(ukeys.len(), 3);
+    }
+
+    #[test]
+    async fn test_check_key() {
+        let (u1, _, _) = prepare_keys(AES_128_KEY_LEN, None, "uuid".into());
+        let (u2, _, _) = prepare_keys(AES_128_KEY_LEN, None, "uuid".into());
+        let (u3, _, _) = prepare_keys(AES_128_KEY_LEN, None, "uuid".into());
+
+        // The first key is always stored
+        assert_eq!(check_key(None, [].iter(), &u1, true), KeyAction::Store);
+
+        // A key identical to the last one or to a pending one is ignored
+        for reject in [false, true] {
+            assert_eq!(
+                check_key(Some(&u1), [].iter(), &u1, reject),
+                KeyAction::Ignore
+            );
+            assert_eq!(
+                check_key(Some(&u2), [&u1].into_iter(), &u1, reject),
+                KeyAction::Ignore
+            );
+        }
+
+        // A different key replaces the last one, unless rejected
+        assert_eq!(
+            check_key(Some(&u1), [&u2].into_iter(), &u3, false),
+            KeyAction::Store
+        );
+        assert_eq!(
+            check_key(Some(&u1), [&u2].into_iter(), &u3, true),
+            KeyAction::Reject
+        );
+
+        // The same key half with a different auth_tag or payload is a
+        // different key
+        let other_tag = UKey {
+            auth_tag: u2.auth_tag.clone(),
+            ..u1.clone()
+        };
+        let other_payload = UKey {
+            payload: Some("other".as_bytes().into()),
+            ..u1.clone()
+        };
+        for other in [other_tag, other_payload] {
+            assert_eq!(
+                check_key(Some(&u1), [&u1].into_iter(), &other, false),
+                KeyAction::Store
+            );
+            assert_eq!(
+                check_key(Some(&u1), [&u1].into_iter(), &other, true),
+                KeyAction::Reject
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_worker_failed_combination() {
+        let uuid = "test-uuid";
+        let (u, v, k) = prepare_keys(AES_128_KEY_LEN, None, uuid.to_string());
+        let (_, wrong_v, _) =
+            prepare_keys(AES_128_KEY_LEN, None, uuid.to_string());
+
+        let (keys_tx, keys_rx) = mpsc::channel::<(
+            KeyMessage,
+            Option<oneshot::Sender<SymmKeyMessage>>,
+        )>(1);
+        let (payload_tx, _payload_rx) = mpsc::channel::<PayloadMessage>(4);
+        let worker = actix_rt::spawn(worker(
+            true,
+            uuid.to_string(),
+            0,
+            true,
+            None,
+            PAYLOAD_WAIT_TIMEOUT,
+            PAYLOAD_MAX_SIZE,
+            keys_rx,
+            payload_tx,
+        ));
+
+        assert!(send_key(keys_tx.clone(), KeyMessage::UKey(u))
+            .await
+            .unwrap()); //#[allow_ci]
+        assert!(send_key(keys_tx.clone(), KeyMessage::VKey(wrong_v))
+            .await
+            .unwrap()); //#[allow_ci]
+        let key = get_symm_key(keys_tx.clone()).await.unwrap(); //#[allow_ci]
+        assert!(key.is_none());
+
+        // The V key that failed to combine is not kept as the last one, so
+        // the correct V key is not rejected as a replacement
+        assert!(send_key(keys_tx.clone(), KeyMessage::VKey(v))
+            .await
+            .unwrap()); //#[allow_ci]
+        let key = get_symm_key(keys_tx.clone()).await.unwrap(); //#[allow_ci]
+        assert!(key.as_ref() == Some(&k));
+
+        assert!(keys_tx.send((KeyMessage::Shutdown, None)).await.is_ok());
+        drop(keys_tx);
+        assert!(worker.await.unwrap().is_ok()); //#[allow_ci]
+    }
+
+    #[actix_rt::test]
+    async fn test_worker_repeated_ukey() {
+        let uuid = "test-uuid";
+        let data = "some_encrypted_data";
+        let (u, v, k) = prepare_keys(
+            AES_128_KEY_LEN,
+            Some(data.as_bytes().into()),
+            uuid.to_string(),
+        );
+        let repeated_u = UKey {
+            decrypted_key: u.decrypted_key.clone(),
+            auth_tag: u.auth_tag.clone(),
+            payload: u.payload.clone(),
+            named_payloads: NamedPayloads::new(),
+        };
+        let repeated_v = VKey {
+            decrypted_key: v.decrypted_key.clone(),
+        };
+        let (other_u, _, _) =
+            prepare_keys(AES_128_KEY_LEN, None, uuid.to_string());
+
+        let (keys_tx, keys_rx) = mpsc::channel::<(
+            KeyMessage,
+            Option<oneshot::Sender<SymmKeyMessage>>,
+        )>(1);
+        let (payload_tx, mut payload_rx) = mpsc::channel::<PayloadMessage>(4);
+        let worker = actix_rt::spawn(worker(
+            true,
+            uuid.to_string(),
+            0,
+            true,
+            None,
+            PAYLOAD_WAIT_TIMEOUT,
+            PAYLOAD_MAX_SIZE,
+            keys_rx,
+            payload_tx,
+        ));
+
+        assert!(send_key(keys_tx.clone(), KeyMessage::UKey(u))
+            .await
+            .unwrap()); //#[allow_ci]
+        assert!(send_key(keys_tx.clone(), KeyMessage::VKey(v))
+            .await
+            .unwrap()); //#[allow_ci]
+        let key = get_symm_key(keys_tx.clone()).await.unwrap(); //#[allow_ci]
+        assert!(key.as_ref() == Some(&k));
+        assert!(payload_rx.recv().await.is_some());
+
+        // Posting the same keys again is accepted, but does not combine the
+        // key again nor run the payload again
+        assert!(
+            send_key(keys_tx.clone(), KeyMessage::UKey(repeated_u))
+                .await
+                .unwrap() //#[allow_ci]
+        );
+        assert!(
+            send_key(keys_tx.clone(), KeyMessage::VKey(repeated_v))
+                .await
+                .unwrap() //#[allow_ci]
+        );
+        let key = get_symm_key(keys_tx.clone()).await.unwrap(); //#[allow_ci]
+        assert!(key.as_ref() == Some(&k));
+        assert!(payload_rx.try_recv().is_err());
+
+        // A different U key is rejected
+        assert!(
+            !send_key(keys_tx.clone(), KeyMessage::UKey(other_u))
+                .await
+                .unwrap() //#[allow_ci]
+        );
+        let key = get_symm_key(keys_tx.clone()).await.unwrap(); //#[allow_ci]
+        assert!(key.as_ref() == Some(&k));
+
+        assert!(keys_tx.send((KeyMessage::Shutdown, None)).await.is_ok());
+        drop(keys_tx);
+        assert!(worker.await.unwrap().is_ok()); //#[allow_ci]
+    }
+
     #[actix_rt::test]
     async fn test_process_keys() {
         let mut ukeys = Vec::new();
@@ -735,6 +1445,7 @@ mod tests {
                     m == PayloadMessage::RunPayload(Payload {
                         symm_key: k_clone,
                         encrypted_payload: data.as_bytes().into(),
+                        named_payloads: NamedPayloads::new(),
                     })
                 );
             };
@@ -749,6 +1460,9 @@ mod tests {
             uuid.to_string(),
             payload_tx.clone(),
             true,
+            None,
+            PAYLOAD_WAIT_TIMEOUT,
+            PAYLOAD_MAX_SIZE,
         )
         .await;
         assert!(result.is_none());
@@ -763,6 +1477,9 @@ mod tests {
             uuid.to_string(),
             payload_tx,
             true,
+            None,
+            PAYLOAD_WAIT_TIMEOUT,
+            PAYLOAD_MAX_SIZE,
         )
         .await;
         assert!(result.is_some());
@@ -771,6 +1488,205 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_process_keys_pull_payload() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let uuid = "test-uuid";
+        let data = b"some payload";
+        let (u, v, k) = prepare_keys(AES_256_KEY_LEN, None, uuid.to_string());
+
+        let mut iv = [0u8; AES_BLOCK_SIZE];
+        rand_bytes(&mut iv).unwrap(); //#[allow_ci]
+        let encrypted = encrypt_aead(k.as_ref(), &iv[..], data).unwrap(); //#[allow_ci]
+
+        let mock_server = MockServer::start().await;
+        let mock = Mock::given(method("GET"))
+            .and(path("/payload"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_bytes(encrypted.clone()),
+            )
+            .expect(1);
+        mock_server.register(mock).await;
+
+        let (mut payload_tx, mut payload_rx) =
+            mpsc::channel::<PayloadMessage>(1);
+
+        let mut ukeys = vec![u];
+        let mut vkeys = vec![v];
+        let url = format!("{}/payload", mock_server.uri());
+        let result = process_keys(
+            &mut ukeys,
+            &mut vkeys,
+            uuid.to_string(),
+            payload_tx,
+            true,
+            Some(&url),
+            PAYLOAD_WAIT_TIMEOUT,
+            PAYLOAD_MAX_SIZE,
+        )
+        .await;
+        assert!(result == Some(k.clone()));
+
+        // The pulled payload is sent to the payloads worker and can be
+        // decrypted with the key derived from the U and V keys
+        match payload_rx.recv().await {
+            Some(PayloadMessage::RunPayload(payload)) => {
+                assert!(payload.symm_key == k);
+                assert_eq!(payload.encrypted_payload.as_ref(), &encrypted);
+                let decrypted = crypto::decrypt_aead(
+                    payload.symm_key.as_ref(),
+                    payload.encrypted_payload.as_ref(),
+                )
+                .unwrap(); //#[allow_ci]
+                assert_eq!(decrypted, data);
+            }
+            _ => panic!("Expected RunPayload message"), //#[allow_ci]
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_process_keys_wait_for_payload() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let uuid = "test-uuid";
+        let data = b"some payload";
+        let (u, v, k) = prepare_keys(AES_256_KEY_LEN, None, uuid.to_string());
+
+        let mut iv = [0u8; AES_BLOCK_SIZE];
+        rand_bytes(&mut iv).unwrap(); //#[allow_ci]
+        let encrypted = encrypt_aead(k.as_ref(), &iv[..], data).unwrap(); //#[allow_ci]
+
+        // The keys arrive before the payload is published: the payload is
+        // first missing, then empty, and only then available
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/payload"))
+            .respond_with(ResponseTemplate::new(404))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/payload"))
+            .respond_with(ResponseTemplate::new(200))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/payload"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_bytes(encrypted.clone()),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let (payload_tx, mut payload_rx) = mpsc::channel::<PayloadMessage>(1);
+
+        let mut ukeys = vec![u];
+        let mut vkeys = vec![v];
+        let url = format!("{}/payload", mock_server.uri());
+        let result = process_keys(
+            &mut ukeys,
+            &mut vkeys,
+            uuid.to_string(),
+            payload_tx,
+            true,
+            Some(&url),
+            PAYLOAD_WAIT_TIMEOUT,
+            PAYLOAD_MAX_SIZE,
+        )
+        .await;
+
+        // The key is available while the payload is awaited
+        assert!(result == Some(k.clone()));
+
+        // Decryption is requested only once the payload is available
+        match payload_rx.recv().await {
+            Some(PayloadMessage::RunPayload(payload)) => {
+                assert!(payload.symm_key == k);
+                let decrypted = crypto::decrypt_aead(
+                    payload.symm_key.as_ref(),
+                    payload.encrypted_payload.as_ref(),
+                )
+                .unwrap(); //#[allow_ci]
+                assert_eq!(decrypted, data);
+            }
+            _ => panic!("Expected RunPayload message"), //#[allow_ci]
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_wait_for_payload_timeout() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+
+        let result = wait_for_payload(
+            &mock_server.uri(),
+            Duration::from_secs(2),
+            PAYLOAD_MAX_SIZE,
+        )
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_wait_for_payload_limits() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/large"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_bytes(vec![0u8; 1025]),
+            )
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/slow"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_bytes(vec![0u8; 16])
+                    .set_delay(Duration::from_secs(30)),
+            )
+            .mount(&mock_server)
+            .await;
+
+        // A payload larger than the secure mount is refused
+        let url = format!("{}/large", mock_server.uri());
+        let result =
+            wait_for_payload(&url, Duration::from_secs(2), 1024).await;
+        assert!(result.unwrap_err().to_string().contains("exceeds")); //#[allow_ci]
+        let result =
+            wait_for_payload(&url, Duration::from_secs(2), 1025).await;
+        assert_eq!(result.unwrap().as_ref().len(), 1025); //#[allow_ci]
+
+        // A request that does not complete is aborted when the wait timeout
+        // expires
+        let start = Instant::now();
+        let url = format!("{}/slow", mock_server.uri());
+        let result =
+            wait_for_payload(&url, Duration::from_secs(2), PAYLOAD_MAX_SIZE)
+                .await;
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
     #[cfg(feature = "testing")]
     async fn test_u_or_v_key(key_len: usize, payload: Option<&[u8]>) {
         let test_config = KeylimeConfig::default();
@@ -795,7 +1711,7 @@ mod tests {
         fixture.keys_tx = keys_tx.clone();
 
         let quotedata = web::Data::new(fixture);
-        let pubkey = quotedata.pub_key.clone();
+        let pubkey = quotedata.pub_key();
 
         // Run server
         let mut app = test::init_service(
@@ -826,14 +1742,27 @@ mod tests {
         });
 
         let uuid = test_config.agent.uuid;
-        let auth_tag = compute_hmac(k.as_ref(), uuid.as_bytes()).unwrap(); //#[allow_ci]
+        let auth_tag =
+            compute_hmac(k.as_ref(), uuid.as_bytes(), HashAlgorithm::Sha384)
+                .unwrap(); //#[allow_ci]
 
         let arbiter = Arbiter::new();
         let p_tx = payload_tx.clone();
         let uuid_clone = uuid.clone();
         // Run keys worker
         assert!(arbiter.spawn(Box::pin(async move {
-            let result = worker(true, uuid_clone, keys_rx, p_tx).await;
+            let result = worker(
+                true,
+                uuid_clone,
+                test_config.agent.max_keyset_size as usize,
+                test_config.agent.reject_key_replacement,
+                None,
+                PAYLOAD_WAIT_TIMEOUT,
+                PAYLOAD_MAX_SIZE,
+                keys_rx,
+                p_tx,
+            )
+            .await;
 
             if result.is_err() {
                 debug!("keys worker failed: {:?}", result);
@@ -870,12 +1799,13 @@ mod tests {
         })));
 
         let encrypted_key =
-            rsa_oaep_encrypt(&quotedata.pub_key, u.as_ref()).unwrap(); //#[allow_ci]
+            rsa_oaep_encrypt(&quotedata.pub_key(), u.as_ref()).unwrap(); //#[allow_ci]
 
         let ukey = KeylimeUKey {
             encrypted_key: general_purpose::STANDARD.encode(&encrypted_key),
             auth_tag: hex::encode(auth_tag),
             payload: payload.map(|p| general_purpose::STANDARD.encode(p)),
+            named_payloads: None,
         };
 
         let req = test::TestRequest::post()
@@ -887,7 +1817,7 @@ mod tests {
         assert!(resp.status().is_success());
 
         let encrypted_key =
-            rsa_oaep_encrypt(&quotedata.pub_key, v.as_ref()).unwrap(); //#[allow_ci]
+            rsa_oaep_encrypt(&quotedata.pub_key(), v.as_ref()).unwrap(); //#[allow_ci]
 
         let vkey = KeylimeVKey {
             encrypted_key: general_purpose::STANDARD.encode(&encrypted_key),
@@ -918,8 +1848,12 @@ mod tests {
 
         // Test verify which calculates an HMAC on the challenge using the combined key as key
         let challenge = "1234567890ABCDEFGHIJ";
-        let expected =
-            compute_hmac(k.as_ref(), challenge.as_bytes()).unwrap(); //#[allow_ci]
+        let expected = compute_hmac(
+            k.as_ref(),
+            challenge.as_bytes(),
+            HashAlgorithm::Sha384,
+        )
+        .unwrap(); //#[allow_ci]
         let req = test::TestRequest::get()
             .uri(&format!("/{API_VERSION}/keys/verify?challenge={challenge}"))
             .to_request();
@@ -1011,6 +1945,37 @@ mod tests {
             test::read_body_json(resp).await;
         assert!(pkey_pub_from_pem(&result.results.pubkey)
             .unwrap() //#[allow_ci]
-            .public_eq(&quotedata.pub_key));
+            .public_eq(&quotedata.pub_key()));
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_rotate_transport_keys() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let pubkey = quotedata.pub_key();
+
+        let rotation = actix_rt::spawn(rotate_transport_keys(
+            quotedata.clone(),
+            Duration::from_millis(100),
+        ));
+        sleep(Duration::from_millis(500)).await;
+        rotation.abort();
+
+        assert!(!pubkey.public_eq(&quotedata.pub_key()));
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_rotate_transport_key_during_exchange() {
+        let quotedata = QuoteData::fixture().unwrap(); //#[allow_ci]
+        let pubkey = quotedata.pub_key();
+
+        *quotedata.last_key_received.lock().unwrap() = Some(Instant::now()); //#[allow_ci]
+        assert!(!rotate_transport_key(&quotedata).unwrap()); //#[allow_ci]
+        assert!(pubkey.public_eq(&quotedata.pub_key()));
+
+        *quotedata.last_key_received.lock().unwrap() = None; //#[allow_ci]
+        assert!(rotate_transport_key(&quotedata).unwrap()); //#[allow_ci]
+        assert!(!pubkey.public_eq(&quotedata.pub_key()));
     }
 }
@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+use log::*;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// When a message was first logged in the current window, when it was last
+// repeated, and how many times it was repeated since
+#[derive(Debug)]
+struct Occurrences {
+    since: Instant,
+    last: Instant,
+    repeated: u64,
+}
+
+/// Collapses identical messages logged repeatedly, such as the errors
+/// returned on every poll by a TPM stuck in a failure state. The first
+/// occurrence of a message is logged, while the following ones within the
+/// window are only counted, and summarized in a single line once the window
+/// expires.
+///
+/// The summaries are logged by `flush`, which must be called periodically so
+/// that they are not delayed until the message occurs again.
+#[derive(Debug)]
+pub(crate) struct RepeatedLog {
+    window: Duration,
+    messages: Mutex<HashMap<String, Occurrences>>,
+}
+
+impl RepeatedLog {
+    pub(crate) fn new(window: Duration) -> Self {
+        RepeatedLog {
+            window,
+            messages: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Logs `message` as a warning, unless it was already logged within the
+    /// window
+    pub(crate) fn warn(&self, message: &str) {
+        for line in self.record(message, Instant::now()) {
+            warn!("{}", line);
+        }
+    }
+
+    /// Logs the summaries of the messages whose window expired
+    pub(crate) fn flush(&self) {
+        for line in self.expire(Instant::now()) {
+            warn!("{}", line);
+        }
+    }
+
+    // Removes the messages whose window expired at `now`, returning the
+    // summaries of those that were repeated, with the time elapsed between
+    // their first and last occurrences
+    fn expire(&self, now: Instant) -> Vec<String> {
+        let mut messages = self.messages.lock().unwrap(); //#[allow_ci]
+        let mut lines = Vec::new();
+
+        messages.retain(|m, o| {
+            if now.duration_since(o.since) < self.window {
+                return true;
+            }
+            if o.repeated > 0 {
+                lines.push(format!(
+                    "{m} ({} more occurrences in {}s)",
+                    o.repeated,
+                    o.last.duration_since(o.since).as_secs()
+                ));
+            }
+            false
+        });
+        lines
+    }
+
+    // Records an occurrence of `message` at `now`, returning the lines to
+    // log: the summaries of the messages whose window expired, followed by
+    // the message itself if it was not logged within the window
+    fn record(&self, message: &str, now: Instant) -> Vec<String> {
+        let mut lines = self.expire(now);
+        let mut messages = self.messages.lock().unwrap(); //#[allow_ci]
+
+        match messages.get_mut(message) {
+            Some(o) => {
+                o.last = now;
+                o.repeated += 1;
+            }
+            None => {
+                _ = messages.insert(
+                    message.to_string(),
+                    Occurrences {
+                        since: now,
+                        last: now,
+                        repeated: 0,
+                    },
+                );
+                lines.push(message.to_string());
+            }
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeated_log() {
+        let log = RepeatedLog::new(Duration::from_secs(60));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let error = "TPM2_Quote failed with TPM response code 0x00000101";
+
+        // Only the first occurrence within the window is logged
+        assert_eq!(log.record(error, at(0)), vec![error]);
+        for secs in 1..5 {
+            assert!(log.record(error, at(secs)).is_empty());
+        }
+
+        // Other messages are not collapsed with it
+        assert_eq!(log.record("other", at(10)), vec!["other"]);
+        assert!(log.record(error, at(20)).is_empty());
+
+        // Once the window expires, the repeated occurrences are summarized
+        // with the time elapsed until the last one, and the message is
+        // logged again
+        assert_eq!(
+            log.record(error, at(61)),
+            vec![
+                format!("{error} (5 more occurrences in 20s)"),
+                error.to_string()
+            ]
+        );
+
+        // A message that was not repeated is not summarized
+        assert_eq!(log.record(error, at(130)), vec![error]);
+    }
+
+    #[test]
+    fn test_repeated_log_expire() {
+        let log = RepeatedLog::new(Duration::from_secs(60));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let error = "TPM2_Quote failed with TPM response code 0x00000101";
+
+        assert_eq!(log.record(error, at(0)), vec![error]);
+        assert!(log.record(error, at(30)).is_empty());
+        assert!(log.record(error, at(45)).is_empty());
+
+        // The summary is produced once the window expires, without waiting
+        // for the message to occur again
+        assert!(log.expire(at(59)).is_empty());
+        assert_eq!(
+            log.expire(at(60)),
+            vec![format!("{error} (2 more occurrences in 45s)")]
+        );
+        assert!(log.expire(at(120)).is_empty());
+
+        // The next occurrence starts a new window
+        assert_eq!(log.record(error, at(121)), vec![error]);
+    }
+}
@@ -32,32 +32,50 @@
 //  missing_docs: there is many functions missing documentations for now
 #![allow(unused, missing_docs)]
 
+mod agent_handler;
+mod archive;
+mod bench;
+mod client_cert;
 mod common;
 mod config;
+mod config_schema;
+mod connection;
+mod cpu_affinity;
 mod crypto;
+mod debug_handler;
 mod error;
 mod errors_handler;
+mod firmware;
 mod keys_handler;
+mod log_limit;
+mod metrics;
 mod notifications_handler;
+mod payload_handler;
 mod payloads;
+mod pcr_log;
 mod permissions;
 mod quotes_handler;
 mod registrar_agent;
+mod reprovision_handler;
 mod revocation;
 mod secure_mount;
 mod serialization;
+mod startup;
+mod state_snapshot;
+mod tls_reload;
+mod tpm_clock;
 mod version_handler;
 
 use actix_web::{dev::Service, http, middleware, rt, web, App, HttpServer};
 use base64::{engine::general_purpose, Engine as _};
-use clap::{Arg, Command as ClapApp};
+use clap::{Arg, ArgAction, Command as ClapApp};
 use common::*;
 use error::{Error, Result};
 use futures::{
-    future::{ok, TryFutureExt},
+    future::{self, ok, TryFutureExt},
     try_join,
 };
-use keylime::ima::MeasurementList;
+use keylime::ima::{ImaFormat, MeasurementList};
 use keylime::tpm;
 use log::*;
 use openssl::{
@@ -68,12 +86,14 @@ use std::{
     convert::TryFrom,
     fs,
     io::{BufReader, Read, Write},
+    net::SocketAddr,
+    ops::RangeInclusive,
     path::{Path, PathBuf},
     str::FromStr,
-    sync::Mutex,
-    time::Duration,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
 };
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, Semaphore};
 use tss_esapi::{
     handles::KeyHandle,
     interface_types::algorithm::AsymmetricAlgorithm,
@@ -89,31 +109,95 @@ extern crate static_assertions;
 
 static NOTFOUND: &[u8] = b"Not Found";
 
+// The running agent executable, measured on startup
+const AGENT_BINARY_PATH: &str = "/proc/self/exe";
+
 // This data is passed in to the actix httpserver threads that
 // handle quotes.
 #[derive(Debug)]
 pub struct QuoteData {
     tpmcontext: Mutex<tpm::Context>,
-    priv_key: PKey<Private>,
-    pub_key: PKey<Public>,
-    ak_handle: KeyHandle,
+    // The AK context and the registration state, stored in the state snapshot
+    agent_data: Mutex<AgentData>,
+    // The transport key pair (NK), which can be rotated in the background
+    transport_keys: RwLock<(PKey<Public>, PKey<Private>)>,
+    // When the last U or V key was received, used to avoid rotating the
+    // transport keys during a key exchange
+    last_key_received: Mutex<Option<Instant>>,
+    // The AK handle, which is replaced when the agent is reprovisioned
+    ak_handle: RwLock<KeyHandle>,
+    // The EK handle, if the EK is persisted and not flushed after the
+    // registration
+    ek_handle: Option<KeyHandle>,
     payload_tx: mpsc::Sender<payloads::PayloadMessage>,
+    // The metadata of the last payload received, updated by the payloads
+    // worker
+    payload_status: Arc<Mutex<payloads::PayloadStatus>>,
+    // Bounds the payloads processed at the same time
+    payload_runs: Arc<payloads::PayloadRuns>,
     revocation_tx: mpsc::Sender<revocation::RevocationMessage>,
     keys_tx: mpsc::Sender<(
         keys_handler::KeyMessage,
         Option<oneshot::Sender<keys_handler::SymmKeyMessage>>,
     )>,
     hash_alg: keylime::algorithms::HashAlgorithm,
+    // The PCR banks included in the quotes, the first one being 'hash_alg'
+    pcr_banks: Vec<keylime::algorithms::HashAlgorithm>,
     enc_alg: keylime::algorithms::EncryptionAlgorithm,
     sign_alg: keylime::algorithms::SignAlgorithm,
+    // The hash algorithm of the HMACs of the payload key auth tag, the key
+    // challenge and the activation auth tag
+    hmac_hash_alg: keylime::algorithms::HashAlgorithm,
     agent_uuid: String,
+    agent_name: String,
     allow_payload_revocation_actions: bool,
     secure_size: String,
     work_dir: PathBuf,
     ima_ml_file: Option<Mutex<fs::File>>,
     measuredboot_ml_file: Option<Mutex<fs::File>>,
     ima_ml: Mutex<MeasurementList>,
+    // Whether to include the expected IMA PCR value in the integrity quotes
+    ima_pcr_aggregate: bool,
     secure_mount: PathBuf,
+    system_facts_command: String,
+    ima_ml_requests: Option<Semaphore>,
+    metrics: Arc<metrics::Metrics>,
+    // Collapses the identical TPM errors logged on every quote request
+    tpm_error_log: log_limit::RepeatedLog,
+    // The integrity quotes generated in the background, if asynchronous
+    // quotes are enabled
+    quote_jobs: Option<quotes_handler::QuoteJobs>,
+    // The firmware version included in the identity quotes, if enabled
+    firmware_version: Option<String>,
+    // The hash of the effective configuration included in the identity
+    // quotes
+    config_hash: Option<String>,
+    // Whether to include the qualifying data in the quote responses, set
+    // when the debug endpoints are enabled
+    debug_quotes: bool,
+    // The mask of the PCRs that can be included in the quotes, limiting the
+    // mask requested by the verifier, if set in 'quote_pcr_selection'
+    quote_pcr_mask: Option<u32>,
+    // The accepted lengths of the nonces in the quote requests
+    nonce_length: RangeInclusive<usize>,
+    // Fixed qualifying data used in identity quotes instead of the request
+    // nonce, to make the quotes deterministic in tests
+    #[cfg(feature = "testing")]
+    fixed_nonce: Option<Vec<u8>>,
+}
+
+impl QuoteData {
+    fn pub_key(&self) -> PKey<Public> {
+        self.transport_keys.read().unwrap().0.clone() //#[allow_ci]
+    }
+
+    fn priv_key(&self) -> PKey<Private> {
+        self.transport_keys.read().unwrap().1.clone() //#[allow_ci]
+    }
+
+    fn ak_handle(&self) -> KeyHandle {
+        *self.ak_handle.read().unwrap() //#[allow_ci]
+    }
 }
 
 #[actix_web::main]
@@ -124,31 +208,76 @@ async fn main() -> Result<()> {
         .override_usage(
             "sudo RUST_LOG=keylime_agent=trace ./target/debug/keylime_agent",
         )
+        .arg(
+            Arg::new("list-revocation-actions")
+                .long("list-revocation-actions")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Print the ordered list of revocation actions and exit",
+                ),
+        )
+        .arg(
+            Arg::new("bench-quotes")
+                .long("bench-quotes")
+                .value_name("COUNT")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Generate COUNT quotes, print their latency and exit"),
+        )
+        .arg(
+            Arg::new("attest-once")
+                .long("attest-once")
+                .value_name("FILE")
+                .help("Register the agent, write a single integrity quote to FILE (or to the standard output if FILE is \"-\") and exit"),
+        )
+        .arg(
+            Arg::new("validate-config")
+                .long("validate-config")
+                .value_name("SCHEMA")
+                .min_values(0)
+                .max_values(1)
+                .help("Check that the configuration is valid, and optionally validate it against the SCHEMA file, printing all the violations found, then exit without accessing the TPM or the network"),
+        )
+        .arg(
+            Arg::new("trace-config")
+                .long("trace-config")
+                .action(ArgAction::SetTrue)
+                .help("Print the value of each configuration option with the source that provided it (default, sys, snippet, user or env) and exit"),
+        )
         .get_matches();
 
     pretty_env_logger::init();
 
-    let ima_ml_path = ima_ml_path_get();
-    let ima_ml_file = if ima_ml_path.exists() {
-        match fs::File::open(&ima_ml_path) {
-            Ok(file) => Some(Mutex::new(file)),
+    // Validate the configuration and exit
+    if matches.contains_id("validate-config") {
+        let schema_path = matches.get_one::<String>("validate-config");
+        match validate_config(schema_path.map(Path::new)) {
+            Ok(()) => {
+                println!("The configuration is valid");
+                return Ok(());
+            }
             Err(e) => {
-                warn!(
-                    "IMA measurement list not accessible: {}",
-                    ima_ml_path.display()
-                );
-                None
+                eprintln!("{e}");
+                std::process::exit(1);
             }
         }
-    } else {
-        warn!(
-            "IMA measurement list not available: {}",
-            ima_ml_path.display()
-        );
-        None
-    };
+    }
+
+    // Print where each configuration option was set and exit
+    if matches.get_flag("trace-config") {
+        for (name, value, source) in config::config_trace_sources()? {
+            println!("{name} = {value} ({source})");
+        }
+        return Ok(());
+    }
+
+    // Load config
+    let mut config = config::KeylimeConfig::new()?;
 
-    let mut measuredboot_ml_path = Path::new(MEASUREDBOOT_ML);
+    let mut measuredboot_ml_path =
+        match config.agent.measuredboot_ml_path.as_ref() {
+            "default" => Path::new(MEASUREDBOOT_ML),
+            p => Path::new(p),
+        };
 
     // Allow setting the binary bios measurements log path when testing
     let env_mb_path: String;
@@ -177,8 +306,103 @@ async fn main() -> Result<()> {
         None
     };
 
-    // Load config
-    let mut config = config::KeylimeConfig::new()?;
+    // Print the revocation actions that would run and exit
+    if matches.get_flag("list-revocation-actions") {
+        let revocation_actions =
+            match config.agent.revocation_actions.as_ref() {
+                "" => None,
+                s => Some(s.to_string()),
+            };
+        let storage = secure_mount::SecureStorage::select(
+            &config.agent.secure_mount_mode,
+            &config.agent.secure_mount_path,
+            secure_mount::can_mount(),
+        )?;
+        let mount = storage.dir(Path::new(&config.agent.keylime_dir));
+        let actions = revocation::list_revocation_actions(
+            revocation_actions,
+            Path::new(&config.agent.revocation_actions_dir),
+            config.agent.allow_payload_revocation_actions,
+            &mount,
+            &revocation::parse_allowed_actions(
+                &config.agent.revocation_allowed_actions,
+            ),
+        )?;
+        if actions.is_empty() {
+            println!("No revocation actions configured");
+        }
+        for (index, (action, command)) in actions.iter().enumerate() {
+            match command {
+                Some(c) => println!("{}: {} ({})", index + 1, action, c),
+                None => println!(
+                    "{}: {} (not found or not allowed)",
+                    index + 1,
+                    action
+                ),
+            }
+        }
+        return Ok(());
+    }
+
+    // The TSS libraries read their log configuration from the environment
+    // when they are first used. The environment is only modified here, while
+    // the main thread is the only one running, as the runtime started by
+    // actix_web::main is single-threaded.
+    if let Some(tss_log) = tss_log_setting(&config.agent.tss_log_level)? {
+        info!("Setting TSS log level: {}", tss_log);
+        std::env::set_var(TSS_LOG_ENV, tss_log);
+    }
+
+    // Move to a private mount namespace, so that the secure mount is not
+    // visible outside the agent. This has to be done before any thread is
+    // started.
+    if config.agent.secure_mount_unshare {
+        secure_mount::unshare_mount_namespace()?;
+    }
+
+    // Exit if the agent does not start serving requests in time
+    let startup_watchdog = startup::StartupWatchdog::start(
+        Duration::from_secs(config.agent.startup_timeout),
+        |_| std::process::exit(1),
+    );
+
+    // Apply the resource limits for the agent process
+    for (resource, limit) in [
+        (permissions::Rlimit::NoFile, config.agent.rlimit_nofile),
+        (permissions::Rlimit::NProc, config.agent.rlimit_nproc),
+    ] {
+        if limit > 0 {
+            permissions::set_rlimit(resource, limit)?;
+        }
+    }
+
+    let ima_ml_path = ima_ml_path_get(&config.agent.ima_ml_path);
+    let (ima_ml_file, ima_ml_format) = if ima_ml_path.exists() {
+        match fs::File::open(&ima_ml_path) {
+            Ok(mut file) => {
+                let format = ImaFormat::detect(&mut file)?;
+                info!(
+                    "Using IMA measurement list {} ({:?} format)",
+                    ima_ml_path.display(),
+                    format
+                );
+                (Some(Mutex::new(file)), format)
+            }
+            Err(e) => {
+                warn!(
+                    "IMA measurement list not accessible: {}",
+                    ima_ml_path.display()
+                );
+                (None, ImaFormat::Ascii)
+            }
+        }
+    } else {
+        warn!(
+            "IMA measurement list not available: {}",
+            ima_ml_path.display()
+        );
+        (None, ImaFormat::Ascii)
+    };
 
     // The agent cannot run when a payload script is defined, but mTLS is disabled and insecure
     // payloads are not explicitly enabled
@@ -194,7 +418,12 @@ async fn main() -> Result<()> {
 
     let secure_size = config.agent.secure_size.clone();
     let work_dir = PathBuf::from(&config.agent.keylime_dir);
-    let mount = secure_mount::mount(&work_dir, &config.agent.secure_size)?;
+    startup_watchdog.enter("secure mount");
+    let secure_storage = secure_mount::SecureStorage::select(
+        &config.agent.secure_mount_mode,
+        &config.agent.secure_mount_path,
+        secure_mount::can_mount(),
+    )?;
 
     let run_as = if permissions::get_euid() == 0 {
         if (config.agent.run_as).is_empty() {
@@ -210,6 +439,19 @@ async fn main() -> Result<()> {
         ));
     };
 
+    // The user the agent runs as may own the secure storage created before.
+    // An invalid user is reported when dropping privileges.
+    let run_as_uid = run_as
+        .and_then(|u| permissions::UserIds::try_from(u.as_str()).ok())
+        .map(|ids| ids.uid());
+    let mount = secure_mount::mount(
+        &work_dir,
+        &config.agent.secure_size,
+        Duration::from_secs(config.agent.secure_mount_wait),
+        &secure_storage,
+        run_as_uid,
+    )?;
+
     // Drop privileges
     if let Some(user_group) = run_as {
         permissions::chown(user_group, &mount)?;
@@ -224,7 +466,41 @@ async fn main() -> Result<()> {
 
     info!("Starting server with API version {}...", API_VERSION);
 
-    let mut ctx = tpm::Context::new()?;
+    // Select the first working TCTI, which is then used for all the
+    // connections to the TPM
+    let tcti_fallback: Vec<&str> = config
+        .agent
+        .tpm_tcti_fallback
+        .split(',')
+        .map(str::trim)
+        .filter(|tcti| !tcti.is_empty())
+        .collect();
+    let tcti = if tcti_fallback.is_empty() {
+        tpm::default_tcti()
+    } else if std::env::var(TCTI_ENV).is_ok() {
+        info!(
+            "Using the TCTI set in the {} environment variable instead of 'tpm_tcti_fallback'",
+            TCTI_ENV
+        );
+        tpm::default_tcti()
+    } else {
+        tpm::select_tcti(&tcti_fallback)?
+    };
+
+    // The TPM clock is read through a separate connection, before the TPM
+    // context is created
+    if config.agent.enable_tpm_clock_check {
+        tpm_clock::check_tpm_clock(
+            &Path::new(&config.agent.keylime_dir)
+                .join(tpm_clock::CLOCK_BASELINE_FILE),
+            Duration::from_secs(config.agent.clock_skew_tolerance),
+            config.agent.tpm_clock_skew_fatal,
+            &tcti,
+        )?;
+    }
+
+    startup_watchdog.enter("TPM EK and AK creation");
+    let mut ctx = tpm::Context::with_tcti(&tcti)?;
 
     //  Retrieve the TPM Vendor, this allows us to warn if someone is using a
     // Software TPM ("SW")
@@ -269,18 +545,53 @@ async fn main() -> Result<()> {
         keylime::algorithms::EncryptionAlgorithm::try_from(
             config.agent.tpm_encryption_alg.as_ref(),
         )?;
-    let tpm_hash_alg = keylime::algorithms::HashAlgorithm::try_from(
+    // The first hash algorithm is used for the AK and the quote signatures,
+    // while the PCRs are quoted in the banks of all the listed algorithms
+    let tpm_pcr_banks = keylime::algorithms::HashAlgorithm::parse_list(
         config.agent.tpm_hash_alg.as_ref(),
     )?;
+    let tpm_hash_alg = tpm_pcr_banks[0];
     let tpm_signing_alg = keylime::algorithms::SignAlgorithm::try_from(
         config.agent.tpm_signing_alg.as_ref(),
     )?;
+    let hmac_hash_alg = match keylime::algorithms::HashAlgorithm::try_from(
+        config.agent.hmac_hash_alg.as_ref(),
+    )? {
+        alg @ (keylime::algorithms::HashAlgorithm::Sha256
+        | keylime::algorithms::HashAlgorithm::Sha384
+        | keylime::algorithms::HashAlgorithm::Sha512) => alg,
+        alg => {
+            return Err(Error::Configuration(format!(
+                "hmac_hash_alg {alg} is not supported, use sha256, sha384 or sha512"
+            )));
+        }
+    };
+    let ak_template = tpm::AkTemplate::new(
+        config.agent.ak_rsa_key_bits,
+        &config.agent.ak_ecc_curve,
+    )?;
+    ctx.set_ak_template(ak_template);
+    if let Some(index) =
+        config::parse_ek_cert_nv_index(&config.agent.ek_cert_nv_index)?
+    {
+        ctx.set_ek_cert_nv_index(index)?;
+    }
 
     // Gather EK values and certs
     let ek_result = match config.agent.ek_handle.as_ref() {
         "" => ctx.create_ek(tpm_encryption_alg, None)?,
         s => ctx.create_ek(tpm_encryption_alg, Some(s))?,
     };
+    check_ek_cert(
+        ek_result.ek_cert.as_deref(),
+        config.agent.require_ek_cert,
+    )?;
+
+    // Use sessions salted with the EK to encrypt the sensitive parameters
+    if config.agent.tpm_session_encryption {
+        info!("Enabling TPM session encryption");
+        ctx.enable_session_encryption(ek_result.key_handle);
+    }
 
     // Calculate the SHA-256 hash of the public key in PEM format
     let ek_hash = hash_ek_pubkey(ek_result.public.clone())?;
@@ -295,8 +606,49 @@ async fn main() -> Result<()> {
 
     let agent_uuid = config.agent.uuid.clone();
 
+    let agent_data_format =
+        AgentDataFormat::try_from(config.agent.agent_data_format.as_str())?;
+
+    let config_hash = config.hash()?;
+    info!("Effective configuration hash: {}", config_hash);
+
+    // Restore the state stored on the last clean shutdown, if the TPM and the
+    // configuration did not change since
+    let snapshot = match config.agent.state_snapshot_path.as_ref() {
+        "" => None,
+        path => match state_snapshot::restore(
+            &mut ctx,
+            ek_result.key_handle,
+            Path::new(path),
+        ) {
+            Some(snapshot)
+                if snapshot.valid(
+                    tpm_hash_alg,
+                    tpm_signing_alg,
+                    &ak_template,
+                    ek_hash.as_bytes(),
+                    &config_hash,
+                )? =>
+            {
+                info!("Restored the agent state from {}", path);
+                Some(snapshot)
+            }
+            Some(_) => {
+                warn!(
+                    "Not using the state snapshot {} because it is not valid with current TPM and configuration",
+                    path
+                );
+                None
+            }
+            None => None,
+        },
+    };
+
     // Try to load persistent Agent data
-    let old_ak = match config.agent.agent_data_path.as_ref() {
+    let old_data = match config.agent.agent_data_path.as_ref() {
+        _ if snapshot.is_some() => snapshot
+            .as_ref()
+            .map(|snapshot| snapshot.agent_data.clone()),
         "" => {
             info!("Agent Data path not set in the configuration file");
             None
@@ -310,29 +662,9 @@ async fn main() -> Result<()> {
                             tpm_hash_alg,
                             tpm_signing_alg,
                             ek_hash.as_bytes(),
-                        ) {
-                            true => {
-                                let ak_result = data.get_ak()?;
-                                match ctx
-                                    .load_ak(ek_result.key_handle, &ak_result)
-                                {
-                                    Ok(ak_handle) => {
-                                        info!(
-                                            "Loaded old AK key from {}",
-                                            path.display()
-                                        );
-                                        Some((ak_handle, ak_result))
-                                    }
-                                    Err(e) => {
-                                        warn!(
-                                            "Loading old AK key from {} failed: {}",
-                                            path.display(),
-                                            e
-                                        );
-                                        None
-                                    }
-                                }
-                            }
+                        ) && ak_template.matches(&data.get_ak()?.public)
+                        {
+                            true => Some(data),
                             false => {
                                 warn!(
                                     "Not using old {} because it is not valid with current configuration",
@@ -355,30 +687,71 @@ async fn main() -> Result<()> {
     };
 
     // Use old AK or generate a new one and update the AgentData
-    let (ak_handle, ak) = match old_ak {
-        Some((ak_handle, ak)) => (ak_handle, ak),
-        None => {
-            let new_ak = ctx.create_ak(
-                ek_result.key_handle,
-                tpm_hash_alg,
-                tpm_signing_alg,
-            )?;
-            let ak_handle = ctx.load_ak(ek_result.key_handle, &new_ak)?;
-            (ak_handle, new_ak)
+    let old_ak_result = match old_data {
+        Some(ref data) => Some(data.get_ak()?),
+        None => None,
+    };
+    let (mut ak_handle, ak, reused) = load_or_create_ak(
+        &mut ctx,
+        ek_result.key_handle,
+        old_ak_result.as_ref(),
+        tpm_hash_alg,
+        tpm_signing_alg,
+    )?;
+    // The registration done with the old AK is only kept if it is reused
+    let old_registration = match old_data {
+        Some(data) if reused => {
+            match snapshot {
+                Some(_) => info!("Loaded old AK key from the state snapshot"),
+                None => info!(
+                    "Loaded old AK key from {}",
+                    config.agent.agent_data_path
+                ),
+            }
+            data.registration_digest().map(String::from)
         }
+        _ => None,
     };
 
-    // Store new AgentData
-    let agent_data_new = AgentData::create(
+    // Make sure the AK and the EK belong to the same TPM before registering
+    ctx.verify_ak_binding(ak_handle, ek_result.key_handle)?;
+    info!("Verified AK is bound to the EK");
+
+    // Measure how fast the TPM generates quotes and exit
+    if let Some(count) = matches.get_one::<u64>("bench-quotes") {
+        startup_watchdog.finish();
+        // Use an ephemeral key, as the quote only depends on its digest
+        let (pubkey, _) = crypto::rsa_generate_pair(2048)?;
+        let stats = bench::bench_quotes(
+            &mut ctx,
+            *count,
+            &pubkey,
+            ak_handle,
+            tpm_hash_alg,
+            tpm_signing_alg,
+        )?;
+        println!("{stats}");
+        return Ok(());
+    }
+
+    // Store new AgentData, keeping the registration done with the same AK
+    let mut agent_data_new = AgentData::create(
         tpm_hash_alg,
         tpm_signing_alg,
         &ak,
         ek_hash.as_bytes(),
     )?;
+    agent_data_new.set_registration_digest(old_registration);
 
     match config.agent.agent_data_path.as_ref() {
         "" => info!("Agent Data not stored"),
-        path => agent_data_new.store(Path::new(&path))?,
+        path => {
+            _ = agent_data_new.store_or_tolerate(
+                Path::new(&path),
+                agent_data_format,
+                config.agent.tolerate_readonly_state,
+            )?
+        }
     }
 
     info!("Agent UUID: {}", agent_uuid);
@@ -391,14 +764,19 @@ async fn main() -> Result<()> {
     // Since we store the u key in memory, discarding this key, which
     // safeguards u and v keys in transit, is not part of the threat model.
 
+    let mut loaded_cert = None;
     let (nk_pub, nk_priv) = match config.agent.server_key.as_ref() {
-        "" => {
-            debug!(
-                "The server_key option was not set in the configuration file"
-            );
-            debug!("Generating new key pair");
-            crypto::rsa_generate_pair(2048)?
-        }
+        "" => match snapshot {
+            Some(ref snapshot) => {
+                debug!("Using the key pair restored from the state snapshot");
+                snapshot.transport_key_pair()?
+            }
+            None => {
+                debug!("The server_key option was not set in the configuration file");
+                debug!("Generating new key pair");
+                crypto::rsa_generate_pair(2048)?
+            }
+        },
         path => {
             let key_path = Path::new(&path);
             if key_path.exists() {
@@ -406,10 +784,33 @@ async fn main() -> Result<()> {
                     "Loading existing key pair from {}",
                     key_path.display()
                 );
-                crypto::load_key_pair(
+                // Load the certificate together with the key, so that the
+                // fallback generates a matching pair
+                let cert_path = match config.agent.server_cert.as_ref() {
+                    "" => None,
+                    p => Some(Path::new(p)).filter(|p| {
+                        config.agent.enable_agent_mtls && p.exists()
+                    }),
+                };
+                let identity = crypto::load_tls_identity(
                     key_path,
                     Some(config.agent.server_key_password.as_ref()),
-                )?
+                    cert_path,
+                    &agent_uuid,
+                    TLS_LOAD_ATTEMPTS,
+                    Duration::from_millis(TLS_LOAD_RETRY_DELAY_MS),
+                    config.agent.generate_self_signed_on_failure,
+                )?;
+                // Reject a weak key provided externally, instead of using it
+                // for TLS and to protect the U and V keys in transit
+                if !identity.ephemeral {
+                    crypto::check_key_strength(
+                        &identity.public,
+                        config.agent.min_key_bits,
+                    )?;
+                }
+                loaded_cert = identity.cert;
+                (identity.public, identity.private)
             } else {
                 debug!("Generating new key pair");
                 let (public, private) = crypto::rsa_generate_pair(2048)?;
@@ -424,33 +825,65 @@ async fn main() -> Result<()> {
         }
     };
 
+    // Endpoints reachable without a client certificate when mTLS is enabled
+    let mtls_optional_endpoints = client_cert::parse_optional_endpoints(
+        &config.agent.mtls_optional_endpoints,
+    );
+
     let cert: X509;
     let mtls_cert;
     let ssl_context;
+    let tls_reloader;
+    let registrar_tls;
     if config.agent.enable_agent_mtls {
-        cert = match config.agent.server_cert.as_ref() {
-            "" => {
-                debug!("The server_cert option was not set in the configuration file");
-                crypto::generate_x509(&nk_priv, &agent_uuid)?
-            }
-            path => {
-                let cert_path = Path::new(&path);
-                if cert_path.exists() {
-                    debug!(
-                        "Loading existing mTLS certificate from {}",
-                        cert_path.display()
-                    );
-                    crypto::load_x509(cert_path)?
-                } else {
-                    debug!("Generating new mTLS certificate");
-                    let cert = crypto::generate_x509(&nk_priv, &agent_uuid)?;
-                    // Write the generated certificate
-                    crypto::write_x509(&cert, cert_path)?;
-                    cert
+        cert = match loaded_cert {
+            Some(cert) => cert,
+            None => match config.agent.server_cert.as_ref() {
+                "" => {
+                    debug!("The server_cert option was not set in the configuration file");
+                    crypto::generate_x509(&nk_priv, &agent_uuid)?
                 }
-            }
+                path => {
+                    let cert_path = Path::new(&path);
+                    if cert_path.exists() {
+                        debug!(
+                            "Loading existing mTLS certificate from {}",
+                            cert_path.display()
+                        );
+                        crypto::load_x509(cert_path)?
+                    } else {
+                        debug!("Generating new mTLS certificate");
+                        let cert =
+                            crypto::generate_x509(&nk_priv, &agent_uuid)?;
+                        // Write the generated certificate
+                        crypto::write_x509(&cert, cert_path)?;
+                        cert
+                    }
+                }
+            },
         };
 
+        // Verify that the server key and certificate can be used together
+        // before registering, so a mismatch is reported on startup instead of
+        // on the first TLS handshake
+        if config.agent.verify_tls_on_startup {
+            if let Err(e) = crypto::check_tls_identity(&cert, &nk_priv) {
+                error!(
+                    "TLS identity check failed for server_key {} and server_cert {}: {}",
+                    config.agent.server_key, config.agent.server_cert, e
+                );
+                return Err(e);
+            }
+            if let Err(e) = crypto::check_server_cert_usage(&cert) {
+                error!(
+                    "The server_cert {} cannot be used for TLS server authentication: {}",
+                    config.agent.server_cert, e
+                );
+                return Err(e);
+            }
+            debug!("TLS identity check succeeded");
+        }
+
         let ca_cert_path = match config.agent.trusted_client_ca.as_ref() {
             "" => {
                 error!("Agent mTLS is enabled, but trusted_client_ca option was not provided");
@@ -483,57 +916,283 @@ async fn main() -> Result<()> {
                 }
             }?;
 
+        if config.agent.verify_tls_on_startup {
+            for ca_cert in &keylime_ca_certs {
+                if let Err(e) = crypto::check_ca_cert_usage(ca_cert) {
+                    error!(
+                        "The trusted_client_ca {} cannot be used to verify client certificates: {}",
+                        ca_cert_path.display(),
+                        e
+                    );
+                    return Err(e);
+                }
+            }
+        }
+
+        // The agent presents its mTLS certificate to the registrar, and
+        // verifies the registrar with the CA in 'registrar_ca', which is
+        // read again when it changes
+        registrar_tls = if config.agent.registrar_tls {
+            Some(registrar_agent::RegistrarTls::with_ca_file(
+                cert.clone(),
+                nk_priv.clone(),
+                Path::new(&config.agent.registrar_ca),
+            )?)
+        } else {
+            None
+        };
+
         mtls_cert = Some(&cert);
-        ssl_context = Some(crypto::generate_mtls_context(
+        if !mtls_optional_endpoints.is_empty() {
+            info!(
+                "Client certificate not required for endpoints: {}",
+                mtls_optional_endpoints.join(", ")
+            );
+        }
+
+        // The TLS context can be replaced on SIGHUP, when the server_key,
+        // server_cert and trusted_client_ca files were renewed
+        let reloader = tls_reload::TlsReloader::new(
+            &config.agent.server_key,
+            &config.agent.server_key_password,
+            &config.agent.server_cert,
+            &config.agent.trusted_client_ca,
+            config.agent.min_key_bits,
+            mtls_optional_endpoints.is_empty(),
+            nk_pub.clone(),
+            crypto::generate_mtls_context(
+                &cert,
+                &nk_priv,
+                keylime_ca_certs.clone(),
+                mtls_optional_endpoints.is_empty(),
+            )?
+            .build()
+            .into_context(),
+        );
+        let mut mtls_context = crypto::generate_mtls_context(
             &cert,
             &nk_priv,
             keylime_ca_certs,
-        )?);
+            mtls_optional_endpoints.is_empty(),
+        )?;
+        reloader.install(&mut mtls_context);
+        tls_reloader = Some(reloader);
+        ssl_context = Some(mtls_context);
     } else {
+        if config.agent.registrar_tls {
+            error!("The option 'registrar_tls' requires agent mTLS to be enabled with 'enable_agent_mtls'");
+            return Err(Error::Configuration("The option 'registrar_tls' requires agent mTLS to be enabled with 'enable_agent_mtls'".to_string()));
+        }
         mtls_cert = None;
         ssl_context = None;
+        tls_reloader = None;
+        registrar_tls = None;
         warn!("mTLS disabled, Tenant and Verifier will reach out to agent via HTTP");
     }
 
-    {
-        // Request keyblob material
-        let keyblob = registrar_agent::do_register_agent(
-            config.agent.registrar_ip.as_ref(),
-            config.agent.registrar_port,
-            &agent_uuid,
-            &PublicBuffer::try_from(ek_result.public.clone())?.marshall()?,
-            ek_result.ek_cert,
-            &PublicBuffer::try_from(ak.public)?.marshall()?,
-            mtls_cert,
-            config.agent.contact_ip.as_ref(),
-            config.agent.contact_port,
-        )
-        .await?;
+    let metrics = Arc::new(metrics::Metrics::default());
+    let payload_status =
+        Arc::new(Mutex::new(payloads::PayloadStatus::default()));
+    let payload_runs = Arc::new(payloads::PayloadRuns::new(
+        config.agent.max_payload_runs as usize,
+    ));
 
-        info!("SUCCESS: Agent {} registered", &agent_uuid);
+    if let Some(pcr) =
+        pcr_option("config_hash_pcr", &config.agent.config_hash_pcr)?
+    {
+        let digest =
+            openssl::hash::hash(tpm_hash_alg.into(), config_hash.as_bytes())?;
+        extend_measurement(
+            &mut ctx,
+            &config.agent.pcr_measurement_log,
+            pcr_log::PcrMeasurement {
+                pcr,
+                hash_alg: tpm_hash_alg,
+                digest: digest.to_vec(),
+                event: "config_hash".to_string(),
+            },
+        )?;
+    }
 
-        let key = ctx.activate_credential(
-            keyblob,
-            ak_handle,
-            ek_result.key_handle,
+    if let Some(pcr) =
+        pcr_option("agent_binary_pcr", &config.agent.agent_binary_pcr)?
+    {
+        let digest =
+            agent_binary_digest(tpm_hash_alg, Path::new(AGENT_BINARY_PATH))?;
+        extend_measurement(
+            &mut ctx,
+            &config.agent.pcr_measurement_log,
+            pcr_log::PcrMeasurement {
+                pcr,
+                hash_alg: tpm_hash_alg,
+                digest,
+                event: "agent_binary".to_string(),
+            },
         )?;
-        // Flush EK if we created it
-        if config.agent.ek_handle.is_empty() {
-            ctx.as_mut().flush_context(ek_result.key_handle.into())?;
+    }
+
+    startup_watchdog.enter("registration");
+    {
+        let ek_tpm =
+            PublicBuffer::try_from(ek_result.public.clone())?.marshall()?;
+        let mut aik_tpm = PublicBuffer::try_from(ak.public)?.marshall()?;
+        let digest = |aik_tpm: &[u8]| {
+            registrar_agent::registration_digest(
+                config.agent.registrar_ip.as_ref(),
+                config.agent.registrar_port,
+                &agent_uuid,
+                &config.agent.agent_name,
+                &ek_tpm,
+                ek_result.ek_cert.clone(),
+                aik_tpm,
+                mtls_cert,
+                config.agent.contact_ip.as_ref(),
+                config.agent.contact_port,
+                Some(&config_hash),
+            )
+        };
+        let mut registration_digest = digest(&aik_tpm)?;
+
+        // The local digest only tells that the data did not change, so check
+        // that the registrar still holds the record, which could have been
+        // removed since the last registration
+        let mut skip_registration = false;
+        if (config.agent.skip_unchanged_registration || snapshot.is_some())
+            && agent_data_new.is_registered(&registration_digest)
+        {
+            match registrar_agent::do_verify_registration(
+                config.agent.registrar_ip.as_ref(),
+                config.agent.registrar_port,
+                &agent_uuid,
+                &ek_tpm,
+                &aik_tpm,
+                config.agent.contact_ip.as_ref(),
+                config.agent.contact_port,
+                registrar_tls.as_ref(),
+            )
+            .await
+            {
+                Ok(discrepancies) if discrepancies.is_empty() => {
+                    skip_registration = true;
+                }
+                Ok(_) => info!(
+                    "The registrar record for agent {} does not match, registering again",
+                    &agent_uuid
+                ),
+                Err(e) => info!(
+                    "Could not get the registrar record for agent {}, registering again: {}",
+                    &agent_uuid, e
+                ),
+            }
         }
-        let mackey = general_purpose::STANDARD.encode(key.value());
-        let auth_tag =
-            crypto::compute_hmac(mackey.as_bytes(), agent_uuid.as_bytes())?;
-        let auth_tag = hex::encode(&auth_tag);
 
-        registrar_agent::do_activate_agent(
-            config.agent.registrar_ip.as_ref(),
-            config.agent.registrar_port,
-            &agent_uuid,
-            &auth_tag,
-        )
-        .await?;
-        info!("SUCCESS: Agent {} activated", &agent_uuid);
+        if skip_registration {
+            info!(
+                "Agent {} already registered with the same data, skipping registration",
+                &agent_uuid
+            );
+            // Flush EK if we created it
+            if config.agent.ek_handle.is_empty() {
+                ctx.as_mut().flush_context(ek_result.key_handle.into())?;
+            }
+        } else {
+            let retry_policy = registrar_agent::RetryPolicy {
+                max_attempts: config.agent.registrar_retry_attempts,
+                interval: Duration::from_secs(
+                    config.agent.registrar_retry_interval,
+                ),
+                max_interval: Duration::from_secs(
+                    config.agent.registrar_retry_max_interval,
+                ),
+            };
+
+            let new_ak = register_with_recovery(
+                &mut ctx,
+                &config,
+                &retry_policy,
+                &metrics,
+                &agent_uuid,
+                &ek_result,
+                &ek_tpm,
+                &aik_tpm,
+                &mut ak_handle,
+                mtls_cert,
+                &config_hash,
+                registrar_tls.as_ref(),
+                tpm_hash_alg,
+                tpm_signing_alg,
+                hmac_hash_alg,
+            )
+            .await?;
+
+            // Store the regenerated AK
+            if let Some(new_ak) = new_ak {
+                agent_data_new = AgentData::create(
+                    tpm_hash_alg,
+                    tpm_signing_alg,
+                    &new_ak,
+                    ek_hash.as_bytes(),
+                )?;
+                if !config.agent.agent_data_path.is_empty() {
+                    _ = agent_data_new.store_or_tolerate(
+                        Path::new(&config.agent.agent_data_path),
+                        agent_data_format,
+                        config.agent.tolerate_readonly_state,
+                    )?;
+                }
+
+                aik_tpm =
+                    PublicBuffer::try_from(new_ak.public)?.marshall()?;
+                registration_digest = digest(&aik_tpm)?;
+            }
+
+            // Flush EK if we created it
+            if config.agent.ek_handle.is_empty() {
+                ctx.as_mut().flush_context(ek_result.key_handle.into())?;
+            }
+
+            if config.agent.verify_registration {
+                match registrar_agent::do_verify_registration(
+                    config.agent.registrar_ip.as_ref(),
+                    config.agent.registrar_port,
+                    &agent_uuid,
+                    &ek_tpm,
+                    &aik_tpm,
+                    config.agent.contact_ip.as_ref(),
+                    config.agent.contact_port,
+                    registrar_tls.as_ref(),
+                )
+                .await
+                {
+                    Ok(discrepancies) if discrepancies.is_empty() => {
+                        info!(
+                            "SUCCESS: Agent {} registration verified",
+                            &agent_uuid
+                        )
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!(
+                            "Could not verify the agent registration: {}",
+                            e
+                        )
+                    }
+                }
+            }
+
+            // Record the registration to skip it on the next start. It is
+            // kept in the state snapshot as well.
+            agent_data_new.set_registration_digest(Some(registration_digest));
+            if config.agent.skip_unchanged_registration
+                && !config.agent.agent_data_path.is_empty()
+            {
+                _ = agent_data_new.store_or_tolerate(
+                    Path::new(&config.agent.agent_data_path),
+                    agent_data_format,
+                    config.agent.tolerate_readonly_state,
+                )?;
+            }
+        }
     }
 
     let (mut payload_tx, mut payload_rx) =
@@ -579,33 +1238,237 @@ async fn main() -> Result<()> {
         allow_payload_revocation_actions,
         work_dir.clone(),
         mount.clone(),
+        agent_uuid.clone(),
+        config.agent.revocation_self_only,
+        metrics.clone(),
+        revocation::parse_allowed_actions(
+            &config.agent.revocation_allowed_actions,
+        ),
     ))
     .map_err(Error::from);
 
+    let firmware_version = if config.agent.include_firmware_version {
+        let version =
+            firmware::read_firmware_version(Path::new(firmware::DMI_ID_DIR))?;
+        info!("Including firmware version {} in identity quotes", version);
+
+        if let Some(pcr) = pcr_option(
+            "firmware_version_pcr",
+            &config.agent.firmware_version_pcr,
+        )? {
+            let digest =
+                openssl::hash::hash(tpm_hash_alg.into(), version.as_bytes())?;
+            extend_measurement(
+                &mut ctx,
+                &config.agent.pcr_measurement_log,
+                pcr_log::PcrMeasurement {
+                    pcr,
+                    hash_alg: tpm_hash_alg,
+                    digest: digest.to_vec(),
+                    event: "firmware_version".to_string(),
+                },
+            )?;
+        }
+
+        Some(version)
+    } else {
+        None
+    };
+
+    let quote_pcr_mask = match config.agent.quote_pcr_selection.as_ref() {
+        "" => None,
+        selection => {
+            let pcrs = config::parse_pcr_selection(selection)?;
+            info!("Including PCRs {:?} in the quotes", pcrs);
+            Some(pcrs.iter().fold(0u32, |mask, pcr| mask | (1 << pcr)))
+        }
+    };
+
     let quotedata = web::Data::new(QuoteData {
         tpmcontext: Mutex::new(ctx),
-        priv_key: nk_priv,
-        pub_key: nk_pub,
-        ak_handle,
+        agent_data: Mutex::new(agent_data_new),
+        transport_keys: RwLock::new((nk_pub, nk_priv)),
+        last_key_received: Mutex::new(None),
+        ak_handle: RwLock::new(ak_handle),
+        ek_handle: match config.agent.ek_handle.as_ref() {
+            "" => None,
+            _ => Some(ek_result.key_handle),
+        },
         keys_tx: keys_tx.clone(),
         payload_tx: payload_tx.clone(),
+        payload_status: payload_status.clone(),
+        payload_runs: payload_runs.clone(),
         revocation_tx: revocation_tx.clone(),
         hash_alg: tpm_hash_alg,
+        pcr_banks: tpm_pcr_banks,
         enc_alg: tpm_encryption_alg,
         sign_alg: tpm_signing_alg,
+        hmac_hash_alg,
         agent_uuid: agent_uuid.clone(),
+        agent_name: config.agent.agent_name.clone(),
         allow_payload_revocation_actions,
         secure_size,
         work_dir,
         ima_ml_file,
         measuredboot_ml_file,
-        ima_ml: Mutex::new(MeasurementList::new()),
+        ima_ml: Mutex::new(MeasurementList::with_format(ima_ml_format)),
+        ima_pcr_aggregate: config.agent.include_ima_pcr_aggregate,
         secure_mount: PathBuf::from(&mount),
+        system_facts_command: config.agent.system_facts_command.clone(),
+        ima_ml_requests: match config.agent.max_ima_requests {
+            0 => None,
+            n => Some(Semaphore::new(n as usize)),
+        },
+        metrics: metrics.clone(),
+        tpm_error_log: log_limit::RepeatedLog::new(Duration::from_secs(
+            TPM_ERROR_LOG_WINDOW,
+        )),
+        quote_jobs: config
+            .agent
+            .async_quotes
+            .then(quotes_handler::QuoteJobs::default),
+        firmware_version,
+        config_hash: Some(config_hash),
+        debug_quotes: config.agent.enable_debug_endpoints,
+        quote_pcr_mask,
+        nonce_length: config.agent.nonce_min_length as usize
+            ..=config.agent.nonce_max_length as usize,
+        #[cfg(feature = "testing")]
+        fixed_nonce: None,
     });
 
+    // Produce a single attestation and exit without serving requests
+    if let Some(output) = matches.get_one::<String>("attest-once") {
+        startup_watchdog.finish();
+        let attestation = quotes_handler::attest_once(&quotedata)?;
+        let attestation = serde_json::to_string_pretty(&attestation)?;
+        match output.as_str() {
+            "-" => println!("{attestation}"),
+            path => {
+                fs::write(path, attestation)?;
+                info!("Attestation written to {}", path);
+            }
+        }
+
+        revocation_tx
+            .send(revocation::RevocationMessage::Shutdown)
+            .await
+            .map_err(|_| {
+                Error::Sender(
+                    "Failed to send Shutdown message to revocation worker"
+                        .to_string(),
+                )
+            })?;
+        revocation_task.await??;
+        if let Err(e) = secure_mount::unmount(
+            Path::new(&config.agent.keylime_dir),
+            &secure_storage,
+        ) {
+            warn!("Failed to unmount the secure storage: {}", e);
+        }
+        return Ok(());
+    }
+
+    if config.agent.transport_key_rotation_interval > 0 {
+        info!(
+            "Rotating the transport key pair every {} seconds",
+            config.agent.transport_key_rotation_interval
+        );
+        _ = rt::spawn(keys_handler::rotate_transport_keys(
+            quotedata.clone(),
+            Duration::from_secs(config.agent.transport_key_rotation_interval),
+        ));
+    }
+
+    if !config.agent.statsd_endpoint.is_empty() {
+        let client =
+            metrics::StatsdClient::new(&config.agent.statsd_endpoint)?;
+        info!(
+            "Pushing metrics to StatsD server {}",
+            config.agent.statsd_endpoint
+        );
+        _ = rt::spawn(metrics::statsd_worker(
+            metrics.clone(),
+            client,
+            Duration::from_secs(STATSD_PUSH_INTERVAL),
+        ));
+    }
+
+    // Log the summaries of the repeated TPM errors once their window expires,
+    // even if the errors stopped occurring
+    let flush_data = quotedata.clone();
+    _ = rt::spawn(async move {
+        loop {
+            rt::time::sleep(Duration::from_secs(
+                TPM_ERROR_LOG_FLUSH_INTERVAL,
+            ))
+            .await;
+            flush_data.tpm_error_log.flush();
+        }
+    });
+
+    let enable_landing_page = config.agent.enable_landing_page;
+    let enable_debug_endpoints = config.agent.enable_debug_endpoints;
+    let enable_metrics = config.agent.enable_metrics;
+    let response_timeout = match config.agent.response_timeout {
+        0 => None,
+        t => Some(Duration::from_secs(t)),
+    };
+    let async_quotes = config.agent.async_quotes;
+    // Reprovisioning is only allowed for clients authenticated with mTLS
+    let reprovision_settings = match (
+        config.agent.enable_reprovision,
+        config.agent.enable_agent_mtls,
+    ) {
+        (false, _) => None,
+        (true, false) => {
+            warn!("The reprovision endpoint requires mTLS, disabling it");
+            None
+        }
+        (true, true) => {
+            Some(web::Data::new(reprovision_handler::RegistrationSettings {
+                registrar_ip: config.agent.registrar_ip.clone(),
+                registrar_port: config.agent.registrar_port,
+                contact_ip: config.agent.contact_ip.clone(),
+                contact_port: config.agent.contact_port,
+                ek_handle: config.agent.ek_handle.clone(),
+                agent_data_path: config.agent.agent_data_path.clone(),
+                agent_data_format,
+                tolerate_readonly_state: config.agent.tolerate_readonly_state,
+                mtls_cert: mtls_cert.cloned(),
+                registrar_tls: registrar_tls.clone(),
+                lock: tokio::sync::Mutex::new(()),
+            }))
+        }
+    };
+    // Pin the server worker threads to the configured cores
+    let server_cpu_affinity =
+        config::parse_cpu_list(&config.agent.server_cpu_affinity)?;
+    cpu_affinity::check_cores(&server_cpu_affinity)?;
+    // Store the agent state on a clean shutdown, to restore it on the next
+    // start
+    let snapshot_data = quotedata.clone();
+    let snapshot_path = match config.agent.state_snapshot_path.as_ref() {
+        "" => None,
+        path => Some(PathBuf::from(path)),
+    };
+
+    let enable_agent_mtls = config.agent.enable_agent_mtls;
     let actix_server =
         HttpServer::new(move || {
+            // The factory runs once in each worker thread
+            cpu_affinity::pin_worker(&server_cpu_affinity);
+            let mtls_optional_endpoints = mtls_optional_endpoints.clone();
             App::new()
+                .wrap_fn(move |req, srv| {
+                    if !enable_agent_mtls {
+                        return future::Either::Left(srv.call(req));
+                    }
+                    match client_cert::check(req, &mtls_optional_endpoints) {
+                        Ok(req) => future::Either::Left(srv.call(req)),
+                        Err(resp) => future::Either::Right(ok(resp)),
+                    }
+                })
                 .wrap(middleware::ErrorHandlers::new().handler(
                     http::StatusCode::NOT_FOUND,
                     errors_handler::wrap_404,
@@ -637,6 +1500,15 @@ async fn main() -> Result<()> {
                 )
                 .service(
                     web::scope(&format!("/{API_VERSION}"))
+                        .service(
+                            web::scope("/agent")
+                                .service(web::resource("/info").route(
+                                    web::get().to(agent_handler::info),
+                                ))
+                                .default_service(web::to(
+                                    errors_handler::agent_default,
+                                )),
+                        )
                         .service(
                             web::scope("/keys")
                                 .service(web::resource("/pubkey").route(
@@ -666,6 +1538,15 @@ async fn main() -> Result<()> {
                                     errors_handler::notifications_default,
                                 )),
                         )
+                        .service(
+                            web::scope("/payload")
+                                .service(web::resource("/status").route(
+                                    web::get().to(payload_handler::status),
+                                ))
+                                .default_service(web::to(
+                                    errors_handler::payload_default,
+                                )),
+                        )
                         .service(
                             web::scope("/quotes")
                                 .service(web::resource("/identity").route(
@@ -674,10 +1555,28 @@ async fn main() -> Result<()> {
                                 .service(web::resource("/integrity").route(
                                     web::get().to(quotes_handler::integrity),
                                 ))
+                                .configure(|cfg| {
+                                    quotes_handler::jobs_config(
+                                        cfg,
+                                        async_quotes,
+                                    )
+                                })
                                 .default_service(web::to(
                                     errors_handler::quotes_default,
                                 )),
                         )
+                        .configure(|cfg| {
+                            debug_handler::debug_config(
+                                cfg,
+                                enable_debug_endpoints,
+                            )
+                        })
+                        .configure(|cfg| {
+                            reprovision_handler::reprovision_config(
+                                cfg,
+                                reprovision_settings.clone(),
+                            )
+                        })
                         .default_service(web::to(
                             errors_handler::api_default,
                         )),
@@ -686,35 +1585,55 @@ async fn main() -> Result<()> {
                     web::resource("/version")
                         .route(web::get().to(version_handler::version)),
                 )
+                .configure(|cfg| {
+                    version_handler::landing_config(cfg, enable_landing_page)
+                })
+                .configure(|cfg| metrics::metrics_config(cfg, enable_metrics))
                 .service(
                     web::resource(r"/v{major:\d+}.{minor:\d+}{tail}*")
                         .to(errors_handler::version_not_supported),
                 )
                 .default_service(web::to(errors_handler::app_default))
         })
+        .on_connect(move |conn, ext| {
+            client_cert::on_connect(conn, ext);
+            if let Some(timeout) = response_timeout {
+                connection::set_response_timeout(conn, timeout);
+            }
+        })
         // Disable default signal handlers.  See:
         // https://github.com/actix/actix-web/issues/2739
         // for details.
         .disable_signals();
 
+    startup_watchdog.enter("server start");
     let server;
-    let ip = &config.agent.ip;
-    let port = config.agent.port;
+    let port = u16::try_from(config.agent.port).map_err(|_| {
+        Error::Configuration(format!(
+            "Invalid port {} set in 'port'",
+            config.agent.port
+        ))
+    })?;
+    let addr = SocketAddr::new(
+        config::parse_ip_address("ip", &config.agent.ip)?,
+        port,
+    );
     if config.agent.enable_agent_mtls && ssl_context.is_some() {
         server = actix_server
             .bind_openssl(
-                format!("{ip}:{port}"),
+                addr,
                 ssl_context.unwrap(), //#[allow_ci]
             )?
             .run();
-        info!("Listening on https://{ip}:{port}");
+        info!("Listening on https://{addr}");
     } else {
-        server = actix_server.bind(format!("{ip}:{port}"))?.run();
-        info!("Listening on http://{ip}:{port}");
+        server = actix_server.bind(addr)?.run();
+        info!("Listening on http://{addr}");
     };
 
     let server_handle = server.handle();
     let server_task = rt::spawn(server).map_err(Error::from);
+    startup_watchdog.finish();
 
     // Only run payload scripts if mTLS is enabled or 'enable_insecure_payload' option is set
     let run_payload = config.agent.enable_agent_mtls
@@ -727,12 +1646,30 @@ async fn main() -> Result<()> {
         revocation_tx.clone(),
         #[cfg(feature = "with-zmq")]
         zmq_tx.clone(),
+        metrics.clone(),
+        payload_runs,
+        payload_status,
     ))
     .map_err(Error::from);
 
+    let payload_pull_url = match config.agent.payload_pull_url.as_ref() {
+        "" => None,
+        s => Some(s.to_string()),
+    };
+
+    // The pulled payload is decrypted into the secure mount, so it cannot be
+    // larger than it
+    let payload_max_size =
+        secure_mount::available_space(&mount, &config.agent.secure_size)?;
+
     let key_task = rt::spawn(keys_handler::worker(
         run_payload,
         agent_uuid,
+        config.agent.max_keyset_size as usize,
+        config.agent.reject_key_replacement,
+        payload_pull_url,
+        Duration::from_secs(config.agent.payload_wait_timeout),
+        payload_max_size,
         keys_rx,
         payload_tx.clone(),
     ))
@@ -751,14 +1688,40 @@ async fn main() -> Result<()> {
             revocation_tx.clone(),
             zmq_ip,
             zmq_port,
+            Duration::from_secs(config.agent.revocation_notification_timeout),
         ))
         .map_err(Error::from)
     } else {
         rt::spawn(ok(())).map_err(Error::from)
     };
 
+    // Unmount the secure storage on shutdown, so that the decrypted payload
+    // and keys do not remain until reboot
+    let mount_work_dir = PathBuf::from(&config.agent.keylime_dir);
+    let mount_storage = secure_storage.clone();
+    let mut sigterm =
+        rt::signal::unix::signal(rt::signal::unix::SignalKind::terminate())?;
+
+    // Reload the mTLS identity on SIGHUP. The handler is only installed when
+    // mTLS is enabled, otherwise SIGHUP terminates the agent as before
+    if let Some(reloader) = tls_reloader {
+        let mut sighup =
+            rt::signal::unix::signal(rt::signal::unix::SignalKind::hangup())?;
+        _ = rt::spawn(async move {
+            while sighup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading the mTLS identity");
+                match reloader.reload() {
+                    Ok(()) => info!("Reloaded the mTLS identity, new connections use the renewed certificate"),
+                    Err(e) => error!("Failed to reload the mTLS identity, keeping the current one: {}", e),
+                }
+            }
+        });
+    }
+
     let shutdown_task = rt::spawn(async move {
-        rt::signal::ctrl_c().await.unwrap(); //#[allow_ci]
+        let ctrl_c = Box::pin(rt::signal::ctrl_c());
+        let terminate = Box::pin(sigterm.recv());
+        _ = future::select(ctrl_c, terminate).await;
 
         info!("Shutting down keylime agent server");
 
@@ -774,6 +1737,20 @@ async fn main() -> Result<()> {
 
         // Await tasks shutdown
         server_stop.await;
+
+        if let Some(path) = snapshot_path {
+            match state_snapshot::save(&snapshot_data, &path) {
+                Ok(()) => {
+                    info!("Stored the agent state in {}", path.display())
+                }
+                Err(e) => warn!("Failed to store the agent state: {}", e),
+            }
+        }
+
+        if let Err(e) = secure_mount::unmount(&mount_work_dir, &mount_storage)
+        {
+            warn!("Failed to unmount the secure storage: {}", e);
+        }
     })
     .map_err(Error::from);
 
@@ -800,6 +1777,370 @@ async fn main() -> Result<()> {
  * to handle error in result, it is good to keep this function separate from
  * the main function.
  */
+// Loads the AK and verifies it can actually be used for signing, since a
+// context can be loaded successfully and still be unusable. The AK is
+// flushed if the check fails.
+fn load_usable_ak(
+    ctx: &mut tpm::Context,
+    ek_handle: KeyHandle,
+    ak: &tpm::AKResult,
+    hash_alg: keylime::algorithms::HashAlgorithm,
+    sign_alg: keylime::algorithms::SignAlgorithm,
+) -> Result<KeyHandle> {
+    let ak_handle = ctx.load_ak(ek_handle, ak)?;
+    if let Err(e) = ctx.check_ak_signing(ak_handle, hash_alg, sign_alg) {
+        ctx.as_mut().flush_context(ak_handle.into())?;
+        return Err(e.into());
+    }
+    Ok(ak_handle)
+}
+
+// Uses the old AK if it can be loaded and used for signing, or generates a
+// new one otherwise. Returns the AK handle, the AK and whether the old AK was
+// reused.
+fn load_or_create_ak(
+    ctx: &mut tpm::Context,
+    ek_handle: KeyHandle,
+    old_ak: Option<&tpm::AKResult>,
+    hash_alg: keylime::algorithms::HashAlgorithm,
+    sign_alg: keylime::algorithms::SignAlgorithm,
+) -> Result<(KeyHandle, tpm::AKResult, bool)> {
+    if let Some(ak) = old_ak {
+        match load_usable_ak(ctx, ek_handle, ak, hash_alg, sign_alg) {
+            Ok(ak_handle) => return Ok((ak_handle, ak.clone(), true)),
+            Err(e) => {
+                warn!(
+                    "Loading old AK key failed, generating a new one: {}",
+                    e
+                )
+            }
+        }
+    }
+    let new_ak = ctx.create_ak(ek_handle, hash_alg, sign_alg)?;
+    let ak_handle = ctx.load_ak(ek_handle, &new_ak)?;
+    Ok((ak_handle, new_ak, false))
+}
+
+// Activates the credential received from the registrar and computes the auth
+// tag proving it, to be sent to activate the agent
+fn activation_auth_tag(
+    ctx: &mut tpm::Context,
+    keyblob: Vec<u8>,
+    ak_handle: KeyHandle,
+    ek_handle: KeyHandle,
+    agent_uuid: &str,
+    hmac_hash_alg: keylime::algorithms::HashAlgorithm,
+) -> Result<String> {
+    let key = ctx.activate_credential(keyblob, ak_handle, ek_handle)?;
+    let mackey = general_purpose::STANDARD.encode(key.value());
+    let auth_tag = crypto::compute_hmac(
+        mackey.as_bytes(),
+        agent_uuid.as_bytes(),
+        hmac_hash_alg,
+    )?;
+    Ok(hex::encode(auth_tag))
+}
+
+// Registers the agent with the given AK, activates the credential received
+// from the registrar and sends the resulting auth tag to activate the agent
+#[allow(clippy::too_many_arguments)]
+async fn register_and_activate(
+    ctx: &mut tpm::Context,
+    config: &config::KeylimeConfig,
+    retry_policy: &registrar_agent::RetryPolicy,
+    metrics: &metrics::Metrics,
+    agent_uuid: &str,
+    ek_result: &tpm::EKResult,
+    ek_tpm: &[u8],
+    aik_tpm: &[u8],
+    ak_handle: KeyHandle,
+    mtls_cert: Option<&X509>,
+    config_hash: &str,
+    registrar_tls: Option<&registrar_agent::RegistrarTls>,
+    hmac_hash_alg: keylime::algorithms::HashAlgorithm,
+) -> Result<()> {
+    // Request keyblob material
+    let keyblob =
+        registrar_agent::with_retries(retry_policy, "Registration", || {
+            metrics.registration_attempt();
+            registrar_agent::do_register_agent(
+                config.agent.registrar_ip.as_ref(),
+                config.agent.registrar_port,
+                agent_uuid,
+                &config.agent.agent_name,
+                ek_tpm,
+                ek_result.ek_cert.clone(),
+                aik_tpm,
+                mtls_cert,
+                config.agent.contact_ip.as_ref(),
+                config.agent.contact_port,
+                Some(config_hash),
+                registrar_tls,
+            )
+        })
+        .await?;
+
+    info!("SUCCESS: Agent {} registered", agent_uuid);
+
+    let auth_tag = activation_auth_tag(
+        ctx,
+        keyblob,
+        ak_handle,
+        ek_result.key_handle,
+        agent_uuid,
+        hmac_hash_alg,
+    )?;
+
+    registrar_agent::with_retries(retry_policy, "Activation", || {
+        registrar_agent::do_activate_agent(
+            config.agent.registrar_ip.as_ref(),
+            config.agent.registrar_port,
+            agent_uuid,
+            &auth_tag,
+            registrar_tls,
+        )
+    })
+    .await?;
+    info!("SUCCESS: Agent {} activated", agent_uuid);
+
+    Ok(())
+}
+
+// Registers and activates the agent. If 'auto_recover_activation' is set
+// and the registrar rejects the activation auth tag, the AK is regenerated
+// and the agent registers again, once. Returns the regenerated AK, if any,
+// in which case `ak_handle` is replaced with its handle.
+#[allow(clippy::too_many_arguments)]
+async fn register_with_recovery(
+    ctx: &mut tpm::Context,
+    config: &config::KeylimeConfig,
+    retry_policy: &registrar_agent::RetryPolicy,
+    metrics: &metrics::Metrics,
+    agent_uuid: &str,
+    ek_result: &tpm::EKResult,
+    ek_tpm: &[u8],
+    aik_tpm: &[u8],
+    ak_handle: &mut KeyHandle,
+    mtls_cert: Option<&X509>,
+    config_hash: &str,
+    registrar_tls: Option<&registrar_agent::RegistrarTls>,
+    hash_alg: keylime::algorithms::HashAlgorithm,
+    sign_alg: keylime::algorithms::SignAlgorithm,
+    hmac_hash_alg: keylime::algorithms::HashAlgorithm,
+) -> Result<Option<tpm::AKResult>> {
+    let mut new_ak: Option<tpm::AKResult> = None;
+    loop {
+        let aik_tpm = match &new_ak {
+            Some(ak) => {
+                PublicBuffer::try_from(ak.public.clone())?.marshall()?
+            }
+            None => aik_tpm.to_vec(),
+        };
+        match register_and_activate(
+            ctx,
+            config,
+            retry_policy,
+            metrics,
+            agent_uuid,
+            ek_result,
+            ek_tpm,
+            &aik_tpm,
+            *ak_handle,
+            mtls_cert,
+            config_hash,
+            registrar_tls,
+            hmac_hash_alg,
+        )
+        .await
+        {
+            Err(e)
+                if config.agent.auto_recover_activation
+                    && new_ak.is_none()
+                    && registrar_agent::is_auth_tag_rejected(&e) =>
+            {
+                warn!(
+                    "The registrar rejected the activation of agent {}: {}. Regenerating the AK and registering again",
+                    agent_uuid, e
+                );
+                let (handle, ak) = regenerate_ak(
+                    ctx,
+                    ek_result.key_handle,
+                    *ak_handle,
+                    hash_alg,
+                    sign_alg,
+                )?;
+                *ak_handle = handle;
+                new_ak = Some(ak);
+            }
+            result => return result.map(|_| new_ak),
+        }
+    }
+}
+
+// Creates and loads a new AK, verifying it is bound to the EK. The new AK is
+// flushed if the check fails.
+fn create_bound_ak(
+    ctx: &mut tpm::Context,
+    ek_handle: KeyHandle,
+    hash_alg: keylime::algorithms::HashAlgorithm,
+    sign_alg: keylime::algorithms::SignAlgorithm,
+) -> Result<(KeyHandle, tpm::AKResult)> {
+    let ak = ctx.create_ak(ek_handle, hash_alg, sign_alg)?;
+    let ak_handle = ctx.load_ak(ek_handle, &ak)?;
+    if let Err(e) = ctx.verify_ak_binding(ak_handle, ek_handle) {
+        ctx.as_mut().flush_context(ak_handle.into())?;
+        return Err(e.into());
+    }
+    Ok((ak_handle, ak))
+}
+
+// Replaces the AK with a newly created one, verifying the new one is bound
+// to the EK before flushing the old AK. The old AK is kept if the new one
+// cannot be used, and a failure to flush it, e.g. when the handle is not
+// transient, is only reported.
+fn regenerate_ak(
+    ctx: &mut tpm::Context,
+    ek_handle: KeyHandle,
+    old_ak_handle: KeyHandle,
+    hash_alg: keylime::algorithms::HashAlgorithm,
+    sign_alg: keylime::algorithms::SignAlgorithm,
+) -> Result<(KeyHandle, tpm::AKResult)> {
+    let (ak_handle, ak) =
+        create_bound_ak(ctx, ek_handle, hash_alg, sign_alg)?;
+    if let Err(e) = ctx.as_mut().flush_context(old_ak_handle.into()) {
+        warn!("Failed to flush the previous AK: {}", e);
+    }
+    Ok((ak_handle, ak))
+}
+
+// Checks whether the agent can register without an EK certificate, which is
+// the case for TPMs not provisioned by the manufacturer (e.g. some vTPMs).
+// The agent is then registered with the EK public key only.
+fn check_ek_cert(ek_cert: Option<&[u8]>, required: bool) -> Result<()> {
+    match ek_cert {
+        Some(_) => Ok(()),
+        None if required => Err(Error::Configuration(
+            "No EK certificate found in the TPM, but 'require_ek_cert' is set as 'true'".to_string(),
+        )),
+        None => {
+            warn!("No EK certificate found in the TPM. The agent will be registered with the EK public key only, and the EK cannot be validated against a certificate");
+            Ok(())
+        }
+    }
+}
+
+// Environment variable used by the TSS libraries to set the log level
+static TSS_LOG_ENV: &str = "TSS2_LOG";
+
+// Environment variable holding the TCTI used to connect to the TPM
+static TCTI_ENV: &str = "TCTI";
+
+// Maps the 'tss_log_level' option to the value of the TSS2_LOG environment
+// variable, applying the level to all the TSS modules. An empty level keeps
+// the environment untouched.
+fn tss_log_setting(level: &str) -> Result<Option<String>> {
+    match level {
+        "" => Ok(None),
+        "none" | "error" | "warning" | "info" | "debug" | "trace" => {
+            Ok(Some(format!("all+{level}")))
+        }
+        _ => Err(Error::Configuration(format!(
+            "Invalid TSS log level set in 'tss_log_level' option: {level}"
+        ))),
+    }
+}
+
+// PCRs extended by the firmware and the boot loader (0-7), by IMA (10) and
+// by the agent to bind the quotes to the nonce (16), which must not be
+// extended with the agent measurements
+const RESERVED_PCRS: [u32; 10] = [0, 1, 2, 3, 4, 5, 6, 7, 10, 16];
+
+// Parses an option holding the index of a PCR to extend. An empty value
+// means no PCR is extended.
+fn pcr_option(option: &str, value: &str) -> Result<Option<u32>> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    match value.parse::<u32>() {
+        Ok(pcr) if RESERVED_PCRS.contains(&pcr) => {
+            Err(Error::Configuration(format!(
+                "PCR {pcr} set in '{option}' option is reserved for the boot and IMA measurements or the quotes, use one of PCRs 8-9, 11-15 or 17-23"
+            )))
+        }
+        Ok(pcr) if pcr <= 23 => Ok(Some(pcr)),
+        _ => Err(Error::Configuration(format!(
+            "Invalid PCR set in '{option}' option: {value}"
+        ))),
+    }
+}
+
+// Loads the configuration, replacing the keywords and checking the values
+// of the options, then validates the merged options against the schema, if
+// given. Neither the TPM nor the network are accessed.
+fn validate_config(schema_path: Option<&Path>) -> Result<()> {
+    _ = config::KeylimeConfig::new_offline()?;
+
+    if let Some(schema_path) = schema_path {
+        let schema = config_schema::ConfigSchema::load(schema_path)?;
+        let violations =
+            schema.validate(&config::config_get_merged_options()?);
+        for violation in &violations {
+            eprintln!("{violation}");
+        }
+        if !violations.is_empty() {
+            return Err(Error::Configuration(format!(
+                "The configuration has {} violation(s) of the schema {}",
+                violations.len(),
+                schema_path.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Computes the `hash_alg` digest of the executable at `path`
+fn agent_binary_digest(
+    hash_alg: keylime::algorithms::HashAlgorithm,
+    path: &Path,
+) -> Result<Vec<u8>> {
+    let binary = fs::read(path).map_err(|e| {
+        Error::Other(format!(
+            "Failed to read agent binary {}: {e}",
+            path.display()
+        ))
+    })?;
+    Ok(openssl::hash::hash(hash_alg.into(), &binary)?.to_vec())
+}
+
+// Extends the PCR with the measurement and records it in the log set in
+// 'pcr_measurement_log', unless it was already extended since boot, which
+// happens when the agent is restarted
+fn extend_measurement(
+    ctx: &mut tpm::Context,
+    log_path: &str,
+    measurement: pcr_log::PcrMeasurement,
+) -> Result<()> {
+    let boot_id = pcr_log::read_boot_id(Path::new(pcr_log::BOOT_ID_PATH))?;
+    let digest = hex::encode(&measurement.digest);
+    if pcr_log::extend_logged(
+        ctx,
+        Path::new(log_path),
+        &boot_id,
+        &measurement,
+    )? {
+        info!(
+            "Extended {} digest {} into PCR {}",
+            measurement.event, digest, measurement.pcr
+        );
+    } else {
+        info!(
+            "The {} digest {} was already extended into PCR {} since boot",
+            measurement.event, digest, measurement.pcr
+        );
+    }
+    Ok(())
+}
+
 fn read_in_file(path: String) -> std::io::Result<String> {
     let file = fs::File::open(path)?;
     let mut buf_reader = BufReader::new(file);
@@ -841,6 +2182,12 @@ mod testing {
                 tpm_signing_alg,
             )?;
             let ak_handle = ctx.load_ak(ek_result.key_handle, &ak_result)?;
+            let agent_data = AgentData::create(
+                tpm_hash_alg,
+                tpm_signing_alg,
+                &ak_result,
+                hash_ek_pubkey(ek_result.public.clone())?.as_bytes(),
+            )?;
             let ak_tpm2b_pub =
                 PublicBuffer::try_from(ak_result.public)?.marshall()?;
 
@@ -898,16 +2245,25 @@ mod testing {
 
             Ok(QuoteData {
                 tpmcontext: Mutex::new(ctx),
-                priv_key: nk_priv,
-                pub_key: nk_pub,
-                ak_handle,
+                agent_data: Mutex::new(agent_data),
+                transport_keys: RwLock::new((nk_pub, nk_priv)),
+                last_key_received: Mutex::new(None),
+                ak_handle: RwLock::new(ak_handle),
+                ek_handle: Some(ek_result.key_handle),
                 keys_tx,
                 payload_tx,
+                payload_status: Arc::new(Mutex::new(
+                    payloads::PayloadStatus::default(),
+                )),
+                payload_runs: Arc::new(payloads::PayloadRuns::new(1)),
                 revocation_tx,
                 hash_alg: keylime::algorithms::HashAlgorithm::Sha256,
+                pcr_banks: vec![keylime::algorithms::HashAlgorithm::Sha256],
                 enc_alg: keylime::algorithms::EncryptionAlgorithm::Rsa,
+                hmac_hash_alg: keylime::algorithms::HashAlgorithm::Sha384,
                 sign_alg: keylime::algorithms::SignAlgorithm::RsaSsa,
                 agent_uuid: test_config.agent.uuid,
+                agent_name: test_config.agent.agent_name,
                 allow_payload_revocation_actions: test_config
                     .agent
                     .allow_payload_revocation_actions,
@@ -916,7 +2272,21 @@ mod testing {
                 ima_ml_file,
                 measuredboot_ml_file,
                 ima_ml: Mutex::new(MeasurementList::new()),
+                ima_pcr_aggregate: false,
                 secure_mount,
+                system_facts_command: test_config.agent.system_facts_command,
+                ima_ml_requests: None,
+                metrics: Arc::new(metrics::Metrics::default()),
+                tpm_error_log: log_limit::RepeatedLog::new(
+                    Duration::from_secs(TPM_ERROR_LOG_WINDOW),
+                ),
+                quote_jobs: None,
+                firmware_version: None,
+                config_hash: None,
+                debug_quotes: false,
+                quote_pcr_mask: None,
+                nonce_length: 1..=tpm::MAX_NONCE_SIZE,
+                fixed_nonce: None,
             })
         }
     }
@@ -932,6 +2302,355 @@ mod tests {
         info!("Initialized logger for testing suite.");
     }
 
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_load_usable_ak() {
+        use keylime::algorithms::{
+            EncryptionAlgorithm, HashAlgorithm, SignAlgorithm,
+        };
+
+        let mut ctx = tpm::Context::new().unwrap(); //#[allow_ci]
+        let ek_result =
+            ctx.create_ek(EncryptionAlgorithm::Rsa, None).unwrap(); //#[allow_ci]
+        let ak = ctx
+            .create_ak(
+                ek_result.key_handle,
+                HashAlgorithm::Sha256,
+                SignAlgorithm::RsaSsa,
+            )
+            .unwrap(); //#[allow_ci]
+
+        let ak_handle = load_usable_ak(
+            &mut ctx,
+            ek_result.key_handle,
+            &ak,
+            HashAlgorithm::Sha256,
+            SignAlgorithm::RsaSsa,
+        );
+        assert!(ak_handle.is_ok());
+        ctx.as_mut()
+            .flush_context(ak_handle.unwrap().into()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+
+        // The AK loads, but cannot sign with a scheme other than the one it
+        // was created with
+        assert!(ctx.load_ak(ek_result.key_handle, &ak).is_ok());
+        assert!(load_usable_ak(
+            &mut ctx,
+            ek_result.key_handle,
+            &ak,
+            HashAlgorithm::Sha256,
+            SignAlgorithm::RsaPss,
+        )
+        .is_err());
+
+        // The old AK is reused when it can sign
+        let (ak_handle, reused_ak, reused) = load_or_create_ak(
+            &mut ctx,
+            ek_result.key_handle,
+            Some(&ak),
+            HashAlgorithm::Sha256,
+            SignAlgorithm::RsaSsa,
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(reused);
+        assert_eq!(reused_ak.public, ak.public);
+        ctx.as_mut().flush_context(ak_handle.into()).unwrap(); //#[allow_ci]
+
+        // An AK that loads but cannot sign is replaced by a new one
+        let (ak_handle, new_ak, reused) = load_or_create_ak(
+            &mut ctx,
+            ek_result.key_handle,
+            Some(&ak),
+            HashAlgorithm::Sha256,
+            SignAlgorithm::RsaPss,
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(!reused);
+        assert_ne!(new_ak.public, ak.public);
+        assert!(ctx
+            .check_ak_signing(
+                ak_handle,
+                HashAlgorithm::Sha256,
+                SignAlgorithm::RsaPss
+            )
+            .is_ok());
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_regenerate_ak() {
+        use keylime::algorithms::{
+            EncryptionAlgorithm, HashAlgorithm, SignAlgorithm,
+        };
+
+        let mut ctx = tpm::Context::new().unwrap(); //#[allow_ci]
+        let ek_result =
+            ctx.create_ek(EncryptionAlgorithm::Rsa, None).unwrap(); //#[allow_ci]
+        let ak = ctx
+            .create_ak(
+                ek_result.key_handle,
+                HashAlgorithm::Sha256,
+                SignAlgorithm::RsaSsa,
+            )
+            .unwrap(); //#[allow_ci]
+        let ak_handle = ctx.load_ak(ek_result.key_handle, &ak).unwrap(); //#[allow_ci]
+
+        let (new_handle, new_ak) = regenerate_ak(
+            &mut ctx,
+            ek_result.key_handle,
+            ak_handle,
+            HashAlgorithm::Sha256,
+            SignAlgorithm::RsaSsa,
+        )
+        .unwrap(); //#[allow_ci]
+        assert_ne!(new_ak.public, ak.public);
+        assert!(ctx
+            .check_ak_signing(
+                new_handle,
+                HashAlgorithm::Sha256,
+                SignAlgorithm::RsaSsa
+            )
+            .is_ok());
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_register_with_recovery() {
+        use keylime::algorithms::{
+            EncryptionAlgorithm, HashAlgorithm, SignAlgorithm,
+        };
+        use serde_json::json;
+        use tss_esapi::{
+            structures::{Digest, Public},
+            traits::UnMarshall,
+        };
+        use wiremock::{
+            matchers::{body_partial_json, method},
+            Mock, MockServer, Request, ResponseTemplate,
+        };
+
+        // The magic number of the keyblob sent by the registrar
+        const TSS_MAGIC: u32 = 3135029470;
+        let challenge = b"0123456789abcdef".to_vec();
+
+        let mut ctx = tpm::Context::new().unwrap(); //#[allow_ci]
+        let ek_result =
+            ctx.create_ek(EncryptionAlgorithm::Rsa, None).unwrap(); //#[allow_ci]
+        let ak = ctx
+            .create_ak(
+                ek_result.key_handle,
+                HashAlgorithm::Sha256,
+                SignAlgorithm::RsaSsa,
+            )
+            .unwrap(); //#[allow_ci]
+        let mut ak_handle = ctx.load_ak(ek_result.key_handle, &ak).unwrap(); //#[allow_ci]
+        let ek_tpm = PublicBuffer::try_from(ek_result.public.clone())
+            .unwrap() //#[allow_ci]
+            .marshall()
+            .unwrap(); //#[allow_ci]
+        let aik_tpm = PublicBuffer::try_from(ak.public.clone())
+            .unwrap() //#[allow_ci]
+            .marshall()
+            .unwrap(); //#[allow_ci]
+
+        // The registrar makes the credential for the AK sent on each
+        // registration, recording the AKs it received
+        let registered = Arc::new(Mutex::new(Vec::new()));
+        let received = registered.clone();
+        let maker = Mutex::new(tpm::Context::new().unwrap()); //#[allow_ci]
+        let ek_public = ek_result.public.clone();
+        let secret = challenge.clone();
+        let make_blob = move |request: &Request| {
+            let body: serde_json::Value =
+                serde_json::from_slice(&request.body).unwrap(); //#[allow_ci]
+            let aik_tpm = general_purpose::STANDARD
+                .decode(body["aik_tpm"].as_str().unwrap()) //#[allow_ci]
+                .unwrap(); //#[allow_ci]
+            received.lock().unwrap().push(aik_tpm.clone()); //#[allow_ci]
+
+            let mut maker = maker.lock().unwrap(); //#[allow_ci]
+            let ctx = maker.as_mut();
+            let ak_public = Public::try_from(
+                PublicBuffer::unmarshall(&aik_tpm).unwrap(), //#[allow_ci]
+            )
+            .unwrap(); //#[allow_ci]
+            let ak = ctx
+                .load_external_public(ak_public, Hierarchy::Null)
+                .unwrap(); //#[allow_ci]
+            let (_, ak_name, _) = ctx.read_public(ak).unwrap(); //#[allow_ci]
+            ctx.flush_context(ak.into()).unwrap(); //#[allow_ci]
+            let ek = ctx
+                .load_external_public(ek_public.clone(), Hierarchy::Null)
+                .unwrap(); //#[allow_ci]
+            let (credential, encrypted) = ctx
+                .make_credential(
+                    ek,
+                    Digest::try_from(secret.clone()).unwrap(), //#[allow_ci]
+                    ak_name,
+                )
+                .unwrap(); //#[allow_ci]
+            ctx.flush_context(ek.into()).unwrap(); //#[allow_ci]
+
+            let credential = credential.value();
+            let encrypted = encrypted.value();
+            let blob = [
+                &TSS_MAGIC.to_be_bytes()[..],
+                &1u32.to_be_bytes(),
+                &(credential.len() as u16).to_be_bytes(),
+                credential,
+                &(encrypted.len() as u16).to_be_bytes(),
+                encrypted,
+            ]
+            .concat();
+            ResponseTemplate::new(200).set_body_json(json!({
+                "code": 200,
+                "status": "Success",
+                "results": {"blob": general_purpose::STANDARD.encode(blob)}
+            }))
+        };
+
+        // The first activation is rejected, and only the auth tag computed
+        // from the credential made for the regenerated AK is accepted
+        let mackey = general_purpose::STANDARD.encode(&challenge);
+        let auth_tag = hex::encode(
+            crypto::compute_hmac(
+                mackey.as_bytes(),
+                b"uuid",
+                HashAlgorithm::Sha384,
+            )
+            .unwrap(), //#[allow_ci]
+        );
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(make_blob)
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("PUT"))
+            .respond_with(ResponseTemplate::new(400))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(body_partial_json(json!({ "auth_tag": auth_tag })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "code": 200,
+                "status": "Success",
+                "results": {}
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let address = mock_server.address();
+        let mut config = config::KeylimeConfig::default();
+        config.agent.registrar_ip = address.ip().to_string();
+        config.agent.registrar_port = address.port().into();
+        config.agent.auto_recover_activation = true;
+        let retry_policy = registrar_agent::RetryPolicy {
+            max_attempts: 1,
+            interval: Duration::from_millis(10),
+            max_interval: Duration::from_millis(10),
+        };
+
+        let new_ak = register_with_recovery(
+            &mut ctx,
+            &config,
+            &retry_policy,
+            &metrics::Metrics::default(),
+            "uuid",
+            &ek_result,
+            &ek_tpm,
+            &aik_tpm,
+            &mut ak_handle,
+            None,
+            "",
+            None,
+            HashAlgorithm::Sha256,
+            SignAlgorithm::RsaSsa,
+            HashAlgorithm::Sha384,
+        )
+        .await
+        .unwrap() //#[allow_ci]
+        .unwrap(); //#[allow_ci]
+
+        // The agent registered again with the regenerated AK, which is
+        // loaded in place of the old one
+        assert_ne!(new_ak.public, ak.public);
+        let new_aik_tpm = PublicBuffer::try_from(new_ak.public)
+            .unwrap() //#[allow_ci]
+            .marshall()
+            .unwrap(); //#[allow_ci]
+        assert_eq!(*registered.lock().unwrap(), vec![aik_tpm, new_aik_tpm]); //#[allow_ci]
+        assert!(ctx
+            .check_ak_signing(
+                ak_handle,
+                HashAlgorithm::Sha256,
+                SignAlgorithm::RsaSsa
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_agent_binary_digest() {
+        use keylime::algorithms::HashAlgorithm;
+
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("keylime_agent");
+        let binary = b"known agent binary contents";
+        fs::write(&path, binary).unwrap(); //#[allow_ci]
+
+        let digest =
+            agent_binary_digest(HashAlgorithm::Sha256, &path).unwrap(); //#[allow_ci]
+        assert_eq!(digest, openssl::sha::sha256(binary).to_vec());
+
+        // A missing binary is reported
+        assert!(agent_binary_digest(
+            HashAlgorithm::Sha256,
+            &dir.path().join("missing")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_pcr_option() {
+        assert_eq!(pcr_option("agent_binary_pcr", "").unwrap(), None); //#[allow_ci]
+        assert_eq!(pcr_option("agent_binary_pcr", "23").unwrap(), Some(23)); //#[allow_ci]
+        assert_eq!(pcr_option("agent_binary_pcr", "8").unwrap(), Some(8)); //#[allow_ci]
+        assert!(pcr_option("agent_binary_pcr", "24").is_err());
+        assert!(pcr_option("agent_binary_pcr", "pcr").is_err());
+
+        // The PCRs used by the boot and IMA measurements and the quotes are
+        // rejected
+        for pcr in RESERVED_PCRS {
+            assert!(pcr_option("agent_binary_pcr", &pcr.to_string()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_check_ek_cert() {
+        assert!(check_ek_cert(None, false).is_ok());
+        assert!(check_ek_cert(None, true).is_err());
+        assert!(check_ek_cert(Some(b"cert"), true).is_ok());
+        assert!(check_ek_cert(Some(b"cert"), false).is_ok());
+    }
+
+    #[test]
+    fn test_tss_log_setting() {
+        assert_eq!(tss_log_setting("").unwrap(), None); //#[allow_ci]
+        assert_eq!(
+            tss_log_setting("debug").unwrap(), //#[allow_ci]
+            Some("all+debug".to_string())
+        );
+        assert_eq!(
+            tss_log_setting("none").unwrap(), //#[allow_ci]
+            Some("all+none".to_string())
+        );
+        assert!(tss_log_setting("verbose").is_err());
+    }
+
     #[test]
     fn test_read_in_file() {
         assert_eq!(
@@ -0,0 +1,362 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+use crate::{Error, QuoteData, Result};
+use actix_web::{http, web, HttpResponse, Responder};
+use log::*;
+use std::{
+    collections::HashMap,
+    net::{ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::time::sleep;
+
+// Prefix added to the name of the metrics pushed to StatsD
+pub static STATSD_PREFIX: &str = "keylime_agent";
+
+// Prefix added to the name of the metrics exposed to Prometheus
+pub static PROMETHEUS_PREFIX: &str = "keylime_agent";
+
+// Content type of the Prometheus text exposition format
+static PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum QuoteKind {
+    Identity,
+    Integrity,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Metric {
+    pub name: &'static str,
+    pub kind: MetricKind,
+    pub value: u64,
+}
+
+/// The metrics collected by the agent
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    quotes_served: AtomicU64,
+    identity_quotes_served: AtomicU64,
+    integrity_quotes_served: AtomicU64,
+    registration_attempts: AtomicU64,
+    ukeys_received: AtomicU64,
+    vkeys_received: AtomicU64,
+    payloads_decrypted: AtomicU64,
+    revocations_processed: AtomicU64,
+    tpm_quote_latency_ms: AtomicU64,
+}
+
+impl Metrics {
+    /// Records a quote served, and the time the TPM took to generate it
+    pub(crate) fn quote_served(
+        &self,
+        kind: QuoteKind,
+        tpm_latency: Duration,
+    ) {
+        _ = self.quotes_served.fetch_add(1, Ordering::Relaxed);
+        let counter = match kind {
+            QuoteKind::Identity => &self.identity_quotes_served,
+            QuoteKind::Integrity => &self.integrity_quotes_served,
+        };
+        _ = counter.fetch_add(1, Ordering::Relaxed);
+        self.tpm_quote_latency_ms
+            .store(tpm_latency.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn registration_attempt(&self) {
+        _ = self.registration_attempts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn ukey_received(&self) {
+        _ = self.ukeys_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn vkey_received(&self) {
+        _ = self.vkeys_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn payload_decrypted(&self) {
+        _ = self.payloads_decrypted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn revocation_processed(&self) {
+        _ = self.revocations_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the current value of the metrics. This is the single
+    /// definition of the metrics exported by the agent, regardless of the
+    /// output format.
+    pub(crate) fn snapshot(&self) -> Vec<Metric> {
+        vec![
+            Metric {
+                name: "quotes_served",
+                kind: MetricKind::Counter,
+                value: self.quotes_served.load(Ordering::Relaxed),
+            },
+            Metric {
+                name: "identity_quotes_served",
+                kind: MetricKind::Counter,
+                value: self.identity_quotes_served.load(Ordering::Relaxed),
+            },
+            Metric {
+                name: "integrity_quotes_served",
+                kind: MetricKind::Counter,
+                value: self.integrity_quotes_served.load(Ordering::Relaxed),
+            },
+            Metric {
+                name: "registration_attempts",
+                kind: MetricKind::Counter,
+                value: self.registration_attempts.load(Ordering::Relaxed),
+            },
+            Metric {
+                name: "ukeys_received",
+                kind: MetricKind::Counter,
+                value: self.ukeys_received.load(Ordering::Relaxed),
+            },
+            Metric {
+                name: "vkeys_received",
+                kind: MetricKind::Counter,
+                value: self.vkeys_received.load(Ordering::Relaxed),
+            },
+            Metric {
+                name: "payloads_decrypted",
+                kind: MetricKind::Counter,
+                value: self.payloads_decrypted.load(Ordering::Relaxed),
+            },
+            Metric {
+                name: "revocations_processed",
+                kind: MetricKind::Counter,
+                value: self.revocations_processed.load(Ordering::Relaxed),
+            },
+            Metric {
+                name: "tpm_quote_latency_ms",
+                kind: MetricKind::Gauge,
+                value: self.tpm_quote_latency_ms.load(Ordering::Relaxed),
+            },
+        ]
+    }
+}
+
+/// Formats the metrics in the StatsD line protocol, one metric per line.
+/// The values of the counters are expected to be the increment since the
+/// last push.
+pub(crate) fn format_statsd(prefix: &str, metrics: &[Metric]) -> String {
+    metrics
+        .iter()
+        .map(|m| {
+            let kind = match m.kind {
+                MetricKind::Counter => "c",
+                MetricKind::Gauge => "g",
+            };
+            format!("{}.{}:{}|{}\n", prefix, m.name, m.value, kind)
+        })
+        .collect()
+}
+
+/// Formats the metrics in the Prometheus text exposition format. Following
+/// the Prometheus naming conventions, the name of the counters is suffixed
+/// with "_total".
+pub(crate) fn format_prometheus(prefix: &str, metrics: &[Metric]) -> String {
+    metrics
+        .iter()
+        .map(|m| {
+            let (kind, suffix) = match m.kind {
+                MetricKind::Counter => ("counter", "_total"),
+                MetricKind::Gauge => ("gauge", ""),
+            };
+            format!(
+                "# TYPE {prefix}_{name}{suffix} {kind}\n{prefix}_{name}{suffix} {value}\n",
+                name = m.name,
+                value = m.value
+            )
+        })
+        .collect()
+}
+
+// Exposes the metrics to be scraped by Prometheus
+async fn metrics(data: web::Data<QuoteData>) -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header((http::header::CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE))
+        .body(format_prometheus(
+            PROMETHEUS_PREFIX,
+            &data.metrics.snapshot(),
+        ))
+}
+
+// Registers the metrics endpoint, if enabled
+pub(crate) fn metrics_config(cfg: &mut web::ServiceConfig, enabled: bool) {
+    if enabled {
+        _ = cfg
+            .service(web::resource("/metrics").route(web::get().to(metrics)));
+    }
+}
+
+/// Client pushing the metrics to a StatsD server over UDP
+#[derive(Debug)]
+pub(crate) struct StatsdClient {
+    socket: UdpSocket,
+    // Counter values sent on the last push, to send only the increments
+    last: HashMap<&'static str, u64>,
+}
+
+impl StatsdClient {
+    pub(crate) fn new(endpoint: &str) -> Result<Self> {
+        let addr = endpoint.to_socket_addrs()?.next().ok_or_else(|| {
+            Error::Configuration(format!(
+                "Could not resolve StatsD endpoint {endpoint}"
+            ))
+        })?;
+        let bind_addr = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.connect(addr)?;
+
+        Ok(StatsdClient {
+            socket,
+            last: HashMap::new(),
+        })
+    }
+
+    pub(crate) fn push(&mut self, metrics: &Metrics) -> Result<()> {
+        let snapshot: Vec<Metric> = metrics
+            .snapshot()
+            .into_iter()
+            .map(|m| match m.kind {
+                MetricKind::Counter => {
+                    let last = self.last.insert(m.name, m.value).unwrap_or(0);
+                    Metric {
+                        value: m.value.saturating_sub(last),
+                        ..m
+                    }
+                }
+                MetricKind::Gauge => m,
+            })
+            .collect();
+
+        let _ = self
+            .socket
+            .send(format_statsd(STATSD_PREFIX, &snapshot).as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Pushes the metrics to the StatsD server periodically
+pub(crate) async fn statsd_worker(
+    metrics: Arc<Metrics>,
+    mut client: StatsdClient,
+    interval: Duration,
+) {
+    loop {
+        sleep(interval).await;
+
+        if let Err(e) = client.push(&metrics) {
+            warn!("Failed to push metrics to StatsD: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_statsd() {
+        let metrics = vec![
+            Metric {
+                name: "quotes_served",
+                kind: MetricKind::Counter,
+                value: 3,
+            },
+            Metric {
+                name: "tpm_quote_latency_ms",
+                kind: MetricKind::Gauge,
+                value: 42,
+            },
+        ];
+
+        assert_eq!(
+            format_statsd("keylime_agent", &metrics),
+            "keylime_agent.quotes_served:3|c\nkeylime_agent.tpm_quote_latency_ms:42|g\n"
+        );
+    }
+
+    #[test]
+    fn test_format_prometheus() {
+        let metrics = vec![
+            Metric {
+                name: "quotes_served",
+                kind: MetricKind::Counter,
+                value: 3,
+            },
+            Metric {
+                name: "tpm_quote_latency_ms",
+                kind: MetricKind::Gauge,
+                value: 42,
+            },
+        ];
+
+        assert_eq!(
+            format_prometheus("keylime_agent", &metrics),
+            "# TYPE keylime_agent_quotes_served_total counter\nkeylime_agent_quotes_served_total 3\n# TYPE keylime_agent_tpm_quote_latency_ms gauge\nkeylime_agent_tpm_quote_latency_ms 42\n"
+        );
+    }
+
+    #[test]
+    fn test_statsd_push() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap(); //#[allow_ci]
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap(); //#[allow_ci]
+        let endpoint = server.local_addr().unwrap().to_string(); //#[allow_ci]
+
+        let metrics = Metrics::default();
+        let mut client = StatsdClient::new(&endpoint).unwrap(); //#[allow_ci]
+        let mut buf = [0u8; 1024];
+
+        metrics.registration_attempt();
+        metrics.quote_served(QuoteKind::Identity, Duration::from_millis(20));
+        metrics.quote_served(QuoteKind::Integrity, Duration::from_millis(30));
+        client.push(&metrics).unwrap(); //#[allow_ci]
+        let len = server.recv(&mut buf).unwrap(); //#[allow_ci]
+        let pushed = std::str::from_utf8(&buf[..len]).unwrap(); //#[allow_ci]
+        for line in [
+            "keylime_agent.quotes_served:2|c",
+            "keylime_agent.identity_quotes_served:1|c",
+            "keylime_agent.integrity_quotes_served:1|c",
+            "keylime_agent.registration_attempts:1|c",
+            "keylime_agent.ukeys_received:0|c",
+            "keylime_agent.tpm_quote_latency_ms:30|g",
+        ] {
+            assert!(pushed.lines().any(|l| l == line));
+        }
+
+        // Only the increments of the counters are pushed
+        metrics.quote_served(QuoteKind::Identity, Duration::from_millis(10));
+        client.push(&metrics).unwrap(); //#[allow_ci]
+        let len = server.recv(&mut buf).unwrap(); //#[allow_ci]
+        let pushed = std::str::from_utf8(&buf[..len]).unwrap(); //#[allow_ci]
+        for line in [
+            "keylime_agent.quotes_served:1|c",
+            "keylime_agent.identity_quotes_served:1|c",
+            "keylime_agent.integrity_quotes_served:0|c",
+            "keylime_agent.registration_attempts:0|c",
+            "keylime_agent.tpm_quote_latency_ms:10|g",
+        ] {
+            assert!(pushed.lines().any(|l| l == line));
+        }
+    }
+}
@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+use crate::common::JsonWrapper;
+use crate::QuoteData;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use log::*;
+
+// This is a Payload Status request, which returns the metadata of the last
+// payload received (whether it was decrypted and processed, its size, digest
+// and when it was received). The payload contents and keys are never returned
+pub async fn status(
+    req: HttpRequest,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    debug!("Returning payload status");
+
+    let status = data.payload_status.lock().unwrap().clone(); //#[allow_ci]
+    let response = JsonWrapper::success(status);
+
+    info!("GET payload status returning 200 response");
+    HttpResponse::Ok().json(response)
+}
+
+#[cfg(feature = "testing")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{AES_128_KEY_LEN, API_VERSION},
+        config::KeylimeConfig,
+        crypto::encrypt_aead,
+        metrics::Metrics,
+        payloads::{self, Payload, PayloadMessage, PayloadStatus},
+        revocation::RevocationMessage,
+    };
+    use actix_web::{test, web, App};
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    #[actix_rt::test]
+    async fn test_payload_status() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{API_VERSION}/payload/status"),
+                web::get().to(status),
+            ))
+            .await;
+
+        // Nothing was received yet
+        let req = test::TestRequest::get()
+            .uri(&format!("/{API_VERSION}/payload/status"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let result: JsonWrapper<PayloadStatus> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.results, PayloadStatus::default());
+
+        // Process a payload in the payloads worker
+        let mut test_config = KeylimeConfig::default();
+        test_config.agent.extract_payload_zip = false;
+        test_config.agent.payload_script = "".to_string();
+        let temp_workdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+
+        let key = [0x42u8; AES_128_KEY_LEN];
+        let plain = b"payload contents".to_vec();
+        let iv = b"ABCDEFGHIJKLMNOP";
+        let encrypted = encrypt_aead(&key, &iv[..], &plain).unwrap(); //#[allow_ci]
+
+        let (payload_tx, payload_rx) = mpsc::channel::<PayloadMessage>(1);
+        let (revocation_tx, mut revocation_rx) =
+            mpsc::channel::<RevocationMessage>(1);
+        #[cfg(feature = "with-zmq")]
+        let (zmq_tx, mut zmq_rx) = mpsc::channel(1);
+
+        let worker = actix_rt::spawn(payloads::worker(
+            test_config,
+            temp_workdir.path().to_path_buf(),
+            payload_rx,
+            revocation_tx,
+            #[cfg(feature = "with-zmq")]
+            zmq_tx,
+            Arc::new(Metrics::default()),
+            quotedata.payload_runs.clone(),
+            quotedata.payload_status.clone(),
+        ));
+
+        let result = payload_tx
+            .send(PayloadMessage::RunPayload(Payload {
+                symm_key: key[..].try_into().unwrap(), //#[allow_ci]
+                encrypted_payload: encrypted.into(),
+                named_payloads: payloads::NamedPayloads::new(),
+            }))
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(
+            revocation_rx.recv().await,
+            Some(RevocationMessage::PayloadDecrypted)
+        );
+        #[cfg(feature = "with-zmq")]
+        assert!(zmq_rx.recv().await.is_some());
+
+        let result = payload_tx.send(PayloadMessage::Shutdown).await;
+        assert!(result.is_ok());
+        drop(payload_tx);
+        assert!(worker.await.unwrap().is_ok()); //#[allow_ci]
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{API_VERSION}/payload/status"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let body = test::read_body(resp).await;
+
+        let result: JsonWrapper<PayloadStatus> =
+            serde_json::from_slice(&body).unwrap(); //#[allow_ci]
+        let status = result.results;
+        assert!(status.received);
+        assert!(status.decrypted);
+        assert!(status.processed);
+        assert_eq!(status.size, Some(plain.len()));
+        assert_eq!(
+            status.digest.as_deref(),
+            Some(hex::encode(openssl::sha::sha256(&plain)).as_str())
+        );
+        assert!(status.received_at.is_some());
+
+        // Neither the payload nor the key are included in the response
+        let body = String::from_utf8_lossy(&body);
+        assert!(!body.contains("payload contents"));
+        assert!(!body.contains(&hex::encode(key)));
+    }
+}
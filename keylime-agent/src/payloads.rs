@@ -2,10 +2,13 @@
 // Copyright 2021 Keylime Authors
 
 use crate::{
-    common::{EncryptedData, SymmKey},
-    config, crypto,
+    archive,
+    common::{EncryptedData, SymmKey, AES_BLOCK_SIZE},
+    config,
+    crypto::{self, PayloadCipherMode},
+    metrics::Metrics,
     revocation::{Revocation, RevocationMessage},
-    Error, Result,
+    secure_mount, Error, Result,
 };
 
 #[cfg(feature = "with-zmq")]
@@ -13,23 +16,55 @@ use crate::revocation::ZmqMessage;
 
 use compress_tools::*;
 use log::*;
+use openssl::sha::Sha256;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::{
+    collections::{BTreeMap, HashSet},
+    ffi::CString,
     fmt::Display,
     fs,
     io::{BufReader, Read, Write},
-    os::unix::fs::PermissionsExt,
-    path::{Path, PathBuf},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{DirBuilderExt, FileExt, OpenOptionsExt, PermissionsExt},
+    },
+    path::{Component, Path, PathBuf},
     process::{Command, Stdio},
-    sync::{Arc, Condvar, Mutex},
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::mpsc::{Receiver, Sender};
+use zeroize::Zeroizing;
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub(crate) struct Payload {
     pub symm_key: SymmKey,
     pub encrypted_payload: EncryptedData,
+    pub named_payloads: NamedPayloads,
+}
+
+/// An additional file delivered with the payload, encrypted with its own key.
+/// The key is encrypted with the payload key, so that, as the payload, the
+/// file can only be decrypted once the U and V keys were combined.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub(crate) struct NamedPayload {
+    pub encrypted_key: EncryptedData,
+    pub encrypted_payload: EncryptedData,
+}
+
+/// The named payloads, indexed by the name of the file they are written to
+pub(crate) type NamedPayloads = BTreeMap<String, NamedPayload>;
+
+/// Check that the name of a named payload is a plain file name, so that it
+/// is written inside the unzipped directory
+pub(crate) fn is_valid_payload_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(n)), None) if n == name
+    )
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
@@ -38,6 +73,58 @@ pub(crate) enum PayloadMessage {
     Shutdown,
 }
 
+/// The metadata of the last payload received, reported in the payload status
+/// endpoint. The payload contents and the key are never stored here.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub(crate) struct PayloadStatus {
+    pub received: bool,
+    pub decrypted: bool,
+    pub processed: bool,
+    // The size in bytes of the decrypted payload
+    pub size: Option<usize>,
+    // The SHA-256 digest of the decrypted payload, hex encoded
+    pub digest: Option<String>,
+    // When the payload was received, in seconds since the Unix epoch
+    pub received_at: Option<u64>,
+}
+
+impl PayloadStatus {
+    fn received() -> Self {
+        PayloadStatus {
+            received: true,
+            received_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_secs()),
+            ..Default::default()
+        }
+    }
+
+    fn decrypted(&mut self, size: usize, digest: &[u8]) {
+        self.decrypted = true;
+        self.size = Some(size);
+        self.digest = Some(hex::encode(digest));
+    }
+}
+
+// Computes the SHA-256 digest of the data written through it
+struct DigestWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 impl Display for PayloadMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -50,25 +137,58 @@ impl Display for PayloadMessage {
 // Parameters are based on Python codebase:
 // https://github.com/keylime/keylime/blob/1ed43ac8f75d5c3bc3a3bbbbb5037f20cf3c5a6a/ \
 // keylime/crypto.py#L189
+//
+// The payload is written to a temporary file in the same directory as it is
+// decrypted, so that the whole decrypted payload is never held in memory. The
+// temporary file is renamed to the destination only once the payload was
+// authenticated, and removed otherwise, so that an existing file is never
+// replaced by unauthenticated data. Returns the size and SHA-256 digest of
+// the decrypted payload.
 fn decrypt_payload(
     symm_key: &SymmKey,
-    encrypted_payload: EncryptedData,
-) -> Result<Vec<u8>> {
-    let decrypted =
-        crypto::decrypt_aead(symm_key.as_ref(), encrypted_payload.as_ref())?;
+    encrypted_payload: &EncryptedData,
+    dec_payload_path: &Path,
+    mode: PayloadCipherMode,
+) -> Result<(usize, [u8; 32])> {
+    let dir = dec_payload_path.parent().ok_or_else(|| {
+        Error::Other(format!(
+            "Invalid decrypted payload path {}",
+            dec_payload_path.display()
+        ))
+    })?;
+    let mut writer = DigestWriter {
+        inner: tempfile::NamedTempFile::new_in(dir)?,
+        hasher: Sha256::new(),
+    };
+
+    let key = symm_key.as_ref();
+    let data = encrypted_payload.as_ref();
+    let result = match mode {
+        PayloadCipherMode::Gcm => {
+            crypto::decrypt_aead_to(key, data, &mut writer)
+        }
+        PayloadCipherMode::CbcHmac => {
+            crypto::decrypt_cbc_hmac_to(key, data, &mut writer)
+        }
+    };
 
+    // On failure, the temporary file is removed when dropped
+    let size = result?;
+    let digest = writer.hasher.finish();
+    _ = writer.inner.persist(dec_payload_path)?;
     info!("Successfully decrypted payload");
-    Ok(decrypted)
+    Ok((size, digest))
 }
 
-// sets up unzipped directory in secure mount location in preparation for
-// writing out symmetric key and encrypted payload. returns file paths for
-// both.
+// sets up the `dir` unzipped directory in secure mount location in
+// preparation for writing out symmetric key and encrypted payload. returns
+// file paths for both.
 fn setup_unzipped(
     config: &config::KeylimeConfig,
     mount: &Path,
+    dir: &str,
 ) -> Result<(PathBuf, PathBuf, PathBuf)> {
-    let unzipped = mount.join("unzipped");
+    let unzipped = mount.join(dir);
 
     // clear any old data
     if Path::new(&unzipped).exists() {
@@ -96,36 +216,112 @@ fn setup_unzipped(
 }
 
 // write symm key data and decrypted payload data out to specified files
+// The key is written only if a key_path is provided, after the payload was
+// decrypted. Returns the size and digest of the decrypted payload
 fn write_out_key_and_payload(
-    dec_payload: &[u8],
+    payload: &EncryptedData,
     dec_payload_path: &Path,
     key: &SymmKey,
-    key_path: &Path,
-) -> Result<()> {
-    let mut key_file = fs::File::create(key_path)?;
-    let bytes = key_file.write(key.as_ref())?;
-    if bytes != key.as_ref().len() {
-        return Err(Error::Other(format!("Error writing symm key to {:?}: key len is {}, but {bytes} bytes were written", key_path, key.as_ref().len())));
-    }
-    info!("Wrote payload decryption key to {:?}", key_path);
+    key_path: Option<&Path>,
+    mode: PayloadCipherMode,
+) -> Result<(usize, [u8; 32])> {
+    let decrypted = decrypt_payload(key, payload, dec_payload_path, mode)?;
+    info!("Wrote decrypted payload to {:?}", dec_payload_path);
 
-    let mut dec_payload_file = fs::File::create(dec_payload_path)?;
-    let bytes = dec_payload_file.write(dec_payload)?;
-    if bytes != dec_payload.len() {
-        return Err(Error::Other(format!("Error writing decrypted payload to {:?}: payload len is {}, but {bytes} bytes were written", dec_payload_path, dec_payload.len())));
+    if let Some(key_path) = key_path {
+        let mut key_file = fs::File::create(key_path)?;
+        let bytes = key_file.write(key.as_ref())?;
+        if bytes != key.as_ref().len() {
+            return Err(Error::Other(format!("Error writing symm key to {:?}: key len is {}, but {bytes} bytes were written", key_path, key.as_ref().len())));
+        }
+        info!("Wrote payload decryption key to {:?}", key_path);
+    } else {
+        info!("Not writing payload decryption key to file");
     }
-    info!("Wrote decrypted payload to {:?}", dec_payload_path);
 
+    Ok(decrypted)
+}
+
+// decrypt the key of a named payload with the payload key, using the same
+// cipher mode as the payloads
+fn decrypt_named_payload_key(
+    symm_key: &SymmKey,
+    encrypted_key: &EncryptedData,
+    mode: PayloadCipherMode,
+) -> Result<SymmKey> {
+    let key = symm_key.as_ref();
+    let data = encrypted_key.as_ref();
+    let mut decrypted = Zeroizing::new(Vec::new());
+    _ = match mode {
+        PayloadCipherMode::Gcm => {
+            crypto::decrypt_aead_to(key, data, &mut *decrypted)?
+        }
+        PayloadCipherMode::CbcHmac => {
+            crypto::decrypt_cbc_hmac_to(key, data, &mut *decrypted)?
+        }
+    };
+    decrypted.as_slice().try_into().map_err(Error::Other)
+}
+
+// decrypt each named payload with its own key into a file with its name in the
+// unzipped directory. Existing files, e.g. extracted from the payload archive,
+// are not replaced
+fn write_out_named_payloads(
+    symm_key: &SymmKey,
+    named_payloads: &NamedPayloads,
+    unzipped: &Path,
+    mode: PayloadCipherMode,
+) -> Result<()> {
+    for (name, named) in named_payloads {
+        if !is_valid_payload_name(name) {
+            return Err(Error::Other(format!(
+                "Invalid named payload name: {name:?}"
+            )));
+        }
+
+        let path = unzipped.join(name);
+        if path.symlink_metadata().is_ok() {
+            return Err(Error::Other(format!(
+                "Named payload {name} conflicts with the existing file {}",
+                path.display()
+            )));
+        }
+
+        let named_key =
+            decrypt_named_payload_key(symm_key, &named.encrypted_key, mode)
+                .map_err(|e| {
+                Error::Other(format!(
+                    "Failed to decrypt the key of named payload {name}: {e}"
+                ))
+            })?;
+        _ = decrypt_payload(
+            &named_key,
+            &named.encrypted_payload,
+            &path,
+            mode,
+        )?;
+        info!("Wrote named payload {name} to {:?}", path);
+    }
     Ok(())
 }
 
-// run a script (such as the init script, if any) and check the status
-fn run(dir: &Path, script: &str) -> Result<()> {
+// run a script (such as the init script, if any) and check the status.
+// If the script does not exist, fail if it is required, or skip it otherwise
+fn run(dir: &Path, script: &str, required: bool) -> Result<()> {
     let script_path = dir.join(script);
     info!("Running script: {:?}", script_path);
 
     if !script_path.exists() {
-        info!("No payload script {script} found in {}", dir.display());
+        if required {
+            return Err(Error::Other(format!(
+                "Payload script {script} not found in {}",
+                dir.display()
+            )));
+        }
+        warn!(
+            "No payload script {script} found in {}, skipping",
+            dir.display()
+        );
         return Ok(());
     }
 
@@ -160,6 +356,331 @@ fn run(dir: &Path, script: &str) -> Result<()> {
     }
 }
 
+// Archives with fewer files are always extracted serially, as the overhead of
+// the parallel extraction is not worth it
+const PARALLEL_UNZIP_MIN_FILES: usize = 32;
+
+// Files up to this size are read into memory and written by the extraction
+// threads, while the larger ones are streamed to disk as they are read
+const PARALLEL_UNZIP_MAX_BUFFERED: usize = 1 << 20;
+
+// Get the path relative to the destination directory of an archive entry,
+// refusing entries that would be extracted outside the destination
+fn archive_entry_path(name: &str) -> Result<PathBuf> {
+    let path = Path::new(name);
+    if path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        Ok(path.to_path_buf())
+    } else {
+        Err(Error::Other(format!(
+            "Invalid file path in payload archive: {name}"
+        )))
+    }
+}
+
+// A file read from the archive, to be written by an extraction thread
+struct ArchiveFile {
+    path: PathBuf,
+    data: Vec<u8>,
+    perm: libc::mode_t,
+    mtime: Option<(libc::time_t, libc::c_long)>,
+}
+
+// Extracts the archive to the destination directory using a pool of at most
+// `threads` threads. The archive is read once by the calling thread, which
+// creates the directories and links and hands the files over to the threads
+// writing them. The threads are only started once PARALLEL_UNZIP_MIN_FILES
+// files were found, the first files being written by the calling thread, so
+// that small archives are extracted serially in the same single pass. The
+// files larger than PARALLEL_UNZIP_MAX_BUFFERED are
+// written by the calling thread as they are read, so that at most
+// `threads * 2` files of that size are held in memory.
+//
+// As with the serial extraction, the ownership stored in the archive is
+// ignored, while the permissions and modification times are preserved. The
+// directories are kept writable by the agent until all the files are
+// written. Duplicated files are extracted once, and links pointing outside
+// the destination are refused.
+fn uncompress_archive_parallel(
+    archive: &Path,
+    dest: &Path,
+    threads: usize,
+) -> Result<()> {
+    let mut dir_builder = fs::DirBuilder::new();
+    _ = dir_builder.recursive(true).mode(0o700);
+
+    let mut reader = archive::ArchiveReader::open(archive)?;
+    let mut seen = HashSet::new();
+    let mut dirs = Vec::new();
+    let mut hardlinks = Vec::new();
+
+    // Bound the number of files read but not written yet, as they are held
+    // in memory
+    let (sender, receiver) = mpsc::sync_channel::<ArchiveFile>(threads * 2);
+    let receiver = &Mutex::new(receiver);
+
+    thread::scope(|scope| {
+        let mut workers = Vec::new();
+        let mut files = 0;
+
+        let mut read = || -> Result<()> {
+            while let Some(entry) = reader.next_entry()? {
+                let path = dest.join(archive_entry_path(&entry.name)?);
+                if let Some(parent) = path.parent() {
+                    dir_builder.create(parent)?;
+                }
+                match entry.kind {
+                    archive::EntryKind::Directory => {
+                        dir_builder.create(&path)?;
+                        dirs.push((path, entry.perm, entry.mtime));
+                    }
+                    archive::EntryKind::File => {
+                        if !seen.insert(path.clone()) {
+                            continue;
+                        }
+                        files += 1;
+                        let buffered = matches!(
+                            entry.size,
+                            Some(size) if size <= PARALLEL_UNZIP_MAX_BUFFERED as u64
+                        );
+                        if !buffered {
+                            stream_archive_file(
+                                &mut reader,
+                                &path,
+                                entry.size,
+                            )?;
+                            set_archive_metadata(
+                                &path,
+                                entry.perm,
+                                entry.mtime,
+                            )?;
+                            continue;
+                        }
+                        let file = ArchiveFile {
+                            path,
+                            data: reader
+                                .read_data(PARALLEL_UNZIP_MAX_BUFFERED)?,
+                            perm: entry.perm,
+                            mtime: entry.mtime,
+                        };
+                        if files < PARALLEL_UNZIP_MIN_FILES {
+                            write_archive_file(&file)?;
+                            continue;
+                        }
+                        if workers.is_empty() {
+                            debug!(
+                                "Unzipping payload using {} threads",
+                                threads
+                            );
+                            workers = (0..threads)
+                                .map(|_| {
+                                    scope.spawn(move || {
+                                        write_archive_files(receiver)
+                                    })
+                                })
+                                .collect();
+                        }
+                        sender.send(file).map_err(|_| {
+                            Error::Other(
+                                "Payload extraction threads stopped"
+                                    .to_string(),
+                            )
+                        })?;
+                    }
+                    archive::EntryKind::Symlink(target) => {
+                        _ = archive_entry_path(&target)?;
+                        std::os::unix::fs::symlink(target, &path)?;
+                    }
+                    // The linked file may not be written yet
+                    archive::EntryKind::Hardlink(target) => {
+                        hardlinks.push((
+                            dest.join(archive_entry_path(&target)?),
+                            path,
+                        ));
+                    }
+                }
+            }
+            Ok(())
+        };
+        let result = read();
+        drop(sender);
+
+        workers.into_iter().try_for_each(|worker| {
+            worker
+                .join()
+                .map_err(|_| {
+                    Error::Other(
+                        "Payload extraction thread panicked".to_string(),
+                    )
+                })?
+                .map_err(Error::Other)
+        })?;
+        result
+    })?;
+
+    for (target, link) in hardlinks {
+        fs::hard_link(target, link)?;
+    }
+
+    // The deepest directories are updated first, as restricting the
+    // permissions of a directory may prevent accessing its content
+    for (path, perm, mtime) in dirs.iter().rev() {
+        set_archive_metadata(path, *perm, *mtime)?;
+    }
+    Ok(())
+}
+
+// Writes the files received until the channel is closed. The errors are
+// returned as strings, as the crate error type cannot be sent between threads
+fn write_archive_files(
+    receiver: &Mutex<mpsc::Receiver<ArchiveFile>>,
+) -> std::result::Result<(), String> {
+    loop {
+        let next = receiver.lock().unwrap().recv(); //#[allow_ci]
+        match next {
+            Ok(file) => write_archive_file(&file).map_err(|e| {
+                format!("Failed to extract {}: {e}", file.path.display())
+            })?,
+            Err(_) => return Ok(()),
+        }
+    }
+}
+
+// Writes the data of the current entry of the archive to a new file as it is
+// read, without holding it in memory
+fn stream_archive_file(
+    reader: &mut archive::ArchiveReader,
+    path: &Path,
+    size: Option<u64>,
+) -> Result<()> {
+    let target = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    reader.read_data_blocks(|offset, block| {
+        Ok(target.write_all_at(block, offset)?)
+    })?;
+    // The file may end with a hole, which is not part of the blocks read
+    if let Some(size) = size {
+        target.set_len(size)?;
+    }
+    Ok(())
+}
+
+// Writes a file read from the archive, creating the target file
+fn write_archive_file(file: &ArchiveFile) -> Result<()> {
+    let mut target = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&file.path)?;
+    target.write_all(&file.data)?;
+    drop(target);
+    set_archive_metadata(&file.path, file.perm, file.mtime)
+}
+
+// Sets the permissions and the modification time stored in the archive
+fn set_archive_metadata(
+    path: &Path,
+    perm: libc::mode_t,
+    mtime: Option<(libc::time_t, libc::c_long)>,
+) -> Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(perm))?;
+
+    if let Some((sec, nsec)) = mtime {
+        let c_path =
+            CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+                Error::Other(format!("Invalid path {}", path.display()))
+            })?;
+        // The access time is left unchanged
+        let times = [
+            libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT,
+            },
+            libc::timespec {
+                tv_sec: sec,
+                tv_nsec: nsec,
+            },
+        ];
+        // SAFETY: the path is NUL terminated and the times array holds the
+        // two elements expected
+        let ret = unsafe {
+            libc::utimensat(
+                libc::AT_FDCWD,
+                c_path.as_ptr(),
+                times.as_ptr(),
+                0,
+            )
+        };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+// Check recursively the files in the directory, rejecting setuid or setgid
+// files and files with a denied extension. Symbolic links are not followed.
+fn check_payload_dir(dir: &Path, denied_extensions: &[String]) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let metadata = fs::symlink_metadata(&path)?;
+
+        if metadata.is_dir() {
+            check_payload_dir(&path, denied_extensions)?;
+            continue;
+        }
+
+        if metadata.permissions().mode() & 0o6000 != 0 {
+            return Err(Error::Other(format!(
+                "Payload file {} has the setuid or setgid bit set",
+                path.display()
+            )));
+        }
+
+        if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+            if denied_extensions
+                .iter()
+                .any(|denied| denied.eq_ignore_ascii_case(ext))
+            {
+                return Err(Error::Other(format!(
+                    "Payload file {} has a denied extension",
+                    path.display()
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Check the files of the payload before running any script, removing the
+// payload if a disallowed file is found
+fn check_payload_files(
+    unzipped: &Path,
+    config: &config::KeylimeConfig,
+) -> Result<()> {
+    let denied_extensions: Vec<String> = config
+        .agent
+        .payload_denied_extensions
+        .split(',')
+        .map(|ext| ext.trim().trim_start_matches('.').to_string())
+        .filter(|ext| !ext.is_empty())
+        .collect();
+
+    if let Err(e) = check_payload_dir(unzipped, &denied_extensions) {
+        error!("Rejecting payload: {e}");
+        fs::remove_dir_all(unzipped)?;
+        return Err(e);
+    }
+
+    Ok(())
+}
+
 // checks if keylime-agent.conf indicates the payload should be unzipped, and does so if needed.
 // the input string is the directory where the unzipped file(s) should be stored.
 fn optional_unzip_payload(
@@ -176,8 +697,21 @@ fn optional_unzip_payload(
 
                 info!("Unzipping payload {} to {:?}", dec_file, unzipped);
 
-                let mut source = fs::File::open(zipped_payload_path)?;
-                uncompress_archive(&mut source, unzipped, Ownership::Ignore)?;
+                let threads = config.agent.payload_unzip_threads as usize;
+                if threads > 1 {
+                    uncompress_archive_parallel(
+                        &zipped_payload_path,
+                        unzipped,
+                        threads,
+                    )?;
+                } else {
+                    let mut source = fs::File::open(zipped_payload_path)?;
+                    uncompress_archive(
+                        &mut source,
+                        unzipped,
+                        Ownership::Ignore,
+                    )?;
+                }
             }
         }
     }
@@ -185,27 +719,128 @@ fn optional_unzip_payload(
     Ok(())
 }
 
-async fn run_encrypted_payload(
-    symm_key: SymmKey,
-    payload: EncryptedData,
+/// Semaphore bounding the number of payloads processed at the same time, as
+/// set in 'max_payload_runs'. Each run holds one of the slots, which selects
+/// the directory it uses in the secure mount, so that concurrent runs do not
+/// clobber each other's files. The first slot uses the "unzipped" directory.
+#[derive(Debug)]
+pub(crate) struct PayloadRuns {
+    free: Mutex<Vec<usize>>,
+    released: Condvar,
+}
+
+// A slot held by a payload run, released when dropped
+struct PayloadRun<'a> {
+    runs: &'a PayloadRuns,
+    slot: usize,
+}
+
+impl PayloadRuns {
+    pub(crate) fn new(max_runs: usize) -> Self {
+        PayloadRuns {
+            free: Mutex::new((0..max_runs.max(1)).rev().collect()),
+            released: Condvar::new(),
+        }
+    }
+
+    // Waits until a slot is free and takes it. A poisoned lock only means
+    // another run panicked, which leaves no state to recover as the
+    // directory is cleared on setup
+    fn acquire(&self) -> PayloadRun<'_> {
+        let mut free = self
+            .free
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        loop {
+            if let Some(slot) = free.pop() {
+                return PayloadRun { runs: self, slot };
+            }
+            free = self
+                .released
+                .wait(free)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+}
+
+impl PayloadRun<'_> {
+    fn dir(&self) -> String {
+        match self.slot {
+            0 => "unzipped".to_string(),
+            n => format!("unzipped.{n}"),
+        }
+    }
+}
+
+impl Drop for PayloadRun<'_> {
+    fn drop(&mut self) {
+        self.runs
+            .free
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(self.slot);
+        self.runs.released.notify_one();
+    }
+}
+
+// decrypts the payload into the unzipped directory, unzips it, writes out the
+// named payloads next to it and runs the payload script. The run holds a slot
+// of `runs` until done, so that concurrent runs do not clobber each other's
+// files. The size and digest of the decrypted payload are recorded in the
+// status.
+fn setup_payload(
+    symm_key: &SymmKey,
+    payload: &EncryptedData,
+    named_payloads: &NamedPayloads,
     config: &config::KeylimeConfig,
     mount: &Path,
-    revocation_tx: Sender<RevocationMessage>,
-    #[cfg(feature = "with-zmq")] zmq_tx: Sender<ZmqMessage>,
+    runs: &PayloadRuns,
+    status: &Mutex<PayloadStatus>,
 ) -> Result<()> {
-    let dec_payload = decrypt_payload(&symm_key, payload)?;
-
+    let slot = runs.acquire();
     let (unzipped, dec_payload_path, key_path) =
-        setup_unzipped(config, mount)?;
+        setup_unzipped(config, mount, &slot.dir())?;
+
+    let key_path = match config.agent.write_key_file {
+        true => Some(key_path.as_path()),
+        false => None,
+    };
 
-    write_out_key_and_payload(
-        &dec_payload,
+    // Fail early instead of running out of space while writing the files.
+    // The decrypted payload is as long as the ciphertext, without the IV and
+    // the tag
+    let dec_len = |p: &EncryptedData| {
+        p.as_ref().len().saturating_sub(2 * AES_BLOCK_SIZE)
+    };
+    let required = dec_len(payload)
+        + named_payloads
+            .values()
+            .map(|n| dec_len(&n.encrypted_payload))
+            .sum::<usize>()
+        + key_path.map_or(0, |_| symm_key.as_ref().len());
+    let available =
+        secure_mount::available_space(&unzipped, &config.agent.secure_size)?;
+    if required as u64 > available {
+        return Err(Error::Other(format!(
+            "Decrypted payload size ({required} bytes) exceeds the space available in the secure mount ({available} bytes). Increase 'secure_size' to run this payload"
+        )));
+    }
+
+    let mode = PayloadCipherMode::try_from(
+        config.agent.payload_cipher_mode.as_str(),
+    )?;
+    let (size, digest) = write_out_key_and_payload(
+        payload,
         &dec_payload_path,
-        &symm_key,
-        &key_path,
+        symm_key,
+        key_path,
+        mode,
     )?;
+    status.lock().unwrap().decrypted(size, &digest); //#[allow_ci]
 
     optional_unzip_payload(&unzipped, config)?;
+    write_out_named_payloads(symm_key, named_payloads, &unzipped, mode)?;
+    check_payload_files(&unzipped, config)?;
     // there may also be also a separate init script
     match config.agent.payload_script.as_ref() {
         "" => {
@@ -213,7 +848,7 @@ async fn run_encrypted_payload(
         }
         script => {
             info!("Payload init script indicated: {}", script);
-            run(&unzipped, script)?;
+            run(&unzipped, script, config.agent.payload_script_required)?;
         }
     }
 
@@ -249,6 +884,34 @@ async fn run_encrypted_payload(
             })?
     }
 
+    Ok(())
+}
+
+async fn run_encrypted_payload(
+    payload: Payload,
+    config: &config::KeylimeConfig,
+    mount: &Path,
+    runs: &PayloadRuns,
+    revocation_tx: Sender<RevocationMessage>,
+    #[cfg(feature = "with-zmq")] zmq_tx: Sender<ZmqMessage>,
+    status: &Mutex<PayloadStatus>,
+) -> Result<()> {
+    if payload.encrypted_payload.as_ref().is_empty() {
+        return Err(Error::Other(
+            "The encrypted payload is empty".to_string(),
+        ));
+    }
+
+    setup_payload(
+        &payload.symm_key,
+        &payload.encrypted_payload,
+        &payload.named_payloads,
+        config,
+        mount,
+        runs,
+        status,
+    )?;
+
     debug!("Sending PayloadDecrypted message to revocation worker");
     if let Err(e) = revocation_tx
         .send(RevocationMessage::PayloadDecrypted)
@@ -268,12 +931,16 @@ async fn run_encrypted_payload(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn worker(
     config: config::KeylimeConfig,
     mount: impl AsRef<Path>,
     mut payload_rx: Receiver<PayloadMessage>,
     mut revocation_tx: Sender<RevocationMessage>,
     #[cfg(feature = "with-zmq")] mut zmq_tx: Sender<ZmqMessage>,
+    metrics: Arc<Metrics>,
+    runs: Arc<PayloadRuns>,
+    status: Arc<Mutex<PayloadStatus>>,
 ) -> Result<()> {
     debug!("Starting payloads worker");
 
@@ -284,20 +951,25 @@ pub(crate) async fn worker(
                 payload_rx.close();
             }
             PayloadMessage::RunPayload(run_payload) => {
+                *status.lock().unwrap() = PayloadStatus::received(); //#[allow_ci]
+
                 // The keys worker will send this message only if mTLS is enabled or
                 // 'enable_insecure_payload' configuration option is set
                 match run_encrypted_payload(
-                    run_payload.symm_key,
-                    run_payload.encrypted_payload,
+                    run_payload,
                     &config,
                     mount.as_ref(),
+                    &runs,
                     revocation_tx.clone(),
                     #[cfg(feature = "with-zmq")]
                     zmq_tx.clone(),
+                    &status,
                 )
                 .await
                 {
                     Ok(_) => {
+                        status.lock().unwrap().processed = true; //#[allow_ci]
+                        metrics.payload_decrypted();
                         info!("Successfully executed encrypted payload");
                     }
                     Err(e) => {
@@ -317,9 +989,8 @@ pub(crate) async fn worker(
 mod tests {
     use super::*;
     #[cfg(feature = "testing")]
-    use crate::crypto::testing::{
-        encrypt_aead, pkey_pub_from_pem, rsa_oaep_encrypt,
-    };
+    use crate::crypto::testing::{pkey_pub_from_pem, rsa_oaep_encrypt};
+    use crate::crypto::{encrypt_aead, testing::encrypt_cbc_hmac};
     use crate::{
         common::{AES_128_KEY_LEN, AES_256_KEY_LEN, API_VERSION},
         config::KeylimeConfig,
@@ -342,6 +1013,11 @@ mod tests {
         u.xor(&v).unwrap() //#[allow_ci]
     }
 
+    fn encrypt(key: &SymmKey, data: &[u8]) -> EncryptedData {
+        let iv = b"ABCDEFGHIJKLMNOP";
+        encrypt_aead(key.as_ref(), &iv[..], data).unwrap().into() //#[allow_ci]
+    }
+
     #[cfg(feature = "testing")]
     fn setup_key_and_payload(key_len: usize) -> (SymmKey, EncryptedData) {
         let u: SymmKey = U[..key_len][..].try_into().unwrap(); //#[allow_ci]
@@ -377,17 +1053,57 @@ echo hello > test-output
         run(
             dir.path(),
             script_path.file_name().unwrap().to_str().unwrap(), //#[allow_ci]
+            true,
         )
         .unwrap(); //#[allow_ci]
         assert!(dir.path().join("test-output").exists());
     }
 
+    #[test]
+    fn test_run_missing_script() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+
+        assert!(run(dir.path(), "nonexistent.sh", true).is_err());
+        assert!(run(dir.path(), "nonexistent.sh", false).is_ok());
+    }
+
     #[cfg(feature = "testing")]
     #[test]
     fn test_decrypt_payload() {
+        let temp_workdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let dec_payload_path = temp_workdir.path().join("dec_payload");
         let (k, payload) = setup_key_and_payload(AES_128_KEY_LEN);
-        let result = decrypt_payload(&k, payload);
+        let result = decrypt_payload(
+            &k,
+            &payload,
+            &dec_payload_path,
+            PayloadCipherMode::Gcm,
+        );
         assert!(result.is_ok());
+
+        let expected = fs::read(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("test-data")
+                .join("payload.zip"),
+        )
+        .unwrap(); //#[allow_ci]
+        let (size, digest) = result.unwrap(); //#[allow_ci]
+        assert_eq!(size, expected.len());
+        assert_eq!(digest, openssl::sha::sha256(&expected));
+        assert_eq!(fs::read(&dec_payload_path).unwrap(), expected); //#[allow_ci]
+
+        // The unauthenticated output is removed if the decryption fails, and
+        // the existing file is kept
+        let other = setup_key(AES_256_KEY_LEN);
+        assert!(decrypt_payload(
+            &other,
+            &payload,
+            &dec_payload_path,
+            PayloadCipherMode::Gcm
+        )
+        .is_err());
+        assert_eq!(fs::read(&dec_payload_path).unwrap(), expected); //#[allow_ci]
+        assert_eq!(fs::read_dir(temp_workdir.path()).unwrap().count(), 1); //#[allow_ci]
     }
 
     #[test]
@@ -397,7 +1113,7 @@ echo hello > test-output
         let secure_mount =
             PathBuf::from(&temp_workdir.path().join("tmpfs-dev"));
         fs::create_dir(&secure_mount).unwrap(); //#[allow_ci]
-        let result = setup_unzipped(&test_config, &secure_mount);
+        let result = setup_unzipped(&test_config, &secure_mount, "unzipped");
         assert!(result.is_ok());
         let (unzipped, dec_payload_path, key_path) = result.unwrap(); //#[allow_ci]
         assert!(unzipped.exists());
@@ -412,15 +1128,39 @@ echo hello > test-output
     fn test_write_out_key_and_payload() {
         let temp_workdir = tempfile::tempdir().unwrap(); //#[allow_ci]
         let k = setup_key(AES_128_KEY_LEN);
-        let payload = b"Testing";
+        let payload = encrypt(&k, b"Testing");
+        let result = write_out_key_and_payload(
+            &payload,
+            &temp_workdir.path().join("dec_payload"),
+            &k,
+            Some(&temp_workdir.path().join("key")),
+            PayloadCipherMode::Gcm,
+        );
+
+        assert!(result.is_ok());
+        assert!(temp_workdir.path().join("key").exists());
+        assert_eq!(
+            fs::read(temp_workdir.path().join("dec_payload")).unwrap(), //#[allow_ci]
+            b"Testing"
+        );
+    }
+
+    #[test]
+    fn test_write_out_payload_without_key() {
+        let temp_workdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let k = setup_key(AES_128_KEY_LEN);
+        let payload = encrypt(&k, b"Testing");
         let result = write_out_key_and_payload(
-            payload,
+            &payload,
             &temp_workdir.path().join("dec_payload"),
             &k,
-            &temp_workdir.path().join("key"),
+            None,
+            PayloadCipherMode::Gcm,
         );
 
         assert!(result.is_ok());
+        assert!(temp_workdir.path().join("dec_payload").exists());
+        assert!(!temp_workdir.path().join("key").exists());
     }
 
     #[test]
@@ -452,6 +1192,144 @@ echo hello > test-output
         assert!(temp_workdir.path().join("autorun.sh").exists());
     }
 
+    #[test]
+    fn test_unzip_payload_parallel() {
+        let mut test_config = KeylimeConfig::default();
+        test_config.agent.payload_unzip_threads = 4;
+        let temp_workdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let payload_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data")
+            .join("payload_many_files.zip");
+
+        let result = fs::copy(
+            payload_path,
+            temp_workdir
+                .path()
+                .join(&test_config.agent.dec_payload_file),
+        );
+        assert!(result.is_ok());
+
+        let result =
+            optional_unzip_payload(temp_workdir.path(), &test_config);
+        assert!(result.is_ok());
+
+        for i in 0..100 {
+            let path = temp_workdir
+                .path()
+                .join(format!("dir{}", i % 4))
+                .join(format!("file{i:03}.txt"));
+            let content = fs::read_to_string(&path).unwrap(); //#[allow_ci]
+            assert_eq!(content, format!("content of file {i}\n"));
+
+            // The permissions and modification times stored in the archive
+            // are preserved
+            let metadata = fs::metadata(&path).unwrap(); //#[allow_ci]
+            let mode = if i % 10 == 0 { 0o750 } else { 0o640 };
+            assert_eq!(metadata.permissions().mode() & 0o7777, mode);
+            assert_eq!(
+                metadata.modified().unwrap(), //#[allow_ci]
+                UNIX_EPOCH + std::time::Duration::from_secs(1672531200)
+            );
+        }
+
+        for i in 0..4 {
+            let metadata =
+                fs::metadata(temp_workdir.path().join(format!("dir{i}")))
+                    .unwrap(); //#[allow_ci]
+            assert_eq!(metadata.permissions().mode() & 0o7777, 0o750);
+            assert_eq!(
+                metadata.modified().unwrap(), //#[allow_ci]
+                UNIX_EPOCH + std::time::Duration::from_secs(1672531200)
+            );
+        }
+    }
+
+    #[test]
+    fn test_unzip_payload_parallel_large_file() {
+        let mut test_config = KeylimeConfig::default();
+        test_config.agent.payload_unzip_threads = 4;
+        let temp_workdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let payload_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data")
+            .join("payload_large_file.zip");
+
+        let result = fs::copy(
+            payload_path,
+            temp_workdir
+                .path()
+                .join(&test_config.agent.dec_payload_file),
+        );
+        assert!(result.is_ok());
+
+        let result =
+            optional_unzip_payload(temp_workdir.path(), &test_config);
+        assert!(result.is_ok());
+
+        // The file larger than the buffered size is streamed to disk
+        let expected: Vec<u8> =
+            (0..(2 << 20) + 3).map(|i| (i % 251) as u8).collect();
+        assert!(expected.len() > PARALLEL_UNZIP_MAX_BUFFERED);
+        let path = temp_workdir.path().join("large.bin");
+        assert_eq!(fs::read(&path).unwrap(), expected); //#[allow_ci]
+        let metadata = fs::metadata(&path).unwrap(); //#[allow_ci]
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+
+        for i in 0..40 {
+            let path = temp_workdir.path().join(format!("file{i:03}.txt"));
+            let content = fs::read_to_string(&path).unwrap(); //#[allow_ci]
+            assert_eq!(content, format!("content of file {i}\n"));
+        }
+    }
+
+    #[test]
+    fn test_check_payload_files() {
+        let mut test_config = KeylimeConfig::default();
+        let temp_workdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let unzipped = temp_workdir.path().join("unzipped");
+        let payload_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data")
+            .join("payload.zip");
+
+        let extract = |config: &KeylimeConfig| {
+            fs::create_dir_all(&unzipped).unwrap(); //#[allow_ci]
+            let _ = fs::copy(
+                &payload_path,
+                unzipped.join(&config.agent.dec_payload_file),
+            )
+            .unwrap(); //#[allow_ci]
+            optional_unzip_payload(&unzipped, config).unwrap(); //#[allow_ci]
+        };
+
+        extract(&test_config);
+        assert!(check_payload_files(&unzipped, &test_config).is_ok());
+
+        // The payload contains a shell script
+        test_config.agent.payload_denied_extensions = "so, .SH".to_string();
+        extract(&test_config);
+        assert!(check_payload_files(&unzipped, &test_config).is_err());
+        assert!(!unzipped.exists());
+
+        // Files with the setuid bit are always rejected
+        test_config.agent.payload_denied_extensions = "".to_string();
+        extract(&test_config);
+        fs::set_permissions(
+            unzipped.join("autorun.sh"),
+            fs::Permissions::from_mode(0o4755),
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(check_payload_files(&unzipped, &test_config).is_err());
+        assert!(!unzipped.exists());
+    }
+
+    #[test]
+    fn test_archive_entry_path() {
+        assert!(archive_entry_path("dir/file.txt").is_ok());
+        assert!(archive_entry_path("./file.txt").is_ok());
+        assert!(archive_entry_path("../file.txt").is_err());
+        assert!(archive_entry_path("dir/../../file.txt").is_err());
+        assert!(archive_entry_path("/etc/passwd").is_err());
+    }
+
     #[cfg(feature = "testing")]
     #[actix_rt::test]
     async fn test_run_encrypted_payload() {
@@ -469,18 +1347,30 @@ echo hello > test-output
         let (mut zmq_tx, mut zmq_rx) = mpsc::channel::<ZmqMessage>(1);
 
         let (k, payload) = setup_key_and_payload(AES_128_KEY_LEN);
+        let status = Mutex::new(PayloadStatus::default());
 
         run_encrypted_payload(
-            k,
-            payload,
+            Payload {
+                symm_key: k,
+                encrypted_payload: payload,
+                named_payloads: NamedPayloads::new(),
+            },
             &test_config,
             &secure_mount,
+            &PayloadRuns::new(1),
             revocation_tx,
             #[cfg(feature = "with-zmq")]
             zmq_tx,
+            &status,
         )
         .await;
 
+        // The metadata of the decrypted payload is recorded
+        let status = status.into_inner().unwrap(); //#[allow_ci]
+        assert!(status.decrypted);
+        assert!(matches!(status.size, Some(size) if size > 0));
+        assert_eq!(status.digest.map(|d| d.len()), Some(64));
+
         let msg = revocation_rx.recv().await;
         assert!(msg == Some(RevocationMessage::PayloadDecrypted));
         revocation_rx.close();
@@ -496,6 +1386,312 @@ echo hello > test-output
         assert!(timestamp_path.exists());
     }
 
+    #[test]
+    fn test_setup_payload_concurrent() {
+        let mut test_config = KeylimeConfig::default();
+        test_config.agent.extract_payload_zip = false;
+        test_config.agent.payload_script = "".to_string();
+        let temp_workdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let mount = temp_workdir.path().to_path_buf();
+        let key = setup_key(AES_128_KEY_LEN);
+        let dec_payload_path = mount
+            .join("unzipped")
+            .join(&test_config.agent.dec_payload_file);
+
+        let payloads = [vec![b'a'; 1 << 20], vec![b'b'; 1 << 20]];
+        let encrypted: Vec<_> =
+            payloads.iter().map(|p| encrypt(&key, p)).collect();
+        let status = Mutex::new(PayloadStatus::default());
+        let runs = PayloadRuns::new(1);
+
+        thread::scope(|scope| {
+            for payload in &encrypted {
+                let _ = scope.spawn(|| {
+                    for _ in 0..20 {
+                        setup_payload(
+                            &key,
+                            payload,
+                            &NamedPayloads::new(),
+                            &test_config,
+                            &mount,
+                            &runs,
+                            &status,
+                        )
+                        .unwrap(); //#[allow_ci]
+
+                        // The payload found is always complete, even if the
+                        // other run replaced it already
+                        let _run = runs.acquire();
+                        let written = fs::read(&dec_payload_path).unwrap(); //#[allow_ci]
+                        assert!(payloads.contains(&written));
+                    }
+                });
+            }
+        });
+
+        // With more than one run allowed, a concurrent run uses its own
+        // directory and does not wait for the first one
+        let runs = PayloadRuns::new(2);
+        let first = runs.acquire();
+        assert_eq!(first.dir(), "unzipped");
+        setup_payload(
+            &key,
+            &encrypted[1],
+            &NamedPayloads::new(),
+            &test_config,
+            &mount,
+            &runs,
+            &status,
+        )
+        .unwrap(); //#[allow_ci]
+        let written = fs::read(
+            mount
+                .join("unzipped.1")
+                .join(&test_config.agent.dec_payload_file),
+        )
+        .unwrap(); //#[allow_ci]
+        assert_eq!(written, payloads[1]);
+        drop(first);
+    }
+
+    #[test]
+    fn test_setup_payload_too_large() {
+        let mut test_config = KeylimeConfig::default();
+        test_config.agent.extract_payload_zip = false;
+        test_config.agent.payload_script = "".to_string();
+        test_config.agent.secure_size = "1k".to_string();
+        let temp_workdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let key = setup_key(AES_128_KEY_LEN);
+        let status = Mutex::new(PayloadStatus::default());
+
+        let result = setup_payload(
+            &key,
+            &encrypt(&key, &[0u8; 2048]),
+            &NamedPayloads::new(),
+            &test_config,
+            temp_workdir.path(),
+            &PayloadRuns::new(1),
+            &status,
+        );
+        assert!(
+            matches!(result, Err(Error::Other(m)) if m.contains("exceeds the space available"))
+        );
+
+        assert!(setup_payload(
+            &key,
+            &encrypt(&key, &[0u8; 512]),
+            &NamedPayloads::new(),
+            &test_config,
+            temp_workdir.path(),
+            &PayloadRuns::new(1),
+            &status,
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_setup_payload_cipher_modes() {
+        let mut test_config = KeylimeConfig::default();
+        test_config.agent.extract_payload_zip = false;
+        test_config.agent.payload_script = "".to_string();
+        let temp_workdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let dec_payload_path = temp_workdir
+            .path()
+            .join("unzipped")
+            .join(&test_config.agent.dec_payload_file);
+        let key = setup_key(AES_256_KEY_LEN);
+        let iv = b"ABCDEFGHIJKLMNOP";
+        let plaintext = b"test payload, longer than the block size";
+        let status = Mutex::new(PayloadStatus::default());
+
+        let gcm: EncryptedData =
+            encrypt_aead(key.as_ref(), &iv[..], plaintext)
+                .unwrap() //#[allow_ci]
+                .into();
+        let cbc_hmac: EncryptedData =
+            encrypt_cbc_hmac(key.as_ref(), &iv[..], plaintext)
+                .unwrap() //#[allow_ci]
+                .into();
+
+        for (mode, payload, other) in
+            [("gcm", &gcm, &cbc_hmac), ("cbc-hmac", &cbc_hmac, &gcm)]
+        {
+            test_config.agent.payload_cipher_mode = mode.to_string();
+
+            let result = setup_payload(
+                &key,
+                payload,
+                &NamedPayloads::new(),
+                &test_config,
+                temp_workdir.path(),
+                &PayloadRuns::new(1),
+                &status,
+            );
+            assert!(result.is_ok(), "{mode}: {result:?}");
+            assert_eq!(fs::read(&dec_payload_path).unwrap(), plaintext); //#[allow_ci]
+
+            // A payload encrypted with the other mode is rejected
+            let result = setup_payload(
+                &key,
+                other,
+                &NamedPayloads::new(),
+                &test_config,
+                temp_workdir.path(),
+                &PayloadRuns::new(1),
+                &status,
+            );
+            assert!(result.is_err(), "{mode}");
+            assert!(!dec_payload_path.exists());
+        }
+    }
+
+    #[test]
+    fn test_setup_payload_large_zip() {
+        let mut test_config = KeylimeConfig::default();
+        test_config.agent.payload_script = "".to_string();
+        let temp_workdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let key = setup_key(AES_256_KEY_LEN);
+        let status = Mutex::new(PayloadStatus::default());
+
+        // The archive contains a 16 MiB file, much larger than the chunks
+        // decrypted at once
+        let zipped = fs::read(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("test-data")
+                .join("payload_large.zip"),
+        )
+        .unwrap(); //#[allow_ci]
+        let payload = encrypt(&key, &zipped);
+
+        setup_payload(
+            &key,
+            &payload,
+            &NamedPayloads::new(),
+            &test_config,
+            temp_workdir.path(),
+            &PayloadRuns::new(1),
+            &status,
+        )
+        .unwrap(); //#[allow_ci]
+
+        // The decrypted archive was written to the secure mount and then
+        // extracted from the file
+        let unzipped = temp_workdir.path().join("unzipped");
+        assert_eq!(
+            fs::read(unzipped.join(&test_config.agent.dec_payload_file))
+                .unwrap(), //#[allow_ci]
+            zipped
+        );
+        let extracted = fs::metadata(unzipped.join("large.bin")).unwrap(); //#[allow_ci]
+        assert_eq!(extracted.len(), 16 << 20);
+
+        let status = status.into_inner().unwrap(); //#[allow_ci]
+        assert_eq!(status.size, Some(zipped.len()));
+        assert_eq!(
+            status.digest,
+            Some(hex::encode(openssl::sha::sha256(&zipped)))
+        );
+    }
+
+    #[test]
+    fn test_is_valid_payload_name() {
+        for name in ["ca-bundle.pem", "agent.conf", ".hidden"] {
+            assert!(is_valid_payload_name(name), "{name}");
+        }
+        for name in ["", ".", "..", "dir/file", "/etc/passwd", "file/", "./f"]
+        {
+            assert!(!is_valid_payload_name(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_setup_payload_named_payloads() {
+        let mut test_config = KeylimeConfig::default();
+        test_config.agent.extract_payload_zip = false;
+        test_config.agent.payload_script = "".to_string();
+        let temp_workdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let unzipped = temp_workdir.path().join("unzipped");
+        let key = setup_key(AES_256_KEY_LEN);
+        let status = Mutex::new(PayloadStatus::default());
+
+        let bundle_key = setup_key(AES_128_KEY_LEN);
+        let conf_key: SymmKey =
+            [0x42u8; AES_256_KEY_LEN][..].try_into().unwrap(); //#[allow_ci]
+        let bundle = b"certificate bundle";
+        let conf = b"configuration file";
+
+        let mut named_payloads = NamedPayloads::new();
+        for (name, k, data) in [
+            ("ca-bundle.pem", &bundle_key, &bundle[..]),
+            ("agent.conf", &conf_key, &conf[..]),
+        ] {
+            _ = named_payloads.insert(
+                name.to_string(),
+                NamedPayload {
+                    encrypted_key: encrypt(&key, k.as_ref()),
+                    encrypted_payload: encrypt(k, data),
+                },
+            );
+        }
+
+        setup_payload(
+            &key,
+            &encrypt(&key, b"payload"),
+            &named_payloads,
+            &test_config,
+            temp_workdir.path(),
+            &PayloadRuns::new(1),
+            &status,
+        )
+        .unwrap(); //#[allow_ci]
+
+        // Each named payload is decrypted with its own key to its own file
+        assert_eq!(
+            fs::read(unzipped.join(&test_config.agent.dec_payload_file))
+                .unwrap(), //#[allow_ci]
+            b"payload"
+        );
+        assert_eq!(fs::read(unzipped.join("ca-bundle.pem")).unwrap(), bundle); //#[allow_ci]
+        assert_eq!(fs::read(unzipped.join("agent.conf")).unwrap(), conf); //#[allow_ci]
+
+        // A named payload key not encrypted with the payload key, e.g. only
+        // encrypted with NK, is rejected
+        let mut wrong_key = named_payloads.clone();
+        if let Some(p) = wrong_key.get_mut("agent.conf") {
+            p.encrypted_key = encrypt(&bundle_key, conf_key.as_ref());
+        }
+        assert!(setup_payload(
+            &key,
+            &encrypt(&key, b"payload"),
+            &wrong_key,
+            &test_config,
+            temp_workdir.path(),
+            &PayloadRuns::new(1),
+            &status,
+        )
+        .is_err());
+        assert!(!unzipped.join("agent.conf").exists());
+
+        // A named payload cannot replace the payload
+        let mut conflict = NamedPayloads::new();
+        _ = conflict.insert(
+            test_config.agent.dec_payload_file.clone(),
+            named_payloads["agent.conf"].clone(),
+        );
+        let result = setup_payload(
+            &key,
+            &encrypt(&key, b"payload"),
+            &conflict,
+            &test_config,
+            temp_workdir.path(),
+            &PayloadRuns::new(1),
+            &status,
+        );
+        assert!(
+            matches!(result, Err(Error::Other(m)) if m.contains("conflicts"))
+        );
+    }
+
     #[cfg(feature = "testing")]
     #[actix_rt::test]
     async fn test_payload_worker() {
@@ -523,6 +1719,9 @@ echo hello > test-output
             &secure_mount.join(format!("unzipped/{DEFAULT_PAYLOAD_SCRIPT}")),
         );
 
+        let status = Arc::new(Mutex::new(PayloadStatus::default()));
+        let worker_status = status.clone();
+
         let arbiter = Arbiter::new();
         assert!(arbiter.spawn(Box::pin(async move {
             let result = worker(
@@ -532,6 +1731,9 @@ echo hello > test-output
                 revocation_tx,
                 #[cfg(feature = "with-zmq")]
                 zmq_tx,
+                Arc::new(Metrics::default()),
+                Arc::new(PayloadRuns::new(1)),
+                worker_status,
             )
             .await;
 
@@ -550,6 +1752,7 @@ echo hello > test-output
         let run_payload = Payload {
             symm_key: k,
             encrypted_payload: payload,
+            named_payloads: NamedPayloads::new(),
         };
 
         let result = payload_tx
@@ -573,5 +1776,9 @@ echo hello > test-output
         drop(payload_tx);
 
         arbiter.join();
+
+        let status = status.lock().unwrap(); //#[allow_ci]
+        assert!(status.received && status.decrypted && status.processed);
+        assert!(status.received_at.is_some());
     }
 }
@@ -0,0 +1,260 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+use crate::error::{Error, Result};
+use keylime::{algorithms::HashAlgorithm, tpm};
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    path::Path,
+};
+
+// File where the kernel exposes the identifier of the current boot
+pub static BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+/// A measurement extended by the agent into a PCR, recorded in the PCR
+/// measurement log as "<pcr> <hash_alg> <hex digest> <event>"
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PcrMeasurement {
+    pub pcr: u32,
+    pub hash_alg: HashAlgorithm,
+    pub digest: Vec<u8>,
+    pub event: String,
+}
+
+impl fmt::Display for PcrMeasurement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.pcr,
+            self.hash_alg,
+            hex::encode(&self.digest),
+            self.event
+        )
+    }
+}
+
+impl TryFrom<&str> for PcrMeasurement {
+    type Error = Error;
+
+    fn try_from(line: &str) -> Result<Self> {
+        let invalid = || {
+            Error::Other(format!(
+                "Invalid entry in PCR measurement log: {line}"
+            ))
+        };
+        let fields: Vec<&str> = line.split(' ').collect();
+        let (pcr, hash_alg, digest, event) = match fields[..] {
+            [pcr, hash_alg, digest, event] => (pcr, hash_alg, digest, event),
+            _ => return Err(invalid()),
+        };
+        Ok(PcrMeasurement {
+            pcr: pcr.parse().map_err(|_| invalid())?,
+            hash_alg: HashAlgorithm::try_from(hash_alg)
+                .map_err(|_| invalid())?,
+            digest: hex::decode(digest).map_err(|_| invalid())?,
+            event: event.to_string(),
+        })
+    }
+}
+
+/// Reads the identifier of the current boot from `path`
+pub(crate) fn read_boot_id(path: &Path) -> Result<String> {
+    let boot_id = fs::read_to_string(path).map_err(|e| {
+        Error::Other(format!(
+            "Unable to read boot identifier from {}: {e}",
+            path.display()
+        ))
+    })?;
+    Ok(boot_id.trim().to_string())
+}
+
+// Reads the measurements recorded in the log at `path` during the boot
+// `boot_id`. The log starts with a "boot_id <id>" line, and a log missing
+// or written during a previous boot holds no measurement of this boot, as
+// the PCRs were reset on reboot
+fn read_log(path: &Path, boot_id: &str) -> Result<Vec<PcrMeasurement>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Vec::new())
+        }
+        Err(e) => {
+            return Err(Error::Other(format!(
+                "Unable to read PCR measurement log {}: {e}",
+                path.display()
+            )))
+        }
+    };
+
+    let mut lines = contents.lines();
+    match lines.next().and_then(|l| l.strip_prefix("boot_id ")) {
+        Some(id) if id == boot_id => {
+            lines.map(PcrMeasurement::try_from).collect()
+        }
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Extends the PCR with the measurement and records it in the log at
+/// `path`, so that the verifier can replay the PCR value. A measurement
+/// already recorded during the boot `boot_id` was extended before the agent
+/// was restarted, and is not extended again.
+///
+/// Returns whether the PCR was extended.
+pub(crate) fn extend_logged(
+    ctx: &mut tpm::Context,
+    path: &Path,
+    boot_id: &str,
+    measurement: &PcrMeasurement,
+) -> Result<bool> {
+    if measurement.event.is_empty() || measurement.event.contains(' ') {
+        return Err(Error::Other(format!(
+            "Invalid event name for PCR measurement: {}",
+            measurement.event
+        )));
+    }
+
+    let logged = read_log(path, boot_id)?;
+    if logged.contains(measurement) {
+        return Ok(false);
+    }
+
+    ctx.extend_pcr_digest(
+        measurement.pcr,
+        measurement.hash_alg,
+        &measurement.digest,
+    )?;
+
+    let record = || -> io::Result<()> {
+        let mut file = if logged.is_empty() {
+            let mut file = fs::File::create(path)?;
+            writeln!(file, "boot_id {boot_id}")?;
+            file
+        } else {
+            fs::OpenOptions::new().append(true).open(path)?
+        };
+        writeln!(file, "{measurement}")
+    };
+    record().map_err(|e| {
+        Error::Other(format!(
+            "PCR {} was extended, but the measurement could not be recorded in {}: {e}",
+            measurement.pcr,
+            path.display()
+        ))
+    })?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(event: &str, digest: &[u8]) -> PcrMeasurement {
+        PcrMeasurement {
+            pcr: 23,
+            hash_alg: HashAlgorithm::Sha256,
+            digest: openssl::sha::sha256(digest).to_vec(),
+            event: event.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_measurement_line() {
+        let m = measurement("agent_binary", b"binary");
+        let line = m.to_string();
+        assert!(line.starts_with("23 sha256 "));
+        assert!(line.ends_with(" agent_binary"));
+        assert_eq!(PcrMeasurement::try_from(line.as_str()).unwrap(), m); //#[allow_ci]
+
+        assert!(PcrMeasurement::try_from("23 sha256 00").is_err());
+        assert!(PcrMeasurement::try_from("23 sha256 00 a b").is_err());
+        assert!(PcrMeasurement::try_from("x sha256 00 a").is_err());
+        assert!(PcrMeasurement::try_from("23 md5 00 a").is_err());
+        assert!(PcrMeasurement::try_from("23 sha256 zz a").is_err());
+    }
+
+    #[test]
+    fn test_read_boot_id() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("boot_id");
+        fs::write(&path, "2d1e0f3a-0c4b-4f5e-9b6a-1c2d3e4f5a6b\n").unwrap(); //#[allow_ci]
+        assert_eq!(
+            read_boot_id(&path).unwrap(), //#[allow_ci]
+            "2d1e0f3a-0c4b-4f5e-9b6a-1c2d3e4f5a6b"
+        );
+        assert!(read_boot_id(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_read_log() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("pcr_measurements.log");
+
+        // A missing log holds no measurement
+        assert!(read_log(&path, "boot").unwrap().is_empty()); //#[allow_ci]
+
+        let m = measurement("config_hash", b"config");
+        fs::write(&path, format!("boot_id boot\n{m}\n")).unwrap(); //#[allow_ci]
+        assert_eq!(read_log(&path, "boot").unwrap(), vec![m]); //#[allow_ci]
+
+        // A log written during a previous boot holds no measurement
+        assert!(read_log(&path, "other").unwrap().is_empty()); //#[allow_ci]
+
+        // A corrupted log is reported
+        fs::write(&path, "boot_id boot\ninvalid\n").unwrap(); //#[allow_ci]
+        assert!(read_log(&path, "boot").is_err());
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_extend_logged() {
+        use tss_esapi::{
+            interface_types::algorithm::HashingAlgorithm,
+            structures::{PcrSelectionListBuilder, PcrSlot},
+        };
+
+        let read_pcr23 = |ctx: &mut tpm::Context| -> Vec<u8> {
+            let selection = PcrSelectionListBuilder::new()
+                .with_selection(HashingAlgorithm::Sha256, &[PcrSlot::Slot23])
+                .build()
+                .unwrap(); //#[allow_ci]
+            let (_, _, digests) = ctx.as_mut().pcr_read(selection).unwrap(); //#[allow_ci]
+            digests.value()[0].to_vec()
+        };
+
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("pcr_measurements.log");
+        let mut ctx = tpm::Context::new().unwrap(); //#[allow_ci]
+        let before = read_pcr23(&mut ctx);
+
+        // The PCR is extended with the digest and the measurement recorded
+        let m = measurement("agent_binary", b"binary");
+        assert!(extend_logged(&mut ctx, &path, "boot", &m).unwrap()); //#[allow_ci]
+        let extended =
+            openssl::sha::sha256(&[before, m.digest.clone()].concat());
+        assert_eq!(read_pcr23(&mut ctx), extended.to_vec());
+        assert_eq!(read_log(&path, "boot").unwrap(), vec![m.clone()]); //#[allow_ci]
+
+        // The same measurement is not extended again on restart
+        assert!(!extend_logged(&mut ctx, &path, "boot", &m).unwrap()); //#[allow_ci]
+        assert_eq!(read_pcr23(&mut ctx), extended.to_vec());
+
+        // A different measurement is appended
+        let other = measurement("config_hash", b"config");
+        assert!(extend_logged(&mut ctx, &path, "boot", &other).unwrap()); //#[allow_ci]
+        assert_eq!(
+            read_log(&path, "boot").unwrap(), //#[allow_ci]
+            vec![m.clone(), other]
+        );
+
+        // The log is reset on a new boot
+        assert!(extend_logged(&mut ctx, &path, "next", &m).unwrap()); //#[allow_ci]
+        assert_eq!(read_log(&path, "next").unwrap(), vec![m]); //#[allow_ci]
+
+        // Event names containing spaces would break the log
+        let invalid = measurement("agent binary", b"binary");
+        assert!(extend_logged(&mut ctx, &path, "next", &invalid).is_err());
+    }
+}
@@ -8,7 +8,7 @@ use std::os::unix::ffi::OsStrExt;
 use std::{
     convert::{TryFrom, TryInto},
     ffi::CString,
-    io,
+    fmt, io,
     path::Path,
     ptr,
 };
@@ -18,6 +18,12 @@ pub(crate) struct UserIds {
     group: libc::group,
 }
 
+impl UserIds {
+    pub(crate) fn uid(&self) -> uid_t {
+        self.passwd.pw_uid
+    }
+}
+
 pub(crate) fn get_gid() -> gid_t {
     unsafe { libc::getgid() }
 }
@@ -143,6 +149,91 @@ pub(crate) fn run_as(user_group: &str) -> Result<()> {
     Ok(())
 }
 
+/// The resource limits that can be set for the agent process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Rlimit {
+    /// Maximum number of open file descriptors
+    NoFile,
+    /// Maximum number of processes for the user running the agent
+    NProc,
+}
+
+impl fmt::Display for Rlimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = match self {
+            Rlimit::NoFile => "nofile",
+            Rlimit::NProc => "nproc",
+        };
+        write!(f, "{value}")
+    }
+}
+
+fn get_rlimit(resource: Rlimit) -> Result<libc::rlimit> {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    let ret = unsafe {
+        match resource {
+            Rlimit::NoFile => libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim),
+            Rlimit::NProc => libc::getrlimit(libc::RLIMIT_NPROC, &mut rlim),
+        }
+    };
+    if ret != 0 {
+        let e = io::Error::last_os_error();
+        return Err(Error::Configuration(format!(
+            "Could not get {resource} limit: {e}"
+        )));
+    }
+    Ok(rlim)
+}
+
+// Validate the limit for the given resource, returning the limit to apply.
+// Both the soft and hard limits are set to the value, so that it cannot be
+// raised again by the process.
+pub(crate) fn check_rlimit(
+    resource: Rlimit,
+    limit: u64,
+) -> Result<libc::rlimit> {
+    if limit == 0 {
+        return Err(Error::Configuration(format!(
+            "Invalid {resource} limit: the value must be greater than 0"
+        )));
+    }
+
+    let current = get_rlimit(resource)?;
+    if current.rlim_max != libc::RLIM_INFINITY && limit > current.rlim_max {
+        return Err(Error::Configuration(format!(
+            "Invalid {resource} limit: {limit} is greater than the current hard limit {}",
+            current.rlim_max
+        )));
+    }
+
+    Ok(libc::rlimit {
+        rlim_cur: limit,
+        rlim_max: limit,
+    })
+}
+
+/// Set the limit for the given resource for the agent process
+pub(crate) fn set_rlimit(resource: Rlimit, limit: u64) -> Result<()> {
+    let rlim = check_rlimit(resource, limit)?;
+    let ret = unsafe {
+        match resource {
+            Rlimit::NoFile => libc::setrlimit(libc::RLIMIT_NOFILE, &rlim),
+            Rlimit::NProc => libc::setrlimit(libc::RLIMIT_NPROC, &rlim),
+        }
+    };
+    if ret != 0 {
+        let e = io::Error::last_os_error();
+        error!("Could not set {} limit: {}", resource, e);
+        return Err(Error::Permission);
+    }
+
+    info!("Set {} limit to {}", resource, limit);
+    Ok(())
+}
+
 pub(crate) fn chown(user_group: &str, path: &Path) -> Result<()> {
     let ids: UserIds = user_group.try_into()?;
 
@@ -168,3 +259,30 @@ pub(crate) fn chown(user_group: &str, path: &Path) -> Result<()> {
     info!("Changed file {} owner to {}.", path.display(), user_group);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_rlimit() {
+        for resource in [Rlimit::NoFile, Rlimit::NProc] {
+            let rlim = check_rlimit(resource, 16).unwrap(); //#[allow_ci]
+            assert_eq!(rlim.rlim_cur, 16);
+            assert_eq!(rlim.rlim_max, 16);
+
+            assert!(check_rlimit(resource, 0).is_err());
+
+            let current = get_rlimit(resource).unwrap(); //#[allow_ci]
+            if current.rlim_max != libc::RLIM_INFINITY {
+                assert!(check_rlimit(resource, current.rlim_max + 1).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_rlimit_display() {
+        assert_eq!(Rlimit::NoFile.to_string(), "nofile");
+        assert_eq!(Rlimit::NProc.to_string(), "nproc");
+    }
+}
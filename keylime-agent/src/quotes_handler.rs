@@ -1,17 +1,27 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
-use crate::common::JsonWrapper;
+use crate::common::{
+    JsonWrapper, IMA_PCR, IMA_REQUESTS_RETRY_AFTER, MAX_QUOTE_JOBS,
+    QUOTE_JOB_EXPIRY, TPM_DATA_PCR,
+};
 use crate::crypto;
+use crate::log_limit::RepeatedLog;
 use crate::serialization::serialize_maybe_base64;
-use crate::{tpm, Error as KeylimeError, QuoteData};
-use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use crate::{metrics, tpm, Error as KeylimeError, QuoteData};
+use actix_web::{http, rt, web, HttpRequest, HttpResponse, Responder};
 use base64::{engine::general_purpose, Engine as _};
+use keylime::algorithms::HashAlgorithm;
 use log::*;
 use serde::{Deserialize, Serialize};
 use std::{
-    fs::{read, read_to_string},
+    collections::HashMap,
+    fs::{read, read_to_string, File},
     io::{Read, Seek},
+    ops::RangeInclusive,
+    process::Command,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 use tss_esapi::structures::PcrSlot;
 
@@ -42,6 +52,236 @@ pub(crate) struct KeylimeQuote {
     pub mb_measurement_list: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ima_measurement_list_entry: Option<u64>,
+    // The expected value of the IMA PCR after extending all the entries up
+    // to the end of the returned list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ima_pcr_aggregate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_facts: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_facts_signature: Option<String>,
+    // The firmware (BIOS) version, included in the identity quotes if
+    // enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firmware_version: Option<String>,
+    // The hash of the effective agent configuration, included in the
+    // identity quotes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_hash: Option<String>,
+    // The qualifying data signed by the TPM, in hex, included only when the
+    // debug endpoints are enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qualifying_data: Option<String>,
+    // The quoted PCR values, each tagged with its bank algorithm and index
+    // as "<alg>:<pcr>:<value in hex>"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pcr_values: Option<Vec<String>>,
+    // The PCR banks quoted, included only when more than one bank is set in
+    // 'tpm_hash_alg'
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pcr_banks: Option<Vec<String>>,
+}
+
+// The banks are only listed when more than the 'hash_alg' bank is quoted, so
+// that the response is unchanged for the verifiers expecting a single bank
+fn pcr_banks(banks: &[HashAlgorithm]) -> Option<Vec<String>> {
+    (banks.len() > 1).then(|| banks.iter().map(|b| b.to_string()).collect())
+}
+
+/// A single attestation produced when the agent runs with `--attest-once`:
+/// the integrity quote and the parameters used to generate it
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Attestation {
+    pub agent_id: String,
+    pub nonce: String,
+    pub mask: String,
+    #[serde(flatten)]
+    pub quote: KeylimeQuote,
+}
+
+// Length of the nonce generated for a single attestation, matching the
+// length of the nonces sent by the verifier
+const ATTEST_ONCE_NONCE_LEN: usize = 20;
+
+// Error generating an integrity quote
+#[derive(Debug)]
+pub(crate) enum QuoteError {
+    // Too many concurrent IMA measurement list requests
+    Busy,
+    Failed(String),
+}
+
+impl QuoteError {
+    fn into_response(self, tpm_error_log: &RepeatedLog) -> HttpResponse {
+        match self {
+            QuoteError::Busy => {
+                warn!("Get quote returning 503 response. Too many concurrent IMA measurement list requests");
+                HttpResponse::ServiceUnavailable()
+                    .insert_header((
+                        http::header::RETRY_AFTER,
+                        IMA_REQUESTS_RETRY_AFTER.to_string(),
+                    ))
+                    .json(JsonWrapper::error(
+                        503,
+                        "Too many concurrent IMA measurement list requests"
+                            .to_string(),
+                    ))
+            }
+            QuoteError::Failed(message) => {
+                tpm_error_log.warn(&format!(
+                    "Get quote returning 500 response. {message}"
+                ));
+                HttpResponse::InternalServerError()
+                    .json(JsonWrapper::error(500, message))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct QuoteJobStatus {
+    pub job_id: String,
+}
+
+type QuoteJobResult = Result<KeylimeQuote, QuoteError>;
+
+/// The integrity quotes generated in the background when asynchronous
+/// quotes are enabled
+#[derive(Debug, Default)]
+pub(crate) struct QuoteJobs {
+    // The creation time and result of each job, None while pending
+    jobs: Mutex<HashMap<String, (Instant, Option<QuoteJobResult>)>>,
+}
+
+impl QuoteJobs {
+    /// Registers a new pending job and returns its id, or None if there are
+    /// too many jobs pending or not retrieved yet
+    pub(crate) fn submit(&self) -> Option<String> {
+        let mut jobs = self.jobs.lock().unwrap(); //#[allow_ci]
+
+        // Drop the results that were never retrieved, and the jobs that
+        // never completed, so that they do not hold a slot forever
+        let expiry = Duration::from_secs(QUOTE_JOB_EXPIRY);
+        jobs.retain(|_, (created, _)| created.elapsed() < expiry);
+
+        if jobs.len() >= MAX_QUOTE_JOBS {
+            return None;
+        }
+
+        let job_id = uuid::Uuid::new_v4().to_string();
+        _ = jobs.insert(job_id.clone(), (Instant::now(), None));
+        Some(job_id)
+    }
+
+    fn complete(&self, job_id: &str, result: QuoteJobResult) {
+        let mut jobs = self.jobs.lock().unwrap(); //#[allow_ci]
+        if let Some((_, job)) = jobs.get_mut(job_id) {
+            *job = Some(result);
+        }
+    }
+
+    // Returns the result of the job if it is complete, removing it. Returns
+    // None if there is no job with the given id.
+    fn take(&self, job_id: &str) -> Option<Option<QuoteJobResult>> {
+        let mut jobs = self.jobs.lock().unwrap(); //#[allow_ci]
+        match jobs.get(job_id) {
+            None => None,
+            Some((_, None)) => Some(None),
+            Some((_, Some(_))) => jobs.remove(job_id).map(|(_, job)| job),
+        }
+    }
+}
+
+// Maximum size of the system facts included in the integrity quote
+const MAX_SYSTEM_FACTS_SIZE: usize = 4096;
+
+// Runs the system facts command and returns its output, with control
+// characters (other than newlines and tabs) removed, and truncated to
+// MAX_SYSTEM_FACTS_SIZE bytes. The command blocks, so this must not run on
+// the async workers.
+fn get_system_facts(command: &str) -> Result<String, KeylimeError> {
+    let output = Command::new("sh").arg("-c").arg(command).output()?;
+    if !output.status.success() {
+        return Err(KeylimeError::Other(format!(
+            "system facts command failed with {}",
+            output.status
+        )));
+    }
+
+    let mut facts: String = String::from_utf8_lossy(&output.stdout)
+        .chars()
+        .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+        .collect();
+
+    if facts.len() > MAX_SYSTEM_FACTS_SIZE {
+        let mut end = MAX_SYSTEM_FACTS_SIZE;
+        while !facts.is_char_boundary(end) {
+            end -= 1;
+        }
+        warn!("System facts truncated to {} bytes", MAX_SYSTEM_FACTS_SIZE);
+        facts.truncate(end);
+    }
+
+    Ok(facts)
+}
+
+// Builds the error message returned when the TPM fails to generate a quote.
+// Only the TPM command and response code are included, which are enough to
+// diagnose the failure without exposing other details of the error.
+//
+// The TPM errors are logged through `tpm_error_log`, as a TPM in a
+// persistent failure state fails every quote request the same way.
+fn quote_error_message(
+    e: &tpm::TpmError,
+    tpm_error_log: &RepeatedLog,
+) -> String {
+    let rc = e.response_code();
+    if let Some(rc) = rc {
+        tpm_error_log.warn(&format!(
+            "{} failed with TPM response code {:#010x}: {}",
+            e.command().unwrap_or("TPM command"),
+            rc,
+            e
+        ));
+    }
+
+    match (e.command(), rc) {
+        (Some(command), Some(rc)) => {
+            format!("Unable to retrieve quote: {command} failed with TPM_RC {rc:#010x}")
+        }
+        (None, Some(rc)) => {
+            format!("Unable to retrieve quote: TPM_RC {rc:#010x}")
+        }
+        _ => "Unable to retrieve quote".to_string(),
+    }
+}
+
+// Checks the nonce of a quote request is present, is strictly alphanumeric
+// ASCII, and has an accepted length, before it is passed to the TPM
+fn check_nonce(
+    nonce: &str,
+    length: &RangeInclusive<usize>,
+) -> Result<(), String> {
+    if nonce.is_empty() {
+        return Err("nonce is required".to_string());
+    }
+
+    if !nonce.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!(
+            "nonce should be strictly alphanumeric: {nonce}"
+        ));
+    }
+
+    if !length.contains(&nonce.len()) {
+        return Err(format!(
+            "nonce length {} is out of the accepted range ({}-{})",
+            nonce.len(),
+            length.start(),
+            length.end()
+        ));
+    }
+
+    Ok(())
 }
 
 // This is a Quote request from the tenant, which does not check
@@ -52,50 +292,71 @@ pub async fn identity(
     param: web::Query<Ident>,
     data: web::Data<QuoteData>,
 ) -> impl Responder {
-    // nonce can only be in alphanumerical format
-    if !param.nonce.chars().all(char::is_alphanumeric) {
-        warn!("Get quote returning 400 response. Parameters should be strictly alphanumeric: {}", param.nonce);
-        return HttpResponse::BadRequest().json(JsonWrapper::error(
-            400,
-            format!(
-                "Parameters should be strictly alphanumeric: {}",
-                param.nonce
-            ),
-        ));
-    }
-
-    if param.nonce.len() > tpm::MAX_NONCE_SIZE {
-        warn!("Get quote returning 400 response. Nonce is too long (max size {}): {}",
-              tpm::MAX_NONCE_SIZE,
-              param.nonce.len()
-        );
-        return HttpResponse::BadRequest().json(JsonWrapper::error(
-            400,
-            format!(
-                "Nonce is too long (max size {}): {}",
-                tpm::MAX_NONCE_SIZE,
-                param.nonce
-            ),
-        ));
+    if let Err(e) = check_nonce(&param.nonce, &data.nonce_length) {
+        warn!("Get quote returning 400 response. {}", e);
+        return HttpResponse::BadRequest().json(JsonWrapper::error(400, e));
     }
 
     debug!("Calling Identity Quote with nonce: {}", param.nonce);
 
-    // must unwrap here due to lock mechanism
-    // https://github.com/rust-lang-nursery/failure/issues/192
-    let mut context = data.tpmcontext.lock().unwrap(); //#[allow_ci]
+    let nonce = param.nonce.as_bytes();
+    #[cfg(feature = "testing")]
+    let nonce = data.fixed_nonce.as_deref().unwrap_or(nonce);
+    let nonce = nonce.to_vec();
 
-    let tpm_quote = match context.quote(
-        param.nonce.as_bytes(),
-        0,
-        &data.pub_key,
-        data.ak_handle,
-        data.hash_alg,
-        data.sign_alg,
-    ) {
-        Ok(quote) => quote,
+    // The TPM operations block, so the quote is generated on the blocking
+    // thread pool
+    let start = Instant::now();
+    let task_data = data.clone();
+    let task_nonce = nonce.clone();
+    let result = web::block(move || {
+        // must unwrap here due to lock mechanism
+        // https://github.com/rust-lang-nursery/failure/issues/192
+        let mut context = task_data.tpmcontext.lock().unwrap(); //#[allow_ci]
+        context.quote(
+            &task_nonce,
+            task_data.quote_pcr_mask.unwrap_or(0),
+            &task_data.pub_key(),
+            task_data.ak_handle(),
+            task_data.hash_alg,
+            &task_data.pcr_banks,
+            task_data.sign_alg,
+        )
+    })
+    .await;
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => {
+            debug!("Unable to retrieve quote: {:?}", e);
+            return HttpResponse::InternalServerError().json(
+                JsonWrapper::error(
+                    500,
+                    "Unable to retrieve quote".to_string(),
+                ),
+            );
+        }
+    };
+    let tpm_quote = match result {
+        Ok(quote) => {
+            data.metrics
+                .quote_served(metrics::QuoteKind::Identity, start.elapsed());
+            quote
+        }
         Err(e) => {
             debug!("Unable to retrieve quote: {:?}", e);
+            return HttpResponse::InternalServerError().json(
+                JsonWrapper::error(
+                    500,
+                    quote_error_message(&e, &data.tpm_error_log),
+                ),
+            );
+        }
+    };
+
+    let pcr_values = match tpm::quote_pcr_values(&tpm_quote) {
+        Ok(values) => values,
+        Err(e) => {
+            debug!("Unable to format the quoted PCR values: {:?}", e);
             return HttpResponse::InternalServerError().json(
                 JsonWrapper::error(
                     500,
@@ -110,10 +371,15 @@ pub async fn identity(
         hash_alg: data.hash_alg.to_string(),
         enc_alg: data.enc_alg.to_string(),
         sign_alg: data.sign_alg.to_string(),
+        firmware_version: data.firmware_version.clone(),
+        config_hash: data.config_hash.clone(),
+        qualifying_data: data.debug_quotes.then(|| hex::encode(nonce)),
+        pcr_values: Some(pcr_values),
+        pcr_banks: pcr_banks(&data.pcr_banks),
         ..Default::default()
     };
 
-    match crypto::pkey_pub_to_pem(&data.pub_key) {
+    match crypto::pkey_pub_to_pem(&data.pub_key()) {
         Ok(pubkey) => quote.pubkey = Some(pubkey),
         Err(e) => {
             debug!("Unable to retrieve public key for quote: {:?}", e);
@@ -141,15 +407,12 @@ pub async fn integrity(
     param: web::Query<Integ>,
     data: web::Data<QuoteData>,
 ) -> impl Responder {
-    // nonce, mask can only be in alphanumerical format
-    if !param.nonce.chars().all(char::is_alphanumeric) {
-        warn!("Get quote returning 400 response. Parameters should be strictly alphanumeric: {}", param.nonce);
-        return HttpResponse::BadRequest().json(JsonWrapper::error(
-            400,
-            format!("nonce should be strictly alphanumeric: {}", param.nonce),
-        ));
+    if let Err(e) = check_nonce(&param.nonce, &data.nonce_length) {
+        warn!("Get quote returning 400 response. {}", e);
+        return HttpResponse::BadRequest().json(JsonWrapper::error(400, e));
     }
 
+    // mask can only be in alphanumerical format
     if !param.mask.chars().all(char::is_alphanumeric) {
         warn!("Get quote returning 400 response. Parameters should be strictly alphanumeric: {}", param.mask);
         return HttpResponse::BadRequest().json(JsonWrapper::error(
@@ -172,25 +435,10 @@ pub async fn integrity(
             }
         };
 
-    if param.nonce.len() > tpm::MAX_NONCE_SIZE {
-        warn!("Get quote returning 400 response. Nonce is too long (max size {}): {}",
-              tpm::MAX_NONCE_SIZE,
-              param.nonce.len()
-        );
-        return HttpResponse::BadRequest().json(JsonWrapper::error(
-            400,
-            format!(
-                "Nonce is too long (max size: {}): {}",
-                tpm::MAX_NONCE_SIZE,
-                param.nonce.len()
-            ),
-        ));
-    }
-
     // If partial="0", include the public key in the quote
     let pubkey = match &param.partial[..] {
         "0" => {
-            let pubkey = match crypto::pkey_pub_to_pem(&data.pub_key) {
+            let pubkey = match crypto::pkey_pub_to_pem(&data.pub_key()) {
                 Ok(pubkey) => pubkey,
                 Err(e) => {
                     debug!("Unable to retrieve public key: {:?}", e);
@@ -227,28 +475,165 @@ pub async fn integrity(
         Some(idx) => idx.parse::<u64>().unwrap_or(0),
     };
 
+    // In asynchronous mode, generate the quote in the background and let the
+    // verifier poll for the result
+    if let Some(jobs) = &data.quote_jobs {
+        let job_id = match jobs.submit() {
+            Some(job_id) => job_id,
+            None => {
+                warn!("Get quote returning 503 response. Too many pending quote jobs");
+                return HttpResponse::ServiceUnavailable()
+                    .insert_header((
+                        http::header::RETRY_AFTER,
+                        IMA_REQUESTS_RETRY_AFTER.to_string(),
+                    ))
+                    .json(JsonWrapper::error(
+                        503,
+                        "Too many pending quote jobs".to_string(),
+                    ));
+            }
+        };
+
+        let nonce = param.nonce.clone();
+        let id = job_id.clone();
+        let data = data.clone();
+        _ = rt::spawn(async move {
+            let task_data = data.clone();
+            let result = web::block(move || {
+                integrity_quote(&task_data, &nonce, mask, pubkey, nth_entry)
+            })
+            .await
+            .unwrap_or_else(|e| {
+                Err(QuoteError::Failed(format!("Quote job failed: {e}")))
+            });
+            if let Some(jobs) = &data.quote_jobs {
+                jobs.complete(&id, result);
+            }
+        });
+
+        info!("GET integrity quote returning 202 response");
+        return HttpResponse::Accepted().json(JsonWrapper {
+            code: 202,
+            status: String::from("Accepted"),
+            results: QuoteJobStatus { job_id },
+        });
+    }
+
+    // The TPM operations and the system facts command block, so the quote is
+    // generated on the blocking thread pool
+    let task_data = data.clone();
+    let nonce = param.nonce.clone();
+    let result = web::block(move || {
+        integrity_quote(&task_data, &nonce, mask, pubkey, nth_entry)
+    })
+    .await
+    .unwrap_or_else(|e| {
+        Err(QuoteError::Failed(format!("Quote failed: {e}")))
+    });
+    match result {
+        Ok(quote) => {
+            info!("GET integrity quote returning 200 response");
+            HttpResponse::Ok().json(JsonWrapper::success(quote))
+        }
+        Err(e) => e.into_response(&data.tpm_error_log),
+    }
+}
+
+// Reads the whole TPM2 event log and encodes it in base64. If the log cannot
+// be read, it is omitted from the quote instead of failing the request
+fn read_measuredboot_log(file: &Mutex<File>) -> Option<String> {
+    let mut f = file.lock().unwrap(); //#[allow_ci]
+    if let Err(e) = f.rewind() {
+        warn!("Failed to rewind measured boot file: {}", e);
+        return None;
+    }
+    let mut ml = Vec::<u8>::new();
+    match f.read_to_end(&mut ml) {
+        Ok(_) => Some(general_purpose::STANDARD.encode(ml)),
+        Err(e) => {
+            warn!("Could not read TPM2 event log: {}", e);
+            None
+        }
+    }
+}
+
+// Limits the PCR mask requested by the verifier to the PCR selection set in
+// 'quote_pcr_selection', if any. PCR 16 is always quoted, so it is never
+// reported as excluded
+fn select_pcr_mask(configured: Option<u32>, requested: u32) -> u32 {
+    match configured {
+        Some(configured) => {
+            let excluded = requested & !configured & !(1 << TPM_DATA_PCR);
+            if excluded != 0 {
+                warn!(
+                    "Excluding the PCRs {:#x} requested by the verifier, as they are not set in 'quote_pcr_selection'",
+                    excluded
+                );
+            }
+            requested & configured
+        }
+        None => requested,
+    }
+}
+
+// Generates the integrity quote, including the measurement lists and the
+// system facts
+fn integrity_quote(
+    data: &QuoteData,
+    nonce: &str,
+    mask: u32,
+    pubkey: Option<String>,
+    nth_entry: u64,
+) -> Result<KeylimeQuote, QuoteError> {
+    // Limit the number of requests reading the IMA measurement list at the
+    // same time, as each of them buffers the list in memory. The permit is
+    // held until the response is generated.
+    let _ima_permit = match (&data.ima_ml_file, &data.ima_ml_requests) {
+        (Some(_), Some(semaphore)) => match semaphore.try_acquire() {
+            Ok(permit) => Some(permit),
+            Err(_) => return Err(QuoteError::Busy),
+        },
+        _ => None,
+    };
+
     // must unwrap here due to lock mechanism
     // https://github.com/rust-lang-nursery/failure/issues/192
     let mut context = data.tpmcontext.lock().unwrap(); //#[allow_ci]
 
+    let mask = select_pcr_mask(data.quote_pcr_mask, mask);
+
     // Generate the ID quote.
+    let start = Instant::now();
     let tpm_quote = match context.quote(
-        param.nonce.as_bytes(),
+        nonce.as_bytes(),
         mask,
-        &data.pub_key,
-        data.ak_handle,
+        &data.pub_key(),
+        data.ak_handle(),
         data.hash_alg,
+        &data.pcr_banks,
         data.sign_alg,
     ) {
-        Ok(tpm_quote) => tpm_quote,
+        Ok(tpm_quote) => {
+            data.metrics
+                .quote_served(metrics::QuoteKind::Integrity, start.elapsed());
+            tpm_quote
+        }
         Err(e) => {
             debug!("Unable to retrieve quote: {:?}", e);
-            return HttpResponse::InternalServerError().json(
-                JsonWrapper::error(
-                    500,
-                    "Unable to retrieve quote".to_string(),
-                ),
-            );
+            return Err(QuoteError::Failed(quote_error_message(
+                &e,
+                &data.tpm_error_log,
+            )));
+        }
+    };
+
+    let pcr_values = match tpm::quote_pcr_values(&tpm_quote) {
+        Ok(values) => values,
+        Err(e) => {
+            debug!("Unable to format the quoted PCR values: {:?}", e);
+            return Err(QuoteError::Failed(
+                "Unable to retrieve quote".to_string(),
+            ));
         }
     };
 
@@ -257,6 +642,9 @@ pub async fn integrity(
         hash_alg: data.hash_alg.to_string(),
         enc_alg: data.enc_alg.to_string(),
         sign_alg: data.sign_alg.to_string(),
+        qualifying_data: data.debug_quotes.then(|| hex::encode(nonce)),
+        pcr_values: Some(pcr_values),
+        pcr_banks: pcr_banks(&data.pcr_banks),
         ..Default::default()
     };
 
@@ -265,83 +653,200 @@ pub async fn integrity(
     match tpm::check_mask(mask, &PcrSlot::Slot0) {
         Ok(true) => {
             if let Some(measuredboot_ml_file) = &data.measuredboot_ml_file {
-                let mut ml = Vec::<u8>::new();
-                let mut f = measuredboot_ml_file.lock().unwrap(); //#[allow_ci]
-                if let Err(e) = f.rewind() {
-                    debug!("Failed to rewind measured boot file: {}", e);
-                    return HttpResponse::InternalServerError().json(
-                        JsonWrapper::error(
-                            500,
-                            "Unable to retrieve quote".to_string(),
-                        ),
-                    );
-                }
-                mb_measurement_list = match f.read_to_end(&mut ml) {
-                    Ok(_) => Some(general_purpose::STANDARD.encode(ml)),
-                    Err(e) => {
-                        warn!("Could not read TPM2 event log: {}", e);
-                        None
-                    }
-                };
+                mb_measurement_list =
+                    read_measuredboot_log(measuredboot_ml_file);
             }
         }
         Err(e) => {
             debug!("Unable to check PCR mask: {:?}", e);
-            return HttpResponse::InternalServerError().json(
-                JsonWrapper::error(
-                    500,
-                    "Unable to retrieve quote".to_string(),
-                ),
-            );
+            return Err(QuoteError::Failed(
+                "Unable to retrieve quote".to_string(),
+            ));
         }
         _ => (),
     }
 
-    // Generate the measurement list
-    let (ima_measurement_list, ima_measurement_list_entry, num_entries) =
+    // Generate the measurement list, and if enabled the IMA PCR aggregate to
+    // let the verifier detect mismatches between the list and the PCR
+    // without replaying the whole list
+    let (ima_measurement_list, ima_measurement_list_entry, ima_pcr_aggregate) =
         if let Some(ima_file) = &data.ima_ml_file {
             let mut ima_ml = data.ima_ml.lock().unwrap(); //#[allow_ci]
-            match ima_ml.read(
-                &mut ima_file.lock().unwrap(), //#[allow_ci]
-                nth_entry,
-            ) {
-                Ok(result) => {
-                    (Some(result.0), Some(result.1), Some(result.2))
-                }
-                Err(e) => {
-                    debug!("Unable to read measurement list: {:?}", e);
-                    return HttpResponse::InternalServerError().json(
-                        JsonWrapper::error(
-                            500,
+            let mut ima_file = ima_file.lock().unwrap(); //#[allow_ci]
+            let (ml, nth_entry, num_entries) =
+                match ima_ml.read(&mut ima_file, nth_entry) {
+                    Ok(result) => result,
+                    Err(e) => {
+                        debug!("Unable to read measurement list: {:?}", e);
+                        return Err(QuoteError::Failed(
                             "Unable to retrieve quote".to_string(),
-                        ),
-                    );
+                        ));
+                    }
+                };
+            // The aggregate is best-effort, and omitted if the list cannot
+            // be replayed
+            let aggregate = if data.ima_pcr_aggregate {
+                match ima_ml.pcr_aggregate(
+                    &mut ima_file,
+                    data.hash_alg,
+                    &ml,
+                    nth_entry,
+                    num_entries,
+                ) {
+                    Ok(aggregate) => Some(hex::encode(aggregate)),
+                    Err(e) => {
+                        warn!(
+                            "Unable to compute the IMA PCR aggregate: {}",
+                            e
+                        );
+                        None
+                    }
                 }
-            }
+            } else {
+                None
+            };
+            (Some(ml), Some(nth_entry), aggregate)
         } else {
             (None, None, None)
         };
 
+    // Gather the system facts and sign them with the NK, which is bound to
+    // the quote through PCR 16
+    let (system_facts, system_facts_signature) =
+        if data.system_facts_command.is_empty() {
+            (None, None)
+        } else {
+            let signed = get_system_facts(&data.system_facts_command)
+                .and_then(|facts| {
+                    let signature =
+                        crypto::asym_sign(&data.priv_key(), &facts)?;
+                    Ok((facts, signature))
+                });
+            match signed {
+                Ok((facts, signature)) => (Some(facts), Some(signature)),
+                Err(e) => {
+                    warn!("Unable to collect system facts: {}", e);
+                    return Err(QuoteError::Failed(
+                        "Unable to collect system facts".to_string(),
+                    ));
+                }
+            }
+        };
+
     // Generate the final quote based on the ID quote
     let quote = KeylimeQuote {
         pubkey,
         ima_measurement_list,
         mb_measurement_list,
         ima_measurement_list_entry,
+        ima_pcr_aggregate,
+        system_facts,
+        system_facts_signature,
         ..id_quote
     };
 
-    let response = JsonWrapper::success(quote);
-    info!("GET integrity quote returning 200 response");
-    HttpResponse::Ok().json(response)
+    Ok(quote)
+}
+
+/// Generates a single integrity quote with a random nonce, including the
+/// public key and the whole measurement lists. The PCRs set in
+/// 'quote_pcr_selection' are quoted, or the IMA PCR if none is set.
+pub(crate) fn attest_once(
+    data: &QuoteData,
+) -> Result<Attestation, KeylimeError> {
+    let len = ATTEST_ONCE_NONCE_LEN
+        .clamp(*data.nonce_length.start(), *data.nonce_length.end());
+    let mut bytes = vec![0u8; len.div_ceil(2)];
+    openssl::rand::rand_bytes(&mut bytes)?;
+    let mut nonce = hex::encode(bytes);
+    nonce.truncate(len);
+
+    let mask = data.quote_pcr_mask.unwrap_or(1 << IMA_PCR);
+    let pubkey = crypto::pkey_pub_to_pem(&data.pub_key())?;
+
+    let quote = integrity_quote(data, &nonce, mask, Some(pubkey), 0)
+        .map_err(|e| match e {
+            QuoteError::Busy => KeylimeError::Other(
+                "Too many concurrent IMA measurement list requests"
+                    .to_string(),
+            ),
+            QuoteError::Failed(message) => KeylimeError::Other(message),
+        })?;
+
+    Ok(Attestation {
+        agent_id: data.agent_uuid.clone(),
+        nonce,
+        mask: format!("{mask:#x}"),
+        quote,
+    })
+}
+
+// This is the request from the cloud verifier polling for an integrity quote
+// generated in the background
+pub async fn quote_job(
+    req: HttpRequest,
+    job_id: web::Path<String>,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    let result = match &data.quote_jobs {
+        Some(jobs) => jobs.take(&job_id),
+        None => None,
+    };
+
+    match result {
+        None => {
+            warn!(
+                "Get quote job returning 404 response. Unknown job {}",
+                job_id
+            );
+            HttpResponse::NotFound().json(JsonWrapper::error(
+                404,
+                format!("Unknown quote job: {job_id}"),
+            ))
+        }
+        Some(None) => {
+            info!("Get quote job returning 202 response");
+            HttpResponse::Accepted()
+                .insert_header((
+                    http::header::RETRY_AFTER,
+                    IMA_REQUESTS_RETRY_AFTER.to_string(),
+                ))
+                .json(JsonWrapper {
+                    code: 202,
+                    status: String::from("Pending"),
+                    results: QuoteJobStatus {
+                        job_id: job_id.into_inner(),
+                    },
+                })
+        }
+        Some(Some(Ok(quote))) => {
+            info!("Get quote job returning 200 response");
+            HttpResponse::Ok().json(JsonWrapper::success(quote))
+        }
+        Some(Some(Err(e))) => e.into_response(&data.tpm_error_log),
+    }
+}
+
+// Registers the endpoint to poll for the quotes generated in the background,
+// if asynchronous quotes are enabled
+pub(crate) fn jobs_config(cfg: &mut web::ServiceConfig, enabled: bool) {
+    if enabled {
+        _ = cfg.service(
+            web::resource("/jobs/{job_id}").route(web::get().to(quote_job)),
+        );
+    }
 }
 
 #[cfg(feature = "testing")]
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{common::API_VERSION, crypto::testing::pkey_pub_from_pem};
+    use crate::{
+        common::API_VERSION, crypto::testing::pkey_pub_from_pem, firmware,
+    };
     use actix_web::{test, web, App};
+    use std::path::Path;
+    use tokio::sync::Semaphore;
 
     #[actix_rt::test]
     async fn test_identity() {
@@ -370,14 +875,23 @@ mod tests {
         assert!(
             pkey_pub_from_pem(&result.results.pubkey.unwrap()) //#[allow_ci]
                 .unwrap() //#[allow_ci]
-                .public_eq(&quotedata.pub_key)
+                .public_eq(&quotedata.pub_key())
         );
         assert!(result.results.quote.starts_with('r'));
+        assert!(result.results.firmware_version.is_none());
+        assert!(result.results.config_hash.is_none());
+        assert!(result.results.qualifying_data.is_none());
+
+        // The quoted PCR 16 is tagged with the SHA-256 bank
+        let pcr_values = result.results.pcr_values.unwrap(); //#[allow_ci]
+        assert_eq!(pcr_values.len(), 1);
+        let value = pcr_values[0].strip_prefix("sha256:16:").unwrap(); //#[allow_ci]
+        assert_eq!(value.len(), 64);
 
         let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
         tpm::testing::check_quote(
             context.as_mut(),
-            quotedata.ak_handle,
+            quotedata.ak_handle(),
             &result.results.quote,
             b"1234567890ABCDEFHIJ",
         )
@@ -385,18 +899,20 @@ mod tests {
     }
 
     #[actix_rt::test]
-    async fn test_integrity_pre() {
-        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+    async fn test_identity_pcr_banks() {
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        fixture.pcr_banks = vec![HashAlgorithm::Sha256, HashAlgorithm::Sha1];
+        let quotedata = web::Data::new(fixture);
         let mut app =
             test::init_service(App::new().app_data(quotedata.clone()).route(
-                &format!("/{API_VERSION}/quotes/integrity"),
-                web::get().to(integrity),
+                &format!("/{API_VERSION}/quotes/identity"),
+                web::get().to(identity),
             ))
             .await;
 
         let req = test::TestRequest::get()
             .uri(&format!(
-                "/{API_VERSION}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408000&partial=0",
+                "/{API_VERSION}/quotes/identity?nonce=1234567890ABCDEFHIJ",
             ))
             .to_request();
 
@@ -406,42 +922,594 @@ mod tests {
         let result: JsonWrapper<KeylimeQuote> =
             test::read_body_json(resp).await;
         assert_eq!(result.results.hash_alg.as_str(), "sha256");
-        assert_eq!(result.results.enc_alg.as_str(), "rsa");
-        assert_eq!(result.results.sign_alg.as_str(), "rsassa");
-        assert!(
-            pkey_pub_from_pem(&result.results.pubkey.unwrap()) //#[allow_ci]
-                .unwrap() //#[allow_ci]
-                .public_eq(&quotedata.pub_key)
+        assert_eq!(
+            result.results.pcr_banks,
+            Some(vec!["sha256".to_string(), "sha1".to_string()])
         );
 
-        if let Some(ima_mutex) = &quotedata.ima_ml_file {
-            let mut ima_ml_file = ima_mutex.lock().unwrap(); //#[allow_ci]
-            ima_ml_file.rewind().unwrap(); //#[allow_ci]
-            let mut ima_ml = String::new();
-            match ima_ml_file.read_to_string(&mut ima_ml) {
-                Ok(_) => {
-                    assert_eq!(
-                        result.results.ima_measurement_list.unwrap().as_str(), //#[allow_ci]
-                        ima_ml
-                    );
-                    assert!(result.results.quote.starts_with('r'));
+        // PCR 16 is quoted in both banks
+        let pcr_values = result.results.pcr_values.unwrap(); //#[allow_ci]
+        assert_eq!(pcr_values.len(), 2);
+        assert!(pcr_values.iter().any(|v| v.starts_with("sha256:16:")));
+        assert!(pcr_values.iter().any(|v| v.starts_with("sha1:16:")));
 
-                    let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
-                    tpm::testing::check_quote(
-                        context.as_mut(),
-                        quotedata.ak_handle,
-                        &result.results.quote,
-                        b"1234567890ABCDEFHIJ",
-                    )
-                    .expect("unable to verify quote");
-                }
-                Err(e) => panic!("Could not read IMA file: {e}"), //#[allow_ci]
-            }
-        } else {
+        let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
+        tpm::testing::check_quote(
+            context.as_mut(),
+            quotedata.ak_handle(),
+            &result.results.quote,
+            b"1234567890ABCDEFHIJ",
+        )
+        .expect("unable to verify quote");
+    }
+
+    #[actix_rt::test]
+    async fn test_pcr_banks() {
+        assert_eq!(pcr_banks(&[HashAlgorithm::Sha256]), None);
+        assert_eq!(
+            pcr_banks(&[HashAlgorithm::Sha1, HashAlgorithm::Sha256]),
+            Some(vec!["sha1".to_string(), "sha256".to_string()])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_nonce_validation() {
+        let length = 1..=tpm::MAX_NONCE_SIZE;
+        assert!(check_nonce("1234567890ABCDEFHIJ", &length).is_ok());
+        assert!(
+            check_nonce(&"a".repeat(tpm::MAX_NONCE_SIZE), &length).is_ok()
+        );
+        assert!(check_nonce("", &length).is_err());
+        assert!(check_nonce(&"a".repeat(tpm::MAX_NONCE_SIZE + 1), &length)
+            .is_err());
+        assert!(check_nonce("nonce-1", &length).is_err());
+        // Unicode alphanumeric characters are not accepted
+        assert!(check_nonce("nonceé", &length).is_err());
+
+        let length = 20..=20;
+        assert!(check_nonce(&"a".repeat(19), &length).is_err());
+        assert!(check_nonce(&"a".repeat(20), &length).is_ok());
+
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{API_VERSION}/quotes/identity"),
+                web::get().to(identity),
+            ))
+            .await;
+
+        for nonce in [String::new(), "a".repeat(tpm::MAX_NONCE_SIZE + 1)] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/{API_VERSION}/quotes/identity?nonce={nonce}"))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 400);
+
+            let result: JsonWrapper<serde_json::Value> =
+                test::read_body_json(resp).await;
+            assert_eq!(result.code, 400);
+        }
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{API_VERSION}/quotes/identity?nonce=1234567890ABCDEFHIJ"
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_metrics() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app = test::init_service(
+            App::new()
+                .app_data(quotedata.clone())
+                .route(
+                    &format!("/{API_VERSION}/quotes/identity"),
+                    web::get().to(identity),
+                )
+                .configure(|cfg| metrics::metrics_config(cfg, true)),
+        )
+        .await;
+
+        let identity_quotes = |body: &str| {
+            body.lines()
+                .find_map(|l| {
+                    l.strip_prefix(
+                        "keylime_agent_identity_quotes_served_total ",
+                    )
+                })
+                .map(|v| v.parse::<u64>().unwrap()) //#[allow_ci]
+        };
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let before =
+            identity_quotes(std::str::from_utf8(&body).unwrap()).unwrap(); //#[allow_ci]
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{API_VERSION}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let after =
+            identity_quotes(std::str::from_utf8(&body).unwrap()).unwrap(); //#[allow_ci]
+        assert_eq!(after, before + 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_firmware_version() {
+        let dmi_dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data/dmi");
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        fixture.firmware_version =
+            Some(firmware::read_firmware_version(&dmi_dir).unwrap()); //#[allow_ci]
+        let quotedata = web::Data::new(fixture);
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{API_VERSION}/quotes/identity"),
+                web::get().to(identity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{API_VERSION}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.results.firmware_version.as_deref(), Some("F.23"));
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_qualifying_data() {
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        fixture.debug_quotes = true;
+        let quotedata = web::Data::new(fixture);
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{API_VERSION}/quotes/identity"),
+                web::get().to(identity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{API_VERSION}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert_eq!(
+            result.results.qualifying_data,
+            Some(hex::encode(b"1234567890ABCDEFHIJ"))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_fixed_nonce() {
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        fixture.fixed_nonce = Some(b"FixedNonce0123456789".to_vec());
+        fixture.debug_quotes = true;
+        let quotedata = web::Data::new(fixture);
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{API_VERSION}/quotes/identity"),
+                web::get().to(identity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{API_VERSION}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+
+        // The quote includes the fixed nonce instead of the requested one
+        assert_eq!(
+            result.results.qualifying_data,
+            Some(hex::encode(b"FixedNonce0123456789"))
+        );
+        let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
+        tpm::testing::check_quote(
+            context.as_mut(),
+            quotedata.ak_handle(),
+            &result.results.quote,
+            b"FixedNonce0123456789",
+        )
+        .expect("unable to verify quote");
+        assert!(tpm::testing::check_quote(
+            context.as_mut(),
+            quotedata.ak_handle(),
+            &result.results.quote,
+            b"1234567890ABCDEFHIJ",
+        )
+        .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_pre() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{API_VERSION}/quotes/integrity"),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{API_VERSION}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408000&partial=0",
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.results.hash_alg.as_str(), "sha256");
+        assert_eq!(result.results.enc_alg.as_str(), "rsa");
+        assert_eq!(result.results.sign_alg.as_str(), "rsassa");
+        assert!(
+            pkey_pub_from_pem(&result.results.pubkey.unwrap()) //#[allow_ci]
+                .unwrap() //#[allow_ci]
+                .public_eq(&quotedata.pub_key())
+        );
+
+        if let Some(ima_mutex) = &quotedata.ima_ml_file {
+            let mut ima_ml_file = ima_mutex.lock().unwrap(); //#[allow_ci]
+            ima_ml_file.rewind().unwrap(); //#[allow_ci]
+            let mut ima_ml = String::new();
+            match ima_ml_file.read_to_string(&mut ima_ml) {
+                Ok(_) => {
+                    assert_eq!(
+                        result.results.ima_measurement_list.unwrap().as_str(), //#[allow_ci]
+                        ima_ml
+                    );
+                    assert!(result.results.quote.starts_with('r'));
+
+                    let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
+                    tpm::testing::check_quote(
+                        context.as_mut(),
+                        quotedata.ak_handle(),
+                        &result.results.quote,
+                        b"1234567890ABCDEFHIJ",
+                    )
+                    .expect("unable to verify quote");
+                }
+                Err(e) => panic!("Could not read IMA file: {e}"), //#[allow_ci]
+            }
+        } else {
             panic!("IMA file was None"); //#[allow_ci]
         }
     }
 
+    #[actix_rt::test]
+    async fn test_attest_once() {
+        let quotedata = QuoteData::fixture().unwrap(); //#[allow_ci]
+
+        let attestation = attest_once(&quotedata).unwrap(); //#[allow_ci]
+        assert_eq!(attestation.agent_id, quotedata.agent_uuid);
+        assert_eq!(attestation.mask, "0x400");
+        assert!(
+            check_nonce(&attestation.nonce, &quotedata.nonce_length).is_ok()
+        );
+        assert!(attestation.quote.ima_measurement_list.is_some());
+        assert!(
+            pkey_pub_from_pem(&attestation.quote.pubkey.unwrap()) //#[allow_ci]
+                .unwrap() //#[allow_ci]
+                .public_eq(&quotedata.pub_key())
+        );
+
+        // The quote is signed over the generated nonce
+        let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
+        tpm::testing::check_quote(
+            context.as_mut(),
+            quotedata.ak_handle(),
+            &attestation.quote.quote,
+            attestation.nonce.as_bytes(),
+        )
+        .expect("unable to verify quote");
+    }
+
+    #[actix_rt::test]
+    async fn test_quote_error_message() {
+        use keylime::tpm::TpmError;
+        use tss_esapi::constants::response_code::Tss2ResponseCode;
+
+        // TPM_RC_VALUE for the first parameter of TPM2_Quote
+        let e = TpmError::from(tss_esapi::Error::Tss2Error(
+            Tss2ResponseCode::from(0x1c4),
+        ))
+        .in_command("TPM2_Quote");
+        let log = RepeatedLog::new(Duration::from_secs(60));
+        assert_eq!(
+            quote_error_message(&e, &log),
+            "Unable to retrieve quote: TPM2_Quote failed with TPM_RC 0x000001c4"
+        );
+
+        let e = TpmError::Other("PCR mismatch".to_string());
+        assert_eq!(quote_error_message(&e, &log), "Unable to retrieve quote");
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_schema() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{API_VERSION}/quotes/integrity"),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{API_VERSION}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408000&partial=1",
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        // The verifier looks up the fields by name, so check the exact keys
+        // of the response rather than deserializing into KeylimeQuote
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let results = body["results"].as_object().unwrap(); //#[allow_ci]
+        let mut keys: Vec<&str> =
+            results.keys().map(|k| k.as_str()).collect();
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            vec![
+                "enc_alg",
+                "hash_alg",
+                "ima_measurement_list",
+                "ima_measurement_list_entry",
+                "pcr_values",
+                "quote",
+                "sign_alg",
+            ]
+        );
+        assert_eq!(results["hash_alg"], quotedata.hash_alg.to_string());
+        assert_eq!(results["enc_alg"], quotedata.enc_alg.to_string());
+        assert_eq!(results["sign_alg"], quotedata.sign_alg.to_string());
+        assert!(results["quote"].as_str().unwrap().starts_with('r')); //#[allow_ci]
+        assert!(results["ima_measurement_list"].is_string());
+        assert!(results["ima_measurement_list_entry"].is_u64());
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_pcr_aggregate() {
+        use keylime::ima::{Encode, Entry};
+        use openssl::hash::{hash, MessageDigest};
+        use std::convert::TryFrom;
+
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        fixture.ima_pcr_aggregate = true;
+        let quotedata = web::Data::new(fixture);
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{API_VERSION}/quotes/integrity"),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{API_VERSION}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408000&partial=0",
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        let aggregate = result.results.ima_pcr_aggregate.unwrap(); //#[allow_ci]
+
+        // Replay the fixture log into the SHA-256 bank
+        let ml = result.results.ima_measurement_list.unwrap(); //#[allow_ci]
+        let mut pcr = vec![0u8; 32];
+        for line in ml.lines() {
+            let entry = Entry::try_from(line).unwrap(); //#[allow_ci]
+            let mut template_data = Vec::new();
+            entry.event_data.encode(&mut template_data).unwrap(); //#[allow_ci]
+            let digest =
+                hash(MessageDigest::sha256(), &template_data).unwrap(); //#[allow_ci]
+            pcr = hash(
+                MessageDigest::sha256(),
+                &[pcr, digest.to_vec()].concat(),
+            )
+            .unwrap() //#[allow_ci]
+            .to_vec();
+        }
+        assert_eq!(aggregate, hex::encode(pcr));
+    }
+
+    #[actix_rt::test]
+    async fn test_quote_jobs_expiry() {
+        let jobs = QuoteJobs::default();
+        let completed = jobs.submit().unwrap(); //#[allow_ci]
+        jobs.complete(
+            &completed,
+            Err(QuoteError::Failed("failed".to_string())),
+        );
+        let pending = jobs.submit().unwrap(); //#[allow_ci]
+        for _ in 2..MAX_QUOTE_JOBS {
+            assert!(jobs.submit().is_some());
+        }
+        assert!(jobs.submit().is_none());
+
+        // The expired jobs are dropped, whether complete or still pending
+        let expired = Instant::now()
+            .checked_sub(Duration::from_secs(QUOTE_JOB_EXPIRY))
+            .unwrap(); //#[allow_ci]
+        let mut entries = jobs.jobs.lock().unwrap(); //#[allow_ci]
+        for (created, _) in entries.values_mut() {
+            *created = expired;
+        }
+        drop(entries);
+        assert!(jobs.submit().is_some());
+        assert!(jobs.take(&completed).is_none());
+        assert!(jobs.take(&pending).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_async() {
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        fixture.quote_jobs = Some(QuoteJobs::default());
+        let quotedata = web::Data::new(fixture);
+        let mut app = test::init_service(
+            App::new().app_data(quotedata.clone()).service(
+                web::scope(&format!("/{API_VERSION}/quotes"))
+                    .route("/integrity", web::get().to(integrity))
+                    .configure(|cfg| jobs_config(cfg, true)),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{API_VERSION}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408000&partial=0",
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 202);
+        let result: JsonWrapper<QuoteJobStatus> =
+            test::read_body_json(resp).await;
+        let job_uri =
+            format!("/{API_VERSION}/quotes/jobs/{}", result.results.job_id);
+
+        // Poll until the quote is generated
+        let mut quote = None;
+        for _ in 0..100 {
+            let req = test::TestRequest::get().uri(&job_uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            if resp.status() == 202 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+            assert!(resp.status().is_success());
+            let result: JsonWrapper<KeylimeQuote> =
+                test::read_body_json(resp).await;
+            quote = Some(result.results);
+            break;
+        }
+
+        let quote = quote.unwrap(); //#[allow_ci]
+        assert!(quote.quote.starts_with('r'));
+        assert!(quote.pubkey.is_some());
+        assert!(quote.ima_measurement_list.is_some());
+
+        // The result is removed once retrieved
+        let req = test::TestRequest::get().uri(&job_uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 404);
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_system_facts() {
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        fixture.system_facts_command =
+            "echo kernel=test; printf 'pkg=\\033x\\n'".to_string();
+        let quotedata = web::Data::new(fixture);
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{API_VERSION}/quotes/integrity"),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{API_VERSION}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408000&partial=0",
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        let facts = result.results.system_facts.unwrap(); //#[allow_ci]
+        assert_eq!(facts, "kernel=test\npkg=x\n");
+        assert!(crypto::asym_verify(
+            &quotedata.pub_key(),
+            &facts,
+            &result.results.system_facts_signature.unwrap(), //#[allow_ci]
+        )
+        .unwrap()); //#[allow_ci]
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_ima_requests_limit() {
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        fixture.ima_ml_requests = Some(Semaphore::new(1));
+        let quotedata = web::Data::new(fixture);
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{API_VERSION}/quotes/integrity"),
+                web::get().to(integrity),
+            ))
+            .await;
+        let uri = format!(
+            "/{API_VERSION}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408000&partial=0",
+        );
+
+        // Hold the only permit, simulating a request in progress
+        let semaphore = quotedata.ima_ml_requests.as_ref().unwrap(); //#[allow_ci]
+        let permit = semaphore.try_acquire().unwrap(); //#[allow_ci]
+
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            resp.headers().get(http::header::RETRY_AFTER).unwrap(), //#[allow_ci]
+            &IMA_REQUESTS_RETRY_AFTER.to_string()
+        );
+
+        // Once the permit is released, the request succeeds
+        drop(permit);
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_rt::test]
+    async fn test_get_system_facts_size() {
+        let facts = get_system_facts(&format!(
+            "head -c {} /dev/zero | tr '\\0' a",
+            MAX_SYSTEM_FACTS_SIZE + 100
+        ))
+        .unwrap(); //#[allow_ci]
+        assert_eq!(facts.len(), MAX_SYSTEM_FACTS_SIZE);
+
+        assert!(get_system_facts("false").is_err());
+    }
+
     #[actix_rt::test]
     async fn test_integrity_post() {
         let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
@@ -488,13 +1556,125 @@ mod tests {
         let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
         tpm::testing::check_quote(
             context.as_mut(),
-            quotedata.ak_handle,
+            quotedata.ak_handle(),
             &result.results.quote,
             b"1234567890ABCDEFHIJ",
         )
         .expect("unable to verify quote");
     }
 
+    #[actix_rt::test]
+    async fn test_integrity_ima_ml_entry() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{API_VERSION}/quotes/integrity"),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        let ima_ml = read_to_string(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("test-data/ima/ascii_runtime_measurements"),
+        )
+        .unwrap(); //#[allow_ci]
+        let entries: Vec<&str> = ima_ml.split_inclusive('\n').collect();
+
+        // Only the entries starting from the given offset are returned. An
+        // offset past the end of the list returns the whole list
+        for (entry, expected) in [(10, 10), (entries.len() + 5, 0)] {
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/{API_VERSION}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408000&partial=1&ima_ml_entry={entry}",
+                ))
+                .to_request();
+
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success());
+
+            let result: JsonWrapper<KeylimeQuote> =
+                test::read_body_json(resp).await;
+            assert_eq!(
+                result.results.ima_measurement_list.as_deref(),
+                Some(entries[expected..].concat().as_str())
+            );
+            assert_eq!(
+                result.results.ima_measurement_list_entry,
+                Some(expected as u64)
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_select_pcr_mask() {
+        // Without a configured selection, the requested mask is used
+        assert_eq!(select_pcr_mask(None, 0x408), 0x408);
+
+        // The requested mask is limited to the configured selection
+        assert_eq!(select_pcr_mask(Some(0xff), 0x408), 0x8);
+        assert_eq!(select_pcr_mask(Some(0x4ff), 0x408), 0x408);
+        assert_eq!(select_pcr_mask(Some(0xff), 0x400), 0);
+    }
+
+    #[test]
+    async fn test_read_measuredboot_log() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/measuredboot/binary_bios_measurements");
+        let expected = read(&path).unwrap(); //#[allow_ci]
+        let file = Mutex::new(File::open(&path).unwrap()); //#[allow_ci]
+
+        // The log is read from the beginning every time
+        for _ in 0..2 {
+            let encoded = read_measuredboot_log(&file).unwrap(); //#[allow_ci]
+            let decoded = general_purpose::STANDARD.decode(encoded).unwrap(); //#[allow_ci]
+            assert_eq!(decoded, expected);
+        }
+
+        // An unreadable log is omitted
+        let temp_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let unreadable = std::fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(temp_dir.path().join("log"))
+            .unwrap(); //#[allow_ci]
+        assert!(read_measuredboot_log(&Mutex::new(unreadable)).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_measuredboot_ml() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/measuredboot/binary_bios_measurements");
+        let mut quotedata = QuoteData::fixture().unwrap(); //#[allow_ci]
+        quotedata.measuredboot_ml_file =
+            Some(Mutex::new(File::open(&path).unwrap())); //#[allow_ci]
+        let mut app = test::init_service(
+            App::new().app_data(web::Data::new(quotedata)).route(
+                &format!("/{API_VERSION}/quotes/integrity"),
+                web::get().to(integrity),
+            ),
+        )
+        .await;
+
+        // The log is returned only if PCR 0 is included in the mask
+        for (mask, included) in [("0x408001", true), ("0x408000", false)] {
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/{API_VERSION}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask={mask}&partial=1",
+                ))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success());
+
+            let result: JsonWrapper<KeylimeQuote> =
+                test::read_body_json(resp).await;
+            let decoded = result
+                .results
+                .mb_measurement_list
+                .map(|ml| general_purpose::STANDARD.decode(ml).unwrap()); //#[allow_ci]
+            assert_eq!(decoded, included.then(|| read(&path).unwrap())); //#[allow_ci]
+        }
+    }
+
     #[actix_rt::test]
     async fn test_missing_ima_file() {
         let mut quotedata = QuoteData::fixture().unwrap(); //#[allow_ci]
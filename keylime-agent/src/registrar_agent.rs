@@ -3,9 +3,21 @@ use crate::error::Error;
 use crate::common::API_VERSION;
 use crate::serialization::*;
 use log::*;
-use openssl::x509::X509;
+use openssl::{
+    pkey::{PKey, Private},
+    sha::sha256,
+    x509::X509,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::Number;
+use std::{
+    fs,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::sleep;
 
 fn is_empty(buf: &[u8]) -> bool {
     buf.is_empty()
@@ -25,9 +37,13 @@ struct Register<'a> {
     #[serde(skip_serializing_if = "Option::is_none")]
     mtls_cert: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    agent_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     ip: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     port: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    config_hash: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -44,6 +60,18 @@ struct Activate<'a> {
 #[derive(Debug, Serialize, Deserialize)]
 struct ActivateResponseResults {}
 
+#[derive(Debug, Serialize, Deserialize)]
+struct AgentResponseResults {
+    #[serde(default, deserialize_with = "deserialize_maybe_base64")]
+    ek_tpm: Option<Vec<u8>>,
+    #[serde(default, deserialize_with = "deserialize_maybe_base64")]
+    aik_tpm: Option<Vec<u8>>,
+    #[serde(default)]
+    ip: Option<String>,
+    #[serde(default)]
+    port: Option<u32>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Response<T> {
     code: Number,
@@ -51,20 +79,237 @@ pub struct Response<T> {
     results: T,
 }
 
+/// Retry policy for the requests to the registrar
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RetryPolicy {
+    pub max_attempts: u32,
+    pub interval: Duration,
+    pub max_interval: Duration,
+}
+
+impl RetryPolicy {
+    // The delay before the given retry doubles on every attempt, starting
+    // from the base interval and capped by the maximum interval
+    fn delay(&self, retry: u32) -> Duration {
+        self.interval
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_interval)
+    }
+}
+
+/// Runs the request to the registrar, retrying with exponential backoff
+/// while it fails with a retryable error, up to the maximum number of
+/// attempts. Client error responses fail immediately.
+pub(crate) async fn with_retries<T, F, Fut>(
+    policy: &RetryPolicy,
+    request: &str,
+    mut op: F,
+) -> crate::error::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = crate::error::Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(e) if e.is_retryable() && attempt < policy.max_attempts => {
+                let delay = policy.delay(attempt);
+                warn!(
+                    "{} failed (attempt {}/{}): {}. Retrying in {:?}",
+                    request, attempt, policy.max_attempts, e, delay
+                );
+                sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Status code returned by the registrar when the auth tag sent on activation
+// does not match the one computed from the secret in the keyblob
+const AUTH_TAG_REJECTED: u16 = 400;
+
+/// Whether the registrar rejected the activation because of a wrong auth
+/// tag, meaning the credential activation produced a wrong secret, which
+/// happens with an EK mismatch or a stale keyblob. Only the rejection of the
+/// activation request matches, not the failures of the other requests.
+pub(crate) fn is_auth_tag_rejected(e: &Error) -> bool {
+    matches!(e, Error::ActivationRejected { .. })
+}
+
+/// The TLS identity presented by the agent to the registrar, and the CA
+/// certificates used to verify the registrar server certificate
+///
+/// When the CA certificates are read from a file, the file is checked before
+/// each request, and the client is rebuilt when it changed, so that a
+/// rotated registrar CA is picked up without restarting the agent
+#[derive(Debug, Clone)]
+pub(crate) struct RegistrarTls {
+    pub cert: X509,
+    pub key: PKey<Private>,
+    // The file the CA certificates are read from, if any
+    ca_path: Option<PathBuf>,
+    trust: Arc<Mutex<RegistrarTrust>>,
+}
+
+// The contents of the CA file and the client built with its certificates
+#[derive(Debug)]
+struct RegistrarTrust {
+    ca_pem: Vec<u8>,
+    client: reqwest::Client,
+}
+
+impl RegistrarTls {
+    /// Trusts the given CA certificates to verify the registrar
+    pub(crate) fn new(
+        cert: X509,
+        key: PKey<Private>,
+        ca_certs: &[X509],
+    ) -> crate::error::Result<Self> {
+        let client = build_registrar_client(&cert, &key, ca_certs)?;
+        Ok(RegistrarTls {
+            cert,
+            key,
+            ca_path: None,
+            trust: Arc::new(Mutex::new(RegistrarTrust {
+                ca_pem: Vec::new(),
+                client,
+            })),
+        })
+    }
+
+    /// Trusts the CA certificates in the file `ca_path` to verify the
+    /// registrar, reading the file again when it changes
+    pub(crate) fn with_ca_file(
+        cert: X509,
+        key: PKey<Private>,
+        ca_path: &Path,
+    ) -> crate::error::Result<Self> {
+        let ca_pem = fs::read(ca_path)?;
+        let ca_certs = parse_ca_certs(ca_path, &ca_pem)?;
+        let client = build_registrar_client(&cert, &key, &ca_certs)?;
+        Ok(RegistrarTls {
+            cert,
+            key,
+            ca_path: Some(ca_path.to_path_buf()),
+            trust: Arc::new(Mutex::new(RegistrarTrust { ca_pem, client })),
+        })
+    }
+
+    /// Reads the CA file again and rebuilds the client if it changed.
+    /// Returns whether the client was rebuilt.
+    pub(crate) fn refresh(&self) -> crate::error::Result<bool> {
+        let ca_path = match &self.ca_path {
+            Some(path) => path,
+            None => return Ok(false),
+        };
+
+        let ca_pem = fs::read(ca_path)?;
+        let mut trust = self.trust.lock().unwrap(); //#[allow_ci]
+        if ca_pem == trust.ca_pem {
+            return Ok(false);
+        }
+
+        let ca_certs = parse_ca_certs(ca_path, &ca_pem)?;
+        trust.client =
+            build_registrar_client(&self.cert, &self.key, &ca_certs)?;
+        trust.ca_pem = ca_pem;
+        info!(
+            "Registrar CA certificates in {} changed, rebuilt the registrar client",
+            ca_path.display()
+        );
+        Ok(true)
+    }
+
+    /// The client used for the requests to the registrar, refreshing the
+    /// trusted CA certificates first. If the CA file cannot be read, the
+    /// previous certificates are kept.
+    pub(crate) fn client(&self) -> reqwest::Client {
+        if let Err(e) = self.refresh() {
+            warn!("Could not refresh the registrar CA certificates: {}", e);
+        }
+        self.trust.lock().unwrap().client.clone() //#[allow_ci]
+    }
+}
+
+// Parses the CA certificates read from `path`, which must not be empty
+fn parse_ca_certs(
+    path: &Path,
+    pem: &[u8],
+) -> crate::error::Result<Vec<X509>> {
+    let ca_certs = X509::stack_from_pem(pem)?;
+    if ca_certs.is_empty() {
+        return Err(Error::Other(format!(
+            "No CA certificate found in {}",
+            path.display()
+        )));
+    }
+    Ok(ca_certs)
+}
+
+// Builds a client presenting the given certificate and key, which only
+// trusts the given CA certificates to verify the registrar
+fn build_registrar_client(
+    cert: &X509,
+    key: &PKey<Private>,
+    ca_certs: &[X509],
+) -> crate::error::Result<reqwest::Client> {
+    let identity = reqwest::Identity::from_pkcs8_pem(
+        &cert.to_pem()?,
+        &key.private_key_to_pem_pkcs8()?,
+    )?;
+    let mut builder = reqwest::Client::builder()
+        .tls_built_in_root_certs(false)
+        .identity(identity);
+    for ca_cert in ca_certs {
+        builder = builder.add_root_certificate(
+            reqwest::Certificate::from_pem(&ca_cert.to_pem()?)?,
+        );
+    }
+    Ok(builder.build()?)
+}
+
+// Gets the client used for the requests to the registrar
+fn registrar_client(
+    tls: Option<&RegistrarTls>,
+) -> crate::error::Result<reqwest::Client> {
+    match tls {
+        Some(tls) => Ok(tls.client()),
+        None => Ok(reqwest::Client::new()),
+    }
+}
+
+// IPv6 addresses are enclosed in brackets in the registrar URL
+fn registrar_address(
+    registrar_ip: &str,
+    registrar_port: u32,
+    tls: bool,
+) -> String {
+    let scheme = if tls { "https" } else { "http" };
+    if registrar_ip.contains(':') {
+        format!("{scheme}://[{registrar_ip}]:{registrar_port}")
+    } else {
+        format!("{scheme}://{registrar_ip}:{registrar_port}")
+    }
+}
+
 pub(crate) async fn do_activate_agent(
     registrar_ip: &str,
     registrar_port: u32,
     agent_uuid: &str,
     auth_tag: &str,
+    tls: Option<&RegistrarTls>,
 ) -> crate::error::Result<()> {
     let data = Activate { auth_tag };
 
     #[cfg(test)]
-    let addr = format!("http://{registrar_ip}:{registrar_port}");
+    let addr = registrar_address(registrar_ip, registrar_port, tls.is_some());
 
     #[cfg(not(test))]
     let addr = format!(
-        "http://{registrar_ip}:{registrar_port}/{API_VERSION}/agents/{agent_uuid}"
+        "{}/{API_VERSION}/agents/{agent_uuid}",
+        registrar_address(registrar_ip, registrar_port, tls.is_some())
     );
 
     info!(
@@ -72,7 +317,14 @@ pub(crate) async fn do_activate_agent(
         addr, agent_uuid
     );
 
-    let resp = reqwest::Client::new().put(&addr).json(&data).send().await?;
+    let resp = registrar_client(tls)?.put(&addr).json(&data).send().await?;
+
+    if resp.status().as_u16() == AUTH_TAG_REJECTED {
+        return Err(Error::ActivationRejected {
+            addr,
+            code: AUTH_TAG_REJECTED,
+        });
+    }
 
     if !resp.status().is_success() {
         return Err(Error::Registrar {
@@ -86,18 +338,102 @@ pub(crate) async fn do_activate_agent(
     Ok(())
 }
 
+// Compares the agent record stored by the registrar with the data sent on
+// registration, returning the fields that do not match. Empty values were
+// not sent, and are not compared.
+fn registration_discrepancies(
+    record: &AgentResponseResults,
+    ek_tpm: &[u8],
+    aik_tpm: &[u8],
+    ip: &str,
+    port: u32,
+) -> Vec<String> {
+    let mut discrepancies = Vec::new();
+
+    if !ek_tpm.is_empty() && record.ek_tpm.as_deref() != Some(ek_tpm) {
+        discrepancies.push("ek_tpm".to_string());
+    }
+    if record.aik_tpm.as_deref() != Some(aik_tpm) {
+        discrepancies.push("aik_tpm".to_string());
+    }
+    if !ip.is_empty() && record.ip.as_deref() != Some(ip) {
+        discrepancies.push(format!(
+            "ip (sent {ip}, stored {})",
+            record.ip.as_deref().unwrap_or("none")
+        ));
+    }
+    if record.port != Some(port) {
+        discrepancies.push(format!(
+            "port (sent {port}, stored {})",
+            record
+                .port
+                .map_or_else(|| "none".to_string(), |p| p.to_string())
+        ));
+    }
+
+    discrepancies
+}
+
+/// Fetches the agent record from the registrar and verifies that the stored
+/// EK, AK and contact address match the ones sent on registration. The
+/// discrepancies found are logged and returned.
 #[allow(clippy::too_many_arguments)]
-pub(crate) async fn do_register_agent(
+pub(crate) async fn do_verify_registration(
     registrar_ip: &str,
     registrar_port: u32,
     agent_uuid: &str,
     ek_tpm: &[u8],
-    ekcert: Option<Vec<u8>>,
     aik_tpm: &[u8],
+    ip: &str,
+    port: u32,
+    tls: Option<&RegistrarTls>,
+) -> crate::error::Result<Vec<String>> {
+    #[cfg(test)]
+    let addr = registrar_address(registrar_ip, registrar_port, tls.is_some());
+
+    #[cfg(not(test))]
+    let addr = format!(
+        "{}/{API_VERSION}/agents/{agent_uuid}",
+        registrar_address(registrar_ip, registrar_port, tls.is_some())
+    );
+
+    info!("Requesting agent record from {} for {}", addr, agent_uuid);
+
+    let resp = registrar_client(tls)?.get(&addr).send().await?;
+
+    if !resp.status().is_success() {
+        return Err(Error::Registrar {
+            addr,
+            code: resp.status().as_u16(),
+        });
+    }
+
+    let resp: Response<AgentResponseResults> = resp.json().await?;
+
+    let discrepancies =
+        registration_discrepancies(&resp.results, ek_tpm, aik_tpm, ip, port);
+    for field in &discrepancies {
+        warn!(
+            "Registrar record for agent {} does not match the registration data: {}",
+            agent_uuid, field
+        );
+    }
+
+    Ok(discrepancies)
+}
+
+// Builds the registration request data
+#[allow(clippy::too_many_arguments)]
+fn register_data<'a>(
+    agent_name: &str,
+    ek_tpm: &'a [u8],
+    ekcert: Option<Vec<u8>>,
+    aik_tpm: &'a [u8],
     mtls_cert_x509: Option<&X509>,
     ip: &str,
     port: u32,
-) -> crate::error::Result<Vec<u8>> {
+    config_hash: Option<&str>,
+) -> crate::error::Result<Register<'a>> {
     let mtls_cert = match mtls_cert_x509 {
         Some(cert) => Some(String::from_utf8(cert.to_pem()?)?),
         None => Some("disabled".to_string()),
@@ -109,21 +445,93 @@ pub(crate) async fn do_register_agent(
         Some(ip.to_string())
     };
 
-    let data = Register {
+    let agent_name = if agent_name.is_empty() {
+        None
+    } else {
+        Some(agent_name.to_string())
+    };
+
+    Ok(Register {
         ekcert,
         ek_tpm,
         aik_tpm,
         mtls_cert,
+        agent_name,
         ip,
         port: Some(port),
-    };
+        config_hash: config_hash.map(String::from),
+    })
+}
+
+/// Calculates the SHA-256 digest of the registration request for the agent,
+/// including the registrar address. If the digest of two registrations
+/// match, the second one would not change the registrar record.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn registration_digest(
+    registrar_ip: &str,
+    registrar_port: u32,
+    agent_uuid: &str,
+    agent_name: &str,
+    ek_tpm: &[u8],
+    ekcert: Option<Vec<u8>>,
+    aik_tpm: &[u8],
+    mtls_cert_x509: Option<&X509>,
+    ip: &str,
+    port: u32,
+    config_hash: Option<&str>,
+) -> crate::error::Result<String> {
+    let data = register_data(
+        agent_name,
+        ek_tpm,
+        ekcert,
+        aik_tpm,
+        mtls_cert_x509,
+        ip,
+        port,
+        config_hash,
+    )?;
+    let request = serde_json::to_vec(&(
+        registrar_ip,
+        registrar_port,
+        agent_uuid,
+        data,
+    ))?;
+    Ok(hex::encode(sha256(&request)))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn do_register_agent(
+    registrar_ip: &str,
+    registrar_port: u32,
+    agent_uuid: &str,
+    agent_name: &str,
+    ek_tpm: &[u8],
+    ekcert: Option<Vec<u8>>,
+    aik_tpm: &[u8],
+    mtls_cert_x509: Option<&X509>,
+    ip: &str,
+    port: u32,
+    config_hash: Option<&str>,
+    tls: Option<&RegistrarTls>,
+) -> crate::error::Result<Vec<u8>> {
+    let data = register_data(
+        agent_name,
+        ek_tpm,
+        ekcert,
+        aik_tpm,
+        mtls_cert_x509,
+        ip,
+        port,
+        config_hash,
+    )?;
 
     #[cfg(test)]
-    let addr = format!("http://{registrar_ip}:{registrar_port}");
+    let addr = registrar_address(registrar_ip, registrar_port, tls.is_some());
 
     #[cfg(not(test))]
     let addr = format!(
-        "http://{registrar_ip}:{registrar_port}/{API_VERSION}/agents/{agent_uuid}"
+        "{}/{API_VERSION}/agents/{agent_uuid}",
+        registrar_address(registrar_ip, registrar_port, tls.is_some())
     );
 
     info!(
@@ -131,7 +539,7 @@ pub(crate) async fn do_register_agent(
         addr, agent_uuid
     );
 
-    let resp = reqwest::Client::new()
+    let resp = registrar_client(tls)?
         .post(&addr)
         .json(&data)
         .send()
@@ -153,14 +561,40 @@ pub(crate) async fn do_register_agent(
     }
 }
 
-#[cfg(feature = "testing")]
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::client_cert::{self, ClientCertificate};
     use crate::crypto;
-    use wiremock::matchers::{any, method};
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    #[cfg(feature = "testing")]
+    use base64::{engine::general_purpose, Engine as _};
+    use openssl::{
+        asn1::Asn1Time,
+        hash::MessageDigest,
+        nid::Nid,
+        x509::{extension::SubjectAlternativeName, X509Name},
+    };
+    #[cfg(feature = "testing")]
+    use serde_json::json;
+    #[cfg(feature = "testing")]
+    use wiremock::matchers::{any, body_partial_json, method};
+    #[cfg(feature = "testing")]
     use wiremock::{Mock, MockServer, ResponseTemplate};
 
+    #[test]
+    fn test_registrar_address() {
+        assert_eq!(
+            registrar_address("127.0.0.1", 8890, false),
+            "http://127.0.0.1:8890"
+        );
+        assert_eq!(
+            registrar_address("::1", 8891, true),
+            "https://[::1]:8891"
+        );
+    }
+
+    #[cfg(feature = "testing")]
     #[actix_rt::test]
     async fn mock_register_agent_ok() {
         let response: Response<RegisterResponseResults> = Response {
@@ -171,6 +605,7 @@ mod tests {
 
         let mock_server = MockServer::start().await;
         let mock = Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "config_hash": "abcd" })))
             .respond_with(ResponseTemplate::new(200).set_body_json(response));
         mock_server.register(mock).await;
 
@@ -192,17 +627,46 @@ mod tests {
             ip,
             port,
             "uuid",
+            "name",
             &mock_data,
             Some(mock_data.to_vec()),
             &mock_data,
             Some(&cert),
             "",
             0,
+            Some("abcd"),
+            None,
         )
         .await;
         assert!(response.is_ok());
     }
 
+    #[test]
+    fn test_registration_digest() {
+        let mock_data = [0u8; 1];
+        let digest = |ip: &str, config_hash: &str| {
+            registration_digest(
+                "127.0.0.1",
+                8890,
+                "uuid",
+                "name",
+                &mock_data,
+                None,
+                &mock_data,
+                None,
+                ip,
+                9002,
+                Some(config_hash),
+            )
+            .unwrap() //#[allow_ci]
+        };
+
+        assert_eq!(digest("10.0.0.1", "abcd"), digest("10.0.0.1", "abcd"));
+        assert_ne!(digest("10.0.0.1", "abcd"), digest("10.0.0.2", "abcd"));
+        assert_ne!(digest("10.0.0.1", "abcd"), digest("10.0.0.1", "abce"));
+    }
+
+    #[cfg(feature = "testing")]
     #[actix_rt::test]
     async fn mock_register_agent_ok_without_ekcert() {
         let response: Response<RegisterResponseResults> = Response {
@@ -211,8 +675,10 @@ mod tests {
             results: RegisterResponseResults { blob: None },
         };
 
+        // The agent is registered with the EK public key only
         let mock_server = MockServer::start().await;
         let mock = Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "ekcert": null })))
             .respond_with(ResponseTemplate::new(200).set_body_json(response));
         mock_server.register(mock).await;
 
@@ -234,17 +700,21 @@ mod tests {
             ip,
             port,
             "uuid",
+            "name",
             &mock_data,
             None,
             &mock_data,
             Some(&cert),
             "",
             0,
+            None,
+            None,
         )
         .await;
         assert!(response.is_ok());
     }
 
+    #[cfg(feature = "testing")]
     #[actix_rt::test]
     async fn mock_register_agent_err() {
         let response: Response<RegisterResponseResults> = Response {
@@ -272,18 +742,266 @@ mod tests {
             ip,
             port,
             "uuid",
+            "name",
             &mock_data,
             Some(mock_data.to_vec()),
             &mock_data,
             Some(&cert),
             "",
             0,
+            None,
+            None,
         )
         .await;
         assert!(response.is_err());
         assert_eq!(response.err().unwrap().http_code().unwrap(), 404); //#[allow_ci]
     }
 
+    #[test]
+    fn test_retry_policy_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            interval: Duration::from_secs(1),
+            max_interval: Duration::from_secs(5),
+        };
+
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(3), Duration::from_secs(4));
+        assert_eq!(policy.delay(4), Duration::from_secs(5));
+        assert_eq!(policy.delay(100), Duration::from_secs(5));
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn mock_register_agent_retry() {
+        let response: Response<RegisterResponseResults> = Response {
+            code: 200.into(),
+            status: "OK".to_string(),
+            results: RegisterResponseResults { blob: None },
+        };
+
+        let mock_server = MockServer::start().await;
+        // The registrar is unavailable for the first two requests
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let uri = mock_server.uri();
+        let uri = uri.split("//").collect::<Vec<&str>>()[1]
+            .split(':')
+            .collect::<Vec<&str>>();
+        assert_eq!(uri.len(), 2);
+
+        let ip = uri[0];
+        let port = uri[1].parse().unwrap(); //#[allow_ci]
+
+        let mock_data = [0u8; 1];
+        let register = || {
+            do_register_agent(
+                ip,
+                port,
+                "uuid",
+                "name",
+                &mock_data,
+                Some(mock_data.to_vec()),
+                &mock_data,
+                None,
+                "",
+                0,
+                None,
+                None,
+            )
+        };
+
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            interval: Duration::from_millis(10),
+            max_interval: Duration::from_millis(20),
+        };
+
+        // Not enough attempts to get past the failures
+        let response = with_retries(
+            &RetryPolicy {
+                max_attempts: 1,
+                ..policy
+            },
+            "Registration",
+            register,
+        )
+        .await;
+        assert_eq!(response.err().unwrap().http_code().unwrap(), 503); //#[allow_ci]
+
+        let response = with_retries(&policy, "Registration", register).await;
+        assert!(response.is_ok());
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn mock_activate_agent_no_retry() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .respond_with(ResponseTemplate::new(400))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let uri = mock_server.uri();
+        let uri = uri.split("//").collect::<Vec<&str>>()[1]
+            .split(':')
+            .collect::<Vec<&str>>();
+        assert_eq!(uri.len(), 2);
+
+        let ip = uri[0];
+        let port = uri[1].parse().unwrap(); //#[allow_ci]
+
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            interval: Duration::from_millis(10),
+            max_interval: Duration::from_millis(20),
+        };
+
+        // Client errors are not retried
+        let response = with_retries(&policy, "Activation", || {
+            do_activate_agent(ip, port, "uuid", "tag", None)
+        })
+        .await;
+        assert_eq!(response.err().unwrap().http_code().unwrap(), 400); //#[allow_ci]
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn mock_activate_agent_auth_tag_rejected() {
+        let response: Response<ActivateResponseResults> = Response {
+            code: 200.into(),
+            status: "OK".to_string(),
+            results: ActivateResponseResults {},
+        };
+
+        // The registrar accepts only the auth tag computed from the
+        // regenerated AK
+        let mock_server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(body_partial_json(json!({"auth_tag": "regenerated"})))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("PUT"))
+            .respond_with(ResponseTemplate::new(400))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let uri = mock_server.uri();
+        let uri = uri.split("//").collect::<Vec<&str>>()[1]
+            .split(':')
+            .collect::<Vec<&str>>();
+        assert_eq!(uri.len(), 2);
+
+        let ip = uri[0];
+        let port = uri[1].parse().unwrap(); //#[allow_ci]
+
+        let response =
+            do_activate_agent(ip, port, "uuid", "stale", None).await;
+        assert!(is_auth_tag_rejected(&response.err().unwrap())); //#[allow_ci]
+
+        let response =
+            do_activate_agent(ip, port, "uuid", "regenerated", None).await;
+        assert!(response.is_ok());
+
+        // Other failures, including a bad request on registration, are not
+        // reported as a rejected auth tag
+        assert!(!is_auth_tag_rejected(&Error::Registrar {
+            addr: "addr".to_string(),
+            code: 404
+        }));
+        assert!(!is_auth_tag_rejected(&Error::Registrar {
+            addr: "addr".to_string(),
+            code: 400
+        }));
+        assert!(!is_auth_tag_rejected(&Error::InvalidRequest));
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn mock_verify_registration() {
+        let ek_tpm = [1u8; 4];
+        let aik_tpm = [2u8; 4];
+
+        let mock_server = MockServer::start().await;
+        let mock = Mock::given(method("GET")).respond_with(
+            ResponseTemplate::new(200).set_body_json(json!({
+                "code": 200,
+                "status": "Success",
+                "results": {
+                    "ek_tpm": general_purpose::STANDARD.encode(ek_tpm),
+                    "aik_tpm": general_purpose::STANDARD.encode([3u8; 4]),
+                    "ip": "127.0.0.1",
+                    "port": 9002,
+                    "regcount": 1
+                }
+            })),
+        );
+        mock_server.register(mock).await;
+
+        let uri = mock_server.uri();
+        let uri = uri.split("//").collect::<Vec<&str>>()[1]
+            .split(':')
+            .collect::<Vec<&str>>();
+        assert_eq!(uri.len(), 2);
+
+        let ip = uri[0];
+        let port = uri[1].parse().unwrap(); //#[allow_ci]
+
+        let discrepancies = do_verify_registration(
+            ip,
+            port,
+            "uuid",
+            &ek_tpm,
+            &aik_tpm,
+            "127.0.0.1",
+            9002,
+            None,
+        )
+        .await
+        .unwrap(); //#[allow_ci]
+        assert_eq!(discrepancies, vec!["aik_tpm".to_string()]);
+
+        let discrepancies = do_verify_registration(
+            ip, port, "uuid", &ek_tpm, &[3u8; 4], "10.0.0.1", 9002, None,
+        )
+        .await
+        .unwrap(); //#[allow_ci]
+        assert_eq!(
+            discrepancies,
+            vec!["ip (sent 10.0.0.1, stored 127.0.0.1)".to_string()]
+        );
+
+        let discrepancies = do_verify_registration(
+            ip,
+            port,
+            "uuid",
+            &ek_tpm,
+            &[3u8; 4],
+            "127.0.0.1",
+            9002,
+            None,
+        )
+        .await
+        .unwrap(); //#[allow_ci]
+        assert!(discrepancies.is_empty());
+    }
+
+    #[cfg(feature = "testing")]
     #[actix_rt::test]
     async fn mock_activate_agent_ok() {
         let response: Response<ActivateResponseResults> = Response {
@@ -308,10 +1026,144 @@ mod tests {
         let ip = uri[0];
         let port = uri[1].parse().unwrap(); //#[allow_ci]
 
-        let response = do_activate_agent(ip, port, "uuid", "tag").await;
+        let response = do_activate_agent(ip, port, "uuid", "tag", None).await;
         assert!(response.is_ok());
     }
 
+    // Generates a self-signed server certificate valid for 127.0.0.1
+    fn generate_server_cert(key: &PKey<Private>) -> X509 {
+        let mut name = X509Name::builder().unwrap(); //#[allow_ci]
+        name.append_entry_by_nid(Nid::COMMONNAME, "127.0.0.1")
+            .unwrap(); //#[allow_ci]
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap(); //#[allow_ci]
+        builder.set_version(2).unwrap(); //#[allow_ci]
+        builder.set_subject_name(&name).unwrap(); //#[allow_ci]
+        builder.set_issuer_name(&name).unwrap(); //#[allow_ci]
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        builder.set_pubkey(key).unwrap(); //#[allow_ci]
+        let san = SubjectAlternativeName::new()
+            .ip("127.0.0.1")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap(); //#[allow_ci]
+        builder.append_extension(san).unwrap(); //#[allow_ci]
+        builder.sign(key, MessageDigest::sha256()).unwrap(); //#[allow_ci]
+        builder.build()
+    }
+
+    #[test]
+    fn test_registrar_ca_refresh() {
+        let key = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let cert = crypto::generate_x509(&key, "uuid").unwrap(); //#[allow_ci]
+        let ca_key = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let ca_cert = generate_server_cert(&ca_key);
+        let rotated_key = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let rotated_cert = generate_server_cert(&rotated_key);
+
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let ca_path = dir.path().join("registrar-ca.crt");
+        fs::write(&ca_path, ca_cert.to_pem().unwrap()).unwrap(); //#[allow_ci]
+        let tls = RegistrarTls::with_ca_file(cert, key, &ca_path).unwrap(); //#[allow_ci]
+
+        // The client is only rebuilt when the CA file changes
+        assert!(!tls.refresh().unwrap()); //#[allow_ci]
+        fs::write(&ca_path, rotated_cert.to_pem().unwrap()).unwrap(); //#[allow_ci]
+        assert!(tls.refresh().unwrap()); //#[allow_ci]
+        assert!(!tls.refresh().unwrap()); //#[allow_ci]
+
+        // The clones share the refreshed client
+        let cloned = tls.clone();
+        fs::write(&ca_path, ca_cert.to_pem().unwrap()).unwrap(); //#[allow_ci]
+        assert!(cloned.refresh().unwrap()); //#[allow_ci]
+        assert!(!tls.refresh().unwrap()); //#[allow_ci]
+
+        // An invalid or missing CA file keeps the previous client
+        fs::write(&ca_path, "invalid").unwrap(); //#[allow_ci]
+        assert!(tls.refresh().is_err());
+        fs::remove_file(&ca_path).unwrap(); //#[allow_ci]
+        assert!(tls.refresh().is_err());
+        let _ = tls.client();
+
+        // Without a CA file, there is nothing to refresh
+        let key = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let cert = crypto::generate_x509(&key, "uuid").unwrap(); //#[allow_ci]
+        let tls = RegistrarTls::new(cert, key, &[ca_cert]).unwrap(); //#[allow_ci]
+        assert!(!tls.refresh().unwrap()); //#[allow_ci]
+    }
+
+    #[actix_rt::test]
+    async fn mock_activate_agent_tls() {
+        let server_key = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let server_cert = generate_server_cert(&server_key);
+        let client_key = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let client_cert = crypto::generate_x509(&client_key, "uuid").unwrap(); //#[allow_ci]
+
+        // The mock registrar accepts the connections without a client
+        // certificate, but rejects their requests
+        let ssl_context = crypto::generate_mtls_context(
+            &server_cert,
+            &server_key,
+            vec![client_cert.clone()],
+            false,
+        )
+        .unwrap(); //#[allow_ci]
+        let server = HttpServer::new(|| {
+            App::new().default_service(web::to(
+                |req: HttpRequest| async move {
+                    if req.conn_data::<ClientCertificate>().is_none() {
+                        return HttpResponse::Forbidden().finish();
+                    }
+                    HttpResponse::Ok().json(Response {
+                        code: 200.into(),
+                        status: "OK".to_string(),
+                        results: ActivateResponseResults {},
+                    })
+                },
+            ))
+        })
+        .on_connect(client_cert::on_connect)
+        .workers(1)
+        .disable_signals()
+        .bind_openssl("127.0.0.1:0", ssl_context)
+        .unwrap(); //#[allow_ci]
+        let port = server.addrs()[0].port().into();
+        let server = server.run();
+        let handle = server.handle();
+        _ = actix_rt::spawn(server);
+
+        let ca_cert = server_cert.to_pem().unwrap(); //#[allow_ci]
+        let tls = RegistrarTls::new(client_cert, client_key, &[server_cert])
+            .unwrap(); //#[allow_ci]
+        let response =
+            do_activate_agent("127.0.0.1", port, "uuid", "tag", Some(&tls))
+                .await;
+        assert!(response.is_ok());
+
+        // A request made without the client certificate is rejected
+        let client = reqwest::Client::builder()
+            .tls_built_in_root_certs(false)
+            .add_root_certificate(
+                reqwest::Certificate::from_pem(&ca_cert).unwrap(), //#[allow_ci]
+            )
+            .build()
+            .unwrap(); //#[allow_ci]
+        let response = client
+            .put(registrar_address("127.0.0.1", port, true))
+            .send()
+            .await
+            .unwrap(); //#[allow_ci]
+        assert_eq!(response.status(), 403);
+
+        handle.stop(false).await;
+    }
+
+    #[cfg(feature = "testing")]
     #[actix_rt::test]
     async fn mock_activate_agent_err() {
         let response: Response<ActivateResponseResults> = Response {
@@ -332,7 +1184,7 @@ mod tests {
         let ip = uri[0];
         let port = uri[1].parse().unwrap(); //#[allow_ci]
 
-        let response = do_activate_agent(ip, port, "uuid", "tag").await;
+        let response = do_activate_agent(ip, port, "uuid", "tag", None).await;
         assert!(response.is_err());
         assert_eq!(response.err().unwrap().http_code().unwrap(), 404); //#[allow_ci]
     }
@@ -0,0 +1,522 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+use crate::common::{
+    hash_ek_pubkey, AgentData, AgentDataFormat, JsonWrapper,
+};
+use crate::{registrar_agent, QuoteData, Result};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use keylime::tpm;
+use log::*;
+use openssl::x509::X509;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tss_esapi::{
+    handles::{KeyHandle, ObjectHandle},
+    structures::PublicBuffer,
+    traits::Marshall,
+};
+
+/// The settings needed to register the agent again after reprovisioning
+#[derive(Debug)]
+pub(crate) struct RegistrationSettings {
+    pub registrar_ip: String,
+    pub registrar_port: u32,
+    pub contact_ip: String,
+    pub contact_port: u32,
+    pub ek_handle: String,
+    pub agent_data_path: String,
+    pub agent_data_format: AgentDataFormat,
+    pub tolerate_readonly_state: bool,
+    pub mtls_cert: Option<X509>,
+    pub registrar_tls: Option<registrar_agent::RegistrarTls>,
+    // Serializes the reprovisioning requests
+    pub lock: tokio::sync::Mutex<()>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Reprovision {
+    pub ak_name: String,
+}
+
+/// The optional body of the reprovision request
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct ReprovisionRequest {
+    /// Whether to regenerate the EK persisted at the handle set in
+    /// 'ek_handle'. A transient EK is always created again.
+    #[serde(default)]
+    pub regenerate_ek: bool,
+}
+
+// Registers the new AK and activates the agent, storing the new AK in the
+// agent data on success
+async fn register_ak(
+    data: &QuoteData,
+    settings: &RegistrationSettings,
+    ek_result: &tpm::EKResult,
+    ak: &tpm::AKResult,
+    ak_handle: KeyHandle,
+) -> Result<()> {
+    let ek_hash = hash_ek_pubkey(ek_result.public.clone())?;
+    let agent_data = AgentData::create(
+        data.hash_alg,
+        data.sign_alg,
+        ak,
+        ek_hash.as_bytes(),
+    )?;
+
+    data.metrics.registration_attempt();
+    let keyblob = registrar_agent::do_register_agent(
+        &settings.registrar_ip,
+        settings.registrar_port,
+        &data.agent_uuid,
+        &data.agent_name,
+        &PublicBuffer::try_from(ek_result.public.clone())?.marshall()?,
+        ek_result.ek_cert.clone(),
+        &PublicBuffer::try_from(ak.public.clone())?.marshall()?,
+        settings.mtls_cert.as_ref(),
+        &settings.contact_ip,
+        settings.contact_port,
+        data.config_hash.as_deref(),
+        settings.registrar_tls.as_ref(),
+    )
+    .await?;
+    info!("SUCCESS: Agent {} registered", &data.agent_uuid);
+
+    let auth_tag = {
+        let mut context = data.tpmcontext.lock().unwrap(); //#[allow_ci]
+        crate::activation_auth_tag(
+            &mut context,
+            keyblob,
+            ak_handle,
+            ek_result.key_handle,
+            &data.agent_uuid,
+            data.hmac_hash_alg,
+        )?
+    };
+
+    registrar_agent::do_activate_agent(
+        &settings.registrar_ip,
+        settings.registrar_port,
+        &data.agent_uuid,
+        &auth_tag,
+        settings.registrar_tls.as_ref(),
+    )
+    .await?;
+    info!("SUCCESS: Agent {} activated", &data.agent_uuid);
+
+    match settings.agent_data_path.as_ref() {
+        "" => info!("Agent Data not stored"),
+        path => {
+            _ = agent_data.store_or_tolerate(
+                Path::new(&path),
+                settings.agent_data_format,
+                settings.tolerate_readonly_state,
+            )?
+        }
+    }
+    // The new AK replaces the old one in the state snapshot as well
+    *data.agent_data.lock().unwrap() = agent_data; //#[allow_ci]
+
+    Ok(())
+}
+
+// Flushes the EK if it is transient, i.e. no 'ek_handle' is set. A failure is
+// only reported, so that it does not prevent flushing the AK.
+fn flush_transient_ek(
+    context: &mut tpm::Context,
+    settings: &RegistrationSettings,
+    ek_handle: KeyHandle,
+) {
+    if settings.ek_handle.is_empty() {
+        if let Err(e) = context.as_mut().flush_context(ek_handle.into()) {
+            warn!("Failed to flush the EK: {e}");
+        }
+    }
+}
+
+// Generates a new AK, and optionally the EK, registers it and replaces the
+// AK used by the agent. Returns the name of the new AK.
+async fn do_reprovision(
+    data: &QuoteData,
+    settings: &RegistrationSettings,
+    request: &ReprovisionRequest,
+) -> Result<String> {
+    let _guard = settings.lock.lock().await;
+
+    let (ek_result, ak, ak_handle) = {
+        let mut context = data.tpmcontext.lock().unwrap(); //#[allow_ci]
+        let ek_result = match settings.ek_handle.as_ref() {
+            "" => context.create_ek(data.enc_alg, None)?,
+            s if request.regenerate_ek => {
+                info!("Regenerating the EK persisted at {s}");
+                context.regenerate_persistent_ek(data.enc_alg, s)?
+            }
+            s => context.create_ek(data.enc_alg, Some(s))?,
+        };
+        match crate::create_bound_ak(
+            &mut context,
+            ek_result.key_handle,
+            data.hash_alg,
+            data.sign_alg,
+        ) {
+            Ok((ak_handle, ak)) => (ek_result, ak, ak_handle),
+            Err(e) => {
+                flush_transient_ek(
+                    &mut context,
+                    settings,
+                    ek_result.key_handle,
+                );
+                return Err(e);
+            }
+        }
+    };
+
+    let result =
+        register_ak(data, settings, &ek_result, &ak, ak_handle).await;
+
+    let mut context = data.tpmcontext.lock().unwrap(); //#[allow_ci]
+    flush_transient_ek(&mut context, settings, ek_result.key_handle);
+
+    if let Err(e) = result {
+        context.as_mut().flush_context(ak_handle.into())?;
+        return Err(e);
+    }
+
+    // Replace the AK while holding the TPM context, so that no quote is
+    // generated with the old AK after it is flushed
+    let old_ak_handle = std::mem::replace(
+        &mut *data.ak_handle.write().unwrap(), //#[allow_ci]
+        ak_handle,
+    );
+    context.as_mut().flush_context(old_ak_handle.into())?;
+
+    let ak_name = context
+        .as_mut()
+        .tr_get_name(ObjectHandle::from(ak_handle))?;
+    Ok(hex::encode(ak_name.value()))
+}
+
+// This is the handler for the POST request to reprovision the agent, which
+// regenerates the AK and registers the agent again. The body may request to
+// regenerate the persistent EK as well.
+pub async fn reprovision(
+    req: HttpRequest,
+    body: web::Bytes,
+    data: web::Data<QuoteData>,
+    settings: web::Data<RegistrationSettings>,
+) -> impl Responder {
+    info!("Reprovisioning agent {}", &data.agent_uuid);
+
+    let request = if body.is_empty() {
+        ReprovisionRequest::default()
+    } else {
+        match serde_json::from_slice::<ReprovisionRequest>(&body) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("POST reprovision returning 400 response. Invalid request body: {e}");
+                return HttpResponse::BadRequest().json(JsonWrapper::error(
+                    400,
+                    format!("Invalid request body: {e}"),
+                ));
+            }
+        }
+    };
+
+    match do_reprovision(&data, &settings, &request).await {
+        Ok(ak_name) => {
+            info!("POST reprovision returning 200 response");
+            HttpResponse::Ok()
+                .json(JsonWrapper::success(Reprovision { ak_name }))
+        }
+        Err(e) => {
+            warn!("POST reprovision returning 500 response. Unable to reprovision agent: {e}");
+            HttpResponse::InternalServerError().json(JsonWrapper::error(
+                500,
+                format!("Unable to reprovision agent: {e}"),
+            ))
+        }
+    }
+}
+
+// Registers the reprovision endpoint, if enabled
+pub(crate) fn reprovision_config(
+    cfg: &mut web::ServiceConfig,
+    settings: Option<web::Data<RegistrationSettings>>,
+) {
+    if let Some(settings) = settings {
+        _ = cfg.service(
+            web::resource("/reprovision")
+                .app_data(settings)
+                .route(web::post().to(reprovision)),
+        );
+    }
+}
+
+#[cfg(feature = "testing")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::API_VERSION;
+    use actix_web::{test, App};
+    use base64::{engine::general_purpose, Engine as _};
+    use serde_json::json;
+    use std::convert::TryFrom;
+    use tss_esapi::{
+        interface_types::resource_handles::Hierarchy,
+        structures::{Digest, Public},
+    };
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+    // Magic number of the credential blob format expected by the agent
+    const TSS_MAGIC: u32 = 3135029470;
+
+    // Mock registrar creating the credential for the AK sent by the agent
+    struct CredentialResponder {
+        data: web::Data<QuoteData>,
+    }
+
+    impl Respond for CredentialResponder {
+        fn respond(&self, request: &Request) -> ResponseTemplate {
+            let body: serde_json::Value =
+                serde_json::from_slice(&request.body).unwrap(); //#[allow_ci]
+            let aik_tpm = general_purpose::STANDARD
+                .decode(body["aik_tpm"].as_str().unwrap()) //#[allow_ci]
+                .unwrap(); //#[allow_ci]
+            let ak_public = Public::try_from(
+                PublicBuffer::try_from(aik_tpm).unwrap(), //#[allow_ci]
+            )
+            .unwrap(); //#[allow_ci]
+
+            let mut context = self.data.tpmcontext.lock().unwrap(); //#[allow_ci]
+            let ctx = context.as_mut();
+            let handle = ctx
+                .load_external_public(ak_public, Hierarchy::Owner)
+                .unwrap(); //#[allow_ci]
+            let (_, ak_name, _) = ctx.read_public(handle).unwrap(); //#[allow_ci]
+            ctx.flush_context(handle.into()).unwrap(); //#[allow_ci]
+            let (credential, secret) = ctx
+                .make_credential(
+                    self.data.ek_handle.unwrap(), //#[allow_ci]
+                    Digest::try_from(vec![0x42u8; 32]).unwrap(), //#[allow_ci]
+                    ak_name,
+                )
+                .unwrap(); //#[allow_ci]
+
+            let mut blob = Vec::new();
+            blob.extend_from_slice(&TSS_MAGIC.to_be_bytes());
+            blob.extend_from_slice(&1u32.to_be_bytes());
+            blob.extend_from_slice(
+                &(credential.value().len() as u16).to_be_bytes(),
+            );
+            blob.extend_from_slice(credential.value());
+            blob.extend_from_slice(
+                &(secret.value().len() as u16).to_be_bytes(),
+            );
+            blob.extend_from_slice(secret.value());
+
+            ResponseTemplate::new(200).set_body_json(json!({
+                "code": 200,
+                "status": "OK",
+                "results": {
+                    "blob": general_purpose::STANDARD.encode(blob),
+                },
+            }))
+        }
+    }
+
+    // Starts a mock registrar expecting `registrations` registrations and
+    // activations of the agent
+    async fn mock_registrar(
+        quotedata: &web::Data<QuoteData>,
+        registrations: u64,
+    ) -> MockServer {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(CredentialResponder {
+                data: quotedata.clone(),
+            })
+            .expect(registrations)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("PUT"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "code": 200,
+                "status": "OK",
+                "results": {},
+            })))
+            .expect(registrations)
+            .mount(&mock_server)
+            .await;
+        mock_server
+    }
+
+    fn registration_settings(
+        mock_server: &MockServer,
+        ek_handle: &str,
+        agent_data_path: &Path,
+    ) -> web::Data<RegistrationSettings> {
+        let address = mock_server.address();
+        web::Data::new(RegistrationSettings {
+            registrar_ip: address.ip().to_string(),
+            registrar_port: u32::from(address.port()),
+            contact_ip: "127.0.0.1".to_string(),
+            contact_port: 9002,
+            ek_handle: ek_handle.to_string(),
+            agent_data_path: agent_data_path.display().to_string(),
+            agent_data_format: AgentDataFormat::Json,
+            tolerate_readonly_state: false,
+            mtls_cert: None,
+            registrar_tls: None,
+            lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    #[actix_rt::test]
+    async fn test_reprovision() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let old_ak_handle = quotedata.ak_handle();
+
+        let mock_server = mock_registrar(&quotedata, 1).await;
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let agent_data_path = dir.path().join("agent_data.json");
+        let settings =
+            registration_settings(&mock_server, "", &agent_data_path);
+
+        let mut app = test::init_service(
+            App::new().app_data(quotedata.clone()).service(
+                web::scope(&format!("/{API_VERSION}")).configure(|cfg| {
+                    reprovision_config(cfg, Some(settings.clone()))
+                }),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/{API_VERSION}/reprovision"))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<Reprovision> =
+            test::read_body_json(resp).await;
+
+        // The agent uses the new AK, and stored it in the agent data
+        let ak_handle = quotedata.ak_handle();
+        assert_ne!(ak_handle, old_ak_handle);
+        let (ak_name, ek_public) = {
+            let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
+            let ctx = context.as_mut();
+            let ak_name =
+                ctx.tr_get_name(ObjectHandle::from(ak_handle)).unwrap(); //#[allow_ci]
+            let ek_handle = quotedata.ek_handle.unwrap(); //#[allow_ci]
+            let (ek_public, _, _) = ctx.read_public(ek_handle).unwrap(); //#[allow_ci]
+            (ak_name, ek_public)
+        };
+        assert_eq!(result.results.ak_name, hex::encode(ak_name.value()));
+
+        let ek_hash = hash_ek_pubkey(ek_public).unwrap(); //#[allow_ci]
+        let agent_data = AgentData::load(&agent_data_path).unwrap(); //#[allow_ci]
+        assert!(agent_data.valid(
+            quotedata.hash_alg,
+            quotedata.sign_alg,
+            ek_hash.as_bytes(),
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn test_reprovision_regenerate_ek() {
+        use tss_esapi::{
+            handles::{PersistentTpmHandle, TpmHandle},
+            interface_types::{
+                dynamic_handles::Persistent, resource_handles::Provision,
+            },
+        };
+
+        // Owner range of the persistent handles
+        const HANDLE: u32 = 0x8100_7e58;
+
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let ek_public = {
+            let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
+            let ek_handle = quotedata.ek_handle.unwrap(); //#[allow_ci]
+            let (ek_public, _, _) =
+                context.as_mut().read_public(ek_handle).unwrap(); //#[allow_ci]
+            ek_public
+        };
+
+        let mock_server = mock_registrar(&quotedata, 2).await;
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let settings = registration_settings(
+            &mock_server,
+            &format!("{HANDLE:#x}"),
+            &dir.path().join("agent_data.json"),
+        );
+
+        let mut app = test::init_service(
+            App::new().app_data(quotedata.clone()).service(
+                web::scope(&format!("/{API_VERSION}")).configure(|cfg| {
+                    reprovision_config(cfg, Some(settings.clone()))
+                }),
+            ),
+        )
+        .await;
+
+        // An invalid body is rejected
+        let req = test::TestRequest::post()
+            .uri(&format!("/{API_VERSION}/reprovision"))
+            .set_payload("{\"regenerate_ek\": \"yes\"}")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+
+        // The EK is persisted when missing, and kept persisted afterwards
+        for _ in 0..2 {
+            let req = test::TestRequest::post()
+                .uri(&format!("/{API_VERSION}/reprovision"))
+                .set_json(json!({"regenerate_ek": true}))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success());
+        }
+
+        let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
+        let ctx = context.as_mut();
+        let persistent = PersistentTpmHandle::new(HANDLE).unwrap(); //#[allow_ci]
+        let handle = ctx
+            .tr_from_tpm_public(TpmHandle::Persistent(persistent))
+            .unwrap(); //#[allow_ci]
+        let (public, _, _) = ctx.read_public(handle.into()).unwrap(); //#[allow_ci]
+        assert_eq!(public, ek_public);
+        _ = ctx
+            .execute_with_nullauth_session(|ctx| {
+                ctx.evict_control(
+                    Provision::Owner,
+                    handle,
+                    Persistent::Persistent(persistent),
+                )
+            })
+            .unwrap(); //#[allow_ci]
+    }
+
+    #[actix_rt::test]
+    async fn test_reprovision_disabled() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app = test::init_service(
+            App::new().app_data(quotedata.clone()).service(
+                web::scope(&format!("/{API_VERSION}"))
+                    .configure(|cfg| reprovision_config(cfg, None)),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/{API_VERSION}/reprovision"))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 404);
+    }
+}
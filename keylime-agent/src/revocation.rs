@@ -6,6 +6,7 @@ use actix_web::rt;
 use crate::config::{AgentConfig, KeylimeConfig};
 use crate::crypto;
 use crate::error::*;
+use crate::metrics::Metrics;
 use crate::secure_mount;
 use log::*;
 use serde::{Deserialize, Serialize};
@@ -16,6 +17,7 @@ use std::{
     io::{ErrorKind, Write},
     path::{Path, PathBuf},
     process::{Child, Command, Output, Stdio},
+    sync::Arc,
     time::Duration,
 };
 use tokio::{
@@ -45,6 +47,55 @@ pub(crate) enum RevocationMessage {
     Shutdown,
 }
 
+// Value of the "type" field of the revocation notifications
+const REVOCATION_TYPE: &str = "revocation";
+
+/// Content of a revocation notification sent by the verifier, as carried in
+/// the signed `msg` field of a [`Revocation`]
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub(crate) struct RevocationNotification {
+    #[serde(rename = "type")]
+    pub(crate) kind: String,
+    pub(crate) agent_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ip: Option<String>,
+    #[serde(
+        rename = "severity_label",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) severity: Option<String>,
+    // Remaining fields, passed unchanged to the revocation actions
+    #[serde(flatten)]
+    pub(crate) payload: serde_json::Map<String, Value>,
+}
+
+impl RevocationNotification {
+    /// Parses the revocation notification from the signed message,
+    /// checking that the required fields are present and valid
+    pub(crate) fn parse(msg: &str) -> Result<Self> {
+        let notification: RevocationNotification = serde_json::from_str(msg)
+            .map_err(|e| {
+                Error::Other(format!("Malformed revocation message: {e}"))
+            })?;
+
+        if notification.kind != REVOCATION_TYPE {
+            return Err(Error::Other(format!(
+                "Malformed revocation message: unexpected type \"{}\"",
+                notification.kind
+            )));
+        }
+
+        if notification.agent_id.is_empty() {
+            return Err(Error::Other(
+                "Malformed revocation message: empty agent_id".to_string(),
+            ));
+        }
+
+        Ok(notification)
+    }
+}
+
 /// Lookup for the action to be executed and return the command string
 ///
 /// The lookup goes in the following order:
@@ -174,6 +225,93 @@ pub(crate) fn run_action(
     Ok(output)
 }
 
+/// Get the ordered list of revocation actions
+///
+/// The actions from the configuration file take precedence over the actions from the
+/// action_list file provided in the payload, if present.
+fn get_action_list(
+    config_actions: Option<String>,
+    mount: &Path,
+) -> Result<Vec<String>> {
+    let actions = config_actions.unwrap_or_default();
+    let mut action_list = actions
+        .split(',')
+        .map(|script| script.trim().to_string())
+        .filter(|script| !script.is_empty())
+        .collect::<Vec<String>>();
+    let action_file = mount.join("unzipped").join("action_list");
+
+    if action_file.exists() {
+        let action_data = fs::read_to_string(&action_file)?;
+
+        let file_actions = action_data
+            .split('\n')
+            .map(|script| script.trim().to_string())
+            .filter(|script| !script.is_empty());
+
+        action_list.extend(file_actions);
+    } else {
+        warn!("WARNING: no action_list found in secure directory");
+    }
+
+    Ok(action_list)
+}
+
+/// Resolve the ordered list of revocation actions without running them
+///
+/// Returns a list of (action, command) tuples, where the command is None if
+/// the action was not found or is not allowed to run
+///
+/// # Arguments
+///
+/// * `config_actions` - Actions from the configuration file
+/// * `actions_dir` - Location of the pre-installed actions
+/// * `allow_payload_actions` - Whether actions provided in the payload are allowed
+/// * `mount` - Location of the secure mount
+/// * `allowed_actions` - The only actions allowed to run, if not empty
+pub(crate) fn list_revocation_actions(
+    config_actions: Option<String>,
+    actions_dir: &Path,
+    allow_payload_actions: bool,
+    mount: &Path,
+    allowed_actions: &[String],
+) -> Result<Vec<(String, Option<String>)>> {
+    let unzipped = mount.join("unzipped");
+
+    Ok(get_action_list(config_actions, mount)?
+        .into_iter()
+        .map(|action| {
+            if !is_action_allowed(&action, allowed_actions) {
+                return (action, None);
+            }
+            let command = lookup_action(
+                &unzipped,
+                actions_dir,
+                &action,
+                allow_payload_actions,
+            )
+            .ok()
+            .map(|(command, _, _)| command);
+            (action, command)
+        })
+        .collect())
+}
+
+/// Parse the actions set in the 'revocation_allowed_actions' option, a
+/// comma separated list of script file names
+pub(crate) fn parse_allowed_actions(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|script| script.trim().to_string())
+        .filter(|script| !script.is_empty())
+        .collect()
+}
+
+// Checks whether the action is allowed to run. All the actions are allowed
+// if the list of allowed actions is empty
+fn is_action_allowed(action: &str, allowed_actions: &[String]) -> bool {
+    allowed_actions.is_empty() || allowed_actions.iter().any(|a| a == action)
+}
+
 /// Runs revocation actions received from tenant post-attestation
 ///
 /// An OK result indicates all actions were run successfully.
@@ -185,6 +323,7 @@ pub(crate) fn run_action(
 /// * `json` - The revocation message content
 /// * `config_actions` - Actions from the configuration file
 /// * `actions_dir` - Location of the pre-installed actions
+/// * `allowed_actions` - The only actions allowed to run, if not empty
 fn run_revocation_actions(
     json: Value,
     config_actions: Option<String>,
@@ -192,41 +331,23 @@ fn run_revocation_actions(
     allow_payload_actions: bool,
     work_dir: &Path,
     mount: &Path,
+    allowed_actions: &[String],
 ) -> Result<Vec<Output>> {
-    // The actions from the configuration file takes precedence over the actions from the
-    // actions_list file
-    let actions = config_actions.unwrap_or_default();
-    let mut action_list = actions
-        .split(',')
-        .map(|script| script.trim())
-        .filter(|script| !script.is_empty())
-        .collect::<Vec<&str>>();
-    let action_data;
+    let action_list = get_action_list(config_actions, mount)?;
     let unzipped = mount.join("unzipped");
-    let action_file = unzipped.join("action_list");
-
-    if action_file.exists() {
-        action_data = std::fs::read_to_string(&action_file)
-            .expect("unable to read action_list");
-
-        let file_actions = action_data
-            .split('\n')
-            .map(|script| script.trim())
-            .filter(|script| !script.is_empty());
-
-        action_list.extend(file_actions);
-    } else {
-        warn!("WARNING: no action_list found in secure directory");
-    }
 
     let mut outputs = Vec::new();
 
     if !action_list.is_empty() {
         for action in action_list {
+            if !is_action_allowed(&action, allowed_actions) {
+                warn!("Revocation action {} is not allowed in 'revocation_allowed_actions', skipping it", action);
+                continue;
+            }
             match run_action(
                 &unzipped,
                 actions_dir,
-                action,
+                &action,
                 json.clone(),
                 allow_payload_actions,
                 work_dir,
@@ -240,7 +361,7 @@ fn run_revocation_actions(
                     );
                     error!("{}", msg);
                     return Err(Error::Script(
-                        String::from(action),
+                        action,
                         e.exe_code()?,
                         e.stderr()?,
                     ));
@@ -255,6 +376,12 @@ fn run_revocation_actions(
 }
 
 /// Process revocation message received from REST API or 0mq
+///
+/// If `self_only` is set, the revocation actions run only if the message
+/// targets the agent with the given UUID. Otherwise, the actions run for the
+/// messages targeting any agent. If `allowed_actions` is not empty, only the
+/// actions it lists run.
+#[allow(clippy::too_many_arguments)]
 fn process_revocation(
     revocation: Revocation,
     revocation_cert: &openssl::x509::X509,
@@ -263,32 +390,58 @@ fn process_revocation(
     allow_payload_revocation_actions: bool,
     work_dir: &Path,
     mount: &Path,
+    agent_uuid: &str,
+    self_only: bool,
+    allowed_actions: &[String],
 ) -> Result<()> {
     let cert_key = revocation_cert.public_key()?;
 
-    // Verify the message and signature with our key
-    let mut verified = crypto::asym_verify(
+    if revocation.signature.is_empty() {
+        error!("Unsigned revocation message");
+        return Err(Error::InvalidRequest);
+    }
+
+    // Verify the message and signature with our key. A malformed signature
+    // is rejected as an invalid one
+    let verified = match crypto::asym_verify(
         &cert_key,
         &revocation.msg,
         &revocation.signature,
-    )?;
+    ) {
+        Ok(verified) => verified,
+        Err(e) => {
+            debug!(
+                "Unable to verify the revocation message signature: {}",
+                e
+            );
+            false
+        }
+    };
 
     if verified {
-        let msg = revocation.msg.as_str();
-        let msg_payload: Value = serde_json::from_str(msg)?;
+        let notification = RevocationNotification::parse(&revocation.msg)?;
 
         debug!(
             "Revocation signature validated for revocation: {}",
-            msg_payload
+            revocation.msg
         );
 
+        if self_only && notification.agent_id != agent_uuid {
+            info!(
+                "Ignoring revocation for agent {}: only the revocations for this agent ({}) are processed",
+                notification.agent_id, agent_uuid
+            );
+            return Ok(());
+        }
+
         let outputs = run_revocation_actions(
-            msg_payload,
+            serde_json::to_value(&notification)?,
             revocation_actions,
             revocation_actions_dir,
             allow_payload_revocation_actions,
             work_dir,
             mount,
+            allowed_actions,
         )?;
 
         for output in outputs {
@@ -308,11 +461,27 @@ fn process_revocation(
     }
 }
 
+// Endpoint of the socket receiving the connection events of the 0mq socket
+#[cfg(feature = "with-zmq")]
+static ZMQ_MONITOR_ENDPOINT: &str = "inproc://revocation-monitor";
+
+// Checks, without blocking, whether the monitor socket received the event
+// notifying the connection to the revocation notifier
+#[cfg(feature = "with-zmq")]
+fn zmq_connected(monitor: &zmq::Socket) -> bool {
+    let mut connected = false;
+    while monitor.recv_multipart(zmq::DONTWAIT).is_ok() {
+        connected = true;
+    }
+    connected
+}
+
 #[cfg(feature = "with-zmq")]
 fn listen_zmq(
     mut revocation_tx: Sender<RevocationMessage>,
     ip: String,
     port: u32,
+    timeout: Duration,
     mut shutdown_rx: oneshot::Receiver<String>,
 ) -> Result<rt::task::JoinHandle<Result<()>>> {
     // Connect to the service via 0mq
@@ -321,6 +490,24 @@ fn listen_zmq(
 
     mysock.set_subscribe(b"")?;
 
+    // Abandon the connection attempts and reads taking longer than the
+    // timeout. The connection is retried in the background, and the
+    // connection events are monitored to warn if the notifier is unreachable
+    let monitor = if timeout.is_zero() {
+        None
+    } else {
+        let millis = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+        mysock.set_connect_timeout(millis)?;
+        mysock.set_rcvtimeo(millis)?;
+        mysock.monitor(
+            ZMQ_MONITOR_ENDPOINT,
+            zmq::SocketEvent::CONNECTED as i32,
+        )?;
+        let monitor = context.socket(zmq::PAIR)?;
+        monitor.connect(ZMQ_MONITOR_ENDPOINT)?;
+        Some(monitor)
+    };
+
     let endpoint = format!("tcp://{ip}:{port}");
 
     info!(
@@ -333,6 +520,9 @@ fn listen_zmq(
     info!("Waiting for revocation messages on 0mq {}", endpoint);
 
     Ok(rt::spawn(async move {
+        let deadline = std::time::Instant::now() + timeout;
+        let mut monitor = monitor;
+
         // Main revocation service loop. If a message is malformed or
         // can not be verified the loop continues.
         loop {
@@ -340,6 +530,15 @@ fn listen_zmq(
                 // Received shutdowm message
                 break;
             };
+            if let Some(m) = &monitor {
+                if zmq_connected(m) {
+                    info!("Connected to revocation notifier at {}", endpoint);
+                    monitor = None;
+                } else if std::time::Instant::now() >= deadline {
+                    warn!("Could not connect to revocation notifier at {} within {} seconds, retrying in the background", endpoint, timeout.as_secs());
+                    monitor = None;
+                }
+            }
             match mysock.get_events() {
                 Ok(v) => {
                     if v.contains(zmq::POLLIN) {
@@ -401,6 +600,7 @@ pub(crate) async fn zmq_worker(
     mut revocation_tx: Sender<RevocationMessage>,
     ip: String,
     port: u32,
+    timeout: Duration,
 ) -> Result<()> {
     debug!("Starting ZMQ revocation listener worker");
 
@@ -424,6 +624,7 @@ pub(crate) async fn zmq_worker(
                     revocation_tx.clone(),
                     ip.clone(),
                     port,
+                    timeout,
                     rx,
                 ) {
                     Ok(t) => Some(t),
@@ -445,6 +646,32 @@ pub(crate) async fn zmq_worker(
     Ok(())
 }
 
+// Loads the certificate used to verify the signature of the revocation
+// messages
+fn load_revocation_cert(path: &Path) -> Option<openssl::x509::X509> {
+    let cert_absolute_path = match path.canonicalize() {
+        Ok(path) => path,
+        Err(e) => {
+            error!("Certicate not available");
+            return None;
+        }
+    };
+
+    info!(
+        "Loading the revocation certificate from {}",
+        cert_absolute_path.display()
+    );
+
+    match crypto::load_x509(&cert_absolute_path) {
+        Ok(cert) => Some(cert),
+        Err(e) => {
+            error!("Unable to load the revocation certificate: {}", e);
+            None
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn worker(
     mut revocation_rx: Receiver<RevocationMessage>,
     revocation_cert_path: impl AsRef<Path>,
@@ -453,10 +680,21 @@ pub(crate) async fn worker(
     allow_payload_revocation_actions: bool,
     work_dir: impl AsRef<Path>,
     mount: impl AsRef<Path>,
+    agent_uuid: String,
+    self_only: bool,
+    metrics: Arc<Metrics>,
+    allowed_actions: Vec<String>,
 ) -> Result<()> {
     debug!("Starting revocation worker");
 
-    let mut revocation_cert: Option<openssl::x509::X509> = None;
+    // The certificate is loaded when the payload is decrypted, unless it is
+    // already available, e.g. when it is not delivered with the payload
+    let mut revocation_cert: Option<openssl::x509::X509> =
+        if revocation_cert_path.as_ref().exists() {
+            load_revocation_cert(revocation_cert_path.as_ref())
+        } else {
+            None
+        };
 
     // Receive message
     while let Some(message) = revocation_rx.recv().await {
@@ -476,8 +714,12 @@ pub(crate) async fn worker(
                             allow_payload_revocation_actions,
                             work_dir.as_ref(),
                             mount.as_ref(),
+                            &agent_uuid,
+                            self_only,
+                            &allowed_actions,
                         ) {
                             Ok(_) => {
+                                metrics.revocation_processed();
                                 info!("Revocation processed successfully");
                             }
                             Err(e) => {
@@ -490,25 +732,8 @@ pub(crate) async fn worker(
             RevocationMessage::PayloadDecrypted => {
                 // The payload worker will send this message after decrypting and optionally
                 // unzipping the payload
-                let cert_absolute_path =
-                    match revocation_cert_path.as_ref().canonicalize() {
-                        Ok(path) => path,
-                        Err(e) => {
-                            error!("Certicate not available");
-                            continue;
-                        }
-                    };
-
-                info!(
-                    "Loading the revocation certificate from {}",
-                    cert_absolute_path.display()
-                );
-
-                revocation_cert = match crypto::load_x509(&cert_absolute_path)
-                {
-                    Ok(cert) => Some(cert),
-                    Err(e) => None,
-                };
+                revocation_cert =
+                    load_revocation_cert(revocation_cert_path.as_ref());
             }
             RevocationMessage::Shutdown => {
                 revocation_rx.close();
@@ -552,6 +777,7 @@ mod tests {
             true,
             work_dir.path(),
             &tmpfs_dir,
+            &[],
         );
 
         assert!(outputs.is_ok());
@@ -567,6 +793,64 @@ mod tests {
         }
     }
 
+    #[test]
+    fn revocation_scripts_allowed() {
+        let json_file = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/unzipped/test_ok.json"
+        );
+        let json_str = std::fs::read_to_string(json_file).unwrap(); //#[allow_ci]
+        let json: Value = serde_json::from_str(&json_str).unwrap(); //#[allow_ci]
+        let actions_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let tmpfs_dir = work_dir.path().join("tmpfs-dev"); //#[allow_ci]
+        fs::create_dir(&tmpfs_dir).unwrap(); //#[allow_ci]
+        let unzipped_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/unzipped");
+        symlink(unzipped_dir, tmpfs_dir.join("unzipped")).unwrap(); //#[allow_ci]
+
+        // The action list in the payload contains local_action_rev_script1.py
+        // and local_action_rev_script2.py
+        let run = |allowed: &str| {
+            run_revocation_actions(
+                json.clone(),
+                Some("local_action_stand_alone.py".to_string()),
+                actions_dir,
+                true,
+                work_dir.path(),
+                &tmpfs_dir,
+                &parse_allowed_actions(allowed),
+            )
+            .unwrap() //#[allow_ci]
+            .len()
+        };
+
+        // Only the allowed action runs
+        assert_eq!(run("local_action_rev_script1.py"), 1);
+        assert_eq!(
+            run(" local_action_stand_alone.py,local_action_rev_script2.py"),
+            2
+        );
+        // The actions not allowed are skipped
+        assert_eq!(run("local_action_hello_shell.sh"), 0);
+        // An empty list allows all the actions
+        assert_eq!(run(""), 3);
+
+        let actions = list_revocation_actions(
+            Some("local_action_stand_alone.py".to_string()),
+            actions_dir,
+            true,
+            &tmpfs_dir,
+            &parse_allowed_actions("local_action_stand_alone.py"),
+        )
+        .unwrap(); //#[allow_ci]
+        assert_eq!(actions.len(), 3);
+        assert!(actions[0].1.is_some());
+        assert!(actions[1].1.is_none());
+        assert!(actions[2].1.is_none());
+    }
+
     #[test]
     fn revocation_scripts_err() {
         let test_config = KeylimeConfig::default();
@@ -591,6 +875,7 @@ mod tests {
             true,
             work_dir.path(),
             &tmpfs_dir,
+            &[],
         );
         assert!(outputs.is_err());
     }
@@ -626,6 +911,7 @@ mod tests {
             true,
             work_dir.path(),
             &tmpfs_dir,
+            &[],
         );
 
         assert!(outputs.is_ok());
@@ -647,6 +933,82 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_list_revocation_actions() {
+        let actions_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let tmpfs_dir = work_dir.path().join("tmpfs-dev"); //#[allow_ci]
+        fs::create_dir(&tmpfs_dir).unwrap(); //#[allow_ci]
+        let unzipped_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/unzipped");
+        symlink(unzipped_dir, tmpfs_dir.join("unzipped")).unwrap(); //#[allow_ci]
+
+        let config_actions = Some(
+            "local_action_hello_shell.sh, local_action_non_existent"
+                .to_string(),
+        );
+
+        let actions = list_revocation_actions(
+            config_actions.clone(),
+            actions_dir,
+            true,
+            &tmpfs_dir,
+            &[],
+        )
+        .unwrap(); //#[allow_ci]
+
+        // The actions from the configuration come first, followed by the
+        // actions from the action_list file
+        assert_eq!(
+            actions,
+            vec![
+                (
+                    "local_action_hello_shell.sh".to_string(),
+                    Some(
+                        actions_dir
+                            .join("local_action_hello_shell.sh")
+                            .display()
+                            .to_string()
+                    )
+                ),
+                ("local_action_non_existent".to_string(), None),
+                (
+                    "local_action_rev_script1.py".to_string(),
+                    Some(
+                        unzipped_dir
+                            .join("local_action_rev_script1.py")
+                            .display()
+                            .to_string()
+                    )
+                ),
+                (
+                    "local_action_rev_script2.py".to_string(),
+                    Some(
+                        unzipped_dir
+                            .join("local_action_rev_script2.py")
+                            .display()
+                            .to_string()
+                    )
+                ),
+            ]
+        );
+
+        // When payload actions are not allowed, they are not resolved
+        let actions = list_revocation_actions(
+            config_actions,
+            actions_dir,
+            false,
+            &tmpfs_dir,
+            &[],
+        )
+        .unwrap(); //#[allow_ci]
+        assert_eq!(actions.len(), 4);
+        assert!(actions[0].1.is_some());
+        assert!(actions[2].1.is_none());
+        assert!(actions[3].1.is_none());
+    }
+
     #[test]
     fn test_lookup_action() {
         let work_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
@@ -761,17 +1123,79 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_revocation_notification_parse() {
+        let msg = json!({
+            "type": "revocation",
+            "ip": "127.0.0.1",
+            "agent_id": "d432fbb3-d2f1-4a97-9ef7-75bd81c00000",
+            "severity_label": {"severity": "emergency"},
+            "event_id": "pcr_validation_failed",
+        });
+
+        // The severity label is expected to be a string
+        assert!(RevocationNotification::parse(&msg.to_string()).is_err());
+
+        let msg = json!({
+            "type": "revocation",
+            "ip": "127.0.0.1",
+            "agent_id": "d432fbb3-d2f1-4a97-9ef7-75bd81c00000",
+            "severity_label": "emergency",
+            "event_id": "pcr_validation_failed",
+        });
+        let notification =
+            RevocationNotification::parse(&msg.to_string()).unwrap(); //#[allow_ci]
+        assert_eq!(notification.kind, "revocation");
+        assert_eq!(
+            notification.agent_id,
+            "d432fbb3-d2f1-4a97-9ef7-75bd81c00000"
+        );
+        assert_eq!(notification.ip.as_deref(), Some("127.0.0.1"));
+        assert_eq!(notification.severity.as_deref(), Some("emergency"));
+        assert_eq!(
+            notification.payload.get("event_id"),
+            Some(&json!("pcr_validation_failed"))
+        );
+
+        // The actions receive the complete message
+        assert_eq!(serde_json::to_value(&notification).unwrap(), msg); //#[allow_ci]
+
+        // Only the type and agent_id fields are required
+        let msg = json!({"type": "revocation", "agent_id": "a"});
+        let notification =
+            RevocationNotification::parse(&msg.to_string()).unwrap(); //#[allow_ci]
+        assert_eq!(notification.ip, None);
+        assert_eq!(notification.severity, None);
+
+        for malformed in [
+            "not json".to_string(),
+            json!({"hello": "there"}).to_string(),
+            json!({"type": "revocation"}).to_string(),
+            json!({"agent_id": "a"}).to_string(),
+            json!({"type": "revocation", "agent_id": 1}).to_string(),
+            json!({"type": "revocation", "agent_id": ""}).to_string(),
+            json!({"type": "other", "agent_id": "a"}).to_string(),
+        ] {
+            let err = RevocationNotification::parse(&malformed).unwrap_err(); //#[allow_ci]
+            assert!(err.to_string().contains("Malformed revocation message"));
+        }
+    }
+
     #[test]
     fn test_process_revocation() {
         let test_config = KeylimeConfig::default();
 
-        let sig_path = Path::new(env!("CARGO_MANIFEST_DIR"))
-            .join("test-data/revocation.sig");
-        let signature = fs::read_to_string(sig_path).unwrap(); //#[allow_ci]
+        let rsa_key_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/test-rsa.pem");
+        let (_, private) =
+            crypto::testing::rsa_import_pair(rsa_key_path).unwrap(); //#[allow_ci]
 
-        let message_path = Path::new(env!("CARGO_MANIFEST_DIR"))
-            .join("test-data/test_ok.json");
-        let msg = fs::read_to_string(message_path).unwrap(); //#[allow_ci]
+        let msg = json!({
+            "type": "revocation",
+            "agent_id": "d432fbb3-d2f1-4a97-9ef7-75bd81c00000",
+        })
+        .to_string();
+        let signature = crypto::asym_sign(&private, &msg).unwrap(); //#[allow_ci]
 
         let revocation = Revocation { msg, signature };
 
@@ -794,8 +1218,169 @@ mod tests {
             test_config.agent.allow_payload_revocation_actions,
             &work_dir,
             &tmpfs_dir,
+            "d432fbb3-d2f1-4a97-9ef7-75bd81c00000",
+            false,
+            &[],
         );
 
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_process_revocation_self_only() {
+        let rsa_key_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/test-rsa.pem");
+        let (_, private) =
+            crypto::testing::rsa_import_pair(rsa_key_path).unwrap(); //#[allow_ci]
+
+        let cert_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/test-cert.pem");
+        let cert = crypto::load_x509(&cert_path).unwrap(); //#[allow_ci]
+
+        let actions_dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions");
+        let work_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+        let tmpfs_dir = work_dir.join("tmpfs-dev");
+
+        let agent_uuid = "d432fbb3-d2f1-4a97-9ef7-75bd81c00000";
+        let msg = json!({
+            "type": "revocation",
+            "agent_id": "c0ffee00-0000-4000-8000-000000000000",
+        })
+        .to_string();
+        let signature = crypto::asym_sign(&private, &msg).unwrap(); //#[allow_ci]
+
+        // The action does not exist, so processing fails if it runs
+        let process = |self_only| {
+            process_revocation(
+                Revocation {
+                    msg: msg.clone(),
+                    signature: signature.clone(),
+                },
+                &cert,
+                &actions_dir,
+                Some("local_action_non_existent".to_string()),
+                false,
+                &work_dir,
+                &tmpfs_dir,
+                agent_uuid,
+                self_only,
+                &[],
+            )
+        };
+
+        // The revocation targets another agent, so no action runs
+        assert!(process(true).is_ok());
+        assert!(process(false).is_err());
+    }
+
+    #[test]
+    fn test_process_revocation_signature() {
+        let rsa_key_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/test-rsa.pem");
+        let (_, private) =
+            crypto::testing::rsa_import_pair(rsa_key_path).unwrap(); //#[allow_ci]
+        let other_private = openssl::pkey::PKey::from_rsa(
+            openssl::rsa::Rsa::generate(2048).unwrap(), //#[allow_ci]
+        )
+        .unwrap(); //#[allow_ci]
+
+        let cert_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/test-cert.pem");
+        let cert = crypto::load_x509(&cert_path).unwrap(); //#[allow_ci]
+
+        let actions_dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions");
+        let work_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+        let tmpfs_dir = work_dir.join("tmpfs-dev");
+
+        let agent_uuid = "d432fbb3-d2f1-4a97-9ef7-75bd81c00000";
+        let msg = json!({
+            "type": "revocation",
+            "agent_id": agent_uuid,
+        })
+        .to_string();
+        let tampered = json!({
+            "type": "revocation",
+            "agent_id": "c0ffee00-0000-4000-8000-000000000000",
+        })
+        .to_string();
+        let signature = crypto::asym_sign(&private, &msg).unwrap(); //#[allow_ci]
+        let other_signature =
+            crypto::asym_sign(&other_private, &msg).unwrap(); //#[allow_ci]
+
+        // The action does not exist, so processing fails with an error
+        // other than InvalidRequest if it runs
+        let process = |msg: &str, signature: &str| {
+            process_revocation(
+                Revocation {
+                    msg: msg.to_string(),
+                    signature: signature.to_string(),
+                },
+                &cert,
+                &actions_dir,
+                Some("local_action_non_existent".to_string()),
+                false,
+                &work_dir,
+                &tmpfs_dir,
+                agent_uuid,
+                false,
+                &[],
+            )
+        };
+
+        // Valid signature, the action runs
+        let result = process(&msg, &signature);
+        assert!(result.is_err());
+        assert!(!matches!(result, Err(Error::InvalidRequest)));
+
+        // Tampered body, wrong signing key, unsigned and malformed
+        // signatures are rejected without running the action
+        for (msg, signature) in [
+            (&tampered, &signature),
+            (&msg, &other_signature),
+            (&msg, &String::new()),
+            (&msg, &"not base64".to_string()),
+        ] {
+            assert!(matches!(
+                process(msg, signature),
+                Err(Error::InvalidRequest)
+            ));
+        }
+    }
+
+    #[cfg(feature = "with-zmq")]
+    #[actix_rt::test]
+    async fn test_listen_zmq_unreachable() {
+        // Get a port nothing listens on
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap() //#[allow_ci]
+            .local_addr()
+            .unwrap() //#[allow_ci]
+            .port();
+
+        let timeout = Duration::from_secs(1);
+        let (revocation_tx, _revocation_rx) =
+            tokio::sync::mpsc::channel::<RevocationMessage>(1);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<String>();
+
+        let start = std::time::Instant::now();
+        let task = listen_zmq(
+            revocation_tx,
+            "127.0.0.1".to_string(),
+            u32::from(port),
+            timeout,
+            shutdown_rx,
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(start.elapsed() < timeout);
+
+        // The service keeps running after the timeout expired
+        sleep(timeout * 2).await;
+        assert!(!task.is_finished());
+
+        shutdown_tx.send("shutdown".to_string()).unwrap(); //#[allow_ci]
+        let result = tokio::time::timeout(timeout, task).await;
+        assert!(result.unwrap().unwrap().is_ok()); //#[allow_ci]
+    }
 }
@@ -4,13 +4,151 @@
 use super::*;
 
 use crate::error::{Error, Result};
+use libc::uid_t;
+use std::ffi::CString;
 use std::fs;
-use std::io::BufRead;
-use std::os::unix::fs::PermissionsExt;
+use std::io::{self, BufRead};
+use std::os::unix::{
+    ffi::OsStrExt,
+    fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+};
 use std::path::PathBuf;
 use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub static MOUNTINFO: &str = "/proc/self/mountinfo";
+pub static MEMINFO: &str = "/proc/meminfo";
+pub static PROC_STATUS: &str = "/proc/self/status";
+
+// The memory-backed file system available in most containers, including
+// the unprivileged ones, and the directory used in it for the secure storage
+pub static SHM_DIR: &str = "/dev/shm";
+pub static SHM_SECURE_DIR: &str = "/dev/shm/keylime-secure";
+
+// The capability required to mount file systems
+const CAP_SYS_ADMIN: u32 = 21;
+
+// Delay between the attempts to mount the secure storage while waiting for
+// the target to be released
+const MOUNT_RETRY_DELAY_MS: u64 = 500;
+
+/// Total and available memory of the host, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MemInfo {
+    total: u64,
+    available: u64,
+}
+
+/*
+ * Get the total and available memory by parsing /proc/meminfo content.
+ *
+ * Each line of /proc/meminfo contains a field name followed by ':' and the
+ * value in kB (check proc (5) for a complete description)
+ */
+fn read_meminfo() -> Result<MemInfo> {
+    let f = fs::File::open(MEMINFO)?;
+    let f = BufReader::new(f);
+    let mut total = None;
+    let mut available = None;
+
+    for line in f.lines().flatten() {
+        let mut iter = line.split_whitespace();
+        let field = match iter.next() {
+            Some("MemTotal:") => &mut total,
+            Some("MemAvailable:") => &mut available,
+            _ => continue,
+        };
+        *field = iter
+            .next()
+            .and_then(|v| v.parse::<u64>().ok())
+            .map(|kb| kb.saturating_mul(1024));
+    }
+
+    match (total, available) {
+        (Some(total), Some(available)) => Ok(MemInfo { total, available }),
+        _ => Err(Error::SecureMount(format!(
+            "Memory information parsing error: missing fields in {MEMINFO}"
+        ))),
+    }
+}
+
+/*
+ * Parse the size of a tmpfs partition, using the syntax accepted by the
+ * 'size' mount option: a number of bytes with an optional k, m or g suffix,
+ * or a percentage of the total memory followed by '%'.
+ *
+ * Return: the size in bytes
+ */
+fn parse_tmpfs_size(size: &str, mem_total: u64) -> Result<u64> {
+    match size.trim().strip_suffix('%') {
+        Some(percent) => {
+            let percent = percent.parse::<u64>().map_err(|_| {
+                Error::SecureMount(format!(
+                    "Invalid secure mount size: \"{}\"",
+                    size.trim()
+                ))
+            })?;
+            Ok(mem_total.saturating_mul(percent) / 100)
+        }
+        None => parse_size(size),
+    }
+}
+
+/*
+ * Parse a size as a number of bytes with an optional k, m or g suffix (case
+ * insensitive), as used in the 'secure_size' option.
+ *
+ * Return: the size in bytes
+ */
+pub(crate) fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let invalid = || {
+        Error::SecureMount(format!("Invalid secure mount size: \"{size}\""))
+    };
+
+    let (number, multiplier) = match size.chars().last() {
+        Some('k' | 'K') => (&size[..size.len() - 1], 1 << 10),
+        Some('m' | 'M') => (&size[..size.len() - 1], 1 << 20),
+        Some('g' | 'G') => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+
+    number
+        .parse::<u64>()
+        .map(|n| n.saturating_mul(multiplier))
+        .map_err(|_| invalid())
+}
+
+/*
+ * Check that the requested size of the secure mount is reasonable for the
+ * host. Pages of a tmpfs partition can be swapped out under memory pressure,
+ * which would expose the keys stored in the secure mount.
+ *
+ * Return: error if the size exceeds the total memory; a warning is logged if
+ *         the size exceeds the available memory
+ */
+fn check_secure_size(secure_size: &str, meminfo: MemInfo) -> Result<()> {
+    let size = parse_tmpfs_size(secure_size, meminfo.total)?;
+
+    if size > meminfo.total {
+        let message = format!(
+            "Requested secure mount size {secure_size} ({size} bytes) exceeds the total memory of the host ({} bytes)",
+            meminfo.total
+        );
+        error!("Secure mount error: {}", message);
+        return Err(Error::SecureMount(message));
+    }
+
+    if size > meminfo.available {
+        warn!(
+            "Requested secure mount size {} ({} bytes) exceeds the available memory of the host ({} bytes)",
+            secure_size, size, meminfo.available
+        );
+    }
+
+    Ok(())
+}
 
 /*
  * Check the mount status of the secure mount directory by parsing /proc/self/mountinfo content.
@@ -73,6 +211,236 @@ fn check_mount(secure_dir: &Path) -> Result<bool> {
     Ok(false)
 }
 
+/// The propagation type of a mount point, which defines whether the mount
+/// events are shared with other mount namespaces
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MountPropagation {
+    // The mount events propagate to and from the peer group
+    Shared,
+    // The mount events propagate only from the master peer group
+    Slave,
+    // The mount events do not propagate
+    Private,
+}
+
+/*
+ * Get the propagation type of the mount point from the optional fields of the
+ * /proc/[pid]/mountinfo content, between the 6th element and the '-'
+ * separator. If the mount point appears more than once, the last mount is
+ * the visible one.
+ *
+ * Return: the propagation type, or None if the mount point is not found
+ */
+fn parse_mount_propagation(
+    mountinfo: &str,
+    mount_point: &Path,
+) -> Option<MountPropagation> {
+    let mut propagation = None;
+
+    for line in mountinfo.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields.len() < 7 || Path::new(fields[4]) != mount_point {
+            continue;
+        }
+
+        let optional = fields[6..].iter().take_while(|&&f| f != "-");
+        let mut current = MountPropagation::Private;
+        for field in optional {
+            if field.starts_with("shared:") {
+                current = MountPropagation::Shared;
+            } else if field.starts_with("master:")
+                && current != MountPropagation::Shared
+            {
+                current = MountPropagation::Slave;
+            }
+        }
+        propagation = Some(current);
+    }
+
+    propagation
+}
+
+/// Check whether the secure mount is shared with other mount namespaces,
+/// which would make it visible, for example, to other containers. A warning is
+/// logged if it is shared.
+pub(crate) fn check_mount_namespace(
+    secure_dir: &Path,
+) -> Result<MountPropagation> {
+    let mountinfo = fs::read_to_string(MOUNTINFO)?;
+    let propagation = parse_mount_propagation(&mountinfo, secure_dir)
+        .ok_or_else(|| {
+            Error::SecureMount(format!(
+                "Secure storage location {} not found in {MOUNTINFO}",
+                secure_dir.display()
+            ))
+        })?;
+
+    if propagation == MountPropagation::Shared {
+        warn!("INSECURE: The secure storage location {} is a shared mount, visible to other mount namespaces (e.g. other containers).", secure_dir.display());
+        warn!("INSECURE: Set 'secure_mount_unshare' to 'true' to mount it in a private mount namespace.");
+    }
+
+    Ok(propagation)
+}
+
+/// Move the agent to a new mount namespace, where the mounts are not
+/// propagated to the original namespace. This must be called before any
+/// thread is started, as the mount namespace cannot be unshared by a
+/// multithreaded process.
+pub(crate) fn unshare_mount_namespace() -> Result<()> {
+    // SAFETY: unshare and mount do not access memory owned by Rust, other
+    // than the NUL-terminated path
+    if unsafe { libc::unshare(libc::CLONE_NEWNS) } != 0 {
+        return Err(Error::SecureMount(format!(
+            "unable to create mount namespace: {}",
+            io::Error::last_os_error()
+        )));
+    }
+
+    let root = CString::new("/")?;
+    if unsafe {
+        libc::mount(
+            std::ptr::null(),
+            root.as_ptr(),
+            std::ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+            std::ptr::null(),
+        )
+    } != 0
+    {
+        return Err(Error::SecureMount(format!(
+            "unable to make mounts private in the new mount namespace: {}",
+            io::Error::last_os_error()
+        )));
+    }
+
+    info!("Created private mount namespace for the secure storage");
+    Ok(())
+}
+
+/*
+ * Get the space available for new files in the secure mount directory, in
+ * bytes. The space is also limited by the 'secure_size' option, which applies
+ * when the directory is not a tmpfs partition (MOUNT_SECURE flag not set).
+ */
+pub(crate) fn available_space(
+    secure_dir: &Path,
+    secure_size: &str,
+) -> Result<u64> {
+    let path = CString::new(secure_dir.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(Error::SecureMount(format!(
+            "unable to get the available space in {}: {}",
+            secure_dir.display(),
+            io::Error::last_os_error()
+        )));
+    }
+    let available = stat.f_bavail.saturating_mul(stat.f_frsize);
+
+    // A size relative to the memory cannot be checked without mounting
+    Ok(match parse_size(secure_size) {
+        Ok(size) => available.min(size),
+        Err(_) => available,
+    })
+}
+
+/// Where the secure storage is kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SecureStorage {
+    /// A tmpfs partition mounted by the agent in the work directory
+    Mount,
+    /// A tmpfs partition mounted at the given path by the orchestrator, e.g.
+    /// a memory-backed volume of the container
+    Provided(PathBuf),
+    /// A private directory in the shared memory file system (/dev/shm), for
+    /// when the agent cannot mount file systems. An anonymous memory file
+    /// (memfd) is not an option, as the storage must hold a directory tree
+    /// reachable by path for the payload scripts.
+    ShmDir,
+}
+
+impl SecureStorage {
+    /// Select the secure storage from the 'secure_mount_mode' and
+    /// 'secure_mount_path' options. In the "auto" mode, which has to be set
+    /// explicitly, the agent mounts the tmpfs partition if it is allowed to,
+    /// and otherwise uses the path provided by the orchestrator, if set, or
+    /// a directory in the shared memory.
+    pub(crate) fn select(
+        mode: &str,
+        path: &str,
+        can_mount: bool,
+    ) -> Result<Self> {
+        let provided = || {
+            match path {
+            "" => Err(Error::Configuration(format!(
+                "The 'secure_mount_mode' option is set as '{mode}' but no path is set in 'secure_mount_path'"
+            ))),
+            p => Ok(SecureStorage::Provided(PathBuf::from(p))),
+        }
+        };
+
+        match mode {
+            "mount" | "" => Ok(SecureStorage::Mount),
+            "provided" => provided(),
+            "shm_dir" => Ok(SecureStorage::ShmDir),
+            "auto" => {
+                if can_mount {
+                    Ok(SecureStorage::Mount)
+                } else if path.is_empty() {
+                    Ok(SecureStorage::ShmDir)
+                } else {
+                    provided()
+                }
+            }
+            other => Err(Error::Configuration(format!(
+                "Invalid value '{other}' set in 'secure_mount_mode': expected 'auto', 'mount', 'provided' or 'shm_dir'"
+            ))),
+        }
+    }
+
+    /// Get the path of the secure storage directory
+    pub(crate) fn dir(&self, work_dir: &Path) -> PathBuf {
+        match self {
+            SecureStorage::Mount => get_secure_dir_path(work_dir),
+            SecureStorage::Provided(path) => path.clone(),
+            SecureStorage::ShmDir => PathBuf::from(SHM_SECURE_DIR),
+        }
+    }
+}
+
+/*
+ * Get the effective capabilities from the CapEff field of the
+ * /proc/[pid]/status content, a bit mask in hexadecimal.
+ */
+fn parse_cap_eff(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+}
+
+/// Check whether the agent is allowed to mount file systems, which is not
+/// the case when running unprivileged, e.g. in unprivileged containers
+pub(crate) fn can_mount() -> bool {
+    if permissions::get_euid() != 0 {
+        return false;
+    }
+    match fs::read_to_string(PROC_STATUS).map(|s| parse_cap_eff(&s)) {
+        Ok(Some(caps)) => caps & (1 << CAP_SYS_ADMIN) != 0,
+        _ => false,
+    }
+}
+
+/// Get the path of the secure mount directory inside the work directory
+pub(crate) fn get_secure_dir_path(work_dir: &Path) -> PathBuf {
+    if MOUNT_SECURE {
+        work_dir.join("secure")
+    } else {
+        work_dir.join("tmpfs-dev")
+    }
+}
+
 /*
  * Return: Result wrap secure mount directory or error code
  *
@@ -80,12 +448,18 @@ fn check_mount(secure_dir: &Path) -> Result<bool> {
  * implementation as the original python version, but the chown/geteuid
  * functions are unsafe function in Rust to use.
  */
-pub(crate) fn mount(work_dir: &Path, secure_size: &str) -> Result<PathBuf> {
+pub(crate) fn mount(
+    work_dir: &Path,
+    secure_size: &str,
+    wait: Duration,
+    storage: &SecureStorage,
+    owner: Option<uid_t>,
+) -> Result<PathBuf> {
     // Use /tmpfs-dev directory if MOUNT_SECURE flag is not set. This
     // is for development environment and does not mount to the system.
     if !MOUNT_SECURE {
         warn!("Using /tmpfs-dev (dev environment)");
-        let secure_dir_path = work_dir.join("tmpfs-dev");
+        let secure_dir_path = get_secure_dir_path(work_dir);
         if !secure_dir_path.exists() {
             fs::create_dir(&secure_dir_path).map_err(|e| {
                 Error::SecureMount(format!(
@@ -98,12 +472,20 @@ pub(crate) fn mount(work_dir: &Path, secure_size: &str) -> Result<PathBuf> {
         return Ok(secure_dir_path);
     }
 
+    match storage {
+        SecureStorage::Mount => {}
+        SecureStorage::Provided(path) => return use_provided_storage(path),
+        SecureStorage::ShmDir => return use_shm_dir(owner),
+    }
+
     // Mount the directory to file system
-    let secure_dir_path = Path::new(work_dir).join("secure");
+    let secure_dir_path = get_secure_dir_path(work_dir);
 
     // If the directory is not mount to file system, mount the directory to
     // file system.
     if !check_mount(&secure_dir_path)? {
+        check_secure_size(secure_size, read_meminfo()?)?;
+
         // Create directory if the directory is not exist. The
         // directory permission is set to 448.
         if !secure_dir_path.exists() {
@@ -127,35 +509,260 @@ pub(crate) fn mount(work_dir: &Path, secure_size: &str) -> Result<PathBuf> {
             &secure_dir_path
         );
 
-        // mount tmpfs with secure directory
-        match Command::new("mount")
-            .args([
-                "-t",
-                "tmpfs",
-                "-o",
-                format!("size={secure_size},mode=0700").as_str(),
-                "tmpfs",
-                secure_dir_path.to_str().unwrap(), //#[allow_ci]
-            ])
-            .output()
-        {
-            Ok(output) => {
-                if !output.status.success() {
-                    return Err(Error::SecureMount(format!(
-                        "unable to mount tmpfs with secure dir: exit status code {}",
-                        output.status
-                    )));
-                }
+        // mount tmpfs with secure directory, waiting for the target to be
+        // released if it is still busy
+        retry_mount(
+            wait,
+            Duration::from_millis(MOUNT_RETRY_DELAY_MS),
+            || mount_tmpfs(&secure_dir_path, secure_size),
+        )?;
+    }
+
+    _ = check_mount_namespace(&secure_dir_path)?;
+
+    Ok(secure_dir_path)
+}
+
+/*
+ * Use the tmpfs partition mounted by the orchestrator as secure storage,
+ * without attempting to mount. The 'secure_size' option does not apply, as
+ * the size is set by the orchestrator.
+ */
+fn use_provided_storage(path: &Path) -> Result<PathBuf> {
+    if !check_mount(path)? {
+        let message = format!(
+            "Secure storage location {} set in 'secure_mount_path' is not a tmpfs mount point",
+            path.display()
+        );
+        error!("Secure mount error: {}", message);
+        return Err(Error::SecureMount(message));
+    }
+
+    info!(
+        "Using secure storage location {:?} provided on tmpfs.",
+        path
+    );
+    _ = check_mount_namespace(path)?;
+    Ok(path.to_path_buf())
+}
+
+/*
+ * Check that an existing directory can be trusted as secure storage: it is
+ * not a symbolic link, it is owned by the agent or by the user the agent runs
+ * as ('owner'), and it is not accessible by other users. Otherwise, another
+ * user could have created it to read the decrypted payload and keys.
+ */
+fn check_private_dir(path: &Path, owner: Option<uid_t>) -> Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    let reason = if !metadata.file_type().is_dir() {
+        "it is not a directory"
+    } else if metadata.uid() != permissions::get_euid()
+        && Some(metadata.uid()) != owner
+    {
+        "it is owned by another user"
+    } else if metadata.mode() & 0o077 != 0 {
+        "it is accessible by other users"
+    } else {
+        return Ok(());
+    };
+
+    let message = format!(
+        "Secure storage location {} cannot be used: {reason}. Remove it to let the agent create it",
+        path.display()
+    );
+    error!("Secure mount error: {}", message);
+    Err(Error::SecureMount(message))
+}
+
+/*
+ * Use a private directory in the shared memory file system as secure
+ * storage, for when the agent cannot mount the tmpfs partition. The files
+ * are kept in memory, but the size is not limited by 'secure_size'.
+ *
+ * As the shared memory is writable by all users, a directory that already
+ * exists is only used if it was created by the agent ('owner' is the user the
+ * agent runs as).
+ */
+fn use_shm_dir(owner: Option<uid_t>) -> Result<PathBuf> {
+    let shm_dir = Path::new(SHM_DIR);
+    if !check_mount(shm_dir)? {
+        warn!(
+            "{} is not a tmpfs mount point, the secure storage may not be memory-backed",
+            shm_dir.display()
+        );
+    }
+
+    let secure_dir_path = PathBuf::from(SHM_SECURE_DIR);
+    match fs::DirBuilder::new().mode(0o700).create(&secure_dir_path) {
+        Ok(()) => info!("Directory {:?} created.", secure_dir_path),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => {
+            return Err(Error::SecureMount(format!(
+                "unable to create secure dir path: {e:?}"
+            )))
+        }
+    }
+    // The mode passed to mkdir is reduced by the umask, and an existing
+    // directory may have been created by another user
+    check_private_dir(&secure_dir_path, owner)?;
+
+    warn!(
+        "Unable to mount the secure storage, using {:?} in the shared memory instead.",
+        secure_dir_path
+    );
+    Ok(secure_dir_path)
+}
+
+fn mount_tmpfs(secure_dir_path: &Path, secure_size: &str) -> Result<()> {
+    match Command::new("mount")
+        .args([
+            "-t",
+            "tmpfs",
+            "-o",
+            format!("size={secure_size},mode=0700").as_str(),
+            "tmpfs",
+            secure_dir_path.to_str().unwrap(), //#[allow_ci]
+        ])
+        .output()
+    {
+        Ok(output) => {
+            if !output.status.success() {
+                return Err(Error::SecureMount(format!(
+                    "unable to mount tmpfs with secure dir: exit status code {}",
+                    output.status
+                )));
             }
+        }
+        Err(e) => {
+            return Err(Error::SecureMount(format!(
+                "unable to mount tmpfs with secure dir: {e}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Run the mount, retrying every `delay` while it fails, until `wait` elapses.
+ * This allows restarting the agent while the previous mount is still being
+ * released. A zero `wait` fails on the first error.
+ */
+fn retry_mount(
+    wait: Duration,
+    delay: Duration,
+    mut mount_fn: impl FnMut() -> Result<()>,
+) -> Result<()> {
+    let deadline = Instant::now() + wait;
+    loop {
+        match mount_fn() {
+            Ok(()) => return Ok(()),
+            Err(e) if Instant::now() + delay > deadline => return Err(e),
             Err(e) => {
+                warn!("Secure mount failed, retrying: {}", e);
+                thread::sleep(delay);
+            }
+        }
+    }
+}
+
+// Remove the content of a directory, keeping the directory
+fn clear_dir(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?.path();
+        if entry.is_dir() {
+            fs::remove_dir_all(&entry)?;
+        } else {
+            fs::remove_file(&entry)?;
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Unmount the secure storage mounted by mount(), discarding the decrypted
+ * payload and keys stored in it. In the development environment (MOUNT_SECURE
+ * flag not set) and in the shared memory the directory is removed instead,
+ * and the content of the storage provided by the orchestrator is removed
+ * without unmounting it.
+ *
+ * Once the agent dropped its privileges, the tmpfs partition cannot be
+ * unmounted, so its content is removed and a warning is logged.
+ *
+ * Nothing is done if the secure storage is not mounted, so it is safe to call
+ * more than once or if the mount never happened.
+ */
+pub(crate) fn unmount(
+    work_dir: &Path,
+    storage: &SecureStorage,
+) -> Result<()> {
+    let secure_dir_path = get_secure_dir_path(work_dir);
+
+    if MOUNT_SECURE {
+        match storage {
+            SecureStorage::Mount => {}
+            SecureStorage::Provided(path) => {
+                clear_dir(path)?;
+                info!("Secure storage location {:?} cleared.", path);
+                return Ok(());
+            }
+            SecureStorage::ShmDir => {
+                let path = Path::new(SHM_SECURE_DIR);
+                if path.exists() {
+                    fs::remove_dir_all(path)?;
+                    info!("Directory {:?} removed.", path);
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    if !MOUNT_SECURE {
+        if secure_dir_path.exists() {
+            fs::remove_dir_all(&secure_dir_path).map_err(|e| {
+                Error::SecureMount(format!(
+                    "unable to remove secure dir path: {e:?}"
+                ))
+            })?;
+            info!("Directory {:?} removed.", &secure_dir_path);
+        }
+        return Ok(());
+    }
+
+    if !check_mount(&secure_dir_path)? {
+        return Ok(());
+    }
+
+    if !can_mount() {
+        clear_dir(&secure_dir_path)?;
+        warn!(
+            "Not allowed to unmount the secure storage location {:?} after dropping privileges, its content was removed but it remains mounted",
+            &secure_dir_path
+        );
+        return Ok(());
+    }
+
+    info!("Unmounting secure storage location {:?}.", &secure_dir_path);
+
+    match Command::new("umount")
+        .arg(secure_dir_path.to_str().unwrap()) //#[allow_ci]
+        .output()
+    {
+        Ok(output) => {
+            if !output.status.success() {
                 return Err(Error::SecureMount(format!(
-                    "unable to mount tmpfs with secure dir: {e}"
+                    "unable to unmount secure dir: exit status code {}",
+                    output.status
                 )));
             }
         }
+        Err(e) => {
+            return Err(Error::SecureMount(format!(
+                "unable to unmount secure dir: {e}"
+            )));
+        }
     }
 
-    Ok(secure_dir_path)
+    Ok(())
 }
 #[cfg(test)]
 mod tests {
@@ -167,7 +774,278 @@ mod tests {
         let work_dir = Path::new(&path);
         let secure_dir_path = Path::new(work_dir).join("secure");
         let secure_size = "1m";
-        let test_mount = mount(&secure_dir_path, secure_size);
+        let test_mount = mount(
+            &secure_dir_path,
+            secure_size,
+            Duration::from_secs(0),
+            &SecureStorage::Mount,
+            None,
+        );
         assert!(check_mount(&secure_dir_path).is_ok());
     }
+
+    #[test]
+    fn test_unmount() {
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+
+        // Unmounting before mounting does nothing
+        assert!(unmount(work_dir.path(), &SecureStorage::Mount).is_ok());
+
+        let secure_dir_path = mount(
+            work_dir.path(),
+            "1m",
+            Duration::from_secs(0),
+            &SecureStorage::Mount,
+            None,
+        )
+        .unwrap(); //#[allow_ci]
+        fs::write(secure_dir_path.join("decrypted_payload"), "payload")
+            .unwrap(); //#[allow_ci]
+
+        unmount(work_dir.path(), &SecureStorage::Mount).unwrap(); //#[allow_ci]
+        assert!(!secure_dir_path.exists());
+        assert!(!check_mount(&secure_dir_path).unwrap()); //#[allow_ci]
+
+        assert!(unmount(work_dir.path(), &SecureStorage::Mount).is_ok());
+    }
+
+    #[test]
+    fn test_clear_dir() {
+        let temp_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let dir = temp_dir.path();
+        fs::write(dir.join("decrypted_payload"), "payload").unwrap(); //#[allow_ci]
+        fs::create_dir(dir.join("unzipped")).unwrap(); //#[allow_ci]
+        fs::write(dir.join("unzipped").join("key"), "key").unwrap(); //#[allow_ci]
+
+        clear_dir(dir).unwrap(); //#[allow_ci]
+        assert!(dir.exists());
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 0); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_check_private_dir() {
+        let temp_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let dir = temp_dir.path().join("secure");
+        fs::DirBuilder::new().mode(0o700).create(&dir).unwrap(); //#[allow_ci]
+        assert!(check_private_dir(&dir, None).is_ok());
+
+        // Accessible by other users
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap(); //#[allow_ci]
+        assert!(check_private_dir(&dir, None).is_err());
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap(); //#[allow_ci]
+
+        // A symbolic link to a private directory is not followed
+        let link = temp_dir.path().join("link");
+        std::os::unix::fs::symlink(&dir, &link).unwrap(); //#[allow_ci]
+        assert!(check_private_dir(&link, None).is_err());
+
+        // Not a directory
+        let file = temp_dir.path().join("file");
+        fs::write(&file, "").unwrap(); //#[allow_ci]
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o600))
+            .unwrap(); //#[allow_ci]
+        assert!(check_private_dir(&file, None).is_err());
+    }
+
+    #[test]
+    fn test_select_secure_storage() {
+        let select = SecureStorage::select;
+
+        // The directory in the shared memory is selected when mounting is
+        // not possible
+        assert_eq!(
+            select("auto", "", false).unwrap(), //#[allow_ci]
+            SecureStorage::ShmDir
+        );
+        assert_eq!(
+            select("auto", "", true).unwrap(), //#[allow_ci]
+            SecureStorage::Mount
+        );
+        assert_eq!(
+            select("auto", "/run/keylime", false).unwrap(), //#[allow_ci]
+            SecureStorage::Provided(PathBuf::from("/run/keylime"))
+        );
+
+        // The mode set in the configuration overrides the detection
+        assert_eq!(
+            select("mount", "", false).unwrap(), //#[allow_ci]
+            SecureStorage::Mount
+        );
+        assert_eq!(
+            select("", "", false).unwrap(), //#[allow_ci]
+            SecureStorage::Mount
+        );
+        assert_eq!(
+            select("shm_dir", "/run/keylime", true).unwrap(), //#[allow_ci]
+            SecureStorage::ShmDir
+        );
+        assert_eq!(
+            select("provided", "/run/keylime", true).unwrap(), //#[allow_ci]
+            SecureStorage::Provided(PathBuf::from("/run/keylime"))
+        );
+        assert!(select("provided", "", true).is_err());
+        assert!(select("shm", "", true).is_err());
+        assert!(select("memfd", "", true).is_err());
+
+        let work_dir = Path::new("/var/lib/keylime");
+        assert_eq!(
+            SecureStorage::ShmDir.dir(work_dir),
+            Path::new(SHM_SECURE_DIR)
+        );
+        assert_eq!(
+            SecureStorage::Mount.dir(work_dir),
+            get_secure_dir_path(work_dir)
+        );
+    }
+
+    #[test]
+    fn test_parse_cap_eff() {
+        let status = "Name:\tkeylime_agent\nCapInh:\t0000000000000000\nCapEff:\t000001ffffffffff\nCapBnd:\t000001ffffffffff\n";
+        let caps = parse_cap_eff(status).unwrap(); //#[allow_ci]
+        assert!(caps & (1 << CAP_SYS_ADMIN) != 0);
+
+        let status = "CapEff:\t00000000a80425fb\n";
+        let caps = parse_cap_eff(status).unwrap(); //#[allow_ci]
+        assert!(caps & (1 << CAP_SYS_ADMIN) == 0);
+
+        assert!(parse_cap_eff("Name:\tkeylime_agent\n").is_none());
+    }
+
+    #[test]
+    fn test_retry_mount() {
+        // The target is busy for the first attempts, then freed up
+        let busy_mount = |busy: u32| {
+            let mut attempts = 0;
+            move || {
+                attempts += 1;
+                if attempts <= busy {
+                    Err(Error::SecureMount("target is busy".to_string()))
+                } else {
+                    Ok(())
+                }
+            }
+        };
+        let delay = Duration::from_millis(10);
+
+        assert!(
+            retry_mount(Duration::from_secs(5), delay, busy_mount(2)).is_ok()
+        );
+
+        // Fail fast without waiting
+        assert!(retry_mount(Duration::from_secs(0), delay, busy_mount(1))
+            .is_err());
+
+        // The target is not freed up in time
+        assert!(retry_mount(
+            Duration::from_millis(50),
+            delay,
+            busy_mount(100)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_mount_propagation() {
+        let mountinfo = "\
+22 1 252:1 / / rw,relatime shared:1 - ext4 /dev/vda1 rw
+36 22 0:32 / /var/lib/keylime/secure rw,relatime shared:12 - tmpfs tmpfs rw,size=1024k,mode=700
+37 22 0:33 / /mnt/private rw,relatime - tmpfs tmpfs rw
+38 22 0:34 / /mnt/slave rw,relatime master:3 - tmpfs tmpfs rw
+39 22 0:35 / /mnt/both rw,relatime shared:5 master:3 - tmpfs tmpfs rw
+40 22 0:36 / /mnt/over rw,relatime shared:6 - tmpfs tmpfs rw
+41 40 0:37 / /mnt/over rw,relatime - tmpfs tmpfs rw
+";
+
+        assert_eq!(
+            parse_mount_propagation(
+                mountinfo,
+                Path::new("/var/lib/keylime/secure")
+            ),
+            Some(MountPropagation::Shared)
+        );
+        assert_eq!(
+            parse_mount_propagation(mountinfo, Path::new("/mnt/private")),
+            Some(MountPropagation::Private)
+        );
+        assert_eq!(
+            parse_mount_propagation(mountinfo, Path::new("/mnt/slave")),
+            Some(MountPropagation::Slave)
+        );
+        assert_eq!(
+            parse_mount_propagation(mountinfo, Path::new("/mnt/both")),
+            Some(MountPropagation::Shared)
+        );
+        // The last mount on a mount point is the visible one
+        assert_eq!(
+            parse_mount_propagation(mountinfo, Path::new("/mnt/over")),
+            Some(MountPropagation::Private)
+        );
+        assert_eq!(
+            parse_mount_propagation(mountinfo, Path::new("/mnt/missing")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_check_mount_namespace() {
+        // Guarded, as /proc may not be available in the test environment
+        if !Path::new(MOUNTINFO).exists() {
+            return;
+        }
+        assert!(check_mount_namespace(Path::new("/")).is_ok());
+    }
+
+    #[test]
+    fn test_parse_tmpfs_size() {
+        let total = 4 << 30;
+        assert_eq!(parse_tmpfs_size("1024", total).unwrap(), 1024); //#[allow_ci]
+        assert_eq!(parse_tmpfs_size("1k", total).unwrap(), 1 << 10); //#[allow_ci]
+        assert_eq!(parse_tmpfs_size("1m", total).unwrap(), 1 << 20); //#[allow_ci]
+        assert_eq!(parse_tmpfs_size("2G", total).unwrap(), 2 << 30); //#[allow_ci]
+        assert_eq!(parse_tmpfs_size("50%", total).unwrap(), 2 << 30); //#[allow_ci]
+        assert!(parse_tmpfs_size("", total).is_err());
+        assert!(parse_tmpfs_size("1t", total).is_err());
+        assert!(parse_tmpfs_size("m", total).is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512k").unwrap(), 512 << 10); //#[allow_ci]
+        assert_eq!(parse_size("1m").unwrap(), 1 << 20); //#[allow_ci]
+        assert_eq!(parse_size("2G").unwrap(), 2 << 30); //#[allow_ci]
+        assert_eq!(parse_size(" 4096 ").unwrap(), 4096); //#[allow_ci]
+        assert!(parse_size("abc").is_err());
+        assert!(parse_size("").is_err());
+        assert!(parse_size("1.5m").is_err());
+        assert!(parse_size("50%").is_err());
+    }
+
+    #[test]
+    fn test_available_space() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        assert_eq!(available_space(dir.path(), "1k").unwrap(), 1 << 10); //#[allow_ci]
+        assert!(available_space(dir.path(), "50%").unwrap() > 0); //#[allow_ci]
+        assert!(available_space(&dir.path().join("missing"), "1k").is_err());
+    }
+
+    #[test]
+    fn test_check_secure_size() {
+        let meminfo = MemInfo {
+            total: 4 << 30,
+            available: 1 << 30,
+        };
+
+        assert!(check_secure_size("1m", meminfo).is_ok());
+        // Larger than the available memory only logs a warning
+        assert!(check_secure_size("2g", meminfo).is_ok());
+        assert!(check_secure_size("5g", meminfo).is_err());
+        assert!(check_secure_size("200%", meminfo).is_err());
+    }
+
+    #[test]
+    fn test_read_meminfo() {
+        let meminfo = read_meminfo().unwrap(); //#[allow_ci]
+        assert!(meminfo.total > 0);
+        assert!(meminfo.available <= meminfo.total);
+    }
 }
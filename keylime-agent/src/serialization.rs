@@ -2,8 +2,11 @@
 // Copyright 2021 Keylime Authors
 
 use base64::{engine::general_purpose, Engine as _};
-use serde::{Deserialize, Serialize};
-use serde_json::Number;
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Serialize,
+};
+use std::fmt;
 
 #[derive(Debug, Deserialize)]
 struct WrappedBase64Encoded(
@@ -29,7 +32,7 @@ where
     String::deserialize(deserializer).and_then(|string| {
         general_purpose::STANDARD
             .decode(string)
-            .map_err(serde::de::Error::custom)
+            .map_err(de::Error::custom)
     })
 }
 
@@ -57,3 +60,94 @@ where
     Option::<WrappedBase64Encoded>::deserialize(deserializer)
         .map(|wrapped| wrapped.map(|wrapped| wrapped.0))
 }
+
+/// Serializes the bytes as a byte string, which is encoded as a CBOR byte
+/// string, and as an array of integers in JSON
+pub(crate) fn serialize_as_bytes<S>(
+    bytes: &[u8],
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_bytes(bytes)
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a byte string or an array of bytes")
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(v)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Vec<u8>, A::Error>
+    where
+        A: de::SeqAccess<'de>,
+    {
+        let mut bytes = Vec::new();
+        while let Some(b) = seq.next_element()? {
+            bytes.push(b);
+        }
+        Ok(bytes)
+    }
+}
+
+/// Deserializes bytes serialized with `serialize_as_bytes`, either from a
+/// byte string or from an array of integers
+pub(crate) fn deserialize_as_bytes<'de, D>(
+    deserializer: D,
+) -> Result<Vec<u8>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserializer.deserialize_byte_buf(BytesVisitor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Data {
+        #[serde(
+            serialize_with = "serialize_as_bytes",
+            deserialize_with = "deserialize_as_bytes"
+        )]
+        bytes: Vec<u8>,
+    }
+
+    #[test]
+    fn test_bytes() {
+        let data = Data {
+            bytes: vec![1, 2, 3, 4],
+        };
+
+        // The bytes are encoded as a byte string in CBOR, and as an array of
+        // integers in JSON
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&data, &mut cbor).unwrap(); //#[allow_ci]
+        assert_eq!(hex::encode(&cbor), "a16562797465734401020304");
+        let decoded: Data = ciborium::from_reader(cbor.as_slice()).unwrap(); //#[allow_ci]
+        assert_eq!(decoded, data);
+        let json = serde_json::to_string(&data).unwrap(); //#[allow_ci]
+        assert_eq!(json, r#"{"bytes":[1,2,3,4]}"#);
+        assert_eq!(serde_json::from_str::<Data>(&json).unwrap(), data); //#[allow_ci]
+
+        // Bytes encoded as an array of integers in CBOR are decoded as well
+        let decoded: Data = ciborium::from_reader(
+            hex::decode("a16562797465738401020304").unwrap().as_slice(), //#[allow_ci]
+        )
+        .unwrap(); //#[allow_ci]
+        assert_eq!(decoded, data);
+    }
+}
@@ -0,0 +1,126 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+use log::*;
+use std::{
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Watchdog limiting the time the agent takes to start serving requests.
+///
+/// The startup is split into named phases. If the startup is not finished
+/// before the timeout expires, the stalled phase is logged and passed to the
+/// `on_timeout` callback. The watchdog runs in a separate thread, so that it
+/// fires even if a blocking TPM operation stalls the async runtime.
+#[derive(Debug)]
+pub(crate) struct StartupWatchdog {
+    phase: Arc<Mutex<&'static str>>,
+    // Dropping the sender stops the watchdog thread
+    done_tx: Option<mpsc::Sender<()>>,
+}
+
+impl StartupWatchdog {
+    /// Starts the watchdog. A zero timeout disables the watchdog.
+    pub(crate) fn start<F>(timeout: Duration, on_timeout: F) -> Self
+    where
+        F: FnOnce(&'static str) + Send + 'static,
+    {
+        let phase = Arc::new(Mutex::new("initialization"));
+
+        if timeout.is_zero() {
+            return StartupWatchdog {
+                phase,
+                done_tx: None,
+            };
+        }
+
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let watched_phase = phase.clone();
+        _ = thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) =
+                done_rx.recv_timeout(timeout)
+            {
+                let phase = *watched_phase.lock().unwrap(); //#[allow_ci]
+                error!(
+                    "Agent startup timed out after {} seconds: the {} phase stalled",
+                    timeout.as_secs(),
+                    phase
+                );
+                on_timeout(phase);
+            }
+        });
+
+        StartupWatchdog {
+            phase,
+            done_tx: Some(done_tx),
+        }
+    }
+
+    /// Records the startup phase being run
+    pub(crate) fn enter(&self, phase: &'static str) {
+        debug!("Entering startup phase: {}", phase);
+        *self.phase.lock().unwrap() = phase; //#[allow_ci]
+    }
+
+    /// Stops the watchdog once the startup is complete
+    pub(crate) fn finish(self) {
+        if let Some(done_tx) = self.done_tx {
+            _ = done_tx.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_startup_watchdog_timeout() {
+        let (tx, rx) = mpsc::channel();
+        let watchdog = StartupWatchdog::start(
+            Duration::from_millis(100),
+            move |phase| {
+                tx.send(phase).unwrap(); //#[allow_ci]
+            },
+        );
+        watchdog.enter("registration");
+
+        let phase = rx.recv_timeout(Duration::from_secs(5)).unwrap(); //#[allow_ci]
+        assert_eq!(phase, "registration");
+    }
+
+    #[test]
+    fn test_startup_watchdog_finish() {
+        let (tx, rx) = mpsc::channel();
+        let watchdog = StartupWatchdog::start(
+            Duration::from_millis(100),
+            move |phase| {
+                tx.send(phase).unwrap(); //#[allow_ci]
+            },
+        );
+        watchdog.enter("registration");
+        watchdog.finish();
+
+        // The callback is dropped without being called
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn test_startup_watchdog_disabled() {
+        let (tx, rx) = mpsc::channel::<&'static str>();
+        let watchdog = StartupWatchdog::start(Duration::ZERO, move |phase| {
+            tx.send(phase).unwrap(); //#[allow_ci]
+        });
+        watchdog.enter("registration");
+
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+}
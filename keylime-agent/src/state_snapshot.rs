@@ -0,0 +1,299 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+//! Snapshot of the agent state, stored on a clean shutdown and restored on
+//! the next start to skip the AK generation and the registration when the
+//! TPM and the configuration did not change.
+//!
+//! The state is encrypted with a random key, which is sealed to the TPM under
+//! the EK, so that the snapshot can only be restored on the same TPM.
+
+use crate::{
+    common::{AgentData, AES_256_KEY_LEN, AES_BLOCK_SIZE},
+    crypto, serialization, QuoteData, Result,
+};
+use keylime::{
+    algorithms::{HashAlgorithm, SignAlgorithm},
+    tpm,
+};
+use log::*;
+use openssl::{
+    pkey::{PKey, Private, Public},
+    rand::rand_bytes,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+use tss_esapi::{
+    handles::KeyHandle,
+    structures::{self, Private as TpmPrivate},
+    traits::{Marshall, UnMarshall},
+};
+use zeroize::Zeroizing;
+
+/// The agent state kept across restarts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct StateSnapshot {
+    // The AK context and the registration state
+    pub agent_data: AgentData,
+    // The hash of the configuration the state was created with
+    config_hash: String,
+    // The private transport key in DER
+    #[serde(
+        serialize_with = "serialization::serialize_as_bytes",
+        deserialize_with = "serialization::deserialize_as_bytes"
+    )]
+    transport_key: Vec<u8>,
+}
+
+// The stored snapshot: the encrypted state and the sealed key to decrypt it
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotFile {
+    #[serde(
+        serialize_with = "serialization::serialize_as_bytes",
+        deserialize_with = "serialization::deserialize_as_bytes"
+    )]
+    sealed_public: Vec<u8>,
+    #[serde(
+        serialize_with = "serialization::serialize_as_bytes",
+        deserialize_with = "serialization::deserialize_as_bytes"
+    )]
+    sealed_private: Vec<u8>,
+    #[serde(
+        serialize_with = "serialization::serialize_as_bytes",
+        deserialize_with = "serialization::deserialize_as_bytes"
+    )]
+    encrypted_state: Vec<u8>,
+}
+
+impl StateSnapshot {
+    pub(crate) fn new(
+        agent_data: AgentData,
+        config_hash: &str,
+        transport_key: &PKey<Private>,
+    ) -> Result<Self> {
+        Ok(StateSnapshot {
+            agent_data,
+            config_hash: config_hash.to_string(),
+            transport_key: transport_key.private_key_to_der()?,
+        })
+    }
+
+    /// The transport key pair restored from the snapshot
+    pub(crate) fn transport_key_pair(
+        &self,
+    ) -> Result<(PKey<Public>, PKey<Private>)> {
+        let private = PKey::private_key_from_der(&self.transport_key)?;
+        let public = crypto::pkey_pub_from_priv(private.clone())?;
+        Ok((public, private))
+    }
+
+    /// Returns whether the snapshot was created with the same TPM, AK
+    /// algorithms and template, and configuration
+    pub(crate) fn valid(
+        &self,
+        hash_alg: HashAlgorithm,
+        sign_alg: SignAlgorithm,
+        ak_template: &tpm::AkTemplate,
+        ek_hash: &[u8],
+        config_hash: &str,
+    ) -> Result<bool> {
+        Ok(self.agent_data.valid(hash_alg, sign_alg, ek_hash)
+            && ak_template.matches(&self.agent_data.get_ak()?.public)
+            && self.config_hash == config_hash)
+    }
+
+    /// Encrypts the snapshot with a key sealed under the EK `ek_handle` and
+    /// stores it in `path`, readable only by the owner
+    pub(crate) fn store(
+        &self,
+        ctx: &mut tpm::Context,
+        ek_handle: KeyHandle,
+        path: &Path,
+    ) -> Result<()> {
+        let mut key = Zeroizing::new([0u8; AES_256_KEY_LEN]);
+        let mut iv = [0u8; AES_BLOCK_SIZE];
+        rand_bytes(key.as_mut())?;
+        rand_bytes(&mut iv)?;
+
+        let mut state = Zeroizing::new(Vec::new());
+        ciborium::into_writer(self, &mut *state)?;
+        let encrypted_state =
+            crypto::encrypt_aead(key.as_ref(), &iv, &state)?;
+        let sealed = ctx.seal_data(ek_handle, key.as_ref())?;
+
+        let file = SnapshotFile {
+            sealed_public: sealed.public.marshall()?,
+            sealed_private: sealed.private.to_vec(),
+            encrypted_state,
+        };
+        let out = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        ciborium::into_writer(&file, out)?;
+        Ok(())
+    }
+
+    /// Loads the snapshot stored in `path`, unsealing its key under the EK
+    /// `ek_handle`
+    pub(crate) fn load(
+        ctx: &mut tpm::Context,
+        ek_handle: KeyHandle,
+        path: &Path,
+    ) -> Result<Self> {
+        let file: SnapshotFile =
+            ciborium::from_reader(fs::File::open(path)?)?;
+        let sealed = tpm::SealedData {
+            public: structures::Public::unmarshall(&file.sealed_public)?,
+            private: TpmPrivate::try_from(file.sealed_private)?,
+        };
+        let key = Zeroizing::new(ctx.unseal_data(ek_handle, &sealed)?);
+        let state = Zeroizing::new(crypto::decrypt_aead(
+            &key,
+            &file.encrypted_state,
+        )?);
+        Ok(ciborium::from_reader(state.as_slice())?)
+    }
+}
+
+/// Restores the snapshot stored in `path`, if any. The snapshot is removed
+/// once read, so that it is only used for the start following the shutdown
+/// that stored it.
+pub(crate) fn restore(
+    ctx: &mut tpm::Context,
+    ek_handle: KeyHandle,
+    path: &Path,
+) -> Option<StateSnapshot> {
+    if !path.exists() {
+        info!("State snapshot not found in: {}", path.display());
+        return None;
+    }
+
+    let snapshot = StateSnapshot::load(ctx, ek_handle, path);
+    if let Err(e) = fs::remove_file(path) {
+        warn!(
+            "Could not remove the state snapshot {}: {}",
+            path.display(),
+            e
+        );
+    }
+    match snapshot {
+        Ok(snapshot) => Some(snapshot),
+        Err(e) => {
+            warn!(
+                "Could not restore the state snapshot {}: {}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Stores the current state of the agent in `path`, on a clean shutdown
+pub(crate) fn save(data: &QuoteData, path: &Path) -> Result<()> {
+    let snapshot = StateSnapshot::new(
+        data.agent_data.lock().unwrap().clone(), //#[allow_ci]
+        data.config_hash.as_deref().unwrap_or_default(),
+        &data.transport_keys.read().unwrap().1, //#[allow_ci]
+    )?;
+
+    let mut ctx = data.tpmcontext.lock().unwrap(); //#[allow_ci]
+
+    // The transient EK is flushed after the registration, so it is created
+    // again to seal the key
+    let (ek_handle, transient) = match data.ek_handle {
+        Some(handle) => (handle, false),
+        None => (ctx.create_ek(data.enc_alg, None)?.key_handle, true),
+    };
+    if transient && ctx.session_encryption_enabled() {
+        ctx.enable_session_encryption(ek_handle);
+    }
+    let result = snapshot.store(&mut ctx, ek_handle, path);
+    if transient {
+        ctx.as_mut().flush_context(ek_handle.into())?;
+    }
+    result
+}
+
+#[cfg(feature = "testing")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::hash_ek_pubkey;
+    use keylime::algorithms::EncryptionAlgorithm;
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut ctx = tpm::Context::new().unwrap(); //#[allow_ci]
+        let ek = ctx.create_ek(EncryptionAlgorithm::Rsa, None).unwrap(); //#[allow_ci]
+        let ak = ctx
+            .create_ak(
+                ek.key_handle,
+                HashAlgorithm::Sha256,
+                SignAlgorithm::RsaSsa,
+            )
+            .unwrap(); //#[allow_ci]
+        let ek_hash = hash_ek_pubkey(ek.public.clone()).unwrap(); //#[allow_ci]
+        let mut agent_data = AgentData::create(
+            HashAlgorithm::Sha256,
+            SignAlgorithm::RsaSsa,
+            &ak,
+            ek_hash.as_bytes(),
+        )
+        .unwrap(); //#[allow_ci]
+        agent_data.set_registration_digest(Some("abcd".to_string()));
+        let (_, transport_key) = crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+        let snapshot =
+            StateSnapshot::new(agent_data, "config", &transport_key).unwrap(); //#[allow_ci]
+
+        let temp_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = temp_dir.path().join("state");
+        snapshot.store(&mut ctx, ek.key_handle, &path).unwrap(); //#[allow_ci]
+
+        // The state is restored once, and not stored in clear
+        let stored = fs::read(&path).unwrap(); //#[allow_ci]
+        assert!(!stored
+            .windows(snapshot.transport_key.len())
+            .any(|w| w == snapshot.transport_key.as_slice()));
+        let restored = restore(&mut ctx, ek.key_handle, &path).unwrap(); //#[allow_ci]
+        assert_eq!(restored, snapshot);
+        assert!(!path.exists());
+        assert!(restore(&mut ctx, ek.key_handle, &path).is_none());
+
+        let template = tpm::AkTemplate::default();
+        let valid = |config_hash| {
+            restored
+                .valid(
+                    HashAlgorithm::Sha256,
+                    SignAlgorithm::RsaSsa,
+                    &template,
+                    ek_hash.as_bytes(),
+                    config_hash,
+                )
+                .unwrap() //#[allow_ci]
+        };
+        assert!(valid("config"));
+        assert!(!valid("changed"));
+        let (_, restored_key) = restored.transport_key_pair().unwrap(); //#[allow_ci]
+        assert!(restored_key.public_eq(&transport_key));
+
+        // The restored AK is loaded instead of generating a new one
+        let (_, loaded_ak, reused) = crate::load_or_create_ak(
+            &mut ctx,
+            ek.key_handle,
+            Some(&restored.agent_data.get_ak().unwrap()), //#[allow_ci]
+            HashAlgorithm::Sha256,
+            SignAlgorithm::RsaSsa,
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(reused);
+        assert_eq!(loaded_ak.public, ak.public);
+    }
+}
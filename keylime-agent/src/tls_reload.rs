@@ -0,0 +1,258 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+use crate::{crypto, Error, Result};
+use openssl::{
+    pkey::{PKey, Public},
+    ssl::{SniError, SslAcceptorBuilder, SslContext},
+};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+/// Reloads the agent mTLS identity (server_key, server_cert and
+/// trusted_client_ca) from its files, so that a renewed certificate is
+/// presented without restarting the agent.
+///
+/// The TLS context is replaced for new connections only, and the connections
+/// already established are kept. The registrar is not updated, so the renewed
+/// certificate must keep the key registered on startup: a new key is rejected
+/// and requires restarting the agent to register it.
+///
+/// The files are read with the privileges of the 'run_as' user, so the
+/// renewed files must be readable by it.
+pub(crate) struct TlsReloader {
+    key_path: PathBuf,
+    key_password: String,
+    cert_path: PathBuf,
+    ca_path: PathBuf,
+    min_key_bits: u32,
+    require_client_cert: bool,
+    registered_key: PKey<Public>,
+    current: Arc<RwLock<SslContext>>,
+}
+
+// Reports a file the agent is not allowed to read, which happens when the
+// renewed files are only readable by root after the privileges were dropped
+fn check_readable(path: &Path) -> Result<()> {
+    match fs::File::open(path) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            Err(Error::Configuration(format!(
+                "The file {} is not readable by the user the agent runs as",
+                path.display()
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl TlsReloader {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        key_path: &str,
+        key_password: &str,
+        cert_path: &str,
+        ca_path: &str,
+        min_key_bits: u32,
+        require_client_cert: bool,
+        registered_key: PKey<Public>,
+        initial: SslContext,
+    ) -> Self {
+        TlsReloader {
+            key_path: PathBuf::from(key_path),
+            key_password: key_password.to_string(),
+            cert_path: PathBuf::from(cert_path),
+            ca_path: PathBuf::from(ca_path),
+            min_key_bits,
+            require_client_cert,
+            registered_key,
+            current: Arc::new(RwLock::new(initial)),
+        }
+    }
+
+    /// Makes the acceptor switch each new connection to the current TLS
+    /// context during the handshake.
+    ///
+    /// The servername callback is used as it runs on every handshake, even
+    /// when the client does not send the server name indication.
+    pub(crate) fn install(&self, builder: &mut SslAcceptorBuilder) {
+        let current = self.current.clone();
+        builder.set_servername_callback(move |ssl, _alert| {
+            let context = current.read().unwrap(); //#[allow_ci]
+            ssl.set_ssl_context(&context)
+                .map_err(|_| SniError::ALERT_FATAL)
+        });
+    }
+
+    /// Loads the TLS identity from the files and replaces the current TLS
+    /// context. On failure, or when the key differs from the registered one,
+    /// the current TLS context is kept.
+    pub(crate) fn reload(&self) -> Result<()> {
+        if self.key_path.as_os_str().is_empty()
+            || self.cert_path.as_os_str().is_empty()
+        {
+            return Err(Error::Configuration(
+                "The TLS identity can only be reloaded when 'server_key' and 'server_cert' are set".to_string(),
+            ));
+        }
+
+        for path in [&self.key_path, &self.cert_path, &self.ca_path] {
+            check_readable(path)?;
+        }
+
+        let (public, private) =
+            crypto::load_key_pair(&self.key_path, Some(&self.key_password))?;
+        if !public.public_eq(&self.registered_key) {
+            return Err(Error::Configuration(
+                "The key in 'server_key' differs from the key registered with the registrar, restart the agent to register it".to_string(),
+            ));
+        }
+        crypto::check_key_strength(&public, self.min_key_bits)?;
+        let cert = crypto::load_x509(&self.cert_path)?;
+        crypto::check_tls_identity(&cert, &private)?;
+        let ca_certs = crypto::load_x509_cert_chain(&self.ca_path)?;
+
+        let context = crypto::generate_mtls_context(
+            &cert,
+            &private,
+            ca_certs,
+            self.require_client_cert,
+        )?
+        .build()
+        .into_context();
+        *self.current.write().unwrap() = context; //#[allow_ci]
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{
+        pkey::{PKey, Private},
+        ssl::{SslAcceptor, SslConnector, SslMethod, SslVerifyMode},
+        x509::X509,
+    };
+    use std::{
+        fs,
+        net::{TcpListener, TcpStream},
+        path::Path,
+        thread,
+    };
+
+    fn write_identity(dir: &Path, key: &PKey<Private>, uuid: &str) -> X509 {
+        let cert = crypto::generate_x509(key, uuid).unwrap(); //#[allow_ci]
+        crypto::write_key_pair(key, &dir.join("server-key.pem"), None)
+            .unwrap(); //#[allow_ci]
+        crypto::write_x509(&cert, &dir.join("server-cert.crt")).unwrap(); //#[allow_ci]
+        crypto::write_x509(&cert, &dir.join("cacert.crt")).unwrap(); //#[allow_ci]
+        cert
+    }
+
+    // Connects to a server using the acceptor, returning the certificate
+    // presented by the server
+    fn presented_cert(acceptor: &SslAcceptor) -> X509 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap(); //#[allow_ci]
+        let addr = listener.local_addr().unwrap(); //#[allow_ci]
+        let acceptor = acceptor.clone();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap(); //#[allow_ci]
+            if let Ok(mut stream) = acceptor.accept(stream) {
+                _ = stream.shutdown();
+            }
+        });
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap(); //#[allow_ci]
+        connector.set_verify(SslVerifyMode::NONE);
+        // The verifier and tenant connect to the agent IP address, without
+        // the server name indication
+        let config = connector
+            .build()
+            .configure()
+            .unwrap() //#[allow_ci]
+            .use_server_name_indication(false)
+            .verify_hostname(false);
+        let stream = TcpStream::connect(addr).unwrap(); //#[allow_ci]
+        let stream = config.connect("127.0.0.1", stream).unwrap(); //#[allow_ci]
+        let cert = stream.ssl().peer_certificate().unwrap(); //#[allow_ci]
+        drop(stream);
+        server.join().unwrap(); //#[allow_ci]
+        cert
+    }
+
+    #[test]
+    fn test_tls_reload() {
+        let temp_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let dir = temp_dir.path();
+        let path = |name: &str| dir.join(name).display().to_string();
+        let key = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let cert = write_identity(dir, &key, "first");
+        let ca_certs = vec![cert.clone()];
+
+        let initial = crypto::generate_mtls_context(
+            &cert,
+            &key,
+            ca_certs.clone(),
+            false,
+        )
+        .unwrap() //#[allow_ci]
+        .build()
+        .into_context();
+        let reloader = TlsReloader::new(
+            &path("server-key.pem"),
+            "",
+            &path("server-cert.crt"),
+            &path("cacert.crt"),
+            2048,
+            false,
+            crypto::pkey_pub_from_priv(key.clone()).unwrap(), //#[allow_ci]
+            initial,
+        );
+        let mut builder =
+            crypto::generate_mtls_context(&cert, &key, ca_certs, false)
+                .unwrap(); //#[allow_ci]
+        reloader.install(&mut builder);
+        let acceptor = builder.build();
+
+        let presented = presented_cert(&acceptor);
+        assert_eq!(presented.to_der().unwrap(), cert.to_der().unwrap()); //#[allow_ci]
+
+        // The renewed certificate is presented after the reload
+        let renewed = write_identity(dir, &key, "renewed");
+        assert!(reloader.reload().is_ok());
+        let presented = presented_cert(&acceptor);
+        assert_eq!(presented.to_der().unwrap(), renewed.to_der().unwrap()); //#[allow_ci]
+
+        // A new key is rejected, as it was not registered with the
+        // registrar, and the current certificate is kept
+        let new_key = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let rekeyed = write_identity(dir, &new_key, "rekeyed");
+        assert!(reloader.reload().is_err());
+        let presented = presented_cert(&acceptor);
+        assert_eq!(presented.to_der().unwrap(), renewed.to_der().unwrap()); //#[allow_ci]
+        assert_ne!(
+            presented.to_der().unwrap(), //#[allow_ci]
+            rekeyed.to_der().unwrap()    //#[allow_ci]
+        );
+
+        // A certificate not matching the key is rejected as well
+        let mismatched = write_identity(dir, &new_key, "mismatched");
+        crypto::write_key_pair(&key, &dir.join("server-key.pem"), None)
+            .unwrap(); //#[allow_ci]
+        assert!(reloader.reload().is_err());
+        let presented = presented_cert(&acceptor);
+        assert_eq!(presented.to_der().unwrap(), renewed.to_der().unwrap()); //#[allow_ci]
+        assert_ne!(
+            presented.to_der().unwrap(),  //#[allow_ci]
+            mismatched.to_der().unwrap()  //#[allow_ci]
+        );
+
+        // An invalid certificate is rejected as well
+        fs::write(dir.join("server-cert.crt"), "not a certificate").unwrap(); //#[allow_ci]
+        assert!(reloader.reload().is_err());
+        let presented = presented_cert(&acceptor);
+        assert_eq!(presented.to_der().unwrap(), renewed.to_der().unwrap()); //#[allow_ci]
+    }
+}
@@ -0,0 +1,279 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+use crate::{pcr_log, Error, Result};
+use keylime::tpm;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::Path, time::Duration};
+
+/// The file in the keylime directory where the reading used as the baseline
+/// for the TPM clock check is stored
+pub(crate) static CLOCK_BASELINE_FILE: &str = "tpm_clock.json";
+
+/// A reading of the TPM clock, along with the system uptime at the time
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ClockReading {
+    pub boot_id: String,
+    pub reset_count: u32,
+    pub restart_count: u32,
+    // Milliseconds elapsed since the TPM started
+    pub tpm_time: u64,
+    // Milliseconds elapsed since the system booted
+    pub uptime: u64,
+}
+
+/// The outcome of comparing a reading of the TPM clock with the baseline
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ClockCheck {
+    /// The skew accumulated since the baseline
+    Skew(Duration),
+    /// The reading cannot be compared with the baseline, for the given
+    /// reason, and becomes the new baseline
+    Rebase(&'static str),
+}
+
+/// Gets the time elapsed since the system booted, including the time the
+/// system was suspended
+fn system_uptime() -> io::Result<Duration> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+/// Compares the time elapsed on the TPM since the `baseline` reading with
+/// the time elapsed on the system, returning the skew if within the
+/// tolerance.
+///
+/// The absolute TPM time cannot be compared with the system uptime, as the
+/// TPM is not necessarily started when the system boots (e.g. a software
+/// TPM). Instead, the first reading of each boot is used as the baseline.
+/// A TPM Reset since the baseline, which clears the TPM state in the middle
+/// of the boot, is always reported. After a TPM Restart or Resume, as on a
+/// resume from hibernation, the TPM time starts over, so the reading becomes
+/// the new baseline.
+///
+/// Both clocks are expected to advance while the system is suspended. If the
+/// TPM is not powered while suspended, the TPM time lags behind and a skew
+/// may be reported after a long suspend.
+pub(crate) fn check_clock_skew(
+    baseline: Option<&ClockReading>,
+    current: &ClockReading,
+    tolerance: Duration,
+) -> Result<ClockCheck> {
+    let baseline = match baseline {
+        Some(b) if b.boot_id == current.boot_id => b,
+        Some(_) => return Ok(ClockCheck::Rebase("the system rebooted")),
+        None => return Ok(ClockCheck::Rebase("no baseline was recorded")),
+    };
+
+    if current.reset_count != baseline.reset_count {
+        return Err(Error::Other(format!(
+            "The TPM reset count changed from {} to {} since the system booted. The TPM may have been reset or tampered with",
+            baseline.reset_count, current.reset_count
+        )));
+    }
+    if current.restart_count != baseline.restart_count {
+        return Ok(ClockCheck::Rebase("the TPM was restarted"));
+    }
+
+    let elapsed = current.uptime.saturating_sub(baseline.uptime);
+    let expected = baseline.tpm_time.saturating_add(elapsed);
+    let skew = Duration::from_millis(current.tpm_time.abs_diff(expected));
+    if skew > tolerance {
+        return Err(Error::Other(format!(
+            "The time elapsed on the TPM ({}s) differs from the system uptime elapsed ({}s) since the baseline reading by {}s, more than the tolerance of {}s set in 'clock_skew_tolerance'. The TPM may have been reset or tampered with",
+            current.tpm_time.saturating_sub(baseline.tpm_time) / 1000,
+            elapsed / 1000,
+            skew.as_secs(),
+            tolerance.as_secs()
+        )));
+    }
+    Ok(ClockCheck::Skew(skew))
+}
+
+// Loads the baseline reading stored in `path`, if any
+fn load_baseline(path: &Path) -> Option<ClockReading> {
+    let contents = fs::read_to_string(path).ok()?;
+    match serde_json::from_str(&contents) {
+        Ok(baseline) => Some(baseline),
+        Err(e) => {
+            warn!(
+                "Ignoring invalid TPM clock baseline {}: {}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Reads the TPM clock and checks its skew against the system uptime since
+/// the baseline reading stored in `baseline_path`. If the skew exceeds the
+/// tolerance, returns an error if `fatal` is set, or logs a warning
+/// otherwise.
+///
+/// This must run before the TPM context is created, see `tpm::read_clock`.
+pub(crate) fn check_tpm_clock(
+    baseline_path: &Path,
+    tolerance: Duration,
+    fatal: bool,
+    tcti: &str,
+) -> Result<()> {
+    let time_info = tpm::read_clock(tcti)?;
+    if !time_info.clock_info().safe() {
+        warn!("The TPM reports that its clock may have been set back");
+    }
+    let current = ClockReading {
+        boot_id: pcr_log::read_boot_id(Path::new(pcr_log::BOOT_ID_PATH))?,
+        reset_count: time_info.clock_info().reset_count(),
+        restart_count: time_info.clock_info().restart_count(),
+        tpm_time: time_info.time(),
+        uptime: system_uptime()?.as_millis() as u64,
+    };
+    let baseline = load_baseline(baseline_path);
+
+    match check_clock_skew(baseline.as_ref(), &current, tolerance) {
+        Ok(ClockCheck::Skew(skew)) => {
+            info!("TPM clock skew within tolerance: {}ms", skew.as_millis());
+            Ok(())
+        }
+        Ok(ClockCheck::Rebase(reason)) => {
+            info!(
+                "Recording the TPM clock baseline in {}, as {}",
+                baseline_path.display(),
+                reason
+            );
+            fs::write(baseline_path, serde_json::to_string(&current)?)?;
+            Ok(())
+        }
+        Err(e) if fatal => {
+            error!("{}", e);
+            Err(e)
+        }
+        Err(e) => {
+            warn!("{}", e);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(tpm_time: u64, uptime: u64) -> ClockReading {
+        ClockReading {
+            boot_id: "boot".to_string(),
+            reset_count: 1,
+            restart_count: 0,
+            tpm_time: tpm_time * 1000,
+            uptime: uptime * 1000,
+        }
+    }
+
+    #[test]
+    fn test_check_clock_skew() {
+        let tolerance = Duration::from_secs(30);
+        // The TPM started 100s after the system booted
+        let baseline = reading(500, 600);
+
+        for tpm_time in [3500, 3480, 3525] {
+            let skew = check_clock_skew(
+                Some(&baseline),
+                &reading(tpm_time, 3600),
+                tolerance,
+            );
+            assert!(matches!(skew, Ok(ClockCheck::Skew(_))), "{tpm_time}");
+        }
+        assert_eq!(
+            check_clock_skew(
+                Some(&baseline),
+                &reading(3525, 3600),
+                tolerance
+            )
+            .unwrap(), //#[allow_ci]
+            ClockCheck::Skew(Duration::from_secs(25))
+        );
+
+        for tpm_time in [0, 3469, 3531] {
+            let skew = check_clock_skew(
+                Some(&baseline),
+                &reading(tpm_time, 3600),
+                tolerance,
+            );
+            assert!(matches!(skew, Err(Error::Other(_))), "{tpm_time}");
+        }
+    }
+
+    #[test]
+    fn test_check_clock_skew_rebase() {
+        let tolerance = Duration::from_secs(30);
+        let baseline = reading(500, 600);
+
+        // Without a baseline of the same boot the skew is not checked
+        let current = reading(0, 3600);
+        assert!(matches!(
+            check_clock_skew(None, &current, tolerance),
+            Ok(ClockCheck::Rebase(_))
+        ));
+        let mut rebooted = current.clone();
+        rebooted.boot_id = "other".to_string();
+        assert!(matches!(
+            check_clock_skew(Some(&baseline), &rebooted, tolerance),
+            Ok(ClockCheck::Rebase(_))
+        ));
+
+        // The TPM time starts over after a TPM Restart
+        let mut restarted = current.clone();
+        restarted.restart_count += 1;
+        assert!(matches!(
+            check_clock_skew(Some(&baseline), &restarted, tolerance),
+            Ok(ClockCheck::Rebase(_))
+        ));
+
+        // A TPM Reset in the middle of the boot is reported
+        let mut reset = reading(3500, 3600);
+        reset.reset_count += 1;
+        assert!(matches!(
+            check_clock_skew(Some(&baseline), &reset, tolerance),
+            Err(Error::Other(_))
+        ));
+    }
+
+    #[test]
+    fn test_system_uptime() {
+        let first = system_uptime().unwrap(); //#[allow_ci]
+        let second = system_uptime().unwrap(); //#[allow_ci]
+        assert!(!first.is_zero());
+        assert!(second >= first);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_tpm_clock_skew() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join(CLOCK_BASELINE_FILE);
+        let tcti = tpm::default_tcti();
+
+        // The first reading is recorded as the baseline
+        check_tpm_clock(&path, Duration::from_secs(30), true, &tcti).unwrap(); //#[allow_ci]
+        let baseline = load_baseline(&path).unwrap(); //#[allow_ci]
+
+        // The following readings are compared with the baseline
+        check_tpm_clock(&path, Duration::from_secs(30), true, &tcti).unwrap(); //#[allow_ci]
+        assert_eq!(load_baseline(&path).unwrap(), baseline); //#[allow_ci]
+
+        // A skew beyond the tolerance is only reported when not fatal
+        let mut skewed = baseline;
+        skewed.tpm_time += 60_000;
+        fs::write(&path, serde_json::to_string(&skewed).unwrap()).unwrap(); //#[allow_ci]
+        assert!(check_tpm_clock(&path, Duration::ZERO, false, &tcti).is_ok());
+        assert!(check_tpm_clock(&path, Duration::ZERO, true, &tcti).is_err());
+    }
+}
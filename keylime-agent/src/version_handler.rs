@@ -1,7 +1,7 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2022 Keylime Authors
 
-use crate::common::{JsonWrapper, API_VERSION};
+use crate::common::{JsonWrapper, API_VERSION, SUPPORTED_API_VERSIONS};
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use log::*;
 use serde::{Deserialize, Serialize};
@@ -9,6 +9,14 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize, Debug)]
 struct KeylimeVersion {
     supported_version: String,
+    // All the API versions served, to let the clients negotiate one
+    supported_versions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LandingInfo {
+    supported_version: String,
+    endpoints: Vec<String>,
 }
 
 // This is the handler for the GET request for the API version
@@ -21,11 +29,52 @@ pub async fn version(req: HttpRequest) -> impl Responder {
 
     let response = JsonWrapper::success(KeylimeVersion {
         supported_version: API_VERSION[1..].to_string(),
+        supported_versions: SUPPORTED_API_VERSIONS
+            .iter()
+            .map(|v| v[1..].to_string())
+            .collect(),
     });
 
     HttpResponse::Ok().json(response)
 }
 
+// This is the handler for the GET request for the root path, which lists the
+// available endpoints
+pub async fn landing(req: HttpRequest) -> impl Responder {
+    info!(
+        "GET invoked from {:?} with uri {}",
+        req.connection_info().peer_addr().unwrap(), //#[allow_ci]
+        req.uri()
+    );
+
+    let endpoints = vec![
+        "GET /version".to_string(),
+        format!("GET /{API_VERSION}/agent/info"),
+        format!("GET /{API_VERSION}/keys/pubkey"),
+        format!("GET /{API_VERSION}/keys/verify"),
+        format!("POST /{API_VERSION}/keys/ukey"),
+        format!("POST /{API_VERSION}/keys/vkey"),
+        format!("POST /{API_VERSION}/notifications/revocation"),
+        format!("GET /{API_VERSION}/payload/status"),
+        format!("GET /{API_VERSION}/quotes/identity"),
+        format!("GET /{API_VERSION}/quotes/integrity"),
+    ];
+
+    let response = JsonWrapper::success(LandingInfo {
+        supported_version: API_VERSION[1..].to_string(),
+        endpoints,
+    });
+
+    HttpResponse::Ok().json(response)
+}
+
+// Registers the landing page on the root path, if enabled
+pub(crate) fn landing_config(cfg: &mut web::ServiceConfig, enabled: bool) {
+    if enabled {
+        _ = cfg.service(web::resource("/").route(web::get().to(landing)));
+    }
+}
+
 #[cfg(feature = "testing")]
 #[cfg(test)]
 mod tests {
@@ -47,5 +96,43 @@ mod tests {
         let body: JsonWrapper<KeylimeVersion> =
             test::read_body_json(resp).await;
         assert_eq!(body.results.supported_version, API_VERSION[1..]);
+        assert!(body
+            .results
+            .supported_versions
+            .contains(&API_VERSION[1..].to_string()));
+    }
+
+    #[actix_rt::test]
+    async fn test_landing() {
+        let mut app = test::init_service(
+            App::new().configure(|cfg| landing_config(cfg, true)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/").to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let body: JsonWrapper<LandingInfo> = test::read_body_json(resp).await;
+        assert_eq!(body.results.supported_version, API_VERSION[1..]);
+        assert!(body.results.endpoints.contains(&"GET /version".to_string()));
+        assert!(body
+            .results
+            .endpoints
+            .contains(&format!("GET /{API_VERSION}/agent/info")));
+    }
+
+    #[actix_rt::test]
+    async fn test_landing_disabled() {
+        let mut app = test::init_service(
+            App::new().configure(|cfg| landing_config(cfg, false)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/").to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 404);
     }
 }
@@ -85,6 +85,30 @@ impl From<HashAlgorithm> for MessageDigest {
     }
 }
 
+impl HashAlgorithm {
+    /// Returns the size in bytes of the digests produced by the algorithm
+    pub fn digest_size(&self) -> usize {
+        MessageDigest::from(*self).size()
+    }
+
+    /// Parses a comma-separated list of hash algorithms, e.g.
+    /// "sha1,sha256". The list must not be empty nor name the same
+    /// algorithm twice
+    pub fn parse_list(value: &str) -> Result<Vec<Self>, AlgorithmError> {
+        let mut algs = Vec::new();
+        for name in value.split(',').map(str::trim) {
+            let alg = HashAlgorithm::try_from(name)?;
+            if algs.contains(&alg) {
+                return Err(AlgorithmError::Hash(format!(
+                    "Hash algorithm {alg} is listed more than once"
+                )));
+            }
+            algs.push(alg);
+        }
+        Ok(algs)
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum EncryptionAlgorithm {
     Rsa,
@@ -202,9 +226,48 @@ mod tests {
         assert!(result.is_ok());
     }
     #[test]
+    fn test_hash_digest_size() {
+        assert_eq!(HashAlgorithm::Sha1.digest_size(), 20);
+        assert_eq!(HashAlgorithm::Sha256.digest_size(), 32);
+        assert_eq!(HashAlgorithm::Sha384.digest_size(), 48);
+        assert_eq!(HashAlgorithm::Sha512.digest_size(), 64);
+    }
+    #[test]
+    fn test_hash_parse_list() {
+        assert_eq!(
+            HashAlgorithm::parse_list("sha256").unwrap(), //#[allow_ci]
+            vec![HashAlgorithm::Sha256]
+        );
+        assert_eq!(
+            HashAlgorithm::parse_list("sha1,sha256").unwrap(), //#[allow_ci]
+            vec![HashAlgorithm::Sha1, HashAlgorithm::Sha256]
+        );
+        assert_eq!(
+            HashAlgorithm::parse_list("sha256, sha1").unwrap(), //#[allow_ci]
+            vec![HashAlgorithm::Sha256, HashAlgorithm::Sha1]
+        );
+        assert!(HashAlgorithm::parse_list("sha1,md5").is_err());
+        assert!(HashAlgorithm::parse_list("sha256,sha256").is_err());
+        assert!(HashAlgorithm::parse_list("sha256,").is_err());
+        assert!(HashAlgorithm::parse_list("").is_err());
+    }
+    #[test]
     fn test_encrypt_try_from() {
         let result = EncryptionAlgorithm::try_from("rsa");
         assert!(result.is_ok());
+        let result = EncryptionAlgorithm::try_from("ecc");
+        assert!(result.is_ok());
+    }
+    #[test]
+    fn test_encrypt_asymmetric() {
+        assert_eq!(
+            AsymmetricAlgorithm::from(EncryptionAlgorithm::Rsa),
+            AsymmetricAlgorithm::Rsa
+        );
+        assert_eq!(
+            AsymmetricAlgorithm::from(EncryptionAlgorithm::Ecc),
+            AsymmetricAlgorithm::Ecc
+        );
     }
     #[test]
     fn test_sign_tryfrom() {
@@ -0,0 +1,39 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+use openssl::sha::sha256;
+
+/// Computes the agent UUID derived from the EK public key, as done by the
+/// agent when the `uuid` option is set as "hash_ek".
+///
+/// The `ek_pub` is the EK public key in PEM format, as encoded by OpenSSL
+/// (SubjectPublicKeyInfo). The UUID is the hex encoded SHA-256 digest of it.
+pub fn hash_ek_to_uuid(ek_pub: &[u8]) -> String {
+    hex::encode(sha256(ek_pub))
+}
+
+// Unit Testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::pkey::PKey;
+    use std::path::Path;
+
+    #[test]
+    fn test_hash_ek_to_uuid() {
+        let ek_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data")
+            .join("ek-pub.pem");
+        let pem = std::fs::read(ek_path).expect("unable to read EK");
+
+        // The agent re-encodes the EK public key with OpenSSL before hashing
+        let key = PKey::public_key_from_pem(&pem).expect("invalid EK");
+        let encoded = key.public_key_to_pem().expect("unable to encode EK");
+        assert_eq!(encoded, pem);
+
+        assert_eq!(
+            hash_ek_to_uuid(&encoded),
+            "015bb1a7b73d80973c2520dd1bf4826370be19ec3c468aa37ce199e99e9a6c0b"
+        );
+    }
+}
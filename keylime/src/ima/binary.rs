@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2023 Keylime Authors
+
+// Parser for the IMA binary measurement list format, as exposed by the kernel
+// in /sys/kernel/security/ima/binary_runtime_measurements.
+//
+// The entries are converted to the representation used in the ASCII
+// measurement list, which is the one expected by the verifier.
+
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Result};
+
+// The template hash in the binary list is always SHA-1
+const TEMPLATE_HASH_LEN: usize = 20;
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.offset >= self.data.len()
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.offset.checked_add(len).ok_or_else(|| {
+            Error::new(ErrorKind::InvalidData, "invalid field length")
+        })?;
+        if end > self.data.len() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "truncated IMA binary entry",
+            ));
+        }
+        let bytes = &self.data[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap())) //#[allow_ci]
+    }
+
+    // Reads a field prefixed with its length
+    fn read_field(&mut self) -> Result<&'a [u8]> {
+        let len = self.read_u32()? as usize;
+        self.read_bytes(len)
+    }
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+// The d-ng field contains the algorithm name followed by ":\0" and the
+// digest. Older kernels omit the prefix for SHA-1 digests.
+fn format_digest_ng(field: &[u8]) -> Result<String> {
+    match field.windows(2).position(|w| w == b":\0") {
+        Some(i) => {
+            let algorithm = std::str::from_utf8(&field[..i])
+                .map_err(|_| invalid_data("invalid digest algorithm"))?;
+            Ok(format!("{}:{}", algorithm, hex::encode(&field[i + 2..])))
+        }
+        None => Ok(format!("sha1:{}", hex::encode(field))),
+    }
+}
+
+fn format_name_ng(field: &[u8]) -> Result<String> {
+    let name = field.strip_suffix(&[0u8]).unwrap_or(field);
+    String::from_utf8(name.to_vec())
+        .map_err(|_| invalid_data("invalid event name"))
+}
+
+fn format_template_data(template: &str, data: &[u8]) -> Result<String> {
+    let mut reader = Reader::new(data);
+    let mut fields = vec![
+        format_digest_ng(reader.read_field()?)?,
+        format_name_ng(reader.read_field()?)?,
+    ];
+
+    match template {
+        "ima-ng" => (),
+        "ima-sig" | "ima-buf" => {
+            fields.push(hex::encode(reader.read_field()?));
+        }
+        template => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported template \"{template}\""),
+            ));
+        }
+    }
+
+    if !reader.is_empty() {
+        return Err(invalid_data("unexpected data in template"));
+    }
+
+    Ok(fields.join(" "))
+}
+
+/// Converts an IMA measurement list in the binary format to the ASCII
+/// format, one entry per line.
+pub fn binary_to_ascii(data: &[u8]) -> Result<String> {
+    let mut reader = Reader::new(data);
+    let mut ml = String::new();
+
+    while !reader.is_empty() {
+        let pcr = reader.read_u32()?;
+        let template_hash = reader.read_bytes(TEMPLATE_HASH_LEN)?;
+        let template = std::str::from_utf8(reader.read_field()?)
+            .map_err(|_| invalid_data("invalid template name"))?;
+        let template_data = reader.read_field()?;
+
+        ml.push_str(&format!(
+            "{} {} {} {}\n",
+            pcr,
+            hex::encode(template_hash),
+            template,
+            format_template_data(template, template_data)?
+        ));
+    }
+
+    Ok(ml)
+}
+
+// Unit Testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_binary_to_ascii() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data")
+            .join("ima")
+            .join("binary_runtime_measurements");
+        let data = std::fs::read(path).expect("unable to read binary list");
+
+        let ml = binary_to_ascii(&data).expect("unable to parse binary list");
+        let entries: Vec<&str> = ml.lines().collect();
+        assert_eq!(
+            entries,
+            vec![
+                "10 1d8d532d463c9f8c205d0df7787669a85f93e260 ima-ng sha1:0000000000000000000000000000000000000000 boot_aggregate",
+                "10 c156ebdcbfcd28fe1060ef4cdec0aab04d3a9b63 ima-ng sha1:19f13b42c2745066347e76454788c0fe083643f3 /init",
+                "10 790ff4fe72889b071a0f7585112710be6d0084fe ima-ng sha1:c90333979f56f38bbd41b81806015b0de502f3cc /bin/sh",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_binary_to_ascii_truncated() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data")
+            .join("ima")
+            .join("binary_runtime_measurements");
+        let data = std::fs::read(path).expect("unable to read binary list");
+
+        assert!(binary_to_ascii(&data[..data.len() - 1]).is_err());
+    }
+}
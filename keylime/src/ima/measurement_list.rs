@@ -1,28 +1,69 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
+use super::{binary_to_ascii, Entry};
+use crate::algorithms::HashAlgorithm;
+use openssl::hash::hash;
 use std::{
     collections::HashSet,
+    convert::TryFrom,
     fs::File,
-    io::{prelude::*, Error, SeekFrom},
+    io::{prelude::*, Error, ErrorKind, SeekFrom},
 };
 
+/// Format of the IMA measurement list file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImaFormat {
+    Ascii,
+    Binary,
+}
+
+impl ImaFormat {
+    /// Detects the format of the IMA measurement list `ima_file`.
+    /// The ASCII format entries start with the PCR number in decimal, while
+    /// the binary format entries start with the PCR number as a 32 bit
+    /// integer, which never starts with a printable digit.
+    pub fn detect(ima_file: &mut File) -> Result<Self, Error> {
+        let mut first = [0u8; 1];
+        let _ = ima_file.seek(SeekFrom::Start(0))?;
+        let n = ima_file.read(&mut first)?;
+        let _ = ima_file.seek(SeekFrom::Start(0))?;
+        if n == 0 || first[0].is_ascii_digit() {
+            Ok(ImaFormat::Ascii)
+        } else {
+            Ok(ImaFormat::Binary)
+        }
+    }
+}
+
 /// MeasurementList models the IMA measurement lists's last two known
 /// numbers of entries in the log and filesizes at that point
 #[derive(Debug)]
 pub struct MeasurementList {
     entries: HashSet<(u64, u64)>,
+    format: ImaFormat,
+    // The last computed PCR aggregate, with its bank and number of entries
+    aggregate: Option<(HashAlgorithm, u64, Vec<u8>)>,
 }
 
 impl MeasurementList {
     pub fn new() -> Self {
+        Self::with_format(ImaFormat::Ascii)
+    }
+
+    /// Creates a MeasurementList for an IMA measurement list file in the
+    /// given `format`
+    pub fn with_format(format: ImaFormat) -> Self {
         Self {
             entries: HashSet::new(),
+            format,
+            aggregate: None,
         }
     }
 
     pub fn reset(&mut self) {
         self.entries = HashSet::new();
+        self.aggregate = None;
     }
 
     fn update(&mut self, num_entries: u64, filesize: u64) -> Option<bool> {
@@ -50,10 +91,23 @@ impl MeasurementList {
     /// automatically read from the 0-th entry.
     /// This function returns the measurement list and the entry from where it
     /// was read and the current number of entries in the file.
+    /// The entries of a binary measurement list are returned converted to
+    /// the ASCII format.
     pub fn read(
         &mut self,
         ima_file: &mut File,
         nth_entry: u64,
+    ) -> Result<(String, u64, u64), Error> {
+        match self.format {
+            ImaFormat::Ascii => self.read_ascii(ima_file, nth_entry),
+            ImaFormat::Binary => Self::read_binary(ima_file, nth_entry),
+        }
+    }
+
+    fn read_ascii(
+        &mut self,
+        ima_file: &mut File,
+        nth_entry: u64,
     ) -> Result<(String, u64, u64), Error> {
         // Try to find the closest entry to the nth_entry
         let (mut num_entries, filesize) = self.find(nth_entry);
@@ -80,10 +134,115 @@ impl MeasurementList {
         let _ = self.update(num_entries, filesize + offset as u64);
 
         match ml {
-            None => self.read(ima_file, 0),
+            None => self.read_ascii(ima_file, 0),
             Some(slice) => Ok((String::from(slice), nth_entry, num_entries)),
         }
     }
+
+    /// Computes the expected value of the IMA PCR after extending the first
+    /// `num_entries` entries of the measurement list into the PCR bank of
+    /// `hash_alg`, given the entries `ml` from `nth_entry` on, as returned by
+    /// read(). The result is cached, so that only the entries added since the
+    /// previous call are replayed, and the file is only read again when `ml`
+    /// does not follow the replayed entries.
+    pub fn pcr_aggregate(
+        &mut self,
+        ima_file: &mut File,
+        hash_alg: HashAlgorithm,
+        ml: &str,
+        nth_entry: u64,
+        num_entries: u64,
+    ) -> Result<Vec<u8>, Error> {
+        let start = vec![0u8; hash_alg.digest_size()];
+        let (mut replayed, mut pcr) = match self.aggregate.take() {
+            Some((alg, n, pcr)) if alg == hash_alg && n <= num_entries => {
+                (n, pcr)
+            }
+            _ => (0, start.clone()),
+        };
+
+        let read_ml;
+        let ml = if nth_entry == replayed {
+            ml
+        } else if nth_entry == 0 {
+            replayed = 0;
+            pcr = start;
+            ml
+        } else {
+            let (ml, nth_read, _) = self.read(ima_file, replayed)?;
+            // The list is read from the beginning if it was reset
+            if nth_read != replayed {
+                replayed = 0;
+                pcr = start;
+            }
+            read_ml = ml;
+            &read_ml
+        };
+
+        for line in ml.lines().take((num_entries - replayed) as usize) {
+            pcr = extend(&pcr, &template_digest(line, hash_alg)?, hash_alg)?;
+            replayed += 1;
+        }
+
+        self.aggregate = Some((hash_alg, replayed, pcr.clone()));
+        Ok(pcr)
+    }
+
+    // The binary entries have variable length and are converted to ASCII,
+    // so the file offsets cannot be cached and the whole file is parsed.
+    fn read_binary(
+        ima_file: &mut File,
+        nth_entry: u64,
+    ) -> Result<(String, u64, u64), Error> {
+        let mut data = Vec::new();
+        let _ = ima_file.seek(SeekFrom::Start(0))?;
+        let _ = ima_file.read_to_end(&mut data)?;
+        let filedata = binary_to_ascii(&data)?;
+
+        let lines: Vec<&str> = filedata.split_inclusive('\n').collect();
+        let num_entries = lines.len() as u64;
+        let nth_entry = if nth_entry > num_entries {
+            0
+        } else {
+            nth_entry
+        };
+
+        Ok((lines[nth_entry as usize..].concat(), nth_entry, num_entries))
+    }
+}
+
+// Returns the digest extended into the PCR bank of `hash_alg` for the entry
+fn template_digest(
+    line: &str,
+    hash_alg: HashAlgorithm,
+) -> Result<Vec<u8>, Error> {
+    let entry = Entry::try_from(line)?;
+
+    // Measurement violations are recorded in the list with a zero template
+    // hash, but extended into the PCR as all ones
+    if entry.template_hash.value().iter().all(|b| *b == 0) {
+        return Ok(vec![0xffu8; hash_alg.digest_size()]);
+    }
+
+    if hash_alg == HashAlgorithm::Sha1 {
+        return Ok(entry.template_hash.value().to_vec());
+    }
+
+    let mut template_data = Vec::new();
+    entry.event_data.encode(&mut template_data)?;
+    hash(hash_alg.into(), &template_data)
+        .map(|d| d.to_vec())
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+fn extend(
+    pcr: &[u8],
+    digest: &[u8],
+    hash_alg: HashAlgorithm,
+) -> Result<Vec<u8>, Error> {
+    hash(hash_alg.into(), &[pcr, digest].concat())
+        .map(|d| d.to_vec())
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
 }
 
 impl Default for MeasurementList {
@@ -130,4 +289,133 @@ mod tests {
         assert_eq!(nth_entry, 0);
         assert_eq!(ml.find("0-entry").unwrap(), 0); //#[allow_ci]
     }
+
+    #[test]
+    fn read_binary_measurement_list_test() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data")
+            .join("ima")
+            .join("binary_runtime_measurements");
+        let mut ima_file = File::open(path).unwrap(); //#[allow_ci]
+
+        let format = ImaFormat::detect(&mut ima_file).unwrap(); //#[allow_ci]
+        assert_eq!(format, ImaFormat::Binary);
+        let mut ima_ml = MeasurementList::with_format(format);
+
+        // Request the 2nd entry, which is available
+        let (ml, nth_entry, num_entries) =
+            ima_ml.read(&mut ima_file, 2).unwrap(); //#[allow_ci]
+        assert_eq!(num_entries, 3);
+        assert_eq!(nth_entry, 2);
+        assert!(ml.starts_with("10 790ff4fe72889b071a0f7585112710be6d0084fe ima-ng sha1:c90333979f56f38bbd41b81806015b0de502f3cc /bin/sh"));
+
+        // Request the 3rd entry, which is not available yet
+        let (ml, nth_entry, num_entries) =
+            ima_ml.read(&mut ima_file, 3).unwrap(); //#[allow_ci]
+        assert_eq!(num_entries, 3);
+        assert_eq!(nth_entry, 3);
+        assert_eq!(ml.len(), 0);
+
+        // Request an entry beyond the next one, getting the entire list
+        let (ml, nth_entry, num_entries) =
+            ima_ml.read(&mut ima_file, 4).unwrap(); //#[allow_ci]
+        assert_eq!(num_entries, 3);
+        assert_eq!(nth_entry, 0);
+        assert_eq!(ml.lines().count(), 3);
+    }
+
+    #[test]
+    fn detect_ascii_format_test() {
+        let mut tf = NamedTempFile::new().unwrap(); //#[allow_ci]
+        tf.write_all(b"10 0-entry\n").unwrap(); //#[allow_ci]
+        tf.flush().unwrap(); //#[allow_ci]
+
+        let mut ima_file = File::open(tf.path()).unwrap(); //#[allow_ci]
+        assert_eq!(
+            ImaFormat::detect(&mut ima_file).unwrap(), //#[allow_ci]
+            ImaFormat::Ascii
+        );
+    }
+
+    // Replays the template hashes into a SHA-1 PCR
+    fn replay_sha1(template_hashes: &[&str]) -> Vec<u8> {
+        template_hashes.iter().fold(vec![0u8; 20], |pcr, h| {
+            let decoded = hex::decode(h).unwrap(); //#[allow_ci]
+            let digest = match decoded {
+                d if d.iter().all(|b| *b == 0) => vec![0xffu8; 20],
+                d => d,
+            };
+            hash(HashAlgorithm::Sha1.into(), &[pcr, digest].concat())
+                .unwrap() //#[allow_ci]
+                .to_vec()
+        })
+    }
+
+    #[test]
+    fn pcr_aggregate_test() {
+        let hashes = [
+            "1d8d532d463c9f8c205d0df7787669a85f93e260",
+            "0000000000000000000000000000000000000000",
+            "790ff4fe72889b071a0f7585112710be6d0084fe",
+        ];
+        let filedata = format!(
+            "10 {} ima-ng sha1:0000000000000000000000000000000000000000 boot_aggregate\n\
+             10 {} ima-ng sha1:0000000000000000000000000000000000000000 /init\n\
+             10 {} ima-ng sha1:c90333979f56f38bbd41b81806015b0de502f3cc /bin/sh\n",
+            hashes[0], hashes[1], hashes[2]
+        );
+        let mut tf = NamedTempFile::new().unwrap(); //#[allow_ci]
+        tf.write_all(filedata.as_bytes()).unwrap(); //#[allow_ci]
+        tf.flush().unwrap(); //#[allow_ci]
+
+        let mut ima_file = File::open(tf.path()).unwrap(); //#[allow_ci]
+        let mut ima_ml = MeasurementList::new();
+
+        // Replay incrementally, using the returned list which follows the
+        // replayed entries
+        let (ml, nth_entry, _) = ima_ml.read(&mut ima_file, 0).unwrap(); //#[allow_ci]
+        let pcr = ima_ml
+            .pcr_aggregate(
+                &mut ima_file,
+                HashAlgorithm::Sha1,
+                &ml,
+                nth_entry,
+                2,
+            )
+            .unwrap(); //#[allow_ci]
+        assert_eq!(pcr, replay_sha1(&hashes[..2]));
+        let (ml, nth_entry, num_entries) =
+            ima_ml.read(&mut ima_file, 2).unwrap(); //#[allow_ci]
+        assert_eq!(ml.lines().count(), 1);
+        let pcr = ima_ml
+            .pcr_aggregate(
+                &mut ima_file,
+                HashAlgorithm::Sha1,
+                &ml,
+                nth_entry,
+                num_entries,
+            )
+            .unwrap(); //#[allow_ci]
+        assert_eq!(pcr, replay_sha1(&hashes));
+
+        // Replay in another bank from the start, reading the entries missing
+        // from the returned list
+        let pcr = ima_ml
+            .pcr_aggregate(&mut ima_file, HashAlgorithm::Sha256, &ml, 2, 3)
+            .unwrap(); //#[allow_ci]
+        assert_eq!(pcr.len(), 32);
+
+        let mut ima_ml = MeasurementList::new();
+        let pcr = ima_ml
+            .pcr_aggregate(&mut ima_file, HashAlgorithm::Sha1, &ml, 2, 3)
+            .unwrap(); //#[allow_ci]
+        assert_eq!(pcr, replay_sha1(&hashes));
+
+        // An entry with an unsupported template cannot be replayed
+        let mut ima_ml = MeasurementList::new();
+        let ml = format!("10 {} unknown-template data\n", hashes[0]);
+        assert!(ima_ml
+            .pcr_aggregate(&mut ima_file, HashAlgorithm::Sha1, &ml, 0, 1)
+            .is_err());
+    }
 }
@@ -1,5 +1,7 @@
+mod binary;
 mod entry;
 mod measurement_list;
 
+pub use binary::*;
 pub use entry::*;
 pub use measurement_list::*;
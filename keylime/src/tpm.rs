@@ -3,8 +3,12 @@
 
 use crate::algorithms::{EncryptionAlgorithm, HashAlgorithm, SignAlgorithm};
 use base64::{engine::general_purpose, Engine as _};
+use bitfield::BitRange;
 use log::*;
 use std::convert::{TryFrom, TryInto};
+use std::ffi::CString;
+use std::io::Read;
+use std::ptr;
 use std::str::FromStr;
 use thiserror::Error;
 
@@ -18,33 +22,59 @@ use tss_esapi::{
     abstraction::{
         ak,
         cipher::Cipher,
-        ek,
+        ek, nv,
         pcr::{read_all, PcrData},
         DefaultKey,
     },
-    attributes::session::SessionAttributesBuilder,
+    attributes::{
+        object::ObjectAttributesBuilder, session::SessionAttributesBuilder,
+    },
     constants::{
-        response_code::Tss2ResponseCodeKind, session_type::SessionType,
+        response_code::{Tss2ResponseCode, Tss2ResponseCodeKind},
+        session_type::SessionType,
+        AlgorithmIdentifier,
     },
     handles::{
-        AuthHandle, KeyHandle, PcrHandle, PersistentTpmHandle, TpmHandle,
+        AuthHandle, KeyHandle, NvIndexTpmHandle, ObjectHandle, PcrHandle,
+        PersistentTpmHandle, SessionHandle, TpmHandle,
     },
     interface_types::{
-        algorithm::HashingAlgorithm, session_handles::AuthSession,
+        algorithm::{
+            AsymmetricAlgorithm, EccSchemeAlgorithm, HashingAlgorithm,
+            PublicAlgorithm, RsaSchemeAlgorithm, SignatureSchemeAlgorithm,
+        },
+        dynamic_handles::Persistent,
+        ecc::EccCurve,
+        key_bits::RsaKeyBits,
+        resource_handles::{NvAuth, Provision},
+        session_handles::{AuthSession, PolicySession},
     },
     structures::{
-        Attest, AttestInfo, Digest, DigestValues, EncryptedSecret, IdObject,
-        PcrSelectionList, PcrSelectionListBuilder, PcrSlot, Signature,
-        SignatureScheme,
+        Attest, AttestInfo, Digest, DigestList, DigestValues, EccScheme,
+        EncryptedSecret, IdObject, KeyDerivationFunctionScheme,
+        KeyedHashScheme, PcrSelectionList, PcrSelectionListBuilder, PcrSlot,
+        PublicBuilder, PublicEccParametersBuilder, PublicKeyRsa,
+        PublicKeyedHashParameters, PublicRsaParametersBuilder, RsaExponent,
+        RsaScheme, SensitiveData, Signature, SignatureScheme,
+        SymmetricDefinitionObject, TimeInfo,
     },
     tcti_ldr::TctiNameConf,
     traits::Marshall,
-    tss2_esys::{TPML_DIGEST, TPML_PCR_SELECTION},
+    tss2_esys::{
+        Esys_Finalize, Esys_Free, Esys_Initialize, Esys_ReadClock,
+        Tss2_TctiLdr_Finalize, Tss2_TctiLdr_Initialize, ESYS_CONTEXT,
+        ESYS_TR_NONE, TPM2B_DIGEST, TPML_DIGEST, TPML_PCR_SELECTION,
+        TPMS_PCR_SELECTION, TPMS_TIME_INFO, TSS2_TCTI_CONTEXT,
+    },
     Error::Tss2Error,
 };
 
 /// Maximum size of nonce used in `quote`.
 pub const MAX_NONCE_SIZE: usize = 64;
+
+/// Range of the TPM handles of the NV indices (TPM_HT_NV_INDEX).
+pub const NV_INDEX_FIRST: u32 = 0x0100_0000;
+pub const NV_INDEX_LAST: u32 = 0x01ff_ffff;
 const TPML_DIGEST_SIZE: usize = std::mem::size_of::<TPML_DIGEST>();
 const TPML_PCR_SELECTION_SIZE: usize =
     std::mem::size_of::<TPML_PCR_SELECTION>();
@@ -71,10 +101,49 @@ pub enum TpmError {
     Base64(#[from] base64::DecodeError),
     #[error("Invalid request")]
     InvalidRequest,
+    #[error("{command} failed: {source}")]
+    Command {
+        command: &'static str,
+        source: Box<TpmError>,
+    },
     #[error("{0}")]
     Other(String),
 }
 
+impl TpmError {
+    /// Records the name of the TPM command that failed with this error
+    pub fn in_command(self, command: &'static str) -> Self {
+        TpmError::Command {
+            command,
+            source: Box::new(self),
+        }
+    }
+
+    /// Returns the name of the TPM command that failed, if known
+    pub fn command(&self) -> Option<&'static str> {
+        match self {
+            TpmError::Command { command, .. } => Some(command),
+            _ => None,
+        }
+    }
+
+    /// Returns the TPM_RC response code returned by the TPM or the TSS, if
+    /// the error was caused by a failed TPM command
+    pub fn response_code(&self) -> Option<u32> {
+        match self {
+            TpmError::Tss2 {
+                err: Tss2Error(rc), ..
+            } => Some(match rc {
+                Tss2ResponseCode::Success => 0,
+                Tss2ResponseCode::FormatZero(rc) => rc.bit_range(31, 0),
+                Tss2ResponseCode::FormatOne(rc) => rc.bit_range(31, 0),
+            }),
+            TpmError::Command { source, .. } => source.response_code(),
+            _ => None,
+        }
+    }
+}
+
 impl From<tss_esapi::Error> for TpmError {
     fn from(err: tss_esapi::Error) -> Self {
         let kind = if let Tss2Error(tss2_rc) = err {
@@ -90,6 +159,109 @@ impl From<tss_esapi::Error> for TpmError {
 
 type Result<T> = std::result::Result<T, TpmError>;
 
+/// Returns the TCTI used to connect to the TPM by default, set in the TCTI
+/// environment variable, or the TPM device otherwise
+pub fn default_tcti() -> String {
+    match std::env::var("TCTI") {
+        Ok(val) => val,
+        Err(_) => if std::path::Path::new("/dev/tpmrm0").exists() {
+            "device:/dev/tpmrm0"
+        } else {
+            "device:/dev/tpm0"
+        }
+        .to_string(),
+    }
+}
+
+/// Returns the first TCTI of `tctis`, e.g. "tabrmd", "device:/dev/tpmrm0"
+/// and "device:/dev/tpm0", with which a connection to the TPM can be
+/// opened. The connection is closed before returning.
+pub fn select_tcti(tctis: &[&str]) -> Result<String> {
+    first_working_tcti(tctis, |tcti| {
+        let tcti = TctiNameConf::from_str(tcti)?;
+        let _ = tss_esapi::Context::new(tcti)?;
+        Ok(())
+    })
+}
+
+// Tries each TCTI in order with `connect` until one succeeds
+fn first_working_tcti(
+    tctis: &[&str],
+    mut connect: impl FnMut(&str) -> Result<()>,
+) -> Result<String> {
+    for tcti in tctis {
+        match connect(tcti) {
+            Ok(()) => {
+                info!("Connected to the TPM using TCTI {}", tcti);
+                return Ok(tcti.to_string());
+            }
+            Err(e) => {
+                warn!(
+                    "Unable to connect to the TPM using TCTI {}: {}",
+                    tcti, e
+                )
+            }
+        }
+    }
+    Err(TpmError::Other(format!(
+        "Unable to connect to the TPM using any of the TCTIs: {}",
+        tctis.join(", ")
+    )))
+}
+
+/// Reads the time and clock of the TPM with TPM2_ReadClock.
+///
+/// The command is not available in the ESAPI wrapper, so it is sent through
+/// a separate connection to the TPM, which is closed before returning. Unless
+/// a resource manager is used, this must be called while no `Context` is
+/// open.
+pub fn read_clock(tcti: &str) -> Result<TimeInfo> {
+    let tcti_path = CString::new(tcti).map_err(|_| {
+        TpmError::Other("Invalid TCTI configuration".to_string())
+    })?;
+    let mut tcti: *mut TSS2_TCTI_CONTEXT = ptr::null_mut();
+    let mut esys: *mut ESYS_CONTEXT = ptr::null_mut();
+    let mut time_info: *mut TPMS_TIME_INFO = ptr::null_mut();
+
+    let (rc, command) = unsafe {
+        let rc = Tss2_TctiLdr_Initialize(tcti_path.as_ptr(), &mut tcti);
+        if rc != 0 {
+            (rc, "TctiLdr_Initialize")
+        } else {
+            let rc = Esys_Initialize(&mut esys, tcti, ptr::null_mut());
+            let result = if rc != 0 {
+                (rc, "Esys_Initialize")
+            } else {
+                let rc = Esys_ReadClock(
+                    esys,
+                    ESYS_TR_NONE,
+                    ESYS_TR_NONE,
+                    ESYS_TR_NONE,
+                    &mut time_info,
+                );
+                Esys_Finalize(&mut esys);
+                (rc, "ReadClock")
+            };
+            Tss2_TctiLdr_Finalize(&mut tcti);
+            result
+        }
+    };
+    if rc != 0 {
+        return Err(TpmError::Other(format!(
+            "failed with response code {rc:#x}"
+        ))
+        .in_command(command));
+    }
+
+    // The structure is allocated by the ESAPI and must be freed with it
+    let info = unsafe {
+        let info = *time_info;
+        Esys_Free(time_info.cast());
+        info
+    };
+    Ok(TimeInfo::try_from(info)?)
+}
+
 /// Holds the output of create_ek.
 #[derive(Clone, Debug)]
 pub struct EKResult {
@@ -105,10 +277,84 @@ pub struct AKResult {
     pub private: tss_esapi::structures::Private,
 }
 
+/// Holds the output of seal_data.
+#[derive(Clone, Debug)]
+pub struct SealedData {
+    pub public: tss_esapi::structures::Public,
+    pub private: tss_esapi::structures::Private,
+}
+
+/// Key parameters of the AK template. The signing scheme is derived from
+/// the signing algorithm passed to `create_ak`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AkTemplate {
+    pub rsa_key_bits: RsaKeyBits,
+    pub ecc_curve: EccCurve,
+}
+
+impl Default for AkTemplate {
+    fn default() -> Self {
+        AkTemplate {
+            rsa_key_bits: RsaKeyBits::Rsa2048,
+            ecc_curve: EccCurve::NistP192,
+        }
+    }
+}
+
+impl AkTemplate {
+    /// Creates an AK template from the RSA key size in bits and the name of
+    /// the ECC curve (e.g. "nist_p256")
+    pub fn new(rsa_key_bits: u32, ecc_curve: &str) -> Result<Self> {
+        let rsa_key_bits = match rsa_key_bits {
+            2048 => RsaKeyBits::Rsa2048,
+            3072 => RsaKeyBits::Rsa3072,
+            4096 => RsaKeyBits::Rsa4096,
+            other => {
+                return Err(TpmError::Other(format!(
+                    "Unsupported AK RSA key size: {other}"
+                )))
+            }
+        };
+        let ecc_curve = match ecc_curve {
+            "nist_p192" => EccCurve::NistP192,
+            "nist_p224" => EccCurve::NistP224,
+            "nist_p256" => EccCurve::NistP256,
+            "nist_p384" => EccCurve::NistP384,
+            "nist_p521" => EccCurve::NistP521,
+            other => {
+                return Err(TpmError::Other(format!(
+                    "Unsupported AK ECC curve: {other}"
+                )))
+            }
+        };
+        Ok(AkTemplate {
+            rsa_key_bits,
+            ecc_curve,
+        })
+    }
+
+    /// Checks whether the public area of an existing AK was created with
+    /// the key parameters of this template
+    pub fn matches(&self, public: &tss_esapi::structures::Public) -> bool {
+        match public {
+            tss_esapi::structures::Public::Rsa { parameters, .. } => {
+                parameters.key_bits() == self.rsa_key_bits
+            }
+            tss_esapi::structures::Public::Ecc { parameters, .. } => {
+                parameters.ecc_curve() == self.ecc_curve
+            }
+            _ => false,
+        }
+    }
+}
+
 /// Wrapper around tss_esapi::Context.
 #[derive(Debug)]
 pub struct Context {
     inner: tss_esapi::Context,
+    session_salt_key: Option<KeyHandle>,
+    ak_template: AkTemplate,
+    ek_cert_nv_index: Option<u32>,
 }
 
 impl AsRef<tss_esapi::Context> for Context {
@@ -124,68 +370,211 @@ impl AsMut<tss_esapi::Context> for Context {
 }
 
 impl Context {
-    /// Creates a connection context.
+    /// Creates a connection context, using the default TCTI.
     pub fn new() -> Result<Self> {
-        let tcti_path = match std::env::var("TCTI") {
-            Ok(val) => val,
-            Err(_) => if std::path::Path::new("/dev/tpmrm0").exists() {
-                "device:/dev/tpmrm0"
-            } else {
-                "device:/dev/tpm0"
-            }
-            .to_string(),
-        };
+        Self::with_tcti(&default_tcti())
+    }
 
-        let tcti = TctiNameConf::from_str(&tcti_path)?;
+    /// Creates a connection context using the given TCTI, e.g. the one
+    /// returned by `select_tcti`.
+    pub fn with_tcti(tcti: &str) -> Result<Self> {
+        let tcti = TctiNameConf::from_str(tcti)?;
         Ok(Self {
             inner: tss_esapi::Context::new(tcti)?,
+            session_salt_key: None,
+            ak_template: AkTemplate::default(),
+            ek_cert_nv_index: None,
         })
     }
 
+    /// Sets the key parameters used for the AKs created with `create_ak`.
+    pub fn set_ak_template(&mut self, ak_template: AkTemplate) {
+        self.ak_template = ak_template;
+    }
+
+    /// Sets the NV index the EK certificate is read from in `create_ek`,
+    /// for platforms storing it at a non-default index. The index must be
+    /// within the NV index range.
+    pub fn set_ek_cert_nv_index(&mut self, index: u32) -> Result<()> {
+        if !(NV_INDEX_FIRST..=NV_INDEX_LAST).contains(&index) {
+            return Err(TpmError::Other(format!(
+                "Invalid EK certificate NV index {index:#x}: NV indices must be between {NV_INDEX_FIRST:#x} and {NV_INDEX_LAST:#x}"
+            )));
+        }
+        self.ek_cert_nv_index = Some(index);
+        Ok(())
+    }
+
+    /// Enables parameter encryption for the sessions used when creating the
+    /// AK and activating credentials. The sessions are salted with the
+    /// `salt_key` (usually the EK), so that the session key cannot be
+    /// derived by observing the TPM bus.
+    pub fn enable_session_encryption(&mut self, salt_key: KeyHandle) {
+        self.session_salt_key = Some(salt_key);
+    }
+
+    /// Returns whether the parameter encryption was enabled with
+    /// `enable_session_encryption`.
+    pub fn session_encryption_enabled(&self) -> bool {
+        self.session_salt_key.is_some()
+    }
+
     /// Creates an EK, returns the key handle and public certificate
     /// in `EKResult`.
+    ///
+    /// The EK is created from the default low range template for the
+    /// algorithm: RSA 2048 for `EncryptionAlgorithm::Rsa` and NIST P-256
+    /// for `EncryptionAlgorithm::Ecc`, as provisioned by the manufacturers.
+    ///
+    /// The certificate is read from the default NV index for the algorithm,
+    /// unless another index was set with `set_ek_cert_nv_index`. If it cannot
+    /// be read, the EK created is flushed.
     pub fn create_ek(
         &mut self,
         alg: EncryptionAlgorithm,
         handle: Option<&str>,
     ) -> Result<EKResult> {
         // Retrieve EK handle, EK pub cert, and TPM pub object
-        let key_handle = match handle {
-            Some(v) => {
-                if v.is_empty() {
-                    ek::create_ek_object(
-                        &mut self.inner,
-                        alg.into(),
-                        DefaultKey,
-                    )?
-                } else {
-                    let handle =
-                        u32::from_str_radix(v.trim_start_matches("0x"), 16)?;
-                    self.inner
-                        .tr_from_tpm_public(TpmHandle::Persistent(
-                            PersistentTpmHandle::new(handle)?,
-                        ))?
-                        .into()
+        let (key_handle, created) = match handle {
+            Some(v) if !v.is_empty() => {
+                let handle =
+                    u32::from_str_radix(v.trim_start_matches("0x"), 16)?;
+                let key_handle = self
+                    .inner
+                    .tr_from_tpm_public(TpmHandle::Persistent(
+                        PersistentTpmHandle::new(handle)?,
+                    ))?
+                    .into();
+                (key_handle, false)
+            }
+            _ => (
+                ek::create_ek_object(
+                    &mut self.inner,
+                    alg.into(),
+                    DefaultKey,
+                )?,
+                true,
+            ),
+        };
+
+        match self.read_ek_cert_and_public(alg, key_handle) {
+            Ok((cert, tpm_pub)) => Ok(EKResult {
+                key_handle,
+                ek_cert: cert,
+                public: tpm_pub,
+            }),
+            Err(e) => {
+                // The transient EK would otherwise be leaked
+                if created {
+                    if let Err(flush_err) =
+                        self.inner.flush_context(key_handle.into())
+                    {
+                        warn!("Failed to flush the EK: {flush_err}");
+                    }
                 }
+                Err(e)
             }
-            None => {
-                ek::create_ek_object(&mut self.inner, alg.into(), DefaultKey)?
+        }
+    }
+
+    /// Replaces the EK persisted at `handle` (hexadecimal string) with the
+    /// EK created from the default template for `alg`, for when the
+    /// persisted object does not match it anymore. Nothing is evicted when
+    /// no object is persisted at the handle.
+    ///
+    /// The owner hierarchy must have an empty authorization.
+    pub fn regenerate_persistent_ek(
+        &mut self,
+        alg: EncryptionAlgorithm,
+        handle: &str,
+    ) -> Result<EKResult> {
+        let persistent = PersistentTpmHandle::new(u32::from_str_radix(
+            handle.trim_start_matches("0x"),
+            16,
+        )?)?;
+        let ek = self.create_ek(alg, None)?;
+
+        let persisted = (|| -> Result<ObjectHandle> {
+            if let Ok(old) = self
+                .inner
+                .tr_from_tpm_public(TpmHandle::Persistent(persistent))
+            {
+                _ = self.inner.execute_with_nullauth_session(|ctx| {
+                    ctx.evict_control(
+                        Provision::Owner,
+                        old,
+                        Persistent::Persistent(persistent),
+                    )
+                })?;
             }
-        };
-        let cert = match ek::retrieve_ek_pubcert(&mut self.inner, alg.into())
-        {
-            Ok(v) => Some(v),
-            Err(_) => {
-                warn!("No EK certificate found in TPM NVRAM");
-                None
+            Ok(self.inner.execute_with_nullauth_session(|ctx| {
+                ctx.evict_control(
+                    Provision::Owner,
+                    ek.key_handle.into(),
+                    Persistent::Persistent(persistent),
+                )
+            })?)
+        })();
+
+        // The transient EK is not needed anymore once persisted
+        if let Err(e) = self.inner.flush_context(ek.key_handle.into()) {
+            warn!("Failed to flush the EK: {e}");
+        }
+
+        Ok(EKResult {
+            key_handle: persisted?.into(),
+            ..ek
+        })
+    }
+
+    // Reads the EK certificate and the public area of the EK
+    fn read_ek_cert_and_public(
+        &mut self,
+        alg: EncryptionAlgorithm,
+        key_handle: KeyHandle,
+    ) -> Result<(Option<Vec<u8>>, tss_esapi::structures::Public)> {
+        let cert = match self.ek_cert_nv_index {
+            Some(index) => Some(self.read_ek_cert(index)?),
+            None => {
+                match ek::retrieve_ek_pubcert(&mut self.inner, alg.into()) {
+                    Ok(v) => Some(v),
+                    Err(_) => {
+                        warn!("No EK certificate found in TPM NVRAM");
+                        None
+                    }
+                }
             }
         };
         let (tpm_pub, _, _) = self.inner.read_public(key_handle)?;
-        Ok(EKResult {
-            key_handle,
-            ek_cert: cert,
-            public: tpm_pub,
-        })
+        Ok((cert, tpm_pub))
+    }
+
+    // Reads the EK certificate stored at the given NV index, using the
+    // index authorization as done for the default indices
+    fn read_ek_cert(&mut self, index: u32) -> Result<Vec<u8>> {
+        let nv_index = NvIndexTpmHandle::new(index)?;
+        let cert = self
+            .inner
+            .execute_without_session(|ctx| {
+                ctx.tr_from_tpm_public(TpmHandle::NvIndex(nv_index))
+            })
+            .and_then(|handle| {
+                let nv_auth = NvAuth::NvIndex(handle.into());
+                self.inner.execute_with_nullauth_session(|ctx| {
+                    nv::read_full(ctx, nv_auth, nv_index)
+                })
+            })
+            .map_err(|e| {
+                TpmError::Other(format!(
+                    "Could not read the EK certificate from NV index {index:#x}: {e}"
+                ))
+            })?;
+        if cert.is_empty() {
+            return Err(TpmError::Other(format!(
+                "No EK certificate found at NV index {index:#x}: the index is empty"
+            )));
+        }
+        Ok(cert)
     }
 
     /// Creates an AK.
@@ -195,20 +584,68 @@ impl Context {
         hash_alg: HashAlgorithm,
         sign_alg: SignAlgorithm,
     ) -> Result<AKResult> {
-        let ak = ak::create_ak(
-            &mut self.inner,
-            handle,
-            hash_alg.into(),
-            sign_alg.into(),
-            None,
-            DefaultKey,
-        )?;
+        let custom_template = self.ak_template != AkTemplate::default();
+        let ak = match self.session_salt_key {
+            Some(_) => self.create_ak_custom(handle, hash_alg, sign_alg)?,
+            None if custom_template => {
+                self.create_ak_custom(handle, hash_alg, sign_alg)?
+            }
+            None => ak::create_ak(
+                &mut self.inner,
+                handle,
+                hash_alg.into(),
+                sign_alg.into(),
+                None,
+                DefaultKey,
+            )?,
+        };
         Ok(AKResult {
             public: ak.out_public,
             private: ak.out_private,
         })
     }
 
+    // Same as ak::create_ak, but using the configured AK template, and a
+    // salted policy session when session encryption is enabled, so that the
+    // command parameters are encrypted.
+    fn create_ak_custom(
+        &mut self,
+        handle: KeyHandle,
+        hash_alg: HashAlgorithm,
+        sign_alg: SignAlgorithm,
+    ) -> Result<tss_esapi::structures::CreateKeyResult> {
+        let ak_pub = create_ak_public(
+            hash_alg.into(),
+            sign_alg.into(),
+            &self.ak_template,
+        )?;
+
+        let ek_auth = self.create_empty_session(SessionType::Policy)?;
+        let ses_handle: SessionHandle = ek_auth.into();
+
+        self.inner
+            .execute_with_temporary_object(
+                ObjectHandle::from(ses_handle),
+                |ctx, _| {
+                    let _ = ctx.execute_with_nullauth_session(|ctx| {
+                        ctx.policy_secret(
+                            PolicySession::try_from(ek_auth)?,
+                            AuthHandle::Endorsement,
+                            Default::default(),
+                            Default::default(),
+                            Default::default(),
+                            None,
+                        )
+                    })?;
+
+                    ctx.execute_with_session(Some(ek_auth), |ctx| {
+                        ctx.create(handle, ak_pub, None, None, None, None)
+                    })
+                },
+            )
+            .map_err(TpmError::from)
+    }
+
     /// Loads an existing AK associated with `handle` and `ak`.
     pub fn load_ak(
         &mut self,
@@ -225,12 +662,100 @@ impl Context {
         Ok(ak_handle)
     }
 
+    /// Seals `data` to the TPM in an object whose parent is the EK `handle`,
+    /// so that it can only be unsealed by the same TPM.
+    pub fn seal_data(
+        &mut self,
+        handle: KeyHandle,
+        data: &[u8],
+    ) -> Result<SealedData> {
+        let object_attributes = ObjectAttributesBuilder::new()
+            .with_fixed_tpm(true)
+            .with_fixed_parent(true)
+            .with_no_da(true)
+            .with_user_with_auth(true)
+            .build()?;
+        let public = PublicBuilder::new()
+            .with_public_algorithm(PublicAlgorithm::KeyedHash)
+            .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+            .with_object_attributes(object_attributes)
+            .with_keyed_hash_parameters(PublicKeyedHashParameters::new(
+                KeyedHashScheme::Null,
+            ))
+            .with_keyed_hash_unique_identifier(Digest::default())
+            .build()?;
+        let sensitive = SensitiveData::try_from(data.to_vec())?;
+
+        let sealed = self.execute_with_ek_policy(|ctx| {
+            ctx.create(handle, public, None, Some(sensitive), None, None)
+        })?;
+        Ok(SealedData {
+            public: sealed.out_public,
+            private: sealed.out_private,
+        })
+    }
+
+    /// Unseals the data sealed with `seal_data` under the EK `handle`.
+    pub fn unseal_data(
+        &mut self,
+        handle: KeyHandle,
+        sealed: &SealedData,
+    ) -> Result<Vec<u8>> {
+        let sealed_handle = self.execute_with_ek_policy(|ctx| {
+            ctx.load(handle, sealed.private.clone(), sealed.public.clone())
+        })?;
+
+        // Use an encrypted session, so that the data is not sent in clear
+        let session = self.create_empty_session(SessionType::Hmac)?;
+        let ses_handle: SessionHandle = session.into();
+        let unsealed = self.inner.execute_with_temporary_object(
+            ObjectHandle::from(ses_handle),
+            |ctx, _| {
+                ctx.execute_with_session(Some(session), |ctx| {
+                    ctx.unseal(sealed_handle.into())
+                })
+            },
+        );
+        self.inner.flush_context(sealed_handle.into())?;
+        Ok(unsealed?.value().to_vec())
+    }
+
+    // Runs `f` with a policy session authorized with PolicySecret(ENDORSEMENT),
+    // which is needed to use the EK as a parent
+    fn execute_with_ek_policy<T>(
+        &mut self,
+        f: impl FnOnce(&mut tss_esapi::Context) -> tss_esapi::Result<T>,
+    ) -> Result<T> {
+        let ek_auth = self.create_empty_session(SessionType::Policy)?;
+        let ses_handle: SessionHandle = ek_auth.into();
+
+        self.inner
+            .execute_with_temporary_object(
+                ObjectHandle::from(ses_handle),
+                |ctx, _| {
+                    let _ = ctx.execute_with_nullauth_session(|ctx| {
+                        ctx.policy_secret(
+                            PolicySession::try_from(ek_auth)?,
+                            AuthHandle::Endorsement,
+                            Default::default(),
+                            Default::default(),
+                            Default::default(),
+                            None,
+                        )
+                    })?;
+
+                    ctx.execute_with_session(Some(ek_auth), f)
+                },
+            )
+            .map_err(TpmError::from)
+    }
+
     fn create_empty_session(
         &mut self,
         ses_type: SessionType,
     ) -> Result<AuthSession> {
         let session = self.inner.start_auth_session(
-            None,
+            self.session_salt_key,
             None,
             None,
             ses_type,
@@ -257,7 +782,16 @@ impl Context {
         ek: KeyHandle,
     ) -> Result<Digest> {
         let (credential, secret) = parse_cred_and_secret(keyblob)?;
+        self.activate_parsed_credential(credential, secret, ak, ek)
+    }
 
+    fn activate_parsed_credential(
+        &mut self,
+        credential: IdObject,
+        secret: EncryptedSecret,
+        ak: KeyHandle,
+        ek: KeyHandle,
+    ) -> Result<Digest> {
         let ek_auth = self.create_empty_session(SessionType::Policy)?;
 
         // We authorize ses2 with PolicySecret(ENDORSEMENT) as per PolicyA
@@ -282,15 +816,103 @@ impl Context {
             .map_err(TpmError::from)
     }
 
+    /// Verifies that the AK is bound to the same TPM as the EK, by making a
+    /// credential for the AK name with the EK and activating it.
+    pub fn verify_ak_binding(
+        &mut self,
+        ak: KeyHandle,
+        ek: KeyHandle,
+    ) -> Result<()> {
+        let challenge = self.inner.get_random(16)?;
+        let (_, ak_name, _) = self.inner.read_public(ak)?;
+        let (credential, secret) =
+            self.inner.make_credential(ek, challenge.clone(), ak_name)?;
+
+        let response = self
+            .activate_parsed_credential(credential, secret, ak, ek)
+            .map_err(|e| {
+                TpmError::Other(format!(
+                    "Unable to establish the AK is bound to the EK: {e}"
+                ))
+            })?;
+
+        if response.value() != challenge.value() {
+            return Err(TpmError::Other(
+                "AK is not bound to the same TPM as the EK".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the AK can be used for signing, by performing a throwaway
+    /// quote over PCR 0 with the given algorithms.
+    pub fn check_ak_signing(
+        &mut self,
+        ak: KeyHandle,
+        hash_alg: HashAlgorithm,
+        sign_alg: SignAlgorithm,
+    ) -> Result<()> {
+        let nonce = self.inner.get_random(16)?;
+        let pcrlist = PcrSelectionListBuilder::new()
+            .with_selection(hash_alg.into(), &[PcrSlot::Slot0])
+            .build()?;
+
+        let _ = self
+            .inner
+            .execute_with_nullauth_session(|ctx| {
+                ctx.quote(
+                    ak,
+                    nonce.value().try_into()?,
+                    sign_alg.to_signature_scheme(hash_alg),
+                    pcrlist,
+                )
+            })
+            .map_err(|e| {
+                TpmError::Other(format!("AK cannot be used for signing: {e}"))
+            })?;
+        Ok(())
+    }
+
+    /// Extends the PCR `pcr` of the `hash_alg` bank with the digest of
+    /// `data`.
+    pub fn extend_pcr(
+        &mut self,
+        pcr: u32,
+        hash_alg: HashAlgorithm,
+        data: &[u8],
+    ) -> Result<()> {
+        let digest = openssl::hash::hash(hash_alg.into(), data)?;
+        self.extend_pcr_digest(pcr, hash_alg, &digest)
+    }
+
+    /// Extends the PCR `pcr` of the `hash_alg` bank with `digest`, already
+    /// computed with `hash_alg`.
+    pub fn extend_pcr_digest(
+        &mut self,
+        pcr: u32,
+        hash_alg: HashAlgorithm,
+        digest: &[u8],
+    ) -> Result<()> {
+        let handle = PcrHandle::try_from(pcr)
+            .map_err(|_| TpmError::Other(format!("Invalid PCR: {pcr}")))?;
+
+        let mut digest_values = DigestValues::new();
+        digest_values.set(hash_alg.into(), Digest::try_from(digest)?);
+
+        self.inner.execute_with_nullauth_session(|ctx| {
+            ctx.pcr_extend(handle, digest_values)
+        })?;
+        Ok(())
+    }
+
     // This function extends Pcr16 with the digest, then creates a PcrList
-    // from the given mask and pcr16.
-    // Note: Currently, this will build the list for both SHA256 and SHA1 as
-    // necessary for the Python components of Keylime.
+    // from the given mask and pcr16, selecting the PCRs in each of the given
+    // banks.
     fn build_pcr_list(
         &mut self,
         digest: DigestValues,
         mask: u32,
-        hash_alg: HashingAlgorithm,
+        banks: &[HashingAlgorithm],
     ) -> Result<PcrSelectionList> {
         // extend digest into pcr16
         self.inner.execute_with_nullauth_session(|ctx| {
@@ -308,7 +930,9 @@ impl Context {
         }
 
         let mut pcrlist = PcrSelectionListBuilder::new();
-        pcrlist = pcrlist.with_selection(hash_alg, &pcrs);
+        for &bank in banks {
+            pcrlist = pcrlist.with_selection(bank, &pcrs);
+        }
         let pcrlist = pcrlist.build()?;
 
         Ok(pcrlist)
@@ -320,6 +944,10 @@ impl Context {
     /// are set to pcrs to include in the list. The LSB in the mask
     /// corresponds to PCR0. Note that PCR16 is always included even
     /// if the bit is not set in `mask`.
+    ///
+    /// The PCRs are quoted in each of the `pcr_banks`, while the quote is
+    /// signed using `hash_alg`.
+    #[allow(clippy::too_many_arguments)]
     pub fn quote(
         &mut self,
         nonce: &[u8],
@@ -327,12 +955,14 @@ impl Context {
         pubkey: &PKeyRef<Public>,
         ak_handle: KeyHandle,
         hash_alg: HashAlgorithm,
+        pcr_banks: &[HashAlgorithm],
         sign_alg: SignAlgorithm,
     ) -> Result<String> {
         let nk_digest = pubkey_to_tpm_digest(pubkey)?;
 
-        let pcrlist =
-            self.build_pcr_list(nk_digest, mask, hash_alg.into())?;
+        let banks: Vec<HashingAlgorithm> =
+            pcr_banks.iter().map(|&bank| bank.into()).collect();
+        let pcrlist = self.build_pcr_list(nk_digest, mask, &banks)?;
 
         let (attestation, sig, pcrs_read, pcr_data) =
             self.inner.execute_with_nullauth_session(|ctx| {
@@ -354,6 +984,73 @@ impl Context {
 assert_eq_size!(TPML_PCR_SELECTION, [u8; 132]);
 assert_eq_size!(TPML_DIGEST, [u8; 532]);
 
+// Builds the AK public template, matching the one used by ak::create_ak
+fn create_ak_public(
+    hash_alg: HashingAlgorithm,
+    sign_alg: SignatureSchemeAlgorithm,
+    template: &AkTemplate,
+) -> Result<tss_esapi::structures::Public> {
+    let obj_attrs = ObjectAttributesBuilder::new()
+        .with_restricted(true)
+        .with_user_with_auth(true)
+        .with_sign_encrypt(true)
+        .with_decrypt(false)
+        .with_fixed_tpm(true)
+        .with_fixed_parent(true)
+        .with_sensitive_data_origin(true)
+        .build()?;
+
+    let key_builder = match AsymmetricAlgorithm::try_from(sign_alg)? {
+        AsymmetricAlgorithm::Rsa => PublicBuilder::new()
+            .with_public_algorithm(PublicAlgorithm::Rsa)
+            .with_name_hashing_algorithm(hash_alg)
+            .with_object_attributes(obj_attrs)
+            .with_rsa_parameters(
+                PublicRsaParametersBuilder::new()
+                    .with_scheme(RsaScheme::create(
+                        RsaSchemeAlgorithm::try_from(
+                            AlgorithmIdentifier::from(sign_alg),
+                        )?,
+                        Some(hash_alg),
+                    )?)
+                    .with_key_bits(template.rsa_key_bits)
+                    .with_exponent(RsaExponent::default())
+                    .with_is_signing_key(true)
+                    .with_is_decryption_key(false)
+                    .with_restricted(true)
+                    .build()?,
+            )
+            .with_rsa_unique_identifier(PublicKeyRsa::default()),
+        AsymmetricAlgorithm::Ecc => PublicBuilder::new()
+            .with_public_algorithm(PublicAlgorithm::Ecc)
+            .with_name_hashing_algorithm(hash_alg)
+            .with_object_attributes(obj_attrs)
+            .with_ecc_parameters(
+                PublicEccParametersBuilder::new()
+                    .with_symmetric(SymmetricDefinitionObject::Null)
+                    .with_ecc_scheme(EccScheme::create(
+                        EccSchemeAlgorithm::try_from(
+                            AlgorithmIdentifier::from(sign_alg),
+                        )?,
+                        Some(hash_alg),
+                        Some(0),
+                    )?)
+                    .with_curve(template.ecc_curve)
+                    .with_key_derivation_function_scheme(
+                        KeyDerivationFunctionScheme::Null,
+                    )
+                    .build()?,
+            ),
+        AsymmetricAlgorithm::Null => {
+            return Err(TpmError::Other(
+                "Unsupported AK signing algorithm".to_string(),
+            ));
+        }
+    };
+
+    Ok(key_builder.build()?)
+}
+
 // Serialize a TPML_PCR_SELECTION into a Vec<u8>
 // The serialization will adjust the data endianness as necessary and add paddings to keep the
 // memory aligment.
@@ -418,6 +1115,185 @@ fn pcrdata_to_vec(
     data_vec
 }
 
+// Deserialize a TPML_PCR_SELECTION from a &[u8] slice.
+// The deserialization will adjust the data endianness as necessary.
+fn deserialize_pcrsel(pcrsel_vec: &[u8]) -> Result<TPML_PCR_SELECTION> {
+    if pcrsel_vec.len() != TPML_PCR_SELECTION_SIZE {
+        return Err(TpmError::InvalidRequest);
+    }
+
+    let mut reader = std::io::Cursor::new(pcrsel_vec);
+    let mut count_vec = [0u8; 4];
+    reader.read_exact(&mut count_vec)?;
+    let count = u32::from_le_bytes(count_vec);
+
+    let mut pcr_selections: [TPMS_PCR_SELECTION; 16] =
+        [TPMS_PCR_SELECTION::default(); 16];
+
+    for selection in &mut pcr_selections {
+        let mut hash_vec = [0u8; 2];
+        reader.read_exact(&mut hash_vec)?;
+        selection.hash = u16::from_le_bytes(hash_vec);
+
+        let mut size_vec = [0u8; 1];
+        reader.read_exact(&mut size_vec)?;
+        selection.sizeofSelect = u8::from_le_bytes(size_vec);
+
+        reader.read_exact(&mut selection.pcrSelect)?;
+
+        // Skip the padding added by serialize_pcrsel
+        let mut padding = [0u8; 1];
+        reader.read_exact(&mut padding)?;
+    }
+
+    Ok(TPML_PCR_SELECTION {
+        count,
+        pcrSelections: pcr_selections,
+    })
+}
+
+// Deserialize a TPML_DIGEST from a &[u8] slice.
+// The deserialization will adjust the data endianness as necessary.
+fn deserialize_digest(digest_vec: &[u8]) -> Result<TPML_DIGEST> {
+    if digest_vec.len() != TPML_DIGEST_SIZE {
+        return Err(TpmError::InvalidRequest);
+    }
+
+    let mut reader = std::io::Cursor::new(digest_vec);
+    let mut count_vec = [0u8; 4];
+
+    reader.read_exact(&mut count_vec)?;
+    let count = u32::from_le_bytes(count_vec);
+
+    let mut digests: [TPM2B_DIGEST; 8] = [TPM2B_DIGEST::default(); 8];
+
+    for digest in &mut digests {
+        let mut size_vec = [0u8; 2];
+        reader.read_exact(&mut size_vec)?;
+        digest.size = u16::from_le_bytes(size_vec);
+        reader.read_exact(&mut digest.buffer)?;
+    }
+
+    Ok(TPML_DIGEST { count, digests })
+}
+
+fn vec_to_pcrdata(val: &[u8]) -> Result<(PcrSelectionList, PcrData)> {
+    let mut reader = std::io::Cursor::new(val);
+    let mut pcrsel_vec = [0u8; TPML_PCR_SELECTION_SIZE];
+    reader.read_exact(&mut pcrsel_vec)?;
+
+    let pcrsel = deserialize_pcrsel(&pcrsel_vec)?;
+    let pcrlist: PcrSelectionList = pcrsel.try_into()?;
+
+    // A TPML_DIGEST holds up to 8 digests, so one follows for each group of
+    // 8 PCRs
+    let mut count_vec = [0u8; 4];
+    reader.read_exact(&mut count_vec)?;
+    let count = u32::from_le_bytes(count_vec);
+
+    let mut digests = Vec::new();
+    for _ in 0..count {
+        let mut digest_vec = [0u8; TPML_DIGEST_SIZE];
+        reader.read_exact(&mut digest_vec)?;
+        let digest = deserialize_digest(&digest_vec)?;
+        let tpm2b_digests = usize::try_from(digest.count)
+            .ok()
+            .and_then(|count| digest.digests.get(..count))
+            .ok_or(TpmError::InvalidRequest)?;
+        for d in tpm2b_digests {
+            digests.push(Digest::try_from(*d)?);
+        }
+    }
+
+    let pcrdata = digests_to_pcrdata(&pcrlist, digests)?;
+    Ok((pcrlist, pcrdata))
+}
+
+// Assigns the digests, in order, to the PCRs selected in `pcrlist`. A
+// DigestList holds at most 8 digests, so the PCR data is built from groups
+// of at most 8 PCRs of the same bank.
+fn digests_to_pcrdata(
+    pcrlist: &PcrSelectionList,
+    digests: Vec<Digest>,
+) -> Result<PcrData> {
+    let mut digests = digests.into_iter();
+    let mut pcrdata: Option<PcrData> = None;
+
+    for selection in pcrlist.get_selections() {
+        for slots in selection.selected().chunks(DigestList::MAX_SIZE) {
+            let group = PcrSelectionListBuilder::new()
+                .with_selection(selection.hashing_algorithm(), slots)
+                .build()?;
+            let mut digest_list = DigestList::new();
+            for _ in slots {
+                digest_list
+                    .add(digests.next().ok_or(TpmError::InvalidRequest)?)?;
+            }
+            if let Some(data) = pcrdata.as_mut() {
+                data.add(&group, &digest_list)?;
+            } else {
+                pcrdata = Some(PcrData::create(&group, &digest_list)?);
+            }
+        }
+    }
+
+    if digests.next().is_some() {
+        return Err(TpmError::InvalidRequest);
+    }
+    match pcrdata {
+        Some(data) => Ok(data),
+        None => Ok(PcrData::create(pcrlist, &DigestList::new())?),
+    }
+}
+
+/// Formats the PCR values of all the banks in `pcr_data`, each tagged with
+/// its bank algorithm and index as "<alg>:<pcr>:<value in hex>".
+///
+/// The width of each value is checked against the digest size of its bank,
+/// so that a SHA-1 value cannot be taken for a truncated SHA-256 value when
+/// the banks are mixed.
+pub fn format_pcr_values(pcr_data: &PcrData) -> Result<Vec<String>> {
+    let mut values = Vec::new();
+    for (alg, bank) in pcr_data.clone() {
+        let hash_alg = match alg {
+            HashingAlgorithm::Sha1 => HashAlgorithm::Sha1,
+            HashingAlgorithm::Sha256 => HashAlgorithm::Sha256,
+            HashingAlgorithm::Sha384 => HashAlgorithm::Sha384,
+            HashingAlgorithm::Sha512 => HashAlgorithm::Sha512,
+            HashingAlgorithm::Sm3_256 => HashAlgorithm::Sm3_256,
+            other => {
+                return Err(TpmError::Other(format!(
+                    "Unsupported PCR bank algorithm: {other:?}"
+                )))
+            }
+        };
+        for (slot, digest) in &bank {
+            let value = digest.value();
+            if value.len() != hash_alg.digest_size() {
+                return Err(TpmError::Other(format!(
+                    "Invalid {hash_alg} PCR value of {} bytes",
+                    value.len()
+                )));
+            }
+            let pcr = u32::from(*slot).trailing_zeros();
+            values.push(format!("{hash_alg}:{pcr}:{}", hex::encode(value)));
+        }
+    }
+    Ok(values)
+}
+
+/// Formats the PCR values included in a quote string, as done by
+/// `format_pcr_values`.
+pub fn quote_pcr_values(quote: &str) -> Result<Vec<String>> {
+    let pcr_str = quote
+        .split(':')
+        .nth(2)
+        .ok_or_else(|| TpmError::Other("Malformed quote".to_string()))?;
+    let pcrblob = general_purpose::STANDARD.decode(pcr_str)?;
+    let (_, pcr_data) = vec_to_pcrdata(&pcrblob)?;
+    format_pcr_values(&pcr_data)
+}
+
 const TSS_MAGIC: u32 = 3135029470;
 
 fn parse_cred_and_secret(
@@ -663,15 +1539,14 @@ fn perform_quote_and_pcr_read(
 
     for attempt in 0..NUM_ATTESTATION_ATTEMPTS {
         // TSS ESAPI quote does not create pcr blob, so create it separately
-        let (pcrs_read, pcr_data) = make_pcr_blob(context, pcrlist.clone())?;
+        let (pcrs_read, pcr_data) =
+            make_pcr_blob(context, pcrlist.clone())
+                .map_err(|e| e.in_command("TPM2_PCR_Read"))?;
 
         // create quote
-        let (attestation, sig) = context.quote(
-            ak_handle,
-            nonce.clone(),
-            sign_scheme,
-            pcrs_read.clone(),
-        )?;
+        let (attestation, sig) = context
+            .quote(ak_handle, nonce.clone(), sign_scheme, pcrs_read.clone())
+            .map_err(|e| TpmError::from(e).in_command("TPM2_Quote"))?;
 
         // Check whether the attestation and pcr_data match
         if check_if_pcr_data_and_attestation_match(
@@ -697,12 +1572,10 @@ fn perform_quote_and_pcr_read(
 
 pub mod testing {
     use super::*;
-    use std::io::prelude::*;
     use tss_esapi::constants::structure_tags::StructureTag;
-    use tss_esapi::structures::{Attest, AttestBuffer, DigestList, Ticket};
+    use tss_esapi::structures::{Attest, AttestBuffer, Ticket};
     use tss_esapi::tss2_esys::{
-        Tss2_MU_TPMT_SIGNATURE_Unmarshal, TPM2B_ATTEST, TPM2B_DIGEST,
-        TPMS_PCR_SELECTION, TPMT_SIGNATURE,
+        Tss2_MU_TPMT_SIGNATURE_Unmarshal, TPM2B_ATTEST, TPMT_SIGNATURE,
     };
 
     macro_rules! create_unmarshal_fn {
@@ -735,92 +1608,6 @@ pub mod testing {
         Tss2_MU_TPMT_SIGNATURE_Unmarshal
     );
 
-    // Deserialize a TPML_PCR_SELECTION from a &[u8] slice.
-    // The deserialization will adjust the data endianness as necessary.
-    fn deserialize_pcrsel(pcrsel_vec: &[u8]) -> Result<TPML_PCR_SELECTION> {
-        if pcrsel_vec.len() != TPML_PCR_SELECTION_SIZE {
-            return Err(TpmError::InvalidRequest);
-        }
-
-        let mut reader = std::io::Cursor::new(pcrsel_vec);
-        let mut count_vec = [0u8; 4];
-        reader.read_exact(&mut count_vec)?;
-        let count = u32::from_le_bytes(count_vec);
-
-        let mut pcr_selections: [TPMS_PCR_SELECTION; 16] =
-            [TPMS_PCR_SELECTION::default(); 16];
-
-        for selection in &mut pcr_selections {
-            let mut hash_vec = [0u8; 2];
-            reader.read_exact(&mut hash_vec)?;
-            selection.hash = u16::from_le_bytes(hash_vec);
-
-            let mut size_vec = [0u8; 1];
-            reader.read_exact(&mut size_vec)?;
-            selection.sizeofSelect = u8::from_le_bytes(size_vec);
-
-            reader.read_exact(&mut selection.pcrSelect)?;
-        }
-
-        Ok(TPML_PCR_SELECTION {
-            count,
-            pcrSelections: pcr_selections,
-        })
-    }
-
-    // Deserialize a TPML_DIGEST from a &[u8] slice.
-    // The deserialization will adjust the data endianness as necessary.
-    fn deserialize_digest(digest_vec: &[u8]) -> Result<TPML_DIGEST> {
-        if digest_vec.len() != TPML_DIGEST_SIZE {
-            return Err(TpmError::InvalidRequest);
-        }
-
-        let mut reader = std::io::Cursor::new(digest_vec);
-        let mut count_vec = [0u8; 4];
-
-        reader.read_exact(&mut count_vec)?;
-        let count = u32::from_le_bytes(count_vec);
-
-        let mut digests: [TPM2B_DIGEST; 8] = [TPM2B_DIGEST::default(); 8];
-
-        for digest in &mut digests {
-            let mut size_vec = [0u8; 2];
-            reader.read_exact(&mut size_vec)?;
-            digest.size = u16::from_le_bytes(size_vec);
-            reader.read_exact(&mut digest.buffer)?;
-        }
-
-        Ok(TPML_DIGEST { count, digests })
-    }
-
-    fn vec_to_pcrdata(val: &[u8]) -> Result<(PcrSelectionList, PcrData)> {
-        let mut reader = std::io::Cursor::new(val);
-        let mut pcrsel_vec = [0u8; TPML_PCR_SELECTION_SIZE];
-        reader.read_exact(&mut pcrsel_vec)?;
-
-        let pcrsel = deserialize_pcrsel(&pcrsel_vec)?;
-        let pcrlist: PcrSelectionList = pcrsel.try_into()?;
-
-        let mut count_vec = [0u8; 4];
-        reader.read_exact(&mut count_vec)?;
-        let count = u32::from_le_bytes(count_vec);
-        // Always 1 PCR digest should follow
-        if count != 1 {
-            return Err(TpmError::InvalidRequest);
-        }
-
-        let mut digest_vec = [0u8; TPML_DIGEST_SIZE];
-        reader.read_exact(&mut digest_vec)?;
-        let digest = deserialize_digest(&digest_vec)?;
-        let mut digest_list = DigestList::new();
-        for i in 0..digest.count {
-            digest_list.add(digest.digests[i as usize].try_into()?)?;
-        }
-
-        let pcrdata = PcrData::create(&pcrlist, &digest_list)?;
-        Ok((pcrlist, pcrdata))
-    }
-
     pub(crate) fn decode_quote_string(
         quote: &str,
     ) -> Result<(AttestBuffer, Signature, PcrSelectionList, PcrData)> {
@@ -888,12 +1675,18 @@ pub mod testing {
             return Err(TpmError::Other("nonce does not match".to_string()));
         }
 
-        // Also ensure digest from quote matches PCR digest
-        let pcrbank = pcrdata
-            .pcr_bank(HashingAlgorithm::Sha256)
-            .ok_or_else(|| TpmError::Other("no SHA256 bank".to_string()))?;
+        // Also ensure digest from quote matches PCR digest, computed over
+        // the PCRs of all the quoted banks
         let mut hasher = Hasher::new(MessageDigest::sha256())?;
         for &sel in pcrsel.get_selections() {
+            let pcrbank = pcrdata
+                .pcr_bank(sel.hashing_algorithm())
+                .ok_or_else(|| {
+                    TpmError::Other(format!(
+                        "no {:?} bank",
+                        sel.hashing_algorithm()
+                    ))
+                })?;
             for i in &sel.selected() {
                 if let Some(digest) = pcrbank.get_digest(*i) {
                     hasher.update(digest.value())?;
@@ -948,6 +1741,310 @@ fn quote_encode_decode() {
     assert_eq!(encoded, buf);
 }
 
+#[test]
+fn first_working_tcti_fallback() {
+    let mut tried = Vec::new();
+    let tcti = first_working_tcti(
+        &["tabrmd", "device:/dev/tpmrm0", "device:/dev/tpm0"],
+        |tcti| {
+            tried.push(tcti.to_string());
+            match tcti {
+                "tabrmd" => Err(TpmError::Other("not running".to_string())),
+                _ => Ok(()),
+            }
+        },
+    )
+    .unwrap(); //#[allow_ci]
+
+    // The first working TCTI is selected, and the next ones are not tried
+    assert_eq!(tcti, "device:/dev/tpmrm0");
+    assert_eq!(tried, vec!["tabrmd", "device:/dev/tpmrm0"]);
+
+    let result = first_working_tcti(&["tabrmd", "device:/dev/tpm0"], |_| {
+        Err(TpmError::Other("unavailable".to_string()))
+    });
+    assert!(result.is_err());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn encrypted_session() {
+    let mut ctx = Context::new().unwrap(); //#[allow_ci]
+    let ek = ctx.create_ek(EncryptionAlgorithm::Rsa, None).unwrap(); //#[allow_ci]
+    ctx.enable_session_encryption(ek.key_handle);
+
+    let ak = ctx
+        .create_ak(
+            ek.key_handle,
+            HashAlgorithm::Sha256,
+            SignAlgorithm::RsaSsa,
+        )
+        .unwrap(); //#[allow_ci]
+    assert!(ctx.load_ak(ek.key_handle, &ak).is_ok());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn create_ek_ecc() {
+    use tss_esapi::structures::Public;
+
+    let mut ctx = Context::new().unwrap(); //#[allow_ci]
+    let ek = ctx.create_ek(EncryptionAlgorithm::Ecc, None).unwrap(); //#[allow_ci]
+    assert!(matches!(
+        &ek.public,
+        Public::Ecc { parameters, .. }
+            if parameters.ecc_curve() == EccCurve::NistP256
+    ));
+
+    let (public, _, _) = ctx.as_mut().read_public(ek.key_handle).unwrap(); //#[allow_ci]
+    assert_eq!(public, ek.public);
+    ctx.as_mut().flush_context(ek.key_handle.into()).unwrap(); //#[allow_ci]
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn read_clock_advances() {
+    let first = read_clock(&default_tcti()).unwrap(); //#[allow_ci]
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    let second = read_clock(&default_tcti()).unwrap(); //#[allow_ci]
+
+    // The time is in milliseconds and keeps counting since the TPM started
+    assert!(second.time() > first.time());
+    assert!(second.clock_info().clock() >= first.clock_info().clock());
+    assert_eq!(
+        second.clock_info().reset_count(),
+        first.clock_info().reset_count()
+    );
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn ek_cert_nv_index() {
+    use openssl::{
+        asn1::Asn1Time, ec::EcGroup, ec::EcKey, hash::MessageDigest,
+        nid::Nid, pkey::PKey, x509::X509NameBuilder, x509::X509,
+    };
+    use tss_esapi::{
+        attributes::NvIndexAttributesBuilder,
+        constants::CapabilityType,
+        interface_types::resource_handles::Provision,
+        structures::{CapabilityData, MaxNvBuffer, NvPublicBuilder},
+    };
+
+    // Owner index range, away from the indices used by the manufacturers
+    const INDEX: u32 = 0x0100_7e57;
+
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap(); //#[allow_ci]
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap(); //#[allow_ci]
+    let mut name = X509NameBuilder::new().unwrap(); //#[allow_ci]
+    name.append_entry_by_nid(Nid::COMMONNAME, "EK").unwrap(); //#[allow_ci]
+    let name = name.build();
+    let mut builder = X509::builder().unwrap(); //#[allow_ci]
+    builder.set_subject_name(&name).unwrap(); //#[allow_ci]
+    builder.set_issuer_name(&name).unwrap(); //#[allow_ci]
+    builder.set_pubkey(&key).unwrap(); //#[allow_ci]
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap()) //#[allow_ci]
+        .unwrap(); //#[allow_ci]
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap()) //#[allow_ci]
+        .unwrap(); //#[allow_ci]
+    builder.sign(&key, MessageDigest::sha256()).unwrap(); //#[allow_ci]
+    let cert = builder.build().to_der().unwrap(); //#[allow_ci]
+
+    let transient_handles = |ctx: &mut Context| -> usize {
+        let (data, _) = ctx
+            .as_mut()
+            .get_capability(
+                CapabilityType::Handles,
+                tss_esapi::constants::tss::TPM2_TRANSIENT_FIRST,
+                64,
+            )
+            .unwrap(); //#[allow_ci]
+        match data {
+            CapabilityData::Handles(handles) => handles.len(),
+            _ => 0,
+        }
+    };
+
+    let mut ctx = Context::new().unwrap(); //#[allow_ci]
+    assert!(ctx.set_ek_cert_nv_index(0x8101_0001).is_err());
+
+    // The index is not defined, and the EK created is flushed
+    ctx.set_ek_cert_nv_index(INDEX).unwrap(); //#[allow_ci]
+    let before = transient_handles(&mut ctx);
+    assert!(ctx.create_ek(EncryptionAlgorithm::Rsa, None).is_err());
+    assert_eq!(transient_handles(&mut ctx), before);
+
+    // Provision the certificate at the index
+    let attributes = NvIndexAttributesBuilder::new()
+        .with_auth_read(true)
+        .with_auth_write(true)
+        .build()
+        .unwrap(); //#[allow_ci]
+    let public = NvPublicBuilder::new()
+        .with_nv_index(NvIndexTpmHandle::new(INDEX).unwrap()) //#[allow_ci]
+        .with_index_name_algorithm(HashingAlgorithm::Sha256)
+        .with_index_attributes(attributes)
+        .with_data_area_size(cert.len())
+        .build()
+        .unwrap(); //#[allow_ci]
+    let nv_handle = ctx
+        .as_mut()
+        .execute_with_nullauth_session(|ctx| {
+            ctx.nv_define_space(Provision::Owner, None, public)
+        })
+        .unwrap(); //#[allow_ci]
+    let data = MaxNvBuffer::try_from(cert.clone()).unwrap(); //#[allow_ci]
+    ctx.as_mut()
+        .execute_with_nullauth_session(|ctx| {
+            ctx.nv_write(NvAuth::NvIndex(nv_handle), nv_handle, data, 0)
+        })
+        .unwrap(); //#[allow_ci]
+
+    let ek = ctx.create_ek(EncryptionAlgorithm::Rsa, None);
+    ctx.as_mut()
+        .execute_with_nullauth_session(|ctx| {
+            ctx.nv_undefine_space(Provision::Owner, nv_handle)
+        })
+        .unwrap(); //#[allow_ci]
+    let ek = ek.unwrap(); //#[allow_ci]
+    assert_eq!(ek.ek_cert, Some(cert));
+    ctx.as_mut().flush_context(ek.key_handle.into()).unwrap(); //#[allow_ci]
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn regenerate_persistent_ek() {
+    // Owner range of the persistent handles
+    const HANDLE: &str = "0x81007e57";
+
+    let mut ctx = Context::new().unwrap(); //#[allow_ci]
+    let public_at = |ctx: &mut Context| {
+        let handle = ctx
+            .as_mut()
+            .tr_from_tpm_public(TpmHandle::Persistent(
+                PersistentTpmHandle::new(0x8100_7e57).unwrap(), //#[allow_ci]
+            ))
+            .unwrap(); //#[allow_ci]
+        let (public, _, _) = ctx.as_mut().read_public(handle.into()).unwrap(); //#[allow_ci]
+        public
+    };
+
+    // Nothing is persisted at the handle yet
+    let ek = ctx
+        .regenerate_persistent_ek(EncryptionAlgorithm::Rsa, HANDLE)
+        .unwrap(); //#[allow_ci]
+    assert_eq!(public_at(&mut ctx), ek.public);
+
+    // Another key persisted at the handle is replaced by the EK
+    let ecc_ek = ctx
+        .regenerate_persistent_ek(EncryptionAlgorithm::Ecc, HANDLE)
+        .unwrap(); //#[allow_ci]
+    assert_eq!(public_at(&mut ctx), ecc_ek.public);
+    assert_ne!(ecc_ek.public, ek.public);
+    let ek = ctx
+        .regenerate_persistent_ek(EncryptionAlgorithm::Rsa, HANDLE)
+        .unwrap(); //#[allow_ci]
+    assert_eq!(public_at(&mut ctx), ek.public);
+
+    ctx.as_mut()
+        .execute_with_nullauth_session(|ctx| {
+            ctx.evict_control(
+                Provision::Owner,
+                ek.key_handle.into(),
+                Persistent::Persistent(
+                    PersistentTpmHandle::new(0x8100_7e57).unwrap(), //#[allow_ci]
+                ),
+            )
+        })
+        .unwrap(); //#[allow_ci]
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn ak_binding() {
+    let mut ctx = Context::new().unwrap(); //#[allow_ci]
+    let ek = ctx.create_ek(EncryptionAlgorithm::Rsa, None).unwrap(); //#[allow_ci]
+    let ak = ctx
+        .create_ak(
+            ek.key_handle,
+            HashAlgorithm::Sha256,
+            SignAlgorithm::RsaSsa,
+        )
+        .unwrap(); //#[allow_ci]
+    let ak_handle = ctx.load_ak(ek.key_handle, &ak).unwrap(); //#[allow_ci]
+
+    assert!(ctx.verify_ak_binding(ak_handle, ek.key_handle).is_ok());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn seal_data() {
+    let mut ctx = Context::new().unwrap(); //#[allow_ci]
+    let ek = ctx.create_ek(EncryptionAlgorithm::Rsa, None).unwrap(); //#[allow_ci]
+    let sealed = ctx.seal_data(ek.key_handle, b"secret").unwrap(); //#[allow_ci]
+    let unsealed = ctx.unseal_data(ek.key_handle, &sealed).unwrap(); //#[allow_ci]
+    assert_eq!(unsealed, b"secret");
+}
+
+#[test]
+fn tpm_error_response_code() {
+    // TPM_RC_VALUE for the first parameter, in the TPM layer
+    let err = TpmError::from(Tss2Error(Tss2ResponseCode::from(0x1c4)))
+        .in_command("TPM2_Quote");
+    assert_eq!(err.command(), Some("TPM2_Quote"));
+    assert_eq!(err.response_code(), Some(0x1c4));
+    assert!(err.to_string().starts_with("TPM2_Quote failed: "));
+
+    let err = TpmError::Other("not a TPM error".to_string());
+    assert_eq!(err.command(), None);
+    assert_eq!(err.response_code(), None);
+}
+
+#[test]
+fn ak_template() {
+    use tss_esapi::structures::Public;
+
+    let template = AkTemplate::new(2048, "nist_p192").unwrap(); //#[allow_ci]
+    assert_eq!(template, AkTemplate::default());
+    assert!(AkTemplate::new(1024, "nist_p192").is_err());
+    assert!(AkTemplate::new(2048, "brainpool").is_err());
+
+    let template = AkTemplate::new(3072, "nist_p256").unwrap(); //#[allow_ci]
+
+    let public = create_ak_public(
+        HashingAlgorithm::Sha256,
+        SignatureSchemeAlgorithm::RsaPss,
+        &template,
+    )
+    .unwrap(); //#[allow_ci]
+    assert!(matches!(
+        &public,
+        Public::Rsa { parameters, .. }
+            if parameters.key_bits() == RsaKeyBits::Rsa3072
+                && parameters.rsa_scheme().algorithm()
+                    == RsaSchemeAlgorithm::RsaPss
+    ));
+    assert!(template.matches(&public));
+    assert!(!AkTemplate::default().matches(&public));
+
+    let public = create_ak_public(
+        HashingAlgorithm::Sha256,
+        SignatureSchemeAlgorithm::EcDsa,
+        &template,
+    )
+    .unwrap(); //#[allow_ci]
+    assert!(matches!(
+        &public,
+        Public::Ecc { parameters, .. }
+            if parameters.ecc_curve() == EccCurve::NistP256
+                && parameters.ecc_scheme().algorithm()
+                    == EccSchemeAlgorithm::EcDsa
+    ));
+    assert!(template.matches(&public));
+}
+
 #[test]
 fn pubkey_to_digest() {
     use openssl::pkey::PKey;
@@ -959,6 +2056,104 @@ fn pubkey_to_digest() {
     assert!(pubkey_to_tpm_digest(pkey.as_ref()).is_ok());
 }
 
+#[test]
+fn format_pcr_values_banks() {
+    let selection = PcrSelectionListBuilder::new()
+        .with_selection(HashingAlgorithm::Sha1, &[PcrSlot::Slot10])
+        .with_selection(
+            HashingAlgorithm::Sha256,
+            &[PcrSlot::Slot10, PcrSlot::Slot16],
+        )
+        .build()
+        .unwrap(); //#[allow_ci]
+    let digests = |values: &[Vec<u8>]| {
+        let mut list = DigestList::new();
+        for value in values {
+            list.add(Digest::try_from(value.clone()).unwrap()).unwrap(); //#[allow_ci]
+        }
+        list
+    };
+
+    let pcr_data = PcrData::create(
+        &selection,
+        &digests(&[vec![0x01; 20], vec![0x02; 32], vec![0x03; 32]]),
+    )
+    .unwrap(); //#[allow_ci]
+    assert_eq!(
+        format_pcr_values(&pcr_data).unwrap(), //#[allow_ci]
+        vec![
+            format!("sha1:10:{}", "01".repeat(20)),
+            format!("sha256:10:{}", "02".repeat(32)),
+            format!("sha256:16:{}", "03".repeat(32)),
+        ]
+    );
+
+    // A SHA-1 sized value in the SHA-256 bank is rejected
+    let pcr_data = PcrData::create(
+        &selection,
+        &digests(&[vec![0x01; 20], vec![0x02; 20], vec![0x03; 32]]),
+    )
+    .unwrap(); //#[allow_ci]
+    assert!(format_pcr_values(&pcr_data).is_err());
+}
+
+#[test]
+fn quote_pcr_values_banks() {
+    // Two banks of 10 PCRs each, which do not fit in a single TPML_DIGEST
+    let slots = [
+        PcrSlot::Slot0,
+        PcrSlot::Slot1,
+        PcrSlot::Slot2,
+        PcrSlot::Slot3,
+        PcrSlot::Slot4,
+        PcrSlot::Slot5,
+        PcrSlot::Slot6,
+        PcrSlot::Slot7,
+        PcrSlot::Slot10,
+        PcrSlot::Slot16,
+    ];
+    let selection = PcrSelectionListBuilder::new()
+        .with_selection(HashingAlgorithm::Sha1, &slots)
+        .with_selection(HashingAlgorithm::Sha256, &slots)
+        .build()
+        .unwrap(); //#[allow_ci]
+    let sha1_values: Vec<Vec<u8>> = (0..10).map(|i| vec![i; 20]).collect();
+    let sha256_values: Vec<Vec<u8>> =
+        (0..10).map(|i| vec![0x80 + i; 32]).collect();
+    let digests: Vec<Digest> = sha1_values
+        .iter()
+        .chain(&sha256_values)
+        .map(|v| Digest::try_from(v.clone()).unwrap()) //#[allow_ci]
+        .collect();
+    let pcr_data = digests_to_pcrdata(&selection, digests).unwrap(); //#[allow_ci]
+
+    let blob = pcrdata_to_vec(selection, pcr_data);
+    let quote =
+        format!("rquote:ssig:{}", general_purpose::STANDARD.encode(blob));
+    let pcrs = [0, 1, 2, 3, 4, 5, 6, 7, 10, 16];
+    let expected: Vec<String> = pcrs
+        .iter()
+        .zip(&sha1_values)
+        .map(|(pcr, v)| format!("sha1:{pcr}:{}", hex::encode(v)))
+        .chain(
+            pcrs.iter()
+                .zip(&sha256_values)
+                .map(|(pcr, v)| format!("sha256:{pcr}:{}", hex::encode(v))),
+        )
+        .collect();
+    assert_eq!(quote_pcr_values(&quote).unwrap(), expected); //#[allow_ci]
+
+    // A truncated blob is rejected
+    let blob = general_purpose::STANDARD
+        .decode(quote.split(':').nth(2).unwrap()) //#[allow_ci]
+        .unwrap(); //#[allow_ci]
+    let truncated = format!(
+        "rquote:ssig:{}",
+        general_purpose::STANDARD.encode(&blob[..blob.len() - 1])
+    );
+    assert!(quote_pcr_values(&truncated).is_err());
+}
+
 #[test]
 fn mask() {
     assert_eq!(read_mask(0x0).unwrap(), vec![]); //#[allow_ci]